@@ -0,0 +1,73 @@
+use std::hint::black_box;
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use emacs_remote::messages::envelope;
+use emacs_remote::messages::index::IndexRequest;
+use emacs_remote::utils::frame::{
+    read_frame, write_frame, write_frame_compressed, DEFAULT_COMPRESSION_LEVEL,
+};
+use emacs_remote::utils::hash::hash;
+
+// What a large file read or index transfer puts on the wire: source text, which compresses,
+// and random bytes, which don't
+fn text(len: usize) -> Vec<u8> {
+    let line = b"    let resolved = server_daemon.resolve_in_workspace(&self.path)?;\n";
+    line.iter().copied().cycle().take(len).collect()
+}
+
+fn noise(len: usize) -> Vec<u8> {
+    (0..len).map(|_| rand::random::<u8>()).collect()
+}
+
+fn envelopes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("envelope");
+    let request = IndexRequest::new(hash("the previous index"), "src/structs".to_string());
+    group.bench_function("encode IndexRequest", |b| {
+        b.iter(|| envelope::encode(black_box(&request)).unwrap())
+    });
+    let encoded = envelope::encode(&request).unwrap();
+    group.bench_function("decode IndexRequest", |b| {
+        b.iter(|| envelope::decode::<IndexRequest>(black_box(&encoded)).unwrap())
+    });
+    group.finish();
+}
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame");
+    for (kind, payload) in [("text", text(1024 * 1024)), ("noise", noise(1024 * 1024))] {
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        let mut buf = Vec::with_capacity(payload.len() + 64);
+
+        group.bench_function(format!("write 1MiB {}", kind), |b| {
+            b.iter(|| {
+                buf.clear();
+                write_frame(&mut buf, black_box(&payload)).unwrap();
+            })
+        });
+        let plain = buf.clone();
+        group.bench_function(format!("read 1MiB {}", kind), |b| {
+            b.iter(|| read_frame(&mut Cursor::new(&plain)).unwrap())
+        });
+
+        group.bench_function(format!("write 1MiB {} zstd", kind), |b| {
+            b.iter(|| {
+                buf.clear();
+                write_frame_compressed(
+                    &mut buf,
+                    black_box(&payload),
+                    Some(DEFAULT_COMPRESSION_LEVEL),
+                )
+                .unwrap();
+            })
+        });
+        let compressed = buf.clone();
+        group.bench_function(format!("read 1MiB {} zstd", kind), |b| {
+            b.iter(|| read_frame(&mut Cursor::new(&compressed)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, envelopes, frames);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+use std::fs;
+use std::hint::black_box;
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use emacs_remote::structs::indexer::Indexer;
+use emacs_remote::utils::hash::hash;
+
+// A tree shaped like a mid-sized project: nested directories of small source files, a few
+// large ones and a build directory the ignore patterns leave out
+fn synthetic_tree(root: &Path) {
+    for module in 0..20 {
+        for submodule in 0..10 {
+            let dir = root.join(format!("src/module{}/sub{}", module, submodule));
+            fs::create_dir_all(&dir).unwrap();
+            for file in 0..10 {
+                let contents = format!("pub fn f{}() -> u32 {{ {} }}\n", file, module).repeat(40);
+                fs::write(dir.join(format!("file{}.rs", file)), contents).unwrap();
+            }
+        }
+    }
+    fs::create_dir_all(root.join("assets")).unwrap();
+    for large in 0..4 {
+        let contents = vec![large as u8; 4 * 1024 * 1024];
+        fs::write(root.join(format!("assets/large{}.bin", large)), contents).unwrap();
+    }
+    fs::create_dir_all(root.join("build")).unwrap();
+    for object in 0..200 {
+        fs::write(root.join(format!("build/out{}.o", object)), "object").unwrap();
+    }
+}
+
+fn hashing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash");
+    let contents = vec![7u8; 16 * 1024 * 1024];
+    group.throughput(Throughput::Bytes(contents.len() as u64));
+    group.bench_function("16MiB", |b| b.iter(|| hash(black_box(&contents))));
+    group.finish();
+}
+
+fn indexing(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    synthetic_tree(&root);
+    let indexer = Indexer::new(&root, &["build/".to_string()]);
+
+    let mut group = c.benchmark_group("index");
+    // a walk of the tree takes long enough that the default 100 samples would drag on
+    group.sample_size(20);
+    group.bench_function("walk 2000 files", |b| b.iter(|| indexer.files()));
+    group.bench_function("build 2000 files", |b| b.iter(|| indexer.build().unwrap()));
+    // what the server does on startup with an index that is still fresh
+    let previous = indexer.build().unwrap();
+    group.bench_function("rebuild 2000 unchanged files", |b| {
+        b.iter(|| indexer.rebuild(black_box(&previous)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, hashing, indexing);
+criterion_main!(benches);
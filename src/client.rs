@@ -1,12 +1,160 @@
+#![allow(clippy::needless_return)]
+
 use std::env;
-use std::path::PathBuf;
-use std::thread::sleep;
-use std::time::Duration;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use env_logger::Env;
+use log::{error, info};
 
+use emacs_remote::error::ClientError;
 use emacs_remote::structs::client::ClientDaemon;
-use emacs_remote::version::VERSION;
+use emacs_remote::utils::net::parse_bind_addr;
+use emacs_remote::utils::seal::PresharedKey;
+use emacs_remote::utils::signal::shutdown_on_signal;
+use emacs_remote::utils::span;
+use emacs_remote::utils::stcp::{
+    is_env_name, SSHOptions, DEFAULT_STRICT_HOST_KEY_CHECKING, SERVER_PSK_FILE,
+};
+use emacs_remote::utils::transport::SocketOptions;
+use emacs_remote::version::{protocol_version_string, LONG_VERSION, VERSION};
+
+fn compression_level(matches: &ArgMatches) -> Option<i32> {
+    return match matches
+        .value_of("compression_level")
+        .unwrap()
+        .parse::<i32>()
+    {
+        Ok(0) => None,
+        Ok(level) => Some(level),
+        Err(e) => {
+            error!("Invalid --compression-level: {}", e);
+            process::exit(1);
+        }
+    };
+}
+
+fn max_frame_size(matches: &ArgMatches) -> usize {
+    return match matches.value_of("max_frame_size").unwrap().parse::<usize>() {
+        Ok(mib) if mib > 0 => mib * 1024 * 1024,
+        _ => {
+            error!("Invalid --max-frame-size, expected a positive number of MiB");
+            process::exit(1);
+        }
+    };
+}
+
+fn remote_env(matches: &ArgMatches) -> Vec<(String, String)> {
+    let vars = match matches.values_of("remote_env") {
+        Some(vars) => vars,
+        None => return Vec::new(),
+    };
+    return vars
+        .map(|var| match var.split_once('=') {
+            Some((name, value)) if is_env_name(name) => (name.to_string(), value.to_string()),
+            _ => {
+                error!("Invalid --remote-env {:?}, expected NAME=VALUE", var);
+                process::exit(1);
+            }
+        })
+        .collect();
+}
+
+fn socket_options(matches: &ArgMatches) -> SocketOptions {
+    let buffer_size = match matches.value_of("socket_buffer_size") {
+        None => None,
+        Some(kib) => match kib.parse::<usize>() {
+            Ok(kib) if kib > 0 => Some(kib * 1024),
+            _ => {
+                error!("Invalid --socket-buffer-size, expected a positive number of KiB");
+                process::exit(1);
+            }
+        },
+    };
+    return SocketOptions {
+        nodelay: !matches.is_present("no_tcp_nodelay"),
+        buffer_size,
+    };
+}
+
+// Runs the --server-version or subcommand the client was started with against the server
+// and prints its response
+fn run_command(client_daemon: &mut ClientDaemon, matches: &ArgMatches) -> Result<(), ClientError> {
+    client_daemon.reset_tcp_connection()?;
+    if matches.is_present("server_version") {
+        let version = client_daemon.server_version()?;
+        println!(
+            "{} ({}), protocol {}",
+            version.server_version,
+            version.build_hash,
+            protocol_version_string(version.protocol_version)
+        );
+        return Ok(());
+    }
+
+    match matches.subcommand() {
+        ("ping", Some(_)) => {
+            let rtt = client_daemon.ping()?;
+            println!("{:.3} ms", rtt.as_secs_f64() * 1000.0);
+        }
+        ("open", Some(args)) => {
+            let path = args.value_of("path").unwrap();
+            let contents = client_daemon.read_file_with(path, args.is_present("force"))?;
+            io::stdout().write_all(&contents)?;
+        }
+        ("index", Some(args)) => {
+            let response = client_daemon.index(args.value_of("path").unwrap_or(""))?;
+            println!("{:x} {}", response.hash, response.path_to_index_file);
+        }
+        ("rescan", Some(args)) => {
+            let response = client_daemon.rescan(args.is_present("full"))?;
+            println!(
+                "{:x} {} files, {} changed",
+                response.hash, response.files, response.changed
+            );
+        }
+        ("watch", Some(args)) => {
+            let since = args.value_of("since").unwrap();
+            let since = match u128::from_str_radix(since, 16) {
+                Ok(since) => since,
+                Err(e) => {
+                    error!("Invalid --since, expected an index hash in hex: {}", e);
+                    process::exit(1);
+                }
+            };
+            let response = client_daemon.changes_since(since)?;
+            if response.too_old {
+                println!("{:x} too old, full rescan", response.index_hash);
+            } else {
+                println!("{:x}", response.index_hash);
+                for path in &response.paths {
+                    println!("{}", path);
+                }
+            }
+        }
+        ("grep", Some(args)) => {
+            let mut stdout = io::stdout();
+            client_daemon.grep_each(
+                args.value_of("pattern").unwrap(),
+                args.is_present("regex"),
+                args.is_present("ignore_case"),
+                args.value_of("glob"),
+                |grep_match| {
+                    // a closed pipe, e.g. into head, is not worth failing the search over
+                    let _ = writeln!(
+                        stdout,
+                        "{}:{}:{}",
+                        grep_match.path, grep_match.line_number, grep_match.line
+                    );
+                },
+            )?;
+        }
+        (name, _) => unreachable!("no such subcommand {}", name),
+    }
+    return Ok(());
+}
 
 fn main() {
     // Set up default emacs_remote path
@@ -16,6 +164,7 @@ fn main() {
 
     let app = App::new("emacs-remote-client-daemon")
         .version(VERSION)
+        .long_version(LONG_VERSION)
         .author("antoniojkim <contact@antoniojkim.com>")
         .about("Starts emacs remote client daemon")
         .arg(
@@ -40,6 +189,91 @@ fn main() {
                 .default_value(default_path.to_str().unwrap())
                 .help("Path to emacs-remote directory"),
         )
+        .arg(
+            Arg::with_name("ssh_binary")
+                .long("ssh-binary")
+                .default_value("ssh")
+                .help("ssh executable used to open the tunnel"),
+        )
+        .arg(
+            Arg::with_name("ssh_arg")
+                .long("ssh-arg")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .help("Extra argument passed to ssh before the host, e.g. --ssh-arg=-J --ssh-arg=bastion"),
+        )
+        .arg(
+            Arg::with_name("remote_env")
+                .long("remote-env")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("NAME=VALUE set for the server started over ssh, e.g. --remote-env RUST_LOG=debug"),
+        )
+        .arg(
+            Arg::with_name("strict_host_key_checking")
+                .long("strict-host-key-checking")
+                .default_value(DEFAULT_STRICT_HOST_KEY_CHECKING)
+                .possible_values(&["yes", "accept-new", "no"])
+                .help("StrictHostKeyChecking policy for the ssh tunnel, a changed host key is never accepted"),
+        )
+        .arg(
+            Arg::with_name("bind_addr")
+                .long("bind-addr")
+                .default_value("127.0.0.1")
+                .help("Address the daemon and both ends of the ssh tunnel listen on"),
+        )
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .takes_value(true)
+                .help("Connects to a server on this host over a Unix domain socket instead of an ssh tunnel"),
+        )
+        .arg(
+            Arg::with_name("workspace_name")
+                .long("workspace-name")
+                .takes_value(true)
+                .help("Selects the workspace of this name on a server started with --workspaces"),
+        )
+        .arg(
+            Arg::with_name("psk")
+                .long("psk")
+                .takes_value(true)
+                .help("Seals the connection to the server with the pre-shared key in this file, the server reads the same key from ~/.emacs_remote/psk"),
+        )
+        .arg(
+            Arg::with_name("max_frame_size")
+                .long("max-frame-size")
+                .default_value("64")
+                .help("Largest message accepted from a peer, in MiB"),
+        )
+        .arg(
+            Arg::with_name("no_tcp_nodelay")
+                .long("no-tcp-nodelay")
+                .takes_value(false)
+                .help("Lets Nagle's algorithm batch small writes, trading latency for fewer packets"),
+        )
+        .arg(
+            Arg::with_name("socket_buffer_size")
+                .long("socket-buffer-size")
+                .takes_value(true)
+                .help("Socket send and receive buffer size in KiB, left to the kernel by default"),
+        )
+        .arg(
+            Arg::with_name("compression_level")
+                .long("compression-level")
+                .default_value("3")
+                .help("zstd level for large messages, 0 disables compression"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .required(false)
+                .takes_value(false)
+                .help("Logs how long each request spends decoding, in its handler, encoding and on the wire"),
+        )
         .arg(
             Arg::with_name("daemon")
                 .short("d")
@@ -47,22 +281,166 @@ fn main() {
                 .required(false)
                 .takes_value(false)
                 .help("If true, starts the --daemon"),
+        )
+        .arg(
+            Arg::with_name("server_version")
+                .long("server-version")
+                .required(false)
+                .takes_value(false)
+                .help("Prints the version and build of the server for --host and exits"),
+        )
+        .subcommand(SubCommand::with_name("ping").about("Prints the round trip time to the server"))
+        .subcommand(
+            SubCommand::with_name("open")
+                .about("Prints a workspace file")
+                .arg(Arg::with_name("path").required(true).help("Path relative to the workspace"))
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Prints the file even if it is binary or over the server's read limit"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Has the server index the workspace and prints the index hash and file")
+                .arg(Arg::with_name("path").help("Only indexes under this path relative to the workspace")),
+        )
+        .subcommand(
+            SubCommand::with_name("rescan")
+                .about("Has the server rebuild its index, e.g. after a git checkout")
+                .arg(
+                    Arg::with_name("full")
+                        .long("full")
+                        .help("Rehashes every file, even those whose size and mtime are unchanged"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Prints the index hash and the files changed since the index had another one")
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Index hash, as printed by index or rescan"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("grep")
+                .about("Searches the indexed files, printing path:line:text for each match")
+                .arg(Arg::with_name("pattern").required(true))
+                .arg(
+                    Arg::with_name("regex")
+                        .short("e")
+                        .long("regex")
+                        .help("Treats the pattern as a regular expression instead of a literal"),
+                )
+                .arg(
+                    Arg::with_name("ignore_case")
+                        .short("i")
+                        .long("ignore-case")
+                        .help("Matches regardless of case"),
+                )
+                .arg(
+                    Arg::with_name("glob")
+                        .long("glob")
+                        .takes_value(true)
+                        .help("Only searches the paths matching this glob"),
+                ),
         );
 
     let matches = app.get_matches_from(env::args_os());
 
-    if matches.is_present("daemon") {
-        let mut client_daemon = ClientDaemon::new(
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    if matches.is_present("trace") {
+        span::init(io::stderr);
+    }
+
+    let command = matches.is_present("server_version") || matches.subcommand_name().is_some();
+    if matches.is_present("daemon") || command {
+        let mut client_daemon = match ClientDaemon::new(
             matches.value_of("host").unwrap().to_string(),
             matches.value_of("workspace").unwrap().to_string(),
             matches.value_of("emacs_remote_path").unwrap().to_string(),
-        )
-        .expect("Unable to create client daemon");
+        ) {
+            Ok(client_daemon) => client_daemon,
+            Err(e) => {
+                error!("Unable to start Client Daemon: {}", e);
+                process::exit(1);
+            }
+        };
+
+        client_daemon.set_ssh_options(SSHOptions {
+            binary: matches.value_of("ssh_binary").unwrap().to_string(),
+            extra_args: matches
+                .values_of("ssh_arg")
+                .map(|args| args.map(String::from).collect())
+                .unwrap_or_default(),
+            strict_host_key_checking: matches
+                .value_of("strict_host_key_checking")
+                .unwrap()
+                .to_string(),
+            server_psk: matches.value_of("psk").map(|_| SERVER_PSK_FILE.to_string()),
+            env: remote_env(&matches),
+            ..SSHOptions::default()
+        });
+        if let Some(path) = matches.value_of("psk") {
+            match PresharedKey::load(Path::new(path)) {
+                Ok(key) => client_daemon.set_preshared_key(Some(key)),
+                Err(e) => {
+                    error!("Unable to load the pre-shared key from {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        client_daemon.set_compression_level(compression_level(&matches));
+        client_daemon.set_max_frame_size(max_frame_size(&matches));
+        client_daemon.set_socket_options(socket_options(&matches));
+        client_daemon.set_socket(matches.value_of("socket").map(PathBuf::from));
+        if let Some(workspace_name) = matches.value_of("workspace_name") {
+            client_daemon.set_workspace_name(workspace_name.to_string());
+        }
+        match parse_bind_addr(matches.value_of("bind_addr").unwrap()) {
+            Ok(bind_addr) => client_daemon.set_bind_addr(bind_addr),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+
+        if let Err(e) = shutdown_on_signal(client_daemon.shutdown_flag()) {
+            error!("Unable to install signal handler: {}", e);
+            process::exit(1);
+        }
+
+        if let Err(e) = client_daemon.init() {
+            error!("Unable to start Client Daemon: {}", e);
+            process::exit(1);
+        }
+
+        if command {
+            let result = run_command(&mut client_daemon, &matches);
+            drop(client_daemon);
+            match result {
+                Ok(()) => {}
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        info!("Client Daemon Initialized!");
 
-        client_daemon.init();
-        println!("Client Daemon Initialized!");
+        let result = client_daemon.listen();
+        // Dropping the daemon tears down the ssh tunnel, process::exit would skip that
+        drop(client_daemon);
 
-        client_daemon.listen();
+        if let Err(e) = result {
+            error!("Client Daemon stopped: {}", e);
+            process::exit(1);
+        }
     } else {
         // handle request case here
     }
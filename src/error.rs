@@ -0,0 +1,426 @@
+extern crate rmp_serde as rmps;
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::messages::error::{
+    BINARY_OR_TOO_LARGE, CANCELLED, CONFLICT, INTERNAL, NOT_FOUND, PERMISSION_DENIED, UNSUPPORTED,
+};
+use crate::messages::messagetype::{MessageType, MessageTypeError};
+use crate::utils::frame::{self, FrameTooLarge};
+use crate::utils::hash::ContentHash;
+use crate::utils::transport::Endpoint;
+use crate::version::protocol_version_string;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    // the peer hung up between frames, an orderly disconnect rather than a failure
+    ConnectionClosed,
+    // nothing is listening on the local end of the tunnel, or on the socket
+    Connect {
+        endpoint: Endpoint,
+        source: io::Error,
+    },
+    Decode(rmps::decode::Error),
+    Encode(rmps::encode::Error),
+    UnexpectedMessageType {
+        expected: MessageType,
+        got: MessageType,
+    },
+    Protocol(String),
+    // the peer sent a message type this build doesn't know about
+    UnknownMessageType(u64),
+    // the peer announced a frame over the max_frame_size limit
+    FrameTooLarge {
+        size: usize,
+        max_frame_size: usize,
+    },
+    // persisted workspace state doesn't match the requested host/workspace
+    InvalidWorkspace(String),
+    WriteConflict {
+        path: String,
+        server_hash: ContentHash,
+    },
+    VersionMismatch {
+        client: u32,
+        server: u32,
+    },
+    // the server didn't answer within the request's timeout
+    Timeout(Duration),
+    // the server told us it can't serve this message type
+    Unsupported(MessageType),
+    // the server answered with an ErrorResponse
+    ServerReported {
+        code: u32,
+        message: String,
+    },
+    // ssh refused the host, its key doesn't match known_hosts
+    HostKeyChanged(String),
+    // ssh to the host failed, with ssh's exit status
+    HostUnreachable {
+        host: String,
+        status: i32,
+    },
+    // the server is a build with a different major version
+    ServerVersionMismatch {
+        client: String,
+        server: String,
+    },
+    // the contents received for a file don't hash to what the server said they would
+    ChecksumMismatch {
+        path: String,
+        expected: ContentHash,
+        actual: ContentHash,
+    },
+    // the server couldn't prove it holds our pre-shared key
+    PresharedKeyMismatch,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "io error: {}", e),
+            ClientError::ConnectionClosed => write!(f, "connection closed"),
+            ClientError::Connect { endpoint, source } => match endpoint {
+                Endpoint::Tcp(addr) => write!(
+                    f,
+                    "could not connect to server on port {}: {}",
+                    addr.port(),
+                    source
+                ),
+                Endpoint::Unix(path) => write!(
+                    f,
+                    "could not connect to server on socket {}: {}",
+                    path.display(),
+                    source
+                ),
+            },
+            ClientError::Decode(e) => write!(f, "failed to decode message: {}", e),
+            ClientError::Encode(e) => write!(f, "failed to encode message: {}", e),
+            ClientError::UnexpectedMessageType { expected, got } => write!(
+                f,
+                "unexpected message type: expected {:?}, got {:?}",
+                expected, got
+            ),
+            ClientError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            ClientError::UnknownMessageType(n) => write!(f, "unknown message type {}", n),
+            ClientError::FrameTooLarge {
+                size,
+                max_frame_size,
+            } => write!(
+                f,
+                "refusing a frame of {} bytes, the limit is {} bytes",
+                size, max_frame_size
+            ),
+            ClientError::InvalidWorkspace(msg) => write!(f, "invalid workspace: {}", msg),
+            ClientError::WriteConflict { path, server_hash } => write!(
+                f,
+                "{} was modified on the server (now {:x}), refusing to overwrite",
+                path, server_hash
+            ),
+            ClientError::VersionMismatch { client, server } => write!(
+                f,
+                "client speaks protocol {} but the server speaks {}, upgrade the older one",
+                protocol_version_string(*client),
+                protocol_version_string(*server)
+            ),
+            ClientError::Timeout(timeout) => write!(
+                f,
+                "server did not respond within {} milliseconds",
+                timeout.as_millis()
+            ),
+            ClientError::Unsupported(msgtype) => {
+                write!(f, "the server does not support {:?}", msgtype)
+            }
+            ClientError::ServerReported { code, message } => {
+                write!(f, "server error {}: {}", code, message)
+            }
+            ClientError::HostKeyChanged(host) => write!(
+                f,
+                "the host key of {} has changed, refusing to connect until it is verified",
+                host
+            ),
+            ClientError::HostUnreachable { host, status } => write!(
+                f,
+                "unable to reach {} over ssh, it exited with status {}",
+                host, status
+            ),
+            ClientError::ServerVersionMismatch { client, server } => write!(
+                f,
+                "client is version {} but the server is version {}, upgrade the older one",
+                client, server
+            ),
+            ClientError::ChecksumMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} was corrupted in transit, expected hash {:x} but got {:x}",
+                path, expected, actual
+            ),
+            ClientError::PresharedKeyMismatch => write!(
+                f,
+                "the server's pre-shared key doesn't match ours, refusing to talk to it"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl ClientError {
+    // The ErrorResponse code reported to emacs when a request fails
+    pub fn code(&self) -> u32 {
+        return match self {
+            ClientError::ServerReported { code, .. } => *code,
+            ClientError::WriteConflict { .. } => CONFLICT,
+            ClientError::Unsupported(_) => UNSUPPORTED,
+            ClientError::HostKeyChanged(_) => PERMISSION_DENIED,
+            ClientError::PresharedKeyMismatch => PERMISSION_DENIED,
+            ClientError::Io(e) => io_error_code(e),
+            ClientError::Connect { source, .. } => io_error_code(source),
+            _ => INTERNAL,
+        };
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        if frame::is_closed(&e) {
+            return ClientError::ConnectionClosed;
+        }
+        if let Some(too_large) = e.get_ref().and_then(|e| e.downcast_ref::<FrameTooLarge>()) {
+            return ClientError::FrameTooLarge {
+                size: too_large.size,
+                max_frame_size: too_large.max_frame_size,
+            };
+        }
+        ClientError::Io(e)
+    }
+}
+
+impl From<rmps::decode::Error> for ClientError {
+    fn from(e: rmps::decode::Error) -> Self {
+        ClientError::Decode(e)
+    }
+}
+
+impl From<rmps::encode::Error> for ClientError {
+    fn from(e: rmps::encode::Error) -> Self {
+        ClientError::Encode(e)
+    }
+}
+
+impl From<MessageTypeError> for ClientError {
+    fn from(e: MessageTypeError) -> Self {
+        match e {
+            MessageTypeError::Untagged => ClientError::Protocol(
+                "message is not an array tagged with a message type".to_string(),
+            ),
+            MessageTypeError::Unknown(n) => ClientError::UnknownMessageType(n),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+    Io(io::Error),
+    Decode(rmps::decode::Error),
+    Encode(rmps::encode::Error),
+    UnexpectedMessageType {
+        expected: MessageType,
+        got: MessageType,
+    },
+    Protocol(String),
+    // the peer sent a message type this build doesn't know about
+    UnknownMessageType(u64),
+    FrameTooLarge {
+        size: usize,
+        max_frame_size: usize,
+    },
+    // a client supplied path resolved to somewhere outside of the workspace
+    PathEscape(String),
+    // the workspace isn't under any of the server's allowed workspaces
+    WorkspaceNotAllowed(String),
+    WorkspaceNotFound(String),
+    // the workspace exists but is a file or something else that can't be served
+    WorkspaceNotADirectory(String),
+    // the handshake asked for a workspace the server doesn't serve
+    UnknownWorkspace(String),
+    // the server's authorizer refused the request, or it is read-only
+    Forbidden(MessageType),
+    // the config file couldn't be parsed
+    Config(String),
+    VersionMismatch {
+        client: u32,
+        server: u32,
+    },
+    // a read that wasn't forced hit a binary file
+    BinaryFile(String),
+    // a read that wasn't forced hit a file over the max read size
+    FileTooLarge {
+        path: String,
+        size: u64,
+        max_read_size: u64,
+    },
+    // a CancelRequest stopped the request with this id
+    Cancelled(u64),
+    // the client didn't seal the connection with the server's pre-shared key
+    PresharedKeyRequired,
+    // the client sent a KeyExchangeRequest to a server started without --psk
+    NoPresharedKey,
+    // something else is already listening where the server was to listen
+    AddrInUse(Endpoint),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Io(e) => write!(f, "io error: {}", e),
+            ServerError::Decode(e) => write!(f, "failed to decode message: {}", e),
+            ServerError::Encode(e) => write!(f, "failed to encode message: {}", e),
+            ServerError::UnexpectedMessageType { expected, got } => write!(
+                f,
+                "unexpected message type: expected {:?}, got {:?}",
+                expected, got
+            ),
+            ServerError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            ServerError::UnknownMessageType(n) => write!(f, "unknown message type {}", n),
+            ServerError::FrameTooLarge {
+                size,
+                max_frame_size,
+            } => write!(
+                f,
+                "refusing a frame of {} bytes, the limit is {} bytes",
+                size, max_frame_size
+            ),
+            ServerError::PathEscape(path) => write!(f, "{} is outside of the workspace", path),
+            ServerError::WorkspaceNotAllowed(workspace) => write!(
+                f,
+                "{} is not inside any of the workspaces this server allows",
+                workspace
+            ),
+            ServerError::WorkspaceNotFound(workspace) => {
+                write!(f, "workspace {} not found", workspace)
+            }
+            ServerError::WorkspaceNotADirectory(workspace) => {
+                write!(f, "workspace {} is not a directory", workspace)
+            }
+            ServerError::UnknownWorkspace(name) => {
+                write!(f, "this server has no workspace named {}", name)
+            }
+            ServerError::Forbidden(msgtype) => {
+                write!(f, "{:?} is not allowed on this server", msgtype)
+            }
+            ServerError::Config(msg) => write!(f, "invalid config: {}", msg),
+            ServerError::VersionMismatch { client, server } => write!(
+                f,
+                "client speaks protocol {} but the server speaks {}",
+                protocol_version_string(*client),
+                protocol_version_string(*server)
+            ),
+            ServerError::BinaryFile(path) => {
+                write!(f, "{} looks like a binary file, not sending it", path)
+            }
+            ServerError::FileTooLarge {
+                path,
+                size,
+                max_read_size,
+            } => write!(
+                f,
+                "{} is {} bytes, over the limit of {} bytes, not sending it",
+                path, size, max_read_size
+            ),
+            ServerError::Cancelled(request_id) => write!(f, "request {} was cancelled", request_id),
+            ServerError::PresharedKeyRequired => write!(
+                f,
+                "this server only accepts connections sealed with its pre-shared key"
+            ),
+            ServerError::NoPresharedKey => {
+                write!(
+                    f,
+                    "this server has no pre-shared key to seal connections with"
+                )
+            }
+            ServerError::AddrInUse(Endpoint::Tcp(addr)) => write!(
+                f,
+                "port {} already in use; is another emacs-remote server running?",
+                addr.port()
+            ),
+            ServerError::AddrInUse(Endpoint::Unix(path)) => write!(
+                f,
+                "{} already in use; is another emacs-remote server running?",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl ServerError {
+    // The ErrorResponse code reported to the client when a request fails
+    pub fn code(&self) -> u32 {
+        return match self {
+            ServerError::Io(e) => io_error_code(e),
+            ServerError::PathEscape(_) => PERMISSION_DENIED,
+            ServerError::WorkspaceNotAllowed(_) => PERMISSION_DENIED,
+            ServerError::WorkspaceNotFound(_) => NOT_FOUND,
+            ServerError::UnknownWorkspace(_) => NOT_FOUND,
+            ServerError::Forbidden(_) => PERMISSION_DENIED,
+            ServerError::BinaryFile(_) => BINARY_OR_TOO_LARGE,
+            ServerError::FileTooLarge { .. } => BINARY_OR_TOO_LARGE,
+            ServerError::Cancelled(_) => CANCELLED,
+            ServerError::PresharedKeyRequired => PERMISSION_DENIED,
+            ServerError::NoPresharedKey => UNSUPPORTED,
+            _ => INTERNAL,
+        };
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        if let Some(too_large) = e.get_ref().and_then(|e| e.downcast_ref::<FrameTooLarge>()) {
+            return ServerError::FrameTooLarge {
+                size: too_large.size,
+                max_frame_size: too_large.max_frame_size,
+            };
+        }
+        ServerError::Io(e)
+    }
+}
+
+impl From<rmps::decode::Error> for ServerError {
+    fn from(e: rmps::decode::Error) -> Self {
+        ServerError::Decode(e)
+    }
+}
+
+impl From<rmps::encode::Error> for ServerError {
+    fn from(e: rmps::encode::Error) -> Self {
+        ServerError::Encode(e)
+    }
+}
+
+impl From<MessageTypeError> for ServerError {
+    fn from(e: MessageTypeError) -> Self {
+        match e {
+            MessageTypeError::Untagged => ServerError::Protocol(
+                "message is not an array tagged with a message type".to_string(),
+            ),
+            MessageTypeError::Unknown(n) => ServerError::UnknownMessageType(n),
+        }
+    }
+}
+
+fn io_error_code(e: &io::Error) -> u32 {
+    return match e.kind() {
+        io::ErrorKind::NotFound => NOT_FOUND,
+        io::ErrorKind::PermissionDenied => PERMISSION_DENIED,
+        io::ErrorKind::AlreadyExists => CONFLICT,
+        _ => INTERNAL,
+    };
+}
@@ -1,12 +1,160 @@
-use std::net::TcpStream;
+extern crate rmp_serde as rmps;
 
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+use tracing::info_span;
+
+use crate::error::{ClientError, ServerError};
+use crate::messages::archive::ArchiveRequest;
+use crate::messages::batch::BatchRequest;
+use crate::messages::cancel::CancelRequest;
+use crate::messages::changes::ChangesSinceRequest;
+use crate::messages::completion::CompletionRequest;
+use crate::messages::diff::DiffRequest;
+use crate::messages::dirlist::DirectoryListRequest;
+use crate::messages::envelope;
+use crate::messages::fileops::{DeleteRequest, MkdirRequest, RenameRequest, TouchRequest};
+use crate::messages::fileread::FileReadRequest;
+use crate::messages::filestat::FileStatRequest;
+use crate::messages::filewrite::FileWriteRequest;
+use crate::messages::grep::GrepRequest;
+use crate::messages::history::{HistoryRequest, RestoreRequest};
+use crate::messages::index::IndexRequest;
+use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+use crate::messages::ping::PingRequest;
+use crate::messages::rescan::RescanRequest;
+use crate::messages::saveall::SaveAllRequest;
+use crate::messages::version::VersionRequest;
+use crate::messages::watch::{UnwatchRequest, WatchRequest};
+use crate::messages::workspaceinfo::WorkspaceInfoRequest;
 use crate::structs::client::ClientDaemon;
 use crate::structs::server::ServerDaemon;
+use crate::utils::connection::Connection;
+use crate::utils::span;
 
 pub trait HandleClientDaemon {
-    fn handle(&self, stream: &mut TcpStream, client_daemon: &mut ClientDaemon) -> Result<(), ()>;
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError>;
 }
 
 pub trait HandleServerDaemon {
-    fn handle(&self, stream: &mut TcpStream, server_daemon: &mut ServerDaemon) -> Result<(), ()>;
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError>;
+}
+
+// Decodes a raw request and hands it to its handler
+pub type ClientHandler = fn(&mut ClientDaemon, &mut Connection, &[u8]) -> Result<(), ClientError>;
+pub type ServerHandler = fn(&ServerDaemon, &mut Connection, &[u8]) -> Result<(), ServerError>;
+
+// Maps each request type to the handler that decodes and serves it
+pub struct Dispatcher<H> {
+    handlers: HashMap<MessageType, H>,
+}
+
+impl<H: Copy> Dispatcher<H> {
+    pub fn get(&self, msgtype: MessageType) -> Option<H> {
+        return self.handlers.get(&msgtype).copied();
+    }
+
+    // Every registered request type, in discriminant order
+    pub fn message_types(&self) -> Vec<MessageType> {
+        let mut message_types: Vec<MessageType> = self.handlers.keys().copied().collect();
+        message_types.sort_by_key(|msgtype| u64::from(*msgtype));
+        return message_types;
+    }
 }
+
+impl Dispatcher<ClientHandler> {
+    pub fn register<T>(&mut self)
+    where
+        T: DeserializeOwned + MessageTypeTrait + HandleClientDaemon,
+    {
+        self.handlers
+            .insert(T::messagetype(), |client_daemon, stream, buf| {
+                let request: T = {
+                    let _span = info_span!(target: span::TARGET, "decode").entered();
+                    envelope::decode(buf)?
+                };
+                let _span = info_span!(target: span::TARGET, "handler").entered();
+                return request.handle(stream, client_daemon);
+            });
+    }
+}
+
+impl Dispatcher<ServerHandler> {
+    pub fn register<T>(&mut self)
+    where
+        T: DeserializeOwned + MessageTypeTrait + HandleServerDaemon,
+    {
+        self.handlers
+            .insert(T::messagetype(), |server_daemon, stream, buf| {
+                let request: T = {
+                    let _span = info_span!(target: span::TARGET, "decode").entered();
+                    envelope::decode(buf)?
+                };
+                let _span = info_span!(target: span::TARGET, "handler").entered();
+                return request.handle(stream, server_daemon);
+            });
+    }
+}
+
+// Requests that emacs sends to the client daemon, which forwards them to the server.
+// A new request type only has to be added here once it implements both handle traits.
+macro_rules! requests {
+    ($($request:ty),* $(,)?) => {
+        impl Dispatcher<ClientHandler> {
+            pub fn client() -> Dispatcher<ClientHandler> {
+                let mut dispatcher: Dispatcher<ClientHandler> = Dispatcher {
+                    handlers: HashMap::new(),
+                };
+                $(dispatcher.register::<$request>();)*
+                return dispatcher;
+            }
+        }
+
+        impl Dispatcher<ServerHandler> {
+            pub fn server() -> Dispatcher<ServerHandler> {
+                let mut dispatcher: Dispatcher<ServerHandler> = Dispatcher {
+                    handlers: HashMap::new(),
+                };
+                $(dispatcher.register::<$request>();)*
+                return dispatcher;
+            }
+        }
+    };
+}
+
+requests!(
+    IndexRequest,
+    FileReadRequest,
+    FileWriteRequest,
+    DirectoryListRequest,
+    PingRequest,
+    GrepRequest,
+    WorkspaceInfoRequest,
+    RenameRequest,
+    DeleteRequest,
+    CompletionRequest,
+    WatchRequest,
+    UnwatchRequest,
+    BatchRequest,
+    VersionRequest,
+    SaveAllRequest,
+    FileStatRequest,
+    TouchRequest,
+    MkdirRequest,
+    CancelRequest,
+    ArchiveRequest,
+    HistoryRequest,
+    RestoreRequest,
+    RescanRequest,
+    ChangesSinceRequest,
+    DiffRequest,
+);
@@ -0,0 +1,157 @@
+extern crate rmp_serde as rmps;
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::archive::{ArchiveChunk, ArchiveDone, ArchiveRequest};
+use crate::messages::envelope;
+use crate::messages::fileread::CHUNK_SIZE;
+use crate::structs::client::ClientDaemon;
+use crate::structs::index::mtime_millis;
+use crate::structs::server::{RequestKey, ServerDaemon};
+use crate::utils;
+use crate::utils::archive::ArchiveWriter;
+use crate::utils::connection::Connection;
+use crate::utils::walk::pattern_matcher;
+
+impl HandleClientDaemon for ArchiveRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.pull_archive(&self.path, &self.ignore, self.compress)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for ArchiveRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let root = server_daemon.resolve_in_workspace("")?;
+        let archive_root = server_daemon.resolve_in_workspace(&self.path)?;
+        // Paths in the archive are relative to the workspace, the same way clients refer
+        // to them, so that unpacking it anywhere mirrors the workspace
+        let files = match archive_root.strip_prefix(&root) {
+            Ok(prefix) => server_daemon.index()?.entries_under(prefix).0,
+            Err(_) => return Err(ServerError::PathEscape(self.path.clone())),
+        };
+        let ignore = pattern_matcher(&root, &self.ignore);
+        let files: Vec<String> = files
+            .into_iter()
+            .filter(|file| {
+                !ignore
+                    .matched_path_or_any_parents(root.join(file), false)
+                    .is_ignore()
+            })
+            .collect();
+
+        let request = RequestKey::of(stream);
+        let chunks = ChunkWriter::new(stream);
+        let (archived, size) = if self.compress {
+            let level = server_daemon.compression_level.unwrap_or(0);
+            let encoder = zstd::Encoder::new(chunks, level)?;
+            let (encoder, archived, size) =
+                write_archive(encoder, server_daemon, request, &root, &files)?;
+            encoder.finish()?.finish()?;
+            (archived, size)
+        } else {
+            let (chunks, archived, size) =
+                write_archive(chunks, server_daemon, request, &root, &files)?;
+            chunks.finish()?;
+            (archived, size)
+        };
+
+        let buffer = envelope::encode(&ArchiveDone::new(archived, size))?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+// Returns the writer along with the files archived and the size of the tar
+fn write_archive<W: Write>(
+    writer: W,
+    server_daemon: &ServerDaemon,
+    request: RequestKey,
+    root: &Path,
+    files: &[String],
+) -> Result<(W, u64, u64), ServerError> {
+    let mut archive = ArchiveWriter::new(writer);
+    let mut archived = 0;
+    for file in files {
+        server_daemon.check_request_cancelled(request)?;
+
+        let path = root.join(file);
+        // files deleted since they were indexed are left out
+        let (mut opened, metadata) = match File::open(&path).and_then(|f| {
+            let metadata = f.metadata()?;
+            Ok((f, metadata))
+        }) {
+            Ok(opened) => opened,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(ServerError::Io(e)),
+        };
+        let mtime = mtime_millis(&metadata) / 1000;
+        let mode = metadata.permissions().mode();
+        archive.append_reader(file, mode, mtime, metadata.len(), &mut opened)?;
+        archived += 1;
+    }
+    let size = archive.written();
+    return Ok((archive.finish()?, archived, size));
+}
+
+// Sends what is written to it as ArchiveChunks of up to CHUNK_SIZE bytes
+struct ChunkWriter<'a> {
+    stream: &'a mut Connection,
+    buf: Vec<u8>,
+}
+
+impl<'a> ChunkWriter<'a> {
+    fn new(stream: &'a mut Connection) -> ChunkWriter<'a> {
+        return ChunkWriter {
+            stream,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = ArchiveChunk::new(std::mem::take(&mut self.buf));
+        let buffer = envelope::encode(&chunk).map_err(io::Error::other)?;
+        self.buf.reserve(CHUNK_SIZE);
+        return self.stream.write_frame(&buffer);
+    }
+
+    // Sends whatever is left over
+    fn finish(mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send()?;
+        }
+        return Ok(());
+    }
+}
+
+impl<'a> Write for ChunkWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.send()?;
+        }
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
@@ -0,0 +1,47 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::batch::{BatchRequest, BatchResponse, Envelope};
+use crate::messages::envelope;
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::{Caller, ServerDaemon};
+use crate::utils;
+use crate::utils::connection::Connection;
+
+impl HandleClientDaemon for BatchRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        // Not retried, part of the batch may already have run
+        let response = client_daemon.send_request_once(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for BatchRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        // requests in the batch are authorized one by one, as if they came on their own
+        let caller = Caller::of(stream);
+        let mut responses = Vec::with_capacity(self.requests.len());
+        for request in &self.requests {
+            let message = server_daemon.handle_batched(&caller, &request.message)?;
+            responses.push(Envelope::new(message));
+        }
+        let response = BatchResponse::new(responses);
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::cancel::{CancelRequest, CancelResponse};
+use crate::messages::envelope;
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::{RequestKey, ServerDaemon};
+use crate::utils;
+use crate::utils::connection::Connection;
+
+impl HandleClientDaemon for CancelRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for CancelRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let response =
+            CancelResponse::new(server_daemon.cancel(RequestKey::with_id(stream, self.request_id)));
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::changes::{ChangesSinceRequest, ChangesSinceResponse};
+use crate::messages::envelope;
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+
+impl HandleClientDaemon for ChangesSinceRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.changes_since(self.since_hash)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for ChangesSinceRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let response = match server_daemon.changes_since(self.since_hash) {
+            (index_hash, Some(paths)) => ChangesSinceResponse::new(index_hash, paths),
+            (index_hash, None) => ChangesSinceResponse::too_old(index_hash),
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
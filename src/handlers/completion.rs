@@ -0,0 +1,65 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::completion::{CompletionRequest, CompletionResponse};
+use crate::messages::envelope;
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+
+// Completions are for a human to pick from, more than this is never useful
+pub const MAX_COMPLETIONS: usize = 1000;
+
+impl HandleClientDaemon for CompletionRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for CompletionRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let limit = (self.limit as usize).min(MAX_COMPLETIONS);
+
+        // Scoring touches nothing but the paths, so it is done under the index lock
+        let scorer = server_daemon.scorer();
+        let mut matches: Vec<(i64, String)> = {
+            let index = server_daemon.index()?;
+            index
+                .files
+                .keys()
+                .filter_map(|path| Some((scorer.score(&self.prefix, path)?, path.clone())))
+                .collect()
+        };
+        // equally good matches go to the shorter path
+        matches.sort_by(|(a_score, a), (b_score, b)| {
+            b_score
+                .cmp(a_score)
+                .then(a.len().cmp(&b.len()))
+                .then(a.cmp(b))
+        });
+
+        let truncated = matches.len() > limit;
+        matches.truncate(limit);
+        let (scores, paths) = matches.into_iter().unzip();
+        let response = CompletionResponse::scored(paths, scores, truncated);
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
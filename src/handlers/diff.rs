@@ -0,0 +1,92 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+
+use similar::TextDiff;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::diff::{DiffRequest, DiffResponse, MAX_DIFF_SIZE};
+use crate::messages::envelope;
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::binary::looks_binary;
+use crate::utils::connection::Connection;
+use crate::utils::hash;
+
+impl HandleClientDaemon for DiffRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.diff(&self.path, self.client_hash)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for DiffRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = server_daemon.resolve_in_workspace(&self.path)?;
+        let rel = server_daemon.relative_to_workspace(&path)?;
+
+        // Too large to diff whatever the base, hashed without reading the file into memory
+        let len = fs::metadata(&path)?.len();
+        if len > MAX_DIFF_SIZE as u64 {
+            let server_hash = hash::hash_reader(&mut File::open(&path)?, len)?;
+            let response = match server_hash == self.client_hash {
+                true => DiffResponse::identical(server_hash),
+                false => DiffResponse::too_large(server_hash),
+            };
+            let buffer = envelope::encode(&response)?;
+            stream.write_frame(&buffer)?;
+            return Ok(());
+        }
+
+        let current = fs::read(&path)?;
+        let server_hash = hash::hash(&current);
+        let response = if server_hash == self.client_hash {
+            DiffResponse::identical(server_hash)
+        } else {
+            let base = match server_daemon.lock_history()?.as_ref() {
+                Some(history) => history.contents_by_hash(&rel, self.client_hash)?,
+                None => {
+                    return Err(ServerError::Io(io::Error::new(
+                        ErrorKind::NotFound,
+                        "this server keeps no history",
+                    )))
+                }
+            };
+            match (diffable(&base), diffable(&current)) {
+                (Some(base), Some(current)) => {
+                    let diff = TextDiff::from_lines(base, current)
+                        .unified_diff()
+                        .header(&format!("a/{}", rel), &format!("b/{}", rel))
+                        .to_string();
+                    DiffResponse::new(server_hash, diff)
+                }
+                _ => DiffResponse::too_large(server_hash),
+            }
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+// The contents as text, None if they are too large or binary to diff
+fn diffable(contents: &[u8]) -> Option<&str> {
+    if contents.len() > MAX_DIFF_SIZE || looks_binary(contents) {
+        return None;
+    }
+    return std::str::from_utf8(contents).ok();
+}
@@ -0,0 +1,96 @@
+extern crate rmp_serde as rmps;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::dirlist::{DirEntry, DirectoryListRequest, DirectoryListResponse};
+use crate::messages::envelope;
+use crate::structs::client::ClientDaemon;
+use crate::structs::index::mtime_millis;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+
+impl HandleClientDaemon for DirectoryListRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for DirectoryListRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let root = fs::canonicalize(&server_daemon.workspace)?;
+        let path = server_daemon.resolve_in_workspace(&self.path)?;
+
+        let mut entries = Vec::new();
+        list(&root, &path, "", self.depth.max(1), &mut entries)?;
+
+        let response = DirectoryListResponse::new(entries);
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+fn list(
+    root: &Path,
+    dir: &Path,
+    prefix: &str,
+    depth: u32,
+    entries: &mut Vec<DirEntry>,
+) -> io::Result<()> {
+    let mut children: Vec<fs::DirEntry> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let name = format!("{}{}", prefix, child.file_name().to_string_lossy());
+        let link_metadata = fs::symlink_metadata(child.path())?;
+        let is_symlink = link_metadata.file_type().is_symlink();
+
+        // Symlinks are only followed when they resolve to somewhere inside the workspace
+        let metadata = if is_symlink {
+            match fs::canonicalize(child.path()) {
+                Ok(target) if target.starts_with(root) => fs::metadata(&target)?,
+                _ => link_metadata,
+            }
+        } else {
+            link_metadata
+        };
+
+        entries.push(DirEntry {
+            name: name.clone(),
+            is_dir: metadata.is_dir(),
+            is_symlink,
+            size: metadata.len(),
+            mtime: mtime_millis(&metadata),
+        });
+
+        if metadata.is_dir() && depth > 1 {
+            list(
+                root,
+                &child.path(),
+                &format!("{}/", name),
+                depth - 1,
+                entries,
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,235 @@
+extern crate rmp_serde as rmps;
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::fileops::{
+    DeleteRequest, FileOpResponse, MkdirRequest, RenameRequest, TouchRequest,
+};
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+use crate::utils::walk::walk_files_with;
+
+impl HandleClientDaemon for RenameRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request_once(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for RenameRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let from = resolve_below_root(server_daemon, &self.from)?;
+        let to = resolve_below_root(server_daemon, &self.to)?;
+
+        // Locks are released before the response goes out
+        let response = {
+            let _guard = server_daemon.lock_writes();
+            let success = if to.exists() {
+                false
+            } else {
+                // Every file that moved is a removal at its old path and an addition at the new
+                let mut changed = tracked_under(server_daemon, &from)?;
+                fs::rename(&from, &to)?;
+                changed.extend(files_under(server_daemon, &to));
+                server_daemon.file_changed(&changed)?;
+                true
+            };
+            FileOpResponse::new(success, server_daemon.index()?.hash)
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+impl HandleClientDaemon for DeleteRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request_once(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for DeleteRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = resolve_below_root(server_daemon, &self.path)?;
+
+        let response = {
+            let _guard = server_daemon.lock_writes();
+            let changed = tracked_under(server_daemon, &path)?;
+            let metadata = fs::symlink_metadata(&path)?;
+            if !metadata.is_dir() {
+                fs::remove_file(&path)?;
+            } else if self.recursive {
+                fs::remove_dir_all(&path)?;
+            } else {
+                // fails on non-empty directories
+                fs::remove_dir(&path)?;
+            }
+            server_daemon.file_changed(&changed)?;
+            FileOpResponse::new(true, server_daemon.index()?.hash)
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+impl HandleClientDaemon for TouchRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        // Not retried, a second attempt would find the file the first one created
+        let response = client_daemon.send_request_once(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for TouchRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = resolve_below_root(server_daemon, &self.path)?;
+
+        let response = {
+            let _guard = server_daemon.lock_writes();
+            // fails with AlreadyExists rather than truncating a file that is there
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            server_daemon.file_changed(&[path])?;
+            FileOpResponse::new(true, server_daemon.index()?.hash)
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+impl HandleClientDaemon for MkdirRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request_once(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for MkdirRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let response = {
+            let _guard = server_daemon.lock_writes();
+            if self.recursive {
+                // One level at a time, so that each directory is resolved, and checked to be
+                // in the workspace, once its parent exists
+                let mut created = false;
+                let mut prefix = PathBuf::new();
+                for component in Path::new(&self.path).components() {
+                    prefix.push(component);
+                    let dir = resolve_below_root(server_daemon, &prefix.to_string_lossy())?;
+                    if !dir.is_dir() {
+                        fs::create_dir(&dir)?;
+                        created = true;
+                    }
+                }
+                if !created {
+                    return Err(ServerError::Io(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} already exists", self.path),
+                    )));
+                }
+            } else {
+                fs::create_dir(resolve_below_root(server_daemon, &self.path)?)?;
+            }
+            // an empty directory has nothing to index
+            FileOpResponse::new(true, server_daemon.index()?.hash)
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+// resolve_in_workspace, but the workspace root itself can't be moved or deleted
+fn resolve_below_root(server_daemon: &ServerDaemon, rel: &str) -> Result<PathBuf, ServerError> {
+    let path = server_daemon.resolve_in_workspace(rel)?;
+    if path == server_daemon.resolve_in_workspace("")? {
+        return Err(ServerError::Io(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "refusing to modify the workspace root",
+        )));
+    }
+    return Ok(path);
+}
+
+// Absolute paths of the indexed files at or below `path`
+fn tracked_under(server_daemon: &ServerDaemon, path: &Path) -> Result<Vec<PathBuf>, ServerError> {
+    let root = server_daemon.resolve_in_workspace("")?;
+    let prefix = match path.strip_prefix(&root) {
+        Ok(prefix) => prefix,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let (files, _) = server_daemon.index()?.entries_under(prefix);
+    return Ok(files.into_iter().map(|file| root.join(file)).collect());
+}
+
+// Files now at or below `path`, the index itself decides which of them are ignored
+fn files_under(server_daemon: &ServerDaemon, path: &Path) -> Vec<PathBuf> {
+    if path.is_dir() {
+        return walk_files_with(path, &server_daemon.ignore);
+    }
+    return vec![path.to_path_buf()];
+}
@@ -0,0 +1,131 @@
+extern crate rmp_serde as rmps;
+
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::fileread::{FileChunk, FileReadRequest, FileReadResponse, CHUNK_SIZE};
+use crate::messages::progress::ProgressNotification;
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::binary;
+use crate::utils::connection::Connection;
+use crate::utils::hash;
+
+impl HandleClientDaemon for FileReadRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+        let chunk_count = response.chunk_count;
+
+        utils::stream::send(stream, &response)?;
+
+        // Chunks are relayed as they arrive instead of reassembling the file here
+        let mut bytes_done = response.offset;
+        for _ in 0..chunk_count {
+            let chunk = client_daemon.server_recv::<FileChunk>()?;
+            bytes_done += chunk.data.len() as u64;
+            utils::stream::send(stream, &chunk)?;
+
+            let progress =
+                ProgressNotification::new(stream.request_id(), bytes_done, response.total_size);
+            if self.progress {
+                utils::stream::send(stream, &progress)?;
+            }
+            client_daemon.report_progress(&progress);
+        }
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for FileReadRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = server_daemon.resolve_in_workspace(&self.path)?;
+
+        let total_size = fs::metadata(path.as_path())?.len();
+        if !self.force {
+            if let Some(max_read_size) = server_daemon.max_read_size {
+                if total_size > max_read_size {
+                    return Err(ServerError::FileTooLarge {
+                        path: self.path.clone(),
+                        size: total_size,
+                        max_read_size,
+                    });
+                }
+            }
+            if binary::file_looks_binary(path.as_path())? {
+                return Err(ServerError::BinaryFile(self.path.clone()));
+            }
+        }
+        if total_size > CHUNK_SIZE as u64 {
+            return send_chunked(stream, File::open(path.as_path())?, total_size, self);
+        }
+
+        let contents = fs::read(path.as_path())?;
+        let h = hash::hash(&contents);
+
+        let response = if h == self.prev_hash {
+            FileReadResponse::unchanged(h)
+        } else {
+            FileReadResponse::new(contents, h)
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+// Streams a large file in CHUNK_SIZE pieces so that it never has to fit in memory. The
+// file is read twice, once for the hash that goes in the header and once to send it.
+fn send_chunked(
+    stream: &mut Connection,
+    mut file: File,
+    total_size: u64,
+    request: &FileReadRequest,
+) -> Result<(), ServerError> {
+    let h = hash::hash_reader(&mut BufReader::new(&mut file), total_size)?;
+    if h == request.prev_hash {
+        let buffer = envelope::encode(&FileReadResponse::unchanged(h))?;
+        stream.write_frame(&buffer)?;
+        return Ok(());
+    }
+
+    // the client's part of the file is only any good if it is still the same file
+    let resumable = request.resume_hash == h
+        && request.offset.is_multiple_of(CHUNK_SIZE as u64)
+        && request.offset < total_size;
+    let offset = if resumable { request.offset } else { 0 };
+    let response = FileReadResponse::resumed(h, total_size, offset);
+    let buffer = envelope::encode(&response)?;
+    stream.write_frame(&buffer)?;
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file).take(total_size - offset);
+    let first = (offset / CHUNK_SIZE as u64) as u32;
+    for index in first..first + response.chunk_count {
+        // a file that shrank since the header was sent yields short chunks and the client
+        // notices that the size doesn't add up
+        let mut data = Vec::with_capacity(CHUNK_SIZE);
+        (&mut reader)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut data)?;
+
+        let buffer = envelope::encode(&FileChunk::new(index, data))?;
+        stream.write_frame(&buffer)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::filestat::{FileStatRequest, FileStatResponse};
+use crate::structs::client::ClientDaemon;
+use crate::structs::index::{mode, mtime_millis};
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+
+impl HandleClientDaemon for FileStatRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for FileStatRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = server_daemon.resolve_link_in_workspace(&self.path)?;
+
+        let metadata = fs::symlink_metadata(&path)?;
+        let is_symlink = metadata.file_type().is_symlink();
+        let symlink_target = if is_symlink {
+            Some(fs::read_link(&path)?.to_string_lossy().to_string())
+        } else {
+            None
+        };
+
+        let response = FileStatResponse {
+            size: metadata.len(),
+            mtime: mtime_millis(&metadata),
+            ctime: (metadata.ctime() * 1000 + metadata.ctime_nsec() / 1_000_000).max(0) as u64,
+            mode: mode(&metadata),
+            is_symlink,
+            symlink_target,
+            is_dir: metadata.is_dir(),
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+extern crate rmp_serde as rmps;
+
+use std::fs;
+use std::io::ErrorKind;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::filewrite::{FileWriteRequest, FileWriteResponse};
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::atomic;
+use crate::utils::connection::Connection;
+use crate::utils::hash;
+
+impl HandleClientDaemon for FileWriteRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request_once(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for FileWriteRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = server_daemon.resolve_in_workspace(&self.path)?;
+
+        // Hold the lock across the hash check and the write so that two clients racing
+        // on the same base_hash can't both succeed, but not while the response is sent
+        let response = {
+            let _guard = server_daemon.lock_writes();
+
+            let current = match fs::read(path.as_path()) {
+                Ok(contents) => Some(contents),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(ServerError::Io(e)),
+            };
+            // A missing file hashes as 0 so that only new-file writes may create it
+            let current_hash = current.as_deref().map_or(0, hash::hash);
+
+            if current_hash != self.base_hash {
+                FileWriteResponse::new(current_hash, true)
+            } else {
+                if let Some(previous) = &current {
+                    server_daemon.keep_history(&path, previous);
+                }
+                atomic::write(&path, &self.contents)?;
+                server_daemon.file_changed(&[path])?;
+                FileWriteResponse::new(hash::hash(&self.contents), false)
+            }
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+extern crate rmp_serde as rmps;
+
+use std::fs;
+use std::thread;
+
+use globset::Glob;
+use log::error;
+use regex::RegexBuilder;
+use tracing::info_span;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::error::ErrorResponse;
+use crate::messages::grep::{GrepDone, GrepMatch, GrepMatchFrame, GrepRequest, GrepResponse};
+use crate::messages::messagetype::MessageType;
+use crate::structs::client::ClientDaemon;
+use crate::structs::grep::read_grep_frame;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::binary;
+use crate::utils::connection::Connection;
+use crate::utils::span;
+use crate::version::supports_streamed_grep;
+
+// Caps the response so that a pattern like "e" can't produce an enormous reply
+pub const MAX_GREP_MATCHES: usize = 10_000;
+
+impl HandleClientDaemon for GrepRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        // Searches can take a while, so they run on a pooled connection and free up the
+        // listen loop for emacs' other requests. Matches are passed on to emacs as they
+        // arrive, followed by a GrepDone, whichever way the server sent them.
+        let pool = client_daemon.pool();
+        let timeout = client_daemon.timeout(MessageType::GrepRequest);
+        let request = self.clone();
+        let mut stream = stream.try_clone()?;
+        thread::spawn(move || {
+            let result = pool.request_each(&request, timeout, |buf| {
+                let done = read_grep_frame(buf, &mut |grep_match| {
+                    utils::stream::send(&mut stream, &GrepMatchFrame::new(grep_match))
+                })?;
+                if let Some(done) = &done {
+                    utils::stream::send(&mut stream, done)?;
+                }
+                Ok(done.is_some())
+            });
+            if let Err(e) = result {
+                let response = ErrorResponse::new(e.code(), e.to_string());
+                if let Err(e) = utils::stream::send(&mut stream, &response) {
+                    error!("Failed to send grep results: {}", e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for GrepRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let pattern = if self.is_regex {
+            self.pattern.clone()
+        } else {
+            regex::escape(&self.pattern)
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(self.case_insensitive)
+            .build()
+            .map_err(|e| ServerError::Protocol(format!("invalid pattern: {}", e)))?;
+        let glob = match &self.path_glob {
+            Some(glob) => Some(
+                Glob::new(glob)
+                    .map_err(|e| ServerError::Protocol(format!("invalid glob: {}", e)))?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+
+        // Searching the index keeps the ignore rules consistent with everything else, the
+        // lock is released before any file is read
+        let files: Vec<String> = server_daemon.index()?.files.keys().cloned().collect();
+        let root = server_daemon.resolve_in_workspace("")?;
+
+        // Peers that can take them get every match as soon as it is found, the rest get
+        // them all at once. Batched requests are always answered with a single message.
+        let streamed = supports_streamed_grep(stream.protocol_version());
+        let mut matches = Vec::new();
+        let mut total = 0;
+        let mut truncated = false;
+        // streamed matches are written out along the way, their writes have spans of their own
+        let search = info_span!(target: span::TARGET, "search").entered();
+        'files: for file in files {
+            server_daemon.check_cancelled(stream)?;
+            if glob.as_ref().is_some_and(|glob| !glob.is_match(&file)) {
+                continue;
+            }
+            let contents = match fs::read(root.join(&file)) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            // binary files are skipped
+            if binary::looks_binary(&contents) {
+                continue;
+            }
+
+            let contents = String::from_utf8_lossy(&contents);
+            for (i, line) in contents.lines().enumerate() {
+                if !regex.is_match(line) {
+                    continue;
+                }
+                if total >= MAX_GREP_MATCHES {
+                    truncated = true;
+                    break 'files;
+                }
+                let grep_match = GrepMatch {
+                    path: file.clone(),
+                    line_number: i as u64 + 1,
+                    line: line.to_string(),
+                };
+                total += 1;
+                if streamed {
+                    let buffer = envelope::encode(&GrepMatchFrame::new(grep_match))?;
+                    stream.write_frame(&buffer)?;
+                } else {
+                    matches.push(grep_match);
+                }
+            }
+        }
+        drop(search);
+
+        let buffer = if streamed {
+            envelope::encode(&GrepDone::new(total as u64, truncated))?
+        } else {
+            envelope::encode(&GrepResponse::new(matches, truncated))?
+        };
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
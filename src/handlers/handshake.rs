@@ -0,0 +1,52 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::ServerError;
+use crate::handle::HandleServerDaemon;
+use crate::messages::envelope;
+use crate::messages::error::ErrorResponse;
+use crate::messages::handshake::{HandshakeRequest, HandshakeResponse};
+use crate::structs::server::ServerDaemon;
+use crate::utils::connection::Connection;
+use crate::version::{protocol_major, PROTOCOL_VERSION, VERSION};
+
+impl HandleServerDaemon for HandshakeRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let workspace = match server_daemon.workspace_daemon(&self.workspace) {
+            Some(workspace) => workspace,
+            None => {
+                let e = ServerError::UnknownWorkspace(self.workspace.clone());
+                let response = ErrorResponse::new(e.code(), e.to_string());
+                stream.write_frame(&envelope::encode(&response)?)?;
+                return Err(e);
+            }
+        };
+
+        // Always answer so the client can report the mismatch, then refuse the connection
+        let mut response = HandshakeResponse::new(PROTOCOL_VERSION, VERSION.to_string(), true);
+        response.salt = workspace.hash_salt();
+        response.index_hash = workspace.index_hash();
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        stream.set_protocol_version(self.protocol_version);
+        stream.set_client_id(self.client_id);
+
+        // Everything after the handshake is compressed if the client can decode it
+        if self.compression {
+            stream.set_compression_level(server_daemon.compression_level);
+        }
+
+        if protocol_major(self.protocol_version) != protocol_major(PROTOCOL_VERSION) {
+            return Err(ServerError::VersionMismatch {
+                client: self.protocol_version,
+                server: PROTOCOL_VERSION,
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::history::{HistoryRequest, HistoryResponse, RestoreRequest, RestoreResponse};
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::atomic;
+use crate::utils::connection::Connection;
+use crate::utils::hash;
+
+impl HandleClientDaemon for HistoryRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for HistoryRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = server_daemon.resolve_in_workspace(&self.path)?;
+        let rel = server_daemon.relative_to_workspace(&path)?;
+
+        let versions = match server_daemon.lock_history()?.as_ref() {
+            Some(history) => history.versions_of(&rel),
+            None => Vec::new(),
+        };
+
+        let buffer = envelope::encode(&HistoryResponse::new(versions))?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+impl HandleClientDaemon for RestoreRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request_once(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for RestoreRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = server_daemon.resolve_in_workspace(&self.path)?;
+        let rel = server_daemon.relative_to_workspace(&path)?;
+
+        // Same locking as FileWriteRequest
+        let response = {
+            let _guard = server_daemon.lock_writes();
+
+            let contents = match server_daemon.lock_history()?.as_ref() {
+                Some(history) => history.contents(&rel, self.version)?,
+                None => {
+                    return Err(ServerError::Io(io::Error::new(
+                        ErrorKind::NotFound,
+                        "this server keeps no history",
+                    )))
+                }
+            };
+            match fs::read(&path) {
+                Ok(current) if current != contents => server_daemon.keep_history(&path, &current),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(ServerError::Io(e)),
+            }
+
+            // the file may have been deleted along with its directory
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            atomic::write(&path, &contents)?;
+            server_daemon.file_changed(&[path])?;
+            RestoreResponse::new(hash::hash(&contents))
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+use std::convert::TryInto;
+
+use crate::error::ServerError;
+use crate::handle::HandleServerDaemon;
+use crate::messages::envelope;
+use crate::messages::error::ErrorResponse;
+use crate::messages::keyexchange::{KeyExchangeRequest, KeyExchangeResponse};
+use crate::structs::server::ServerDaemon;
+use crate::utils::connection::Connection;
+use crate::utils::seal::{self, Nonce, NONCE_SIZE};
+
+impl HandleServerDaemon for KeyExchangeRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let key = match &server_daemon.preshared_key {
+            Some(key) => key,
+            None => return refuse(stream, ServerError::NoPresharedKey),
+        };
+        let client_nonce: Nonce = match self.nonce.as_slice().try_into() {
+            Ok(nonce) => nonce,
+            Err(_) => {
+                let e = ServerError::Protocol(format!(
+                    "key exchange nonces are {} bytes, got {}",
+                    NONCE_SIZE,
+                    self.nonce.len()
+                ));
+                return refuse(stream, e);
+            }
+        };
+
+        let server_nonce = seal::nonce();
+        let proof = key.proof(&client_nonce, &server_nonce)?;
+        let response = KeyExchangeResponse::new(server_nonce.to_vec(), proof.to_vec());
+        stream.write_frame(&envelope::encode(&response)?)?;
+
+        // The client seals the handshake that follows, if its key is another one the
+        // handshake fails to open
+        let (sealer, opener) = key.server_keys(&client_nonce, &server_nonce)?;
+        stream.seal(sealer, opener);
+        Ok(())
+    }
+}
+
+// Answered in the clear, nothing is sealed yet
+fn refuse(stream: &mut Connection, e: ServerError) -> Result<(), ServerError> {
+    let response = ErrorResponse::new(e.code(), e.to_string());
+    stream.write_frame(&envelope::encode(&response)?)?;
+    return Err(e);
+}
@@ -0,0 +1,39 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::ping::{PingRequest, PingResponse};
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+
+impl HandleClientDaemon for PingRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for PingRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        _server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let response = PingResponse::new(self.nonce, self.sent_at_millis);
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
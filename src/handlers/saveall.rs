@@ -0,0 +1,184 @@
+extern crate rmp_serde as rmps;
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::saveall::{SaveAllRequest, SaveAllResponse, SaveFile, SaveStatus};
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::atomic;
+use crate::utils::connection::Connection;
+use crate::utils::hash;
+
+impl HandleClientDaemon for SaveAllRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request_once(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for SaveAllRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let mut paths = Vec::with_capacity(self.files.len());
+        let mut seen = HashSet::new();
+        for file in &self.files {
+            let path = server_daemon.resolve_in_workspace(&file.path)?;
+            if !seen.insert(path.clone()) {
+                return Err(ServerError::Protocol(format!(
+                    "{} is saved more than once",
+                    file.path
+                )));
+            }
+            paths.push(path);
+        }
+
+        // Same locking as FileWriteRequest, every base hash is checked and every file
+        // written without another write getting in between
+        let response = {
+            let _guard = server_daemon.lock_writes();
+
+            let mut statuses = Vec::with_capacity(self.files.len());
+            let mut previous = Vec::with_capacity(self.files.len());
+            let mut conflict = false;
+            for (file, path) in self.files.iter().zip(&paths) {
+                let current = current_contents(path)?;
+                // A missing file hashes as 0 so that only new-file writes may create it
+                let current_hash = current.as_deref().map_or(0, hash::hash);
+                let conflicted = current_hash != file.base_hash;
+                conflict |= conflicted;
+                statuses.push(SaveStatus::new(file.path.clone(), current_hash, conflicted));
+                previous.push(current);
+            }
+
+            if conflict {
+                SaveAllResponse::new(false, statuses)
+            } else {
+                for (path, contents) in paths.iter().zip(&previous) {
+                    if let Some(contents) = contents {
+                        server_daemon.keep_history(path, contents);
+                    }
+                }
+                write_all(&self.files, &paths)?;
+                server_daemon.file_changed(&paths)?;
+                let statuses = self
+                    .files
+                    .iter()
+                    .map(|file| {
+                        SaveStatus::new(file.path.clone(), hash::hash(&file.contents), false)
+                    })
+                    .collect();
+                SaveAllResponse::new(true, statuses)
+            }
+        };
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+// None if there is no such file yet
+fn current_contents(path: &Path) -> Result<Option<Vec<u8>>, ServerError> {
+    return match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ServerError::Io(e)),
+    };
+}
+
+// A file of the request on its way into place
+struct Staged {
+    path: PathBuf,
+    // the new contents, next to `path` so that renaming them over it is atomic
+    temp: PathBuf,
+    // a second name for the file being replaced, None if there was none
+    backup: Option<PathBuf>,
+}
+
+// Every file is written out in full before any of them is put in place, so that all that
+// can still fail by then is a rename, and the renames done before it are undone
+fn write_all(files: &[SaveFile], paths: &[PathBuf]) -> Result<(), ServerError> {
+    let mut staged = Vec::with_capacity(files.len());
+    for (file, path) in files.iter().zip(paths) {
+        let path = atomic::target(path);
+        match atomic::stage(&path, &file.contents) {
+            Ok(temp) => staged.push(Staged {
+                path,
+                temp,
+                backup: None,
+            }),
+            Err(e) => {
+                discard(&staged);
+                return Err(ServerError::Io(e));
+            }
+        }
+    }
+
+    for i in 0..staged.len() {
+        if let Err(e) = replace(&mut staged[i]) {
+            roll_back(&staged[..i]);
+            discard(&staged[i..]);
+            return Err(ServerError::Io(e));
+        }
+    }
+
+    for file in &staged {
+        if let Some(backup) = &file.backup {
+            let _ = fs::remove_file(backup);
+        }
+        atomic::sync_parent(&file.path);
+    }
+    return Ok(());
+}
+
+fn replace(file: &mut Staged) -> io::Result<()> {
+    let backup = atomic::sibling(&file.path, "old");
+    match fs::hard_link(&file.path, &backup) {
+        Ok(()) => file.backup = Some(backup),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    return fs::rename(&file.temp, &file.path);
+}
+
+// Puts back what was there before files that were already renamed into place
+fn roll_back(replaced: &[Staged]) {
+    for file in replaced {
+        let restored = match &file.backup {
+            Some(backup) => fs::rename(backup, &file.path),
+            None => fs::remove_file(&file.path),
+        };
+        if let Err(e) = restored {
+            error!("Failed to roll back {}: {}", file.path.display(), e);
+        }
+    }
+}
+
+fn discard(staged: &[Staged]) {
+    for file in staged {
+        let _ = fs::remove_file(&file.temp);
+        if let Some(backup) = &file.backup {
+            let _ = fs::remove_file(backup);
+        }
+    }
+}
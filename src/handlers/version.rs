@@ -0,0 +1,44 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::version::{VersionRequest, VersionResponse};
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+use crate::version::{BUILD_HASH, PROTOCOL_VERSION, VERSION};
+
+impl HandleClientDaemon for VersionRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.server_version()?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for VersionRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        _server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let response = VersionResponse::new(
+            VERSION.to_string(),
+            PROTOCOL_VERSION,
+            BUILD_HASH.to_string(),
+        );
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::watch::{UnwatchRequest, WatchRequest, WatchResponse};
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+
+impl HandleClientDaemon for WatchRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for WatchRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = relative_to_root(server_daemon, &self.path)?;
+        let watching = server_daemon.watch(stream.id(), &path, self.recursive);
+        let response = WatchResponse::new(watching);
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+impl HandleClientDaemon for UnwatchRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.send_request(self)?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for UnwatchRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let path = relative_to_root(server_daemon, &self.path)?;
+        let watching = server_daemon.unwatch(stream.id(), &path);
+        let response = WatchResponse::new(watching);
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
+
+// The same spelling of a path that notifications use, so "src/../src/" watches "src"
+fn relative_to_root(server_daemon: &ServerDaemon, path: &str) -> Result<String, ServerError> {
+    let root = server_daemon.resolve_in_workspace("")?;
+    let resolved = server_daemon.resolve_in_workspace(path)?;
+    return match resolved.strip_prefix(&root) {
+        Ok(rel) => Ok(rel.to_string_lossy().to_string()),
+        Err(_) => Err(ServerError::PathEscape(path.to_string())),
+    };
+}
@@ -0,0 +1,57 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::{ClientError, ServerError};
+use crate::handle::{HandleClientDaemon, HandleServerDaemon};
+use crate::messages::envelope;
+use crate::messages::workspaceinfo::{WorkspaceInfoRequest, WorkspaceInfoResponse};
+use crate::structs::client::ClientDaemon;
+use crate::structs::server::ServerDaemon;
+use crate::utils;
+use crate::utils::connection::Connection;
+use crate::version::{PROTOCOL_VERSION, VERSION};
+
+impl HandleClientDaemon for WorkspaceInfoRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        client_daemon: &mut ClientDaemon,
+    ) -> Result<(), ClientError> {
+        let response = client_daemon.workspace_info()?;
+
+        utils::stream::send(stream, &response)?;
+
+        Ok(())
+    }
+}
+
+impl HandleServerDaemon for WorkspaceInfoRequest {
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        server_daemon: &ServerDaemon,
+    ) -> Result<(), ServerError> {
+        let root = server_daemon.resolve_in_workspace("")?;
+        let (file_count, index_hash) = {
+            let index = server_daemon.index()?;
+            (index.files.len() as u64, index.hash)
+        };
+
+        let response = WorkspaceInfoResponse::new(
+            VERSION.to_string(),
+            PROTOCOL_VERSION,
+            server_daemon
+                .supported_message_types()
+                .into_iter()
+                .map(|msgtype| u64::from(msgtype) as u32)
+                .collect(),
+            file_count,
+            index_hash,
+            root.to_string_lossy().to_string(),
+        );
+
+        let buffer = envelope::encode(&response)?;
+        stream.write_frame(&buffer)?;
+
+        Ok(())
+    }
+}
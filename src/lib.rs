@@ -1,3 +1,6 @@
+#![allow(clippy::needless_return)]
+
+pub mod error;
 pub mod handle;
 pub mod handlers;
 pub mod messages;
@@ -1,2 +1,27 @@
+pub mod archive;
+pub mod batch;
+pub mod cancel;
+pub mod changes;
+pub mod completion;
+pub mod diff;
+pub mod dirlist;
+pub mod envelope;
+pub mod error;
+pub mod filechanged;
+pub mod fileops;
+pub mod fileread;
+pub mod filestat;
+pub mod filewrite;
+pub mod grep;
+pub mod handshake;
+pub mod history;
 pub mod index;
+pub mod keyexchange;
 pub mod messagetype;
+pub mod ping;
+pub mod progress;
+pub mod rescan;
+pub mod saveall;
+pub mod version;
+pub mod watch;
+pub mod workspaceinfo;
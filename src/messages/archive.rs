@@ -0,0 +1,80 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+
+// Asks for a tar of every indexed file under `path`, for seeding a local mirror in one go.
+// Answered with ArchiveChunks, the pieces of the archive in order, followed by an
+// ArchiveDone. Sent inside an Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ArchiveRequest {
+    // relative to the workspace, empty for all of it
+    pub path: String,
+    // .gitignore style patterns left out on top of what the index already leaves out
+    pub ignore: Vec<String>,
+    // zstd compresses the archive as a whole, which does better than frame compression
+    pub compress: bool,
+}
+
+impl MessageTypeTrait for ArchiveRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::ArchiveRequest;
+    }
+}
+impl ArchiveRequest {
+    pub fn new(path: String, ignore: Vec<String>, compress: bool) -> ArchiveRequest {
+        ArchiveRequest {
+            path,
+            ignore,
+            compress,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ArchiveChunk {
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+impl MessageTypeTrait for ArchiveChunk {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::ArchiveChunk;
+    }
+}
+impl ArchiveChunk {
+    pub fn new(data: Vec<u8>) -> ArchiveChunk {
+        ArchiveChunk { data }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ArchiveDone {
+    // files in the archive and the size of the tar before any compression
+    pub files: u64,
+    pub size: u64,
+    // where the client daemon unpacked the archive when it answers emacs, empty from the
+    // server
+    #[serde(default)]
+    pub unpacked_to: String,
+}
+
+impl MessageTypeTrait for ArchiveDone {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::ArchiveDone;
+    }
+}
+impl ArchiveDone {
+    pub fn new(files: u64, size: u64) -> ArchiveDone {
+        ArchiveDone {
+            files,
+            size,
+            unpacked_to: String::new(),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+// One msgpack encoded message inside a batch, e.g. a FileReadRequest or its response
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Envelope {
+    #[serde(with = "serde_bytes")]
+    pub message: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new(message: Vec<u8>) -> Envelope {
+        Envelope { message }
+    }
+}
+
+// Requests the server runs one after the other, answered with a single BatchResponse so
+// that they cost one round trip between them. Batches can't be nested.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BatchRequest {
+    message_type: u64,
+    pub requests: Vec<Envelope>,
+}
+
+impl MessageTypeTrait for BatchRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::BatchRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for BatchRequest {
+    type Response = BatchResponse;
+}
+impl BatchRequest {
+    pub fn new(requests: Vec<Envelope>) -> BatchRequest {
+        BatchRequest {
+            message_type: BatchRequest::messagetype().into(),
+            requests,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BatchResponse {
+    message_type: u64,
+    // one per request in the same order, an ErrorResponse for those that failed
+    pub responses: Vec<Envelope>,
+}
+
+impl MessageTypeTrait for BatchResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::BatchResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl BatchResponse {
+    pub fn new(responses: Vec<Envelope>) -> BatchResponse {
+        BatchResponse {
+            message_type: BatchResponse::messagetype().into(),
+            responses,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+// Asks the server to stop the request tagged with `request_id`, which is usually in flight
+// on another connection. Long handlers check for it as they go and answer with a CANCELLED
+// ErrorResponse. Sent inside an Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CancelRequest {
+    pub request_id: u64,
+}
+
+impl MessageTypeTrait for CancelRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::CancelRequest;
+    }
+}
+impl Response for CancelRequest {
+    type Response = CancelResponse;
+}
+impl CancelRequest {
+    pub fn new(request_id: u64) -> CancelRequest {
+        CancelRequest { request_id }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CancelResponse {
+    // false if no request with that id was in flight, e.g. it had already finished
+    pub cancelled: bool,
+}
+
+impl MessageTypeTrait for CancelResponse {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::CancelResponse;
+    }
+}
+impl CancelResponse {
+    pub fn new(cancelled: bool) -> CancelResponse {
+        CancelResponse { cancelled }
+    }
+}
@@ -0,0 +1,64 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// Asks which files changed since the index had the hash `since_hash`, for a client that was
+// disconnected and missed the FileChangedNotifications pushed in the meantime. Answered by
+// a ChangesSinceResponse. Sent inside an Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ChangesSinceRequest {
+    pub since_hash: ContentHash,
+}
+
+impl MessageTypeTrait for ChangesSinceRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::ChangesSinceRequest;
+    }
+}
+impl Response for ChangesSinceRequest {
+    type Response = ChangesSinceResponse;
+}
+impl ChangesSinceRequest {
+    pub fn new(since_hash: ContentHash) -> ChangesSinceRequest {
+        ChangesSinceRequest { since_hash }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ChangesSinceResponse {
+    // the index now
+    pub index_hash: ContentHash,
+    // files added, changed or removed since, sorted
+    pub paths: Vec<String>,
+    // the server no longer remembers that far back, or never saw the hash. Nothing can be
+    // trusted and `paths` is empty.
+    pub too_old: bool,
+}
+
+impl MessageTypeTrait for ChangesSinceResponse {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::ChangesSinceResponse;
+    }
+}
+impl ChangesSinceResponse {
+    pub fn new(index_hash: ContentHash, paths: Vec<String>) -> ChangesSinceResponse {
+        ChangesSinceResponse {
+            index_hash,
+            paths,
+            too_old: false,
+        }
+    }
+
+    pub fn too_old(index_hash: ContentHash) -> ChangesSinceResponse {
+        ChangesSinceResponse {
+            index_hash,
+            paths: Vec::new(),
+            too_old: true,
+        }
+    }
+}
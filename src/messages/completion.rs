@@ -0,0 +1,69 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CompletionRequest {
+    message_type: u64,
+    // matched as a case insensitive subsequence of the workspace relative path
+    pub prefix: String,
+    // most paths to return, capped at MAX_COMPLETIONS
+    pub limit: u32,
+}
+
+impl MessageTypeTrait for CompletionRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::CompletionRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for CompletionRequest {
+    type Response = CompletionResponse;
+}
+impl CompletionRequest {
+    pub fn new(prefix: String, limit: u32) -> CompletionRequest {
+        CompletionRequest {
+            message_type: CompletionRequest::messagetype().into(),
+            prefix,
+            limit,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CompletionResponse {
+    message_type: u64,
+    // best match first
+    pub paths: Vec<String>,
+    // more paths matched than were returned
+    pub truncated: bool,
+    // the score of each path, higher is better, for clients that rank them again. Empty
+    // from servers that predate them.
+    #[serde(default)]
+    pub scores: Vec<i64>,
+}
+
+impl MessageTypeTrait for CompletionResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::CompletionResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl CompletionResponse {
+    pub fn new(paths: Vec<String>, truncated: bool) -> CompletionResponse {
+        return CompletionResponse::scored(paths, Vec::new(), truncated);
+    }
+
+    pub fn scored(paths: Vec<String>, scores: Vec<i64>, truncated: bool) -> CompletionResponse {
+        CompletionResponse {
+            message_type: CompletionResponse::messagetype().into(),
+            paths,
+            truncated,
+            scores,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// Files larger than this, on either side, aren't diffed
+pub const MAX_DIFF_SIZE: usize = 1024 * 1024;
+
+// Asks how the server's copy of `path` differs from the version with the hash
+// `client_hash`, without either side sending the whole file. The server has to know that
+// version, it is the file as it is now or one kept in its history. Answered by a
+// DiffResponse. Sent inside an Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DiffRequest {
+    pub path: String,
+    pub client_hash: ContentHash,
+}
+
+impl MessageTypeTrait for DiffRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::DiffRequest;
+    }
+}
+impl Response for DiffRequest {
+    type Response = DiffResponse;
+}
+impl DiffRequest {
+    pub fn new(path: String, client_hash: ContentHash) -> DiffRequest {
+        DiffRequest { path, client_hash }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DiffResponse {
+    pub server_hash: ContentHash,
+    // the server's copy is the client's version, `diff` is empty
+    pub identical: bool,
+    // they differ, but one of them is binary or over MAX_DIFF_SIZE and `diff` is empty
+    pub too_large: bool,
+    // from the client's version to the server's, in the unified format
+    pub diff: String,
+}
+
+impl MessageTypeTrait for DiffResponse {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::DiffResponse;
+    }
+}
+impl DiffResponse {
+    pub fn new(server_hash: ContentHash, diff: String) -> DiffResponse {
+        DiffResponse {
+            server_hash,
+            identical: false,
+            too_large: false,
+            diff,
+        }
+    }
+
+    pub fn identical(server_hash: ContentHash) -> DiffResponse {
+        DiffResponse {
+            server_hash,
+            identical: true,
+            too_large: false,
+            diff: String::new(),
+        }
+    }
+
+    pub fn too_large(server_hash: ContentHash) -> DiffResponse {
+        DiffResponse {
+            server_hash,
+            identical: false,
+            too_large: true,
+            diff: String::new(),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DirectoryListRequest {
+    message_type: u64,
+    // path of the directory relative to the workspace
+    pub path: String,
+    // number of levels to list, 1 for just the immediate children
+    pub depth: u32,
+}
+
+impl MessageTypeTrait for DirectoryListRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::DirectoryListRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for DirectoryListRequest {
+    type Response = DirectoryListResponse;
+}
+impl DirectoryListRequest {
+    pub fn new(path: String, depth: u32) -> DirectoryListRequest {
+        DirectoryListRequest {
+            message_type: DirectoryListRequest::messagetype().into(),
+            path,
+            depth,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    // path relative to the listed directory
+    pub name: String,
+    pub is_dir: bool,
+    pub is_symlink: bool,
+    pub size: u64,
+    // milliseconds since the unix epoch
+    pub mtime: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DirectoryListResponse {
+    message_type: u64,
+    pub entries: Vec<DirEntry>,
+}
+
+impl MessageTypeTrait for DirectoryListResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::DirectoryListResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl DirectoryListResponse {
+    pub fn new(entries: Vec<DirEntry>) -> DirectoryListResponse {
+        DirectoryListResponse {
+            message_type: DirectoryListResponse::messagetype().into(),
+            entries,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+extern crate rmp_serde as rmps;
+
+use std::convert::TryFrom;
+use std::io::Read;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::info_span;
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+use crate::utils::span;
+
+// A message as it travels between the daemons: the MessageType discriminant, the request
+// id it answers and the payload. On the wire it is the msgpack array every message has,
+// the discriminant followed by the payload's fields, with the request id in the frame
+// header. Payloads that are enveloped leave the discriminant to the Envelope instead of
+// carrying their own message_type field, so it is always the one for their type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope<T> {
+    pub msg_type: MessageType,
+    // 0 if the frame wasn't tagged with one
+    pub request_id: u64,
+    pub payload: T,
+}
+
+impl<T: MessageTypeTrait> Envelope<T> {
+    pub fn new(payload: T) -> Envelope<T> {
+        return Envelope::tagged(payload, 0);
+    }
+
+    pub fn tagged(payload: T, request_id: u64) -> Envelope<T> {
+        Envelope {
+            msg_type: T::messagetype(),
+            request_id,
+            payload,
+        }
+    }
+}
+
+impl<T: Serialize> Envelope<T> {
+    // The frame payload, the request id goes in the frame header
+    pub fn encode(&self) -> Result<Vec<u8>, rmps::encode::Error> {
+        let fields = rmps::encode::to_vec(&self.payload)?;
+        let mut rest = &fields[..];
+        let len = rmp::decode::read_array_len(&mut rest).map_err(|e| {
+            rmps::encode::Error::Syntax(format!("message payloads must be structs: {}", e))
+        })?;
+
+        let mut buf = Vec::with_capacity(fields.len() + 9);
+        rmp::encode::write_array_len(&mut buf, len + 1)?;
+        rmp::encode::write_uint(&mut buf, self.msg_type.into())?;
+        buf.extend_from_slice(rest);
+        return Ok(buf);
+    }
+}
+
+impl<T: DeserializeOwned + MessageTypeTrait> Envelope<T> {
+    // Fails if the frame holds a message of any other type than T
+    pub fn decode(buf: &[u8], request_id: u64) -> Result<Envelope<T>, rmps::decode::Error> {
+        let mut rest = buf;
+        let len = rmp::decode::read_array_len(&mut rest)
+            .map_err(|e| rmps::decode::Error::Syntax(format!("untagged message: {}", e)))?;
+        let discriminant: u64 = rmp::decode::read_int(&mut rest)
+            .map_err(|e| rmps::decode::Error::Syntax(format!("untagged message: {}", e)))?;
+        let msg_type = MessageType::try_from(discriminant).map_err(|_| {
+            rmps::decode::Error::Syntax(format!("unknown message type {}", discriminant))
+        })?;
+        if msg_type != T::messagetype() {
+            return Err(rmps::decode::Error::Syntax(format!(
+                "expected {:?}, got {:?}",
+                T::messagetype(),
+                msg_type
+            )));
+        }
+
+        // The payload's own array header in front of the frame's fields, read through
+        // rather than copied so that large payloads are only ever held once
+        let mut header = Vec::with_capacity(5);
+        rmp::encode::write_array_len(&mut header, len.saturating_sub(1))
+            .map_err(|e| rmps::decode::Error::Syntax(e.to_string()))?;
+        return Ok(Envelope {
+            msg_type,
+            request_id,
+            payload: rmps::from_read((&header[..]).chain(rest))?,
+        });
+    }
+}
+
+// How every message is encoded, whether its type is enveloped or still carries its own
+// message_type field
+pub fn encode<T: Serialize + MessageTypeTrait>(
+    message: &T,
+) -> Result<Vec<u8>, rmps::encode::Error> {
+    let _span = info_span!(target: span::TARGET, "encode").entered();
+    if !T::ENVELOPED {
+        return rmps::encode::to_vec(message);
+    }
+    let envelope = Envelope {
+        msg_type: T::messagetype(),
+        request_id: 0,
+        payload: message,
+    };
+    return envelope.encode();
+}
+
+pub fn decode<T: DeserializeOwned + MessageTypeTrait>(
+    buf: &[u8],
+) -> Result<T, rmps::decode::Error> {
+    if !T::ENVELOPED {
+        return rmps::from_slice(buf);
+    }
+    return Ok(Envelope::<T>::decode(buf, 0)?.payload);
+}
@@ -0,0 +1,46 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+
+// Codes carried by an ErrorResponse
+// the request's message type isn't one this side knows how to serve
+pub const UNSUPPORTED: u32 = 1;
+pub const NOT_FOUND: u32 = 2;
+// includes paths that resolve outside of the workspace
+pub const PERMISSION_DENIED: u32 = 3;
+// the file changed underneath the request, or one it would create already exists
+pub const CONFLICT: u32 = 4;
+pub const INTERNAL: u32 = 5;
+// the file is binary or over the server's read limit, resend with force to read it anyway
+pub const BINARY_OR_TOO_LARGE: u32 = 6;
+// a CancelRequest stopped the request before it finished
+pub const CANCELLED: u32 = 7;
+// the server is at its connection limit, sent in place of the first response before it
+// hangs up
+pub const BUSY: u32 = 8;
+
+// Sent back in place of the expected response so the peer isn't left waiting
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ErrorResponse {
+    message_type: u64,
+    pub code: u32,
+    pub message: String,
+}
+
+impl MessageTypeTrait for ErrorResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::ErrorResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl ErrorResponse {
+    pub fn new(code: u32, message: String) -> ErrorResponse {
+        ErrorResponse {
+            message_type: ErrorResponse::messagetype().into(),
+            code,
+            message,
+        }
+    }
+}
@@ -0,0 +1,63 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+use crate::utils::hash::ContentHash;
+
+// Pushed by the server whenever a file in the workspace changes, in between responses
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FileChangedNotification {
+    message_type: u64,
+    // path relative to the workspace
+    pub path: String,
+    // hash of the new contents, 0 if the file was removed
+    pub new_hash: ContentHash,
+}
+
+impl MessageTypeTrait for FileChangedNotification {
+    fn messagetype() -> MessageType {
+        return MessageType::FileChangedNotification;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl FileChangedNotification {
+    pub fn new(path: String, new_hash: ContentHash) -> FileChangedNotification {
+        FileChangedNotification {
+            message_type: FileChangedNotification::messagetype().into(),
+            path,
+            new_hash,
+        }
+    }
+}
+
+// Pushed instead of a FileChangedNotification per file when many files change at once,
+// e.g. during a build. Sent inside an Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct BulkChangeNotification {
+    // paths relative to the workspace, the client fetches whatever it needs of them again
+    pub paths: Vec<String>,
+}
+
+impl MessageTypeTrait for BulkChangeNotification {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::BulkChangeNotification;
+    }
+}
+impl BulkChangeNotification {
+    pub fn new(paths: Vec<String>) -> BulkChangeNotification {
+        BulkChangeNotification { paths }
+    }
+
+    // The same changes as one FileChangedNotification per path, with no hash to compare
+    // against
+    pub fn expand(self) -> Vec<FileChangedNotification> {
+        return self
+            .paths
+            .into_iter()
+            .map(|path| FileChangedNotification::new(path, 0))
+            .collect();
+    }
+}
@@ -0,0 +1,137 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// Both paths are relative to the workspace root, an existing `to` is never overwritten
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RenameRequest {
+    message_type: u64,
+    pub from: String,
+    pub to: String,
+}
+
+impl MessageTypeTrait for RenameRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::RenameRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for RenameRequest {
+    type Response = FileOpResponse;
+}
+impl RenameRequest {
+    pub fn new(from: String, to: String) -> RenameRequest {
+        RenameRequest {
+            message_type: RenameRequest::messagetype().into(),
+            from,
+            to,
+        }
+    }
+}
+
+// Non-empty directories are only removed when `recursive` is set
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DeleteRequest {
+    message_type: u64,
+    pub path: String,
+    pub recursive: bool,
+}
+
+impl MessageTypeTrait for DeleteRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::DeleteRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for DeleteRequest {
+    type Response = FileOpResponse;
+}
+impl DeleteRequest {
+    pub fn new(path: String, recursive: bool) -> DeleteRequest {
+        DeleteRequest {
+            message_type: DeleteRequest::messagetype().into(),
+            path,
+            recursive,
+        }
+    }
+}
+
+// Creates an empty file, the path must not exist yet but its directory must. Sent inside an
+// Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TouchRequest {
+    pub path: String,
+}
+
+impl MessageTypeTrait for TouchRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::TouchRequest;
+    }
+}
+impl Response for TouchRequest {
+    type Response = FileOpResponse;
+}
+impl TouchRequest {
+    pub fn new(path: String) -> TouchRequest {
+        TouchRequest { path }
+    }
+}
+
+// Creates a directory, along with any missing parents when `recursive` is set. The path
+// itself must not exist yet either way.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MkdirRequest {
+    pub path: String,
+    pub recursive: bool,
+}
+
+impl MessageTypeTrait for MkdirRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::MkdirRequest;
+    }
+}
+impl Response for MkdirRequest {
+    type Response = FileOpResponse;
+}
+impl MkdirRequest {
+    pub fn new(path: String, recursive: bool) -> MkdirRequest {
+        MkdirRequest { path, recursive }
+    }
+}
+
+// Answers RenameRequest, DeleteRequest, TouchRequest and MkdirRequest
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FileOpResponse {
+    message_type: u64,
+    // false if the operation was refused, e.g. renaming onto an existing path
+    pub success: bool,
+    // index hash once the operation has been folded in
+    pub index_hash: ContentHash,
+}
+
+impl MessageTypeTrait for FileOpResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::FileOpResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl FileOpResponse {
+    pub fn new(success: bool, index_hash: ContentHash) -> FileOpResponse {
+        FileOpResponse {
+            message_type: FileOpResponse::messagetype().into(),
+            success,
+            index_hash,
+        }
+    }
+}
@@ -0,0 +1,167 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// Files larger than this are sent as a FileReadResponse header followed by FileChunks
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FileReadRequest {
+    message_type: u64,
+    // path of the file relative to the workspace
+    pub path: String,
+    // hash of the contents the client already has, 0 if none
+    pub prev_hash: ContentHash,
+    // read the file even if it is binary or larger than the server's limit
+    #[serde(default)]
+    pub force: bool,
+    // interleave a ProgressNotification with each FileChunk the client relays, see
+    // messages::progress. The server ignores it.
+    #[serde(default)]
+    pub progress: bool,
+    // resumes a chunked transfer that was cut short: the chunks are sent from this offset
+    // on, a multiple of CHUNK_SIZE, if the file still hashes to resume_hash. Otherwise the
+    // whole file is sent again.
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default)]
+    pub resume_hash: ContentHash,
+}
+
+impl MessageTypeTrait for FileReadRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::FileReadRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for FileReadRequest {
+    type Response = FileReadResponse;
+}
+impl FileReadRequest {
+    pub fn new(path: String, prev_hash: ContentHash) -> FileReadRequest {
+        return FileReadRequest::forced(path, prev_hash, false);
+    }
+
+    pub fn forced(path: String, prev_hash: ContentHash, force: bool) -> FileReadRequest {
+        FileReadRequest {
+            message_type: FileReadRequest::messagetype().into(),
+            path,
+            prev_hash,
+            force,
+            progress: false,
+            offset: 0,
+            resume_hash: 0,
+        }
+    }
+
+    // Picks a chunked transfer of the file hashing to `resume_hash` back up at `offset`
+    pub fn resumed(
+        path: String,
+        force: bool,
+        resume_hash: ContentHash,
+        offset: u64,
+    ) -> FileReadRequest {
+        let mut request = FileReadRequest::forced(path, 0, force);
+        request.offset = offset;
+        request.resume_hash = resume_hash;
+        return request;
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FileReadResponse {
+    message_type: u64,
+    // empty when unchanged is set
+    #[serde(with = "serde_bytes")]
+    pub contents: Vec<u8>,
+    pub hash: ContentHash,
+    // the file still matches prev_hash so its contents were not resent
+    pub unchanged: bool,
+    pub total_size: u64,
+    // number of FileChunk messages that follow, 0 if contents holds the whole file
+    pub chunk_count: u32,
+    // where in the file the chunks start, the request's offset if it resumed a transfer
+    #[serde(default)]
+    pub offset: u64,
+}
+
+impl MessageTypeTrait for FileReadResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::FileReadResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl FileReadResponse {
+    pub fn new(contents: Vec<u8>, hash: ContentHash) -> FileReadResponse {
+        FileReadResponse {
+            message_type: FileReadResponse::messagetype().into(),
+            total_size: contents.len() as u64,
+            contents,
+            hash,
+            unchanged: false,
+            chunk_count: 0,
+            offset: 0,
+        }
+    }
+    pub fn unchanged(hash: ContentHash) -> FileReadResponse {
+        FileReadResponse {
+            message_type: FileReadResponse::messagetype().into(),
+            contents: Vec::new(),
+            hash,
+            unchanged: true,
+            total_size: 0,
+            chunk_count: 0,
+            offset: 0,
+        }
+    }
+    pub fn chunked(hash: ContentHash, total_size: u64) -> FileReadResponse {
+        return FileReadResponse::resumed(hash, total_size, 0);
+    }
+    // The chunks from `offset` on, numbered from the start of the file. There are none
+    // past the end of it.
+    pub fn resumed(hash: ContentHash, total_size: u64, offset: u64) -> FileReadResponse {
+        FileReadResponse {
+            message_type: FileReadResponse::messagetype().into(),
+            contents: Vec::new(),
+            hash,
+            unchanged: false,
+            total_size,
+            chunk_count: total_size
+                .saturating_sub(offset)
+                .div_ceil(CHUNK_SIZE as u64) as u32,
+            offset,
+        }
+    }
+}
+
+// One piece of a chunked FileReadResponse, sent in order
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FileChunk {
+    message_type: u64,
+    pub index: u32,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+impl MessageTypeTrait for FileChunk {
+    fn messagetype() -> MessageType {
+        return MessageType::FileChunk;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl FileChunk {
+    pub fn new(index: u32, data: Vec<u8>) -> FileChunk {
+        FileChunk {
+            message_type: FileChunk::messagetype().into(),
+            index,
+            data,
+        }
+    }
+}
@@ -0,0 +1,53 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+// Sent inside an Envelope, see messages::envelope
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FileStatRequest {
+    // path relative to the workspace, a symlink is described itself rather than its target
+    pub path: String,
+}
+
+impl MessageTypeTrait for FileStatRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::FileStatRequest;
+    }
+}
+impl Response for FileStatRequest {
+    type Response = FileStatResponse;
+}
+impl FileStatRequest {
+    pub fn new(path: String) -> FileStatRequest {
+        FileStatRequest { path }
+    }
+}
+
+// Sent inside an Envelope, see messages::envelope
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FileStatResponse {
+    pub size: u64,
+    // milliseconds since the unix epoch
+    pub mtime: u64,
+    // when the metadata last changed, milliseconds since the unix epoch
+    pub ctime: u64,
+    // unix permission and file type bits
+    pub mode: u32,
+    pub is_symlink: bool,
+    // where the symlink points, as written in it, None if it isn't one
+    pub symlink_target: Option<String>,
+    pub is_dir: bool,
+}
+
+impl MessageTypeTrait for FileStatResponse {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::FileStatResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return true;
+    }
+}
@@ -0,0 +1,64 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FileWriteRequest {
+    message_type: u64,
+    // path of the file relative to the workspace
+    pub path: String,
+    #[serde(with = "serde_bytes")]
+    pub contents: Vec<u8>,
+    // hash of the contents the edit was based on, 0 for a new file
+    pub base_hash: ContentHash,
+}
+
+impl MessageTypeTrait for FileWriteRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::FileWriteRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for FileWriteRequest {
+    type Response = FileWriteResponse;
+}
+impl FileWriteRequest {
+    pub fn new(path: String, contents: Vec<u8>, base_hash: ContentHash) -> FileWriteRequest {
+        FileWriteRequest {
+            message_type: FileWriteRequest::messagetype().into(),
+            path,
+            contents,
+            base_hash,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FileWriteResponse {
+    message_type: u64,
+    // hash of the file on the server after the request was handled
+    pub hash: ContentHash,
+    // the file changed since base_hash so nothing was written
+    pub conflict: bool,
+}
+
+impl MessageTypeTrait for FileWriteResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::FileWriteResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl FileWriteResponse {
+    pub fn new(hash: ContentHash, conflict: bool) -> FileWriteResponse {
+        FileWriteResponse {
+            message_type: FileWriteResponse::messagetype().into(),
+            hash,
+            conflict,
+        }
+    }
+}
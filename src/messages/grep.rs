@@ -0,0 +1,127 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GrepRequest {
+    message_type: u64,
+    pub pattern: String,
+    // pattern is a regex rather than a literal string
+    pub is_regex: bool,
+    pub case_insensitive: bool,
+    // only search files whose workspace relative path matches this glob, e.g. "src/**/*.rs"
+    pub path_glob: Option<String>,
+}
+
+impl MessageTypeTrait for GrepRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::GrepRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl GrepRequest {
+    pub fn new(
+        pattern: String,
+        is_regex: bool,
+        case_insensitive: bool,
+        path_glob: Option<String>,
+    ) -> GrepRequest {
+        GrepRequest {
+            message_type: GrepRequest::messagetype().into(),
+            pattern,
+            is_regex,
+            case_insensitive,
+            path_glob,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct GrepMatch {
+    // path relative to the workspace
+    pub path: String,
+    // 1 based
+    pub line_number: u64,
+    pub line: String,
+}
+
+// One match, sent as soon as it is found. The last one is followed by a GrepDone.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GrepMatchFrame {
+    message_type: u64,
+    pub grep_match: GrepMatch,
+}
+
+impl MessageTypeTrait for GrepMatchFrame {
+    fn messagetype() -> MessageType {
+        return MessageType::GrepMatchFrame;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl GrepMatchFrame {
+    pub fn new(grep_match: GrepMatch) -> GrepMatchFrame {
+        GrepMatchFrame {
+            message_type: GrepMatchFrame::messagetype().into(),
+            grep_match,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GrepDone {
+    message_type: u64,
+    // number of GrepMatchFrames sent before this one
+    pub total: u64,
+    // the search stopped after MAX_GREP_MATCHES matches
+    pub truncated: bool,
+}
+
+impl MessageTypeTrait for GrepDone {
+    fn messagetype() -> MessageType {
+        return MessageType::GrepDone;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl GrepDone {
+    pub fn new(total: u64, truncated: bool) -> GrepDone {
+        GrepDone {
+            message_type: GrepDone::messagetype().into(),
+            total,
+            truncated,
+        }
+    }
+}
+
+// Every match in one message, what servers before protocol 3.1 answer with. A GrepRequest
+// in a batch is answered with one too.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GrepResponse {
+    message_type: u64,
+    pub matches: Vec<GrepMatch>,
+    // the search stopped after MAX_GREP_MATCHES matches
+    pub truncated: bool,
+}
+
+impl MessageTypeTrait for GrepResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::GrepResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl GrepResponse {
+    pub fn new(matches: Vec<GrepMatch>, truncated: bool) -> GrepResponse {
+        GrepResponse {
+            message_type: GrepResponse::messagetype().into(),
+            matches,
+            truncated,
+        }
+    }
+}
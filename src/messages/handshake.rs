@@ -0,0 +1,95 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// Must be the first message sent on every connection to the server
+#[derive(Deserialize, Serialize, Debug)]
+pub struct HandshakeRequest {
+    message_type: u64,
+    pub protocol_version: u32,
+    // whether the client can decode compressed frames
+    pub compression: bool,
+    // name of the workspace to serve on this connection, the server's own if empty. Servers
+    // before protocol 3.4 ignore it, like any field past the ones they know.
+    #[serde(default)]
+    pub workspace: String,
+    // picked at random by the client and sent on each of its connections, so that a
+    // CancelRequest on one reaches the requests on the others. With 0, as sent by clients
+    // before protocol 3.7, requests can only be cancelled from their own connection.
+    #[serde(default)]
+    pub client_id: u64,
+}
+
+impl MessageTypeTrait for HandshakeRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::HandshakeRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for HandshakeRequest {
+    type Response = HandshakeResponse;
+}
+impl HandshakeRequest {
+    pub fn new(protocol_version: u32, compression: bool) -> HandshakeRequest {
+        return HandshakeRequest::with_workspace(protocol_version, compression, String::new());
+    }
+
+    pub fn with_workspace(
+        protocol_version: u32,
+        compression: bool,
+        workspace: String,
+    ) -> HandshakeRequest {
+        HandshakeRequest {
+            message_type: HandshakeRequest::messagetype().into(),
+            protocol_version,
+            compression,
+            workspace,
+            client_id: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct HandshakeResponse {
+    message_type: u64,
+    pub protocol_version: u32,
+    pub server_version: String,
+    // whether the server can decode compressed frames
+    pub compression: bool,
+    // what the client salts the content hashes it caches the workspace's files under with,
+    // see utils::hash::salted. 0 from servers before protocol 3.8.
+    #[serde(default)]
+    pub salt: u64,
+    // the workspace's index as the client connected, see ChangesSinceRequest. 0 from servers
+    // before protocol 3.9.
+    #[serde(default)]
+    pub index_hash: ContentHash,
+}
+
+impl MessageTypeTrait for HandshakeResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::HandshakeResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl HandshakeResponse {
+    pub fn new(
+        protocol_version: u32,
+        server_version: String,
+        compression: bool,
+    ) -> HandshakeResponse {
+        HandshakeResponse {
+            message_type: HandshakeResponse::messagetype().into(),
+            protocol_version,
+            server_version,
+            compression,
+            salt: 0,
+            index_hash: 0,
+        }
+    }
+}
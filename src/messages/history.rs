@@ -0,0 +1,103 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// What a file held before the server overwrote it, see structs::history
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FileVersion {
+    // numbered across the whole workspace, a later write always has a higher version
+    pub version: u64,
+    // relative to the workspace
+    pub path: String,
+    pub hash: ContentHash,
+    pub size: u64,
+    // when it was overwritten, seconds since the unix epoch
+    pub replaced_at: u64,
+}
+
+// Lists the versions of `path` the server still has, answered by a HistoryResponse. Sent
+// inside an Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct HistoryRequest {
+    pub path: String,
+}
+
+impl MessageTypeTrait for HistoryRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::HistoryRequest;
+    }
+}
+impl Response for HistoryRequest {
+    type Response = HistoryResponse;
+}
+impl HistoryRequest {
+    pub fn new(path: String) -> HistoryRequest {
+        HistoryRequest { path }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct HistoryResponse {
+    // oldest first, empty if the server keeps no history
+    pub versions: Vec<FileVersion>,
+}
+
+impl MessageTypeTrait for HistoryResponse {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::HistoryResponse;
+    }
+}
+impl HistoryResponse {
+    pub fn new(versions: Vec<FileVersion>) -> HistoryResponse {
+        HistoryResponse { versions }
+    }
+}
+
+// Puts `version` of `path` back in place. What the file held until then becomes a version
+// of its own, so a restore can be undone like any other write. Sent inside an Envelope, see
+// messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RestoreRequest {
+    pub path: String,
+    pub version: u64,
+}
+
+impl MessageTypeTrait for RestoreRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::RestoreRequest;
+    }
+}
+impl Response for RestoreRequest {
+    type Response = RestoreResponse;
+}
+impl RestoreRequest {
+    pub fn new(path: String, version: u64) -> RestoreRequest {
+        RestoreRequest { path, version }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RestoreResponse {
+    // hash of the file on the server now that it was restored
+    pub hash: ContentHash,
+}
+
+impl MessageTypeTrait for RestoreResponse {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::RestoreResponse;
+    }
+}
+impl RestoreResponse {
+    pub fn new(hash: ContentHash) -> RestoreResponse {
+        RestoreResponse { hash }
+    }
+}
@@ -1,51 +1,54 @@
 pub use serde::{Deserialize, Serialize};
 
-use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
 
-#[derive(Deserialize, Serialize, Debug)]
+// Sent inside an Envelope, see messages::envelope
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct IndexRequest {
-    message_type: u64,
-    pub prev_hash: u64,
+    pub prev_hash: ContentHash,
     pub index_path: String,
 }
 
 impl MessageTypeTrait for IndexRequest {
+    const ENVELOPED: bool = true;
+
     fn messagetype() -> MessageType {
         return MessageType::IndexRequest;
     }
-    fn is_valid(&self) -> bool {
-        return self.message_type == (Self::messagetype() as u64);
-    }
+}
+impl Response for IndexRequest {
+    type Response = IndexResponse;
 }
 impl IndexRequest {
-    pub fn new(prev_hash: u64, index_path: String) -> IndexRequest {
+    pub fn new(prev_hash: ContentHash, index_path: String) -> IndexRequest {
         IndexRequest {
-            message_type: IndexRequest::messagetype().into(),
             prev_hash,
             index_path,
         }
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+// Sent inside an Envelope, see messages::envelope
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct IndexResponse {
-    message_type: u64,
-    pub hash: u64,
+    pub hash: ContentHash,
     pub path_to_index_file: String,
 }
 
 impl MessageTypeTrait for IndexResponse {
+    const ENVELOPED: bool = true;
+
     fn messagetype() -> MessageType {
         return MessageType::IndexResponse;
     }
     fn is_valid(&self) -> bool {
-        return self.message_type == (Self::messagetype() as u64);
+        return true;
     }
 }
 impl IndexResponse {
-    pub fn new(hash: u64, path_to_index_file: String) -> IndexResponse {
+    pub fn new(hash: ContentHash, path_to_index_file: String) -> IndexResponse {
         IndexResponse {
-            message_type: IndexResponse::messagetype().into(),
             hash,
             path_to_index_file,
         }
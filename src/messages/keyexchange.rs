@@ -0,0 +1,50 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+// Sent in place of the HandshakeRequest by clients with a pre-shared key, every frame
+// after the KeyExchangeResponse is sealed with keys derived from it and both nonces, the
+// handshake included. See utils::seal. Sent inside an Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct KeyExchangeRequest {
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+impl MessageTypeTrait for KeyExchangeRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::KeyExchangeRequest;
+    }
+}
+impl Response for KeyExchangeRequest {
+    type Response = KeyExchangeResponse;
+}
+impl KeyExchangeRequest {
+    pub fn new(nonce: Vec<u8>) -> KeyExchangeRequest {
+        KeyExchangeRequest { nonce }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct KeyExchangeResponse {
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    // shows the server holds the same key, before the client seals anything with it
+    #[serde(with = "serde_bytes")]
+    pub proof: Vec<u8>,
+}
+
+impl MessageTypeTrait for KeyExchangeResponse {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::KeyExchangeResponse;
+    }
+}
+impl KeyExchangeResponse {
+    pub fn new(nonce: Vec<u8>, proof: Vec<u8>) -> KeyExchangeResponse {
+        KeyExchangeResponse { nonce, proof }
+    }
+}
@@ -3,6 +3,7 @@ use std::clone::Clone;
 pub use serde::{Deserialize, Serialize};
 
 use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+use crate::utils::hash::ContentHash;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct LsRequest {
@@ -10,7 +11,7 @@ pub struct LsRequest {
     // path of the directory
     pub ls_path: String,
     // hash computed by combining directory itself and contents
-    pub prev_hash: u64,
+    pub prev_hash: ContentHash,
 }
 
 impl MessageTypeTrait for LsRequest {
@@ -22,7 +23,7 @@ impl MessageTypeTrait for LsRequest {
     }
 }
 impl LsRequest {
-    pub fn new(prev_hash: u64, index_path: String) -> LsRequest {
+    pub fn new(prev_hash: ContentHash, index_path: String) -> LsRequest {
         LsRequest {
             message_type: LsRequest::messagetype().into(),
             prev_hash,
@@ -34,7 +35,7 @@ impl LsRequest {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct LsResponse {
     message_type: u64,
-    pub hash: u64,
+    pub hash: ContentHash,
     pub path_to_index_file: String,
 }
 
@@ -47,7 +48,7 @@ impl MessageTypeTrait for LsResponse {
     }
 }
 impl LsResponse {
-    pub fn new(hash: u64, path_to_index_file: String) -> LsResponse {
+    pub fn new(hash: ContentHash, path_to_index_file: String) -> LsResponse {
         LsResponse {
             message_type: LsResponse::messagetype().into(),
             hash,
@@ -1,29 +1,200 @@
 extern crate rmp_serde as rmps;
 
+use std::ops::RangeInclusive;
+
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::de::DeserializeOwned;
+
+// Discriminants no released message type will ever be given, for trying out new messages
+// between builds that agree on them. A peer that doesn't know one answers it like any other
+// unknown message type.
+pub const EXPERIMENTAL_MESSAGE_TYPES: RangeInclusive<u64> = 0xf000..=0xffff;
 
-#[derive(IntoPrimitive, TryFromPrimitive, Eq, PartialEq, Debug)]
+// The first element of every message on the wire. Each variant is pinned to its
+// discriminant, a new one takes the next free number and a retired one's number is never
+// reused, so the order of the variants doesn't matter.
+#[derive(IntoPrimitive, TryFromPrimitive, Clone, Copy, Eq, PartialEq, Hash, Debug)]
 #[repr(u64)]
 pub enum MessageType {
     // // To test basic ping
     // PingRequest,
     // PingResponse,
     // For getting shell output from server
-    ShellRequest,
+    ShellRequest = 0,
     // For getting a file from server
-    FileRequest,
+    FileRequest = 1,
     // Response containing a path
-    PathResponse,
+    PathResponse = 2,
     // Response containing a path
-    DataResponse,
+    DataResponse = 3,
 
     // Projectile commands
-    ProjectileInvalidCacheRequest,
-    IndexRequest,
-    IndexResponse,
+    ProjectileInvalidCacheRequest = 4,
+    IndexRequest = 5,
+    IndexResponse = 6,
+
+    // File contents
+    FileReadRequest = 7,
+    FileReadResponse = 8,
+    FileWriteRequest = 9,
+    FileWriteResponse = 10,
+
+    // Directory browsing
+    DirectoryListRequest = 11,
+    DirectoryListResponse = 12,
+
+    // Protocol version negotiation, first message on every connection
+    HandshakeRequest = 13,
+    HandshakeResponse = 14,
+
+    // Heartbeat used to detect dead tunnels
+    PingRequest = 15,
+    PingResponse = 16,
+
+    // Piece of a large FileReadResponse
+    FileChunk = 17,
+
+    // Workspace wide search
+    GrepRequest = 18,
+    GrepResponse = 19,
+
+    // Pushed by the server when a workspace file changes
+    FileChangedNotification = 20,
+
+    // Sent back in place of a response when a request can't be served
+    ErrorResponse = 21,
+
+    // Server capabilities and workspace stats
+    WorkspaceInfoRequest = 22,
+    WorkspaceInfoResponse = 23,
+
+    // Renaming and deleting workspace files
+    RenameRequest = 24,
+    DeleteRequest = 25,
+    FileOpResponse = 26,
+
+    // Fuzzy path completion against the index
+    CompletionRequest = 27,
+    CompletionResponse = 28,
+
+    // Per-connection interest in FileChangedNotifications
+    WatchRequest = 29,
+    UnwatchRequest = 30,
+    WatchResponse = 31,
+
+    // Several requests for the price of one round trip
+    BatchRequest = 32,
+    BatchResponse = 33,
+
+    // Which build the server is running
+    VersionRequest = 34,
+    VersionResponse = 35,
+
+    // Grep results streamed as they are found
+    GrepMatchFrame = 36,
+    GrepDone = 37,
+
+    // Writes several files at once, all or nothing
+    SaveAllRequest = 38,
+    SaveAllResponse = 39,
+
+    // Full metadata of a single path
+    FileStatRequest = 40,
+    FileStatResponse = 41,
+
+    // Pushed by the server in place of many FileChangedNotifications
+    BulkChangeNotification = 42,
+
+    // Creating empty files and directories, answered by a FileOpResponse
+    TouchRequest = 43,
+    MkdirRequest = 44,
+
+    // Stops a long running request on the server
+    CancelRequest = 45,
+    CancelResponse = 46,
+
+    // A tar of a whole subtree, streamed in pieces
+    ArchiveRequest = 47,
+    ArchiveChunk = 48,
+    ArchiveDone = 49,
+
+    // Seals the connection with a pre-shared key, before the handshake
+    KeyExchangeRequest = 50,
+    KeyExchangeResponse = 51,
+
+    // Earlier versions of files the server overwrote, and putting one back
+    HistoryRequest = 52,
+    HistoryResponse = 53,
+    RestoreRequest = 54,
+    RestoreResponse = 55,
+
+    // Rebuilds the index on demand, after changes the watcher may have missed
+    RescanRequest = 56,
+    RescanResponse = 57,
+
+    // How much of a chunked transfer has been relayed to emacs
+    ProgressNotification = 58,
+
+    // Catching up on the notifications missed while disconnected
+    ChangesSinceRequest = 59,
+    ChangesSinceResponse = 60,
+
+    // How the server's copy of a file differs from an earlier version
+    DiffRequest = 61,
+    DiffResponse = 62,
+}
+
+impl MessageType {
+    // Requests that change the workspace, none of which a read-only server serves
+    pub fn is_mutating(self) -> bool {
+        return matches!(
+            self,
+            MessageType::ShellRequest
+                | MessageType::FileWriteRequest
+                | MessageType::RenameRequest
+                | MessageType::DeleteRequest
+                | MessageType::SaveAllRequest
+                | MessageType::TouchRequest
+                | MessageType::MkdirRequest
+                | MessageType::RestoreRequest
+        );
+    }
+
+    // Requests that can safely be sent twice, a batch may hold anything
+    pub fn is_idempotent(self) -> bool {
+        return !self.is_mutating() && self != MessageType::BatchRequest;
+    }
+}
+
+pub fn is_experimental(discriminant: u64) -> bool {
+    return EXPERIMENTAL_MESSAGE_TYPES.contains(&discriminant);
+}
+
+// Why the first element of a message couldn't be read as a MessageType
+#[derive(Debug, PartialEq)]
+pub enum MessageTypeError {
+    // the message isn't an array starting with a u64
+    Untagged,
+    // a discriminant this build doesn't know about, usually from a newer peer
+    Unknown(u64),
 }
 
 pub trait MessageTypeTrait {
+    // whether the type is sent inside an Envelope instead of carrying its own message_type
+    // field, see messages::envelope
+    const ENVELOPED: bool = false;
+
     fn messagetype() -> MessageType;
-    fn is_valid(&self) -> bool;
+    // An enveloped message is valid once the Envelope has checked its discriminant, the
+    // others check their own message_type field
+    fn is_valid(&self) -> bool {
+        return Self::ENVELOPED;
+    }
+}
+
+// Requests answered by a single message of one type, which ClientDaemon::send_request then
+// returns, so a request can't be paired with the wrong response. Streamed responses, like
+// a grep's matches, are read by whatever sends the request.
+pub trait Response: MessageTypeTrait {
+    type Response: DeserializeOwned + MessageTypeTrait;
 }
@@ -0,0 +1,58 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+// Heartbeat, the server echoes nonce and sent_at_millis back unchanged
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PingRequest {
+    message_type: u64,
+    pub nonce: u64,
+    // client wall clock when the ping was sent, milliseconds since the epoch
+    pub sent_at_millis: u64,
+}
+
+impl MessageTypeTrait for PingRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::PingRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for PingRequest {
+    type Response = PingResponse;
+}
+impl PingRequest {
+    pub fn new(nonce: u64, sent_at_millis: u64) -> PingRequest {
+        PingRequest {
+            message_type: PingRequest::messagetype().into(),
+            nonce,
+            sent_at_millis,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PingResponse {
+    message_type: u64,
+    pub nonce: u64,
+    pub sent_at_millis: u64,
+}
+
+impl MessageTypeTrait for PingResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::PingResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl PingResponse {
+    pub fn new(nonce: u64, sent_at_millis: u64) -> PingResponse {
+        PingResponse {
+            message_type: PingResponse::messagetype().into(),
+            nonce,
+            sent_at_millis,
+        }
+    }
+}
@@ -0,0 +1,31 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+
+// How far along a chunked transfer is, sent to emacs after each FileChunk it is relayed
+// when its FileReadRequest asked for progress. Sent inside an Envelope, see
+// messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ProgressNotification {
+    // id of the request whose response is being transferred
+    pub request_id: u64,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+impl MessageTypeTrait for ProgressNotification {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::ProgressNotification;
+    }
+}
+impl ProgressNotification {
+    pub fn new(request_id: u64, bytes_done: u64, bytes_total: u64) -> ProgressNotification {
+        ProgressNotification {
+            request_id,
+            bytes_done,
+            bytes_total,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// Has the server walk the workspace again even though its index doesn't look stale, after
+// files changed behind the watcher's back, e.g. a git checkout or an rsync. Answered by a
+// RescanResponse once the index is rebuilt. Sent inside an Envelope, see messages::envelope.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RescanRequest {
+    // rehash every file, even those whose size and mtime are unchanged, which rsync -t and
+    // some checkouts preserve
+    pub full: bool,
+}
+
+impl MessageTypeTrait for RescanRequest {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::RescanRequest;
+    }
+}
+impl Response for RescanRequest {
+    type Response = RescanResponse;
+}
+impl RescanRequest {
+    pub fn new(full: bool) -> RescanRequest {
+        RescanRequest { full }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RescanResponse {
+    // the rebuilt index
+    pub hash: ContentHash,
+    pub files: u64,
+    // files added, changed or removed by the rescan, each also pushed as a notification
+    pub changed: u64,
+}
+
+impl MessageTypeTrait for RescanResponse {
+    const ENVELOPED: bool = true;
+
+    fn messagetype() -> MessageType {
+        return MessageType::RescanResponse;
+    }
+}
+impl RescanResponse {
+    pub fn new(hash: ContentHash, files: u64, changed: u64) -> RescanResponse {
+        RescanResponse {
+            hash,
+            files,
+            changed,
+        }
+    }
+}
@@ -0,0 +1,99 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// One of the files of a SaveAllRequest, written the same way as a FileWriteRequest
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SaveFile {
+    // path of the file relative to the workspace
+    pub path: String,
+    #[serde(with = "serde_bytes")]
+    pub contents: Vec<u8>,
+    // hash of the contents the edit was based on, 0 for a new file
+    pub base_hash: ContentHash,
+}
+
+impl SaveFile {
+    pub fn new(path: String, contents: Vec<u8>, base_hash: ContentHash) -> SaveFile {
+        SaveFile {
+            path,
+            contents,
+            base_hash,
+        }
+    }
+}
+
+// Writes every file or none of them
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SaveAllRequest {
+    message_type: u64,
+    pub files: Vec<SaveFile>,
+}
+
+impl MessageTypeTrait for SaveAllRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::SaveAllRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for SaveAllRequest {
+    type Response = SaveAllResponse;
+}
+impl SaveAllRequest {
+    pub fn new(files: Vec<SaveFile>) -> SaveAllRequest {
+        SaveAllRequest {
+            message_type: SaveAllRequest::messagetype().into(),
+            files,
+        }
+    }
+}
+
+// What happened to one of the files of a SaveAllRequest
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SaveStatus {
+    pub path: String,
+    // hash of the file on the server after the request was handled
+    pub hash: ContentHash,
+    // the file changed since its base_hash
+    pub conflict: bool,
+}
+
+impl SaveStatus {
+    pub fn new(path: String, hash: ContentHash, conflict: bool) -> SaveStatus {
+        SaveStatus {
+            path,
+            hash,
+            conflict,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SaveAllResponse {
+    message_type: u64,
+    // false if any file conflicted, in which case nothing was written
+    pub saved: bool,
+    // one per requested file, in the order they were requested
+    pub files: Vec<SaveStatus>,
+}
+
+impl MessageTypeTrait for SaveAllResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::SaveAllResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl SaveAllResponse {
+    pub fn new(saved: bool, files: Vec<SaveStatus>) -> SaveAllResponse {
+        SaveAllResponse {
+            message_type: SaveAllResponse::messagetype().into(),
+            saved,
+            files,
+        }
+    }
+}
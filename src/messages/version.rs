@@ -0,0 +1,66 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+// Asks which build the server is running
+#[derive(Deserialize, Serialize, Debug)]
+pub struct VersionRequest {
+    message_type: u64,
+}
+
+impl MessageTypeTrait for VersionRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::VersionRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for VersionRequest {
+    type Response = VersionResponse;
+}
+impl VersionRequest {
+    pub fn new() -> VersionRequest {
+        VersionRequest {
+            message_type: VersionRequest::messagetype().into(),
+        }
+    }
+}
+
+impl Default for VersionRequest {
+    fn default() -> Self {
+        VersionRequest::new()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VersionResponse {
+    message_type: u64,
+    pub server_version: String,
+    pub protocol_version: u32,
+    // commit the server was built from, "unknown" outside a git checkout
+    pub build_hash: String,
+}
+
+impl MessageTypeTrait for VersionResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::VersionResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl VersionResponse {
+    pub fn new(
+        server_version: String,
+        protocol_version: u32,
+        build_hash: String,
+    ) -> VersionResponse {
+        VersionResponse {
+            message_type: VersionResponse::messagetype().into(),
+            server_version,
+            protocol_version,
+            build_hash,
+        }
+    }
+}
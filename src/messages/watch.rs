@@ -0,0 +1,87 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+
+// Narrows the FileChangedNotifications pushed on this connection. A connection hears about
+// every change until its first WatchRequest, from then on only about what it watches.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WatchRequest {
+    message_type: u64,
+    // relative to the workspace root, "" for the whole workspace
+    pub path: String,
+    // whether files in subdirectories of `path` count too, or only its direct children
+    pub recursive: bool,
+}
+
+impl MessageTypeTrait for WatchRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::WatchRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for WatchRequest {
+    type Response = WatchResponse;
+}
+impl WatchRequest {
+    pub fn new(path: String, recursive: bool) -> WatchRequest {
+        WatchRequest {
+            message_type: WatchRequest::messagetype().into(),
+            path,
+            recursive,
+        }
+    }
+}
+
+// Stops watching a path registered with a WatchRequest
+#[derive(Deserialize, Serialize, Debug)]
+pub struct UnwatchRequest {
+    message_type: u64,
+    pub path: String,
+}
+
+impl MessageTypeTrait for UnwatchRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::UnwatchRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for UnwatchRequest {
+    type Response = WatchResponse;
+}
+impl UnwatchRequest {
+    pub fn new(path: String) -> UnwatchRequest {
+        UnwatchRequest {
+            message_type: UnwatchRequest::messagetype().into(),
+            path,
+        }
+    }
+}
+
+// Answers both WatchRequest and UnwatchRequest
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WatchResponse {
+    message_type: u64,
+    // every path the connection watches once the request has been applied
+    pub watching: Vec<String>,
+}
+
+impl MessageTypeTrait for WatchResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::WatchResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl WatchResponse {
+    pub fn new(watching: Vec<String>) -> WatchResponse {
+        WatchResponse {
+            message_type: WatchResponse::messagetype().into(),
+            watching,
+        }
+    }
+}
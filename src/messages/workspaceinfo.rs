@@ -0,0 +1,85 @@
+pub use serde::{Deserialize, Serialize};
+
+use crate::messages::messagetype::{MessageType, MessageTypeTrait, Response};
+use crate::utils::hash::ContentHash;
+
+// Asks the server what it supports, clients send it before relying on optional features
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WorkspaceInfoRequest {
+    message_type: u64,
+}
+
+impl MessageTypeTrait for WorkspaceInfoRequest {
+    fn messagetype() -> MessageType {
+        return MessageType::WorkspaceInfoRequest;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl Response for WorkspaceInfoRequest {
+    type Response = WorkspaceInfoResponse;
+}
+impl WorkspaceInfoRequest {
+    pub fn new() -> WorkspaceInfoRequest {
+        WorkspaceInfoRequest {
+            message_type: WorkspaceInfoRequest::messagetype().into(),
+        }
+    }
+}
+
+impl Default for WorkspaceInfoRequest {
+    fn default() -> Self {
+        WorkspaceInfoRequest::new()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WorkspaceInfoResponse {
+    message_type: u64,
+    pub server_version: String,
+    pub protocol_version: u32,
+    // MessageType discriminants of every request the server can serve
+    pub supported_message_types: Vec<u32>,
+    pub file_count: u64,
+    pub index_hash: ContentHash,
+    // canonical path of the workspace on the server
+    pub workspace_root: String,
+}
+
+impl MessageTypeTrait for WorkspaceInfoResponse {
+    fn messagetype() -> MessageType {
+        return MessageType::WorkspaceInfoResponse;
+    }
+    fn is_valid(&self) -> bool {
+        return self.message_type == (Self::messagetype() as u64);
+    }
+}
+impl WorkspaceInfoResponse {
+    pub fn new(
+        server_version: String,
+        protocol_version: u32,
+        supported_message_types: Vec<u32>,
+        file_count: u64,
+        index_hash: ContentHash,
+        workspace_root: String,
+    ) -> WorkspaceInfoResponse {
+        WorkspaceInfoResponse {
+            message_type: WorkspaceInfoResponse::messagetype().into(),
+            server_version,
+            protocol_version,
+            supported_message_types,
+            file_count,
+            index_hash,
+            workspace_root,
+        }
+    }
+
+    pub fn supports(&self, message_type: MessageType) -> bool {
+        let discriminant: u64 = message_type.into();
+        return self
+            .supported_message_types
+            .iter()
+            .any(|supported| *supported as u64 == discriminant);
+    }
+}
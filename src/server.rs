@@ -1,44 +1,203 @@
+#![allow(clippy::needless_return)]
+
 extern crate dirs;
-extern crate rmp_serde as rmps;
-extern crate rmpv;
-extern crate serde_json;
 
-use std::convert::TryFrom;
+use std::collections::BTreeMap;
 use std::env;
-use std::fs;
-use std::io::Read;
-use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches};
+use daemonize::Daemonize;
+use env_logger::{Env, Target};
+use log::{error, info};
+
+use emacs_remote::error::ServerError;
+use emacs_remote::structs::config::ServerConfig;
+use emacs_remote::structs::indexer::Indexer;
+use emacs_remote::structs::server::{
+    check_workspace, ServerDaemon, DEFAULT_QUEUE_DEPTH, DEFAULT_WORKERS,
+};
+use emacs_remote::utils::logfile::{log_dir, RotatingFile, SharedFile};
+use emacs_remote::utils::net::{parse_bind_addr, DEFAULT_BIND_ADDR};
+use emacs_remote::utils::paths::ensure_dirs;
+use emacs_remote::utils::pidfile::{self, pid_file};
+use emacs_remote::utils::seal::PresharedKey;
+use emacs_remote::utils::signal::shutdown_on_signal;
+use emacs_remote::utils::span;
+use emacs_remote::version::{LONG_VERSION, VERSION};
+
+// Exit status when another process holds the port or socket, so that a script starting
+// the server can tell it apart from other failures
+const EXIT_ADDR_IN_USE: i32 = 3;
+// How long --replace waits for the server it stops to finish its requests and exit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Resolves a setting with the precedence CLI flag > environment variable > config file
+fn resolve(
+    matches: &ArgMatches,
+    name: &str,
+    env_var: &str,
+    config: Option<String>,
+) -> Option<String> {
+    return matches
+        .value_of(name)
+        .map(String::from)
+        .or_else(|| env::var(env_var).ok())
+        .or(config);
+}
 
-use clap::{App, Arg};
+// Walks the workspace the same way the index does and prints what would be indexed
+fn print_index(workspace: &str, patterns: &[String]) -> io::Result<()> {
+    let root = fs::canonicalize(workspace)?;
+    let indexer = Indexer::new(&root, patterns);
 
-use emacs_remote::handle::HandleServerDaemon;
-use emacs_remote::messages::index::IndexRequest;
-use emacs_remote::messages::messagetype::MessageType;
-use emacs_remote::structs::server::ServerDaemon;
-use emacs_remote::version::VERSION;
+    // top level entry -> (file count, total size)
+    let mut breakdown: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    let mut count = 0;
+    let mut total_size = 0;
+    for file in indexer.files() {
+        let size = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
+        let top_level = match file
+            .strip_prefix(&root)
+            .ok()
+            .and_then(|rel| rel.components().next())
+        {
+            Some(component) => component.as_os_str().to_string_lossy().to_string(),
+            None => continue,
+        };
+        let entry = breakdown.entry(top_level).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += size;
+        count += 1;
+        total_size += size;
+    }
 
-fn handle_connection(stream: &mut TcpStream, server_daemon: &mut ServerDaemon) -> Result<(), ()> {
-    let mut buf = [0; 1024];
-    stream.read(&mut buf).unwrap();
+    println!(
+        "{}: {} files, {}",
+        root.display(),
+        count,
+        human_size(total_size)
+    );
+    for (top_level, (count, size)) in &breakdown {
+        println!(
+            "  {:<40} {:>8} files {:>10}",
+            top_level,
+            count,
+            human_size(*size)
+        );
+    }
+    Ok(())
+}
 
-    let value: rmpv::Value = rmps::decode::from_read_ref(&buf).unwrap();
-    println!("Request: {}", serde_json::to_string(&value).unwrap());
+fn exit_status(e: &ServerError) -> i32 {
+    return match e {
+        ServerError::AddrInUse(_) => EXIT_ADDR_IN_USE,
+        _ => 1,
+    };
+}
 
-    assert!(value.is_array());
-    assert!(value[0].is_u64());
-    let msgtype = MessageType::try_from(value[0].as_u64().unwrap()).unwrap();
+// Stops the server recorded in the pid file, if there is one, and waits for it to exit
+fn replace(pid_file: &Path) -> Result<(), String> {
+    let pid = match pidfile::stop(pid_file) {
+        Ok(Some(pid)) => pid,
+        Ok(None) => return Ok(()),
+        Err(e) => return Err(format!("Unable to stop the running server: {}", e)),
+    };
+    info!("Stopping server {}", pid);
+    if !pidfile::wait_for_exit(pid, REPLACE_TIMEOUT) {
+        return Err(format!(
+            "Server {} didn't exit within {} seconds",
+            pid,
+            REPLACE_TIMEOUT.as_secs()
+        ));
+    }
+    return Ok(());
+}
 
-    match msgtype {
-        MessageType::IndexRequest => {
-            let request: IndexRequest = rmp_serde::from_read_ref(&buf).unwrap();
-            return request.handle(stream, server_daemon);
+// Forks into the background, the parent exits once the child has written the pid file.
+// Output goes to server.log next to the pid file.
+fn daemonize(pid_file: &Path) -> Result<(), String> {
+    match pidfile::running_pid(pid_file) {
+        Ok(Some(pid)) => {
+            return Err(format!(
+                "A server is already running with pid {}, stop it with --stop",
+                pid
+            ))
         }
-        _ => {
-            println!("Invalid type: {:?}", msgtype);
-            return Err(());
+        Ok(None) => {}
+        Err(e) => return Err(format!("Unable to read {}: {}", pid_file.display(), e)),
+    }
+
+    let log_path = pid_file.with_file_name("server.log");
+    let log = File::options()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(|e| format!("Unable to open {}: {}", log_path.display(), e))?;
+    // relative paths keep working once the daemon has moved to /
+    let working_directory = env::current_dir().map_err(|e| e.to_string())?;
+
+    return Daemonize::new()
+        .pid_file(pid_file)
+        .working_directory(working_directory)
+        .stdout(log.try_clone().map_err(|e| e.to_string())?)
+        .stderr(log)
+        .start()
+        .map_err(|e| format!("Unable to daemonize: {}", e));
+}
+
+// Logs go to stderr unless a log file is given, `trace` adds the timings of request spans
+fn init_logging(log_file: Option<RotatingFile>, trace: bool) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    let log_file = log_file.map(SharedFile::new);
+    if let Some(log_file) = &log_file {
+        builder.target(Target::Pipe(Box::new(log_file.clone())));
+    }
+    builder.init();
+    if trace {
+        match log_file {
+            Some(log_file) => span::init(move || log_file.clone()),
+            None => span::init(io::stderr),
+        }
+    }
+}
+
+// Relative paths are taken to be under ~/.emacs_remote/server/logs
+fn open_log_file(
+    matches: &ArgMatches,
+    emacs_remote_path: &str,
+    log_file: &str,
+) -> Result<RotatingFile, String> {
+    let max_size = match matches.value_of("log_max_size").unwrap().parse::<u64>() {
+        Ok(mib) if mib > 0 => mib * 1024 * 1024,
+        _ => return Err("Invalid --log-max-size, expected a positive number of MiB".to_string()),
+    };
+    let keep = matches
+        .value_of("log_keep")
+        .unwrap()
+        .parse::<usize>()
+        .map_err(|e| format!("Invalid --log-keep: {}", e))?;
+
+    ensure_dirs(Path::new(emacs_remote_path)).map_err(|e| e.to_string())?;
+    let path = log_dir(emacs_remote_path).join(log_file);
+    return RotatingFile::open(&path, max_size, keep)
+        .map_err(|e| format!("Unable to open {}: {}", path.display(), e));
+}
+
+fn human_size(size: u64) -> String {
+    let mut size = size as f64;
+    for unit in &["B", "KB", "MB", "GB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
         }
+        size /= 1024.0;
     }
+    return format!("{:.1} TB", size);
 }
 
 fn main() {
@@ -46,42 +205,565 @@ fn main() {
     let mut default_path = PathBuf::new();
     default_path.push(dirs::home_dir().unwrap());
     default_path.push(".emacs_remote");
+    let default_workers = DEFAULT_WORKERS.to_string();
+    let default_queue_depth = DEFAULT_QUEUE_DEPTH.to_string();
 
     let app = App::new("emacs-remote-server-daemon")
         .version(VERSION)
+        .long_version(LONG_VERSION)
         .author("antoniojkim <contact@antoniojkim.com>")
         .about("Starts emacs remote server daemon")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .help("Path to the config file, defaults to ~/.emacs_remote/config.toml"),
+        )
         .arg(
             Arg::with_name("workspace")
                 .short("w")
                 .long("workspace")
                 .takes_value(true)
-                .required(true)
-                .help("Specifies the path to workspace on the remote server"),
+                .help("Specifies the path to workspace on the remote server [env: EMACS_REMOTE_WORKSPACE]"),
+        )
+        .arg(
+            Arg::with_name("workspace_name")
+                .long("workspace-name")
+                .takes_value(true)
+                .help("Name clients select the workspace by, besides selecting none [env: EMACS_REMOTE_WORKSPACE_NAME]"),
+        )
+        .arg(
+            Arg::with_name("workspaces")
+                .long("workspaces")
+                .takes_value(true)
+                .use_delimiter(true)
+                .help("Comma separated name=path pairs of more workspaces to serve, each with its own index"),
         )
         .arg(
             Arg::with_name("emacs_remote_path")
                 .short("r")
                 .long("emacs_remote_path")
-                .default_value(default_path.to_str().unwrap())
-                .help("Path to emacs-remote directory"),
+                .takes_value(true)
+                .help("Path to emacs-remote directory, defaults to ~/.emacs_remote [env: EMACS_REMOTE_PATH]"),
         )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
-                .default_value("9130")
-                .help("Specifies the port that the server is listening on"),
+                .takes_value(true)
+                .help("Specifies the port that the server is listening on, defaults to 9130 [env: EMACS_REMOTE_PORT]"),
+        )
+        .arg(
+            Arg::with_name("bind_addr")
+                .long("bind-addr")
+                .takes_value(true)
+                .help("Address to listen on, defaults to 127.0.0.1 [env: EMACS_REMOTE_BIND_ADDR]"),
+        )
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .takes_value(true)
+                .help("Listens on this Unix domain socket instead of a TCP port [env: EMACS_REMOTE_SOCKET]"),
+        )
+        .arg(
+            Arg::with_name("psk")
+                .long("psk")
+                .takes_value(true)
+                .help("Only accepts connections sealed with the pre-shared key in this file [env: EMACS_REMOTE_PSK]"),
+        )
+        .arg(
+            Arg::with_name("metrics_port")
+                .long("metrics-port")
+                .takes_value(true)
+                .help("Serves Prometheus metrics over http on this port, off by default [env: EMACS_REMOTE_METRICS_PORT]"),
+        )
+        .arg(
+            Arg::with_name("max_frame_size")
+                .long("max-frame-size")
+                .default_value("64")
+                .help("Largest message accepted from a peer, in MiB"),
+        )
+        .arg(
+            Arg::with_name("allowed_workspaces")
+                .long("allowed-workspaces")
+                .takes_value(true)
+                .use_delimiter(true)
+                .help("Comma separated directories the workspace has to be inside of, any by default"),
+        )
+        .arg(
+            Arg::with_name("read_only")
+                .long("read-only")
+                .takes_value(false)
+                .help("Refuses every request that would write, rename or delete workspace files"),
+        )
+        .arg(
+            Arg::with_name("max_read_size")
+                .long("max-read-size")
+                .default_value("64")
+                .help("Larger files are only sent to clients that force the read, in MiB, 0 for no limit"),
+        )
+        .arg(
+            Arg::with_name("history_max_size")
+                .long("history-max-size")
+                .default_value("256")
+                .help("Space in MiB kept for the files writes replace, so they can be restored, 0 keeps none"),
+        )
+        .arg(
+            Arg::with_name("history_max_age")
+                .long("history-max-age")
+                .default_value("30")
+                .help("Days a replaced file is kept for"),
+        )
+        .arg(
+            Arg::with_name("idle_timeout")
+                .long("idle-timeout")
+                .default_value("300")
+                .help("Closes client connections that are silent for this many seconds, 0 keeps them open"),
+        )
+        .arg(
+            Arg::with_name("max_connections")
+                .long("max-connections")
+                .default_value("256")
+                .help("Refuses client connections past this many open ones, 0 for no limit"),
+        )
+        .arg(
+            Arg::with_name("max_connections_per_ip")
+                .long("max-connections-per-ip")
+                .default_value("0")
+                .help("Refuses client connections past this many open ones from the same address, 0 for no limit"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .default_value(&default_workers)
+                .help("Threads client connections are served on, one connection each while it is open"),
+        )
+        .arg(
+            Arg::with_name("queue_depth")
+                .long("queue-depth")
+                .default_value(&default_queue_depth)
+                .help("Client connections that may wait for a worker, those past it are refused as busy"),
+        )
+        .arg(
+            Arg::with_name("no_tcp_nodelay")
+                .long("no-tcp-nodelay")
+                .takes_value(false)
+                .help("Lets Nagle's algorithm batch small writes, trading latency for fewer packets"),
+        )
+        .arg(
+            Arg::with_name("socket_buffer_size")
+                .long("socket-buffer-size")
+                .takes_value(true)
+                .help("Socket send and receive buffer size in KiB, left to the kernel by default"),
+        )
+        .arg(
+            Arg::with_name("compression_level")
+                .long("compression-level")
+                .default_value("3")
+                .help("zstd level for large messages, 0 disables compression"),
+        )
+        .arg(
+            Arg::with_name("index_threads")
+                .long("index-threads")
+                .takes_value(true)
+                .help("Caps the threads used to hash the workspace, defaults to one per core"),
+        )
+        .arg(
+            Arg::with_name("log_file")
+                .long("log-file")
+                .takes_value(true)
+                .help("Logs to this file instead of stderr, relative paths are under ~/.emacs_remote/server/logs"),
+        )
+        .arg(
+            Arg::with_name("log_max_size")
+                .long("log-max-size")
+                .default_value("10")
+                .help("Size in MiB at which --log-file is rotated"),
+        )
+        .arg(
+            Arg::with_name("log_keep")
+                .long("log-keep")
+                .default_value("5")
+                .help("Number of rotated log files kept next to --log-file"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .takes_value(false)
+                .help("Logs how long each request spends decoding, in its handler, encoding and writing"),
+        )
+        .arg(
+            Arg::with_name("daemonize")
+                .long("daemonize")
+                .takes_value(false)
+                .help("Runs the server in the background and records its pid in ~/.emacs_remote/server/server.pid"),
+        )
+        .arg(
+            Arg::with_name("stop")
+                .long("stop")
+                .takes_value(false)
+                .help("Stops the server started with --daemonize and exits"),
+        )
+        .arg(
+            Arg::with_name("replace")
+                .long("replace")
+                .takes_value(false)
+                .help("Stops the server started with --daemonize, if one is running, before starting"),
+        )
+        .arg(
+            Arg::with_name("print_index")
+                .long("print-index")
+                .alias("dry-run")
+                .takes_value(false)
+                .help("Prints what would be indexed and exits without starting the server"),
         );
 
     let matches = app.get_matches_from(env::args_os());
+    let trace = matches.is_present("trace");
 
-    let mut server_daemon = ServerDaemon::new(
-        matches.value_of("emacs_remote_path").unwrap().to_string(),
-        matches.value_of("port").unwrap().to_string(),
-        matches.value_of("workspace").unwrap().to_string(),
-    );
+    let config_path = match matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        None => ServerConfig::default_path(),
+    };
+    let config = match ServerConfig::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            init_logging(None, trace);
+            error!("Unable to load config: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let emacs_remote_path = resolve(
+        &matches,
+        "emacs_remote_path",
+        "EMACS_REMOTE_PATH",
+        config.emacs_remote_path,
+    )
+    .unwrap_or_else(|| default_path.to_str().unwrap().to_string());
+    let pid_file = pid_file(&emacs_remote_path);
+
+    match matches.value_of("log_file") {
+        Some(log_file) => match open_log_file(&matches, &emacs_remote_path, log_file) {
+            Ok(log_file) => init_logging(Some(log_file), trace),
+            Err(e) => {
+                init_logging(None, trace);
+                error!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => init_logging(None, trace),
+    }
+
+    if matches.is_present("stop") {
+        match pidfile::stop(&pid_file) {
+            Ok(Some(pid)) => info!("Sent SIGTERM to server {}", pid),
+            Ok(None) => info!("No server is running"),
+            Err(e) => {
+                error!("Unable to stop the server: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let workspace = match resolve(
+        &matches,
+        "workspace",
+        "EMACS_REMOTE_WORKSPACE",
+        config.workspace,
+    ) {
+        Some(workspace) => workspace,
+        None => {
+            error!("No workspace given, pass --workspace or set it in the config file");
+            process::exit(1);
+        }
+    };
+    // checked up front so that a mistyped path is reported here rather than in the log of
+    // a daemonized server
+    if let Err(e) = check_workspace(&workspace) {
+        error!("{}", e);
+        process::exit(1);
+    }
+    let port = resolve(
+        &matches,
+        "port",
+        "EMACS_REMOTE_PORT",
+        config.port.map(|port| port.to_string()),
+    )
+    .unwrap_or_else(|| "9130".to_string());
+    let bind_addr = match resolve(
+        &matches,
+        "bind_addr",
+        "EMACS_REMOTE_BIND_ADDR",
+        config.bind_addr,
+    ) {
+        Some(bind_addr) => match parse_bind_addr(&bind_addr) {
+            Ok(bind_addr) => bind_addr,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => DEFAULT_BIND_ADDR,
+    };
+    let socket = resolve(&matches, "socket", "EMACS_REMOTE_SOCKET", config.socket);
+    let metrics_port = match resolve(
+        &matches,
+        "metrics_port",
+        "EMACS_REMOTE_METRICS_PORT",
+        config.metrics_port.map(|port| port.to_string()),
+    ) {
+        Some(metrics_port) => match metrics_port.parse::<u16>() {
+            Ok(metrics_port) => Some(metrics_port),
+            Err(e) => {
+                error!("Invalid metrics port {}: {}", metrics_port, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    if matches.is_present("print_index") {
+        if let Err(e) = print_index(&workspace, &config.ignore) {
+            error!("Unable to index {}: {}", workspace, e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // loaded up front for the same reason as the workspace is checked
+    let preshared_key = match resolve(&matches, "psk", "EMACS_REMOTE_PSK", config.psk) {
+        Some(path) => match PresharedKey::load(Path::new(&path)) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                error!("Unable to load the pre-shared key from {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-    server_daemon.init();
-    server_daemon.listen();
+    if matches.is_present("replace") {
+        if let Err(e) = replace(&pid_file) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    // Before any thread is started, only the forking thread survives a fork
+    let daemonized = matches.is_present("daemonize");
+    if daemonized {
+        if let Err(e) = ensure_dirs(Path::new(&emacs_remote_path)) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        if let Err(e) = daemonize(&pid_file) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(index_threads) = matches.value_of("index_threads") {
+        let num_threads = match index_threads.parse::<usize>() {
+            Ok(num_threads) if num_threads > 0 => num_threads,
+            _ => {
+                error!("Invalid --index-threads, expected a positive number");
+                process::exit(1);
+            }
+        };
+        // indexing is the only user of the global pool
+        if let Err(e) = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build_global()
+        {
+            error!("Unable to set up the index thread pool: {}", e);
+            process::exit(1);
+        }
+    }
+
+    let mut server_daemon = match ServerDaemon::new(emacs_remote_path, port, workspace) {
+        Ok(server_daemon) => server_daemon,
+        Err(e) => {
+            error!("Unable to start Server Daemon: {}", e);
+            process::exit(1);
+        }
+    };
+    server_daemon.workspace_name = resolve(
+        &matches,
+        "workspace_name",
+        "EMACS_REMOTE_WORKSPACE_NAME",
+        config.workspace_name,
+    )
+    .unwrap_or_default();
+    server_daemon.workspaces = match matches.values_of("workspaces") {
+        Some(workspaces) => {
+            let mut named = BTreeMap::new();
+            for workspace in workspaces {
+                match workspace.split_once('=') {
+                    Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+                        named.insert(name.to_string(), path.to_string());
+                    }
+                    _ => {
+                        error!(
+                            "Invalid --workspaces entry {}, expected name=path",
+                            workspace
+                        );
+                        process::exit(1);
+                    }
+                }
+            }
+            named
+        }
+        None => config.workspaces,
+    };
+    server_daemon.ignore = config.ignore;
+    server_daemon.allowed_workspaces = match matches.values_of("allowed_workspaces") {
+        Some(allowed) => allowed.map(PathBuf::from).collect(),
+        None => config
+            .allowed_workspaces
+            .into_iter()
+            .map(PathBuf::from)
+            .collect(),
+    };
+    server_daemon.read_only = matches.is_present("read_only") || config.read_only;
+    server_daemon.hash_salt = config.hash_salt;
+    server_daemon.hash_salts = config.hash_salts;
+    server_daemon.preshared_key = preshared_key;
+    server_daemon.bind_addr = bind_addr;
+    server_daemon.metrics_port = metrics_port;
+    server_daemon.socket = socket.map(PathBuf::from);
+
+    server_daemon.compression_level = match matches
+        .value_of("compression_level")
+        .unwrap()
+        .parse::<i32>()
+    {
+        Ok(0) => None,
+        Ok(level) => Some(level),
+        Err(e) => {
+            error!("Invalid --compression-level: {}", e);
+            process::exit(1);
+        }
+    };
+
+    server_daemon.max_frame_size =
+        match matches.value_of("max_frame_size").unwrap().parse::<usize>() {
+            Ok(mib) if mib > 0 => mib * 1024 * 1024,
+            _ => {
+                error!("Invalid --max-frame-size, expected a positive number of MiB");
+                process::exit(1);
+            }
+        };
+
+    server_daemon.max_read_size = match matches.value_of("max_read_size").unwrap().parse::<u64>() {
+        Ok(0) => None,
+        Ok(mib) => Some(mib * 1024 * 1024),
+        Err(e) => {
+            error!("Invalid --max-read-size: {}", e);
+            process::exit(1);
+        }
+    };
+
+    server_daemon.max_history_size =
+        match matches.value_of("history_max_size").unwrap().parse::<u64>() {
+            Ok(0) => None,
+            Ok(mib) => Some(mib * 1024 * 1024),
+            Err(e) => {
+                error!("Invalid --history-max-size: {}", e);
+                process::exit(1);
+            }
+        };
+
+    server_daemon.max_history_age =
+        match matches.value_of("history_max_age").unwrap().parse::<u64>() {
+            Ok(days) if days > 0 => Duration::from_secs(days * 24 * 60 * 60),
+            _ => {
+                error!("Invalid --history-max-age, expected a positive number of days");
+                process::exit(1);
+            }
+        };
+
+    server_daemon.idle_timeout = match matches.value_of("idle_timeout").unwrap().parse::<u64>() {
+        Ok(0) => None,
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(e) => {
+            error!("Invalid --idle-timeout: {}", e);
+            process::exit(1);
+        }
+    };
+
+    server_daemon.max_connections = match matches.value_of("max_connections").unwrap().parse() {
+        Ok(0) => None,
+        Ok(max_connections) => Some(max_connections),
+        Err(e) => {
+            error!("Invalid --max-connections: {}", e);
+            process::exit(1);
+        }
+    };
+
+    server_daemon.max_connections_per_ip =
+        match matches.value_of("max_connections_per_ip").unwrap().parse() {
+            Ok(0) => None,
+            Ok(max_connections) => Some(max_connections),
+            Err(e) => {
+                error!("Invalid --max-connections-per-ip: {}", e);
+                process::exit(1);
+            }
+        };
+
+    server_daemon.workers = match matches.value_of("workers").unwrap().parse::<usize>() {
+        Ok(workers) if workers > 0 => workers,
+        _ => {
+            error!("Invalid --workers, expected a positive number");
+            process::exit(1);
+        }
+    };
+
+    server_daemon.queue_depth = match matches.value_of("queue_depth").unwrap().parse() {
+        Ok(queue_depth) => queue_depth,
+        Err(e) => {
+            error!("Invalid --queue-depth: {}", e);
+            process::exit(1);
+        }
+    };
+
+    server_daemon.socket_options.nodelay = !matches.is_present("no_tcp_nodelay");
+    if let Some(kib) = matches.value_of("socket_buffer_size") {
+        server_daemon.socket_options.buffer_size = match kib.parse::<usize>() {
+            Ok(kib) if kib > 0 => Some(kib * 1024),
+            _ => {
+                error!("Invalid --socket-buffer-size, expected a positive number of KiB");
+                process::exit(1);
+            }
+        };
+    }
+
+    if let Err(e) = shutdown_on_signal(server_daemon.shutdown_flag()) {
+        error!("Unable to install signal handler: {}", e);
+        process::exit(1);
+    }
+
+    // Bound before indexing, which can take a while, so that a taken port is reported
+    // straight away
+    let listener = match server_daemon.bind() {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(exit_status(&e));
+        }
+    };
+    if let Err(e) = server_daemon.init() {
+        error!("Unable to initialize Server Daemon: {}", e);
+        process::exit(1);
+    }
+    let result = Arc::new(server_daemon).listen_on(listener);
+    if daemonized {
+        let _ = fs::remove_file(&pid_file);
+    }
+    if let Err(e) = result {
+        error!("Server Daemon stopped: {}", e);
+        process::exit(exit_status(&e));
+    }
 }
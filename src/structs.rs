@@ -1,2 +1,9 @@
+pub mod archive;
+pub mod batch;
 pub mod client;
+pub mod config;
+pub mod grep;
+pub mod history;
+pub mod index;
+pub mod indexer;
 pub mod server;
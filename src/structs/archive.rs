@@ -0,0 +1,77 @@
+extern crate rmp_serde as rmps;
+
+use std::io::{self, Read};
+
+use crate::error::ClientError;
+use crate::messages::archive::{ArchiveChunk, ArchiveDone};
+use crate::messages::messagetype::MessageType;
+use crate::structs::client::ClientDaemon;
+use crate::utils::stream::{decode, peek_message_type};
+
+// The archive answering the request with `request_id`, read out of the ArchiveChunks the
+// server sends for it
+pub struct ArchiveReader<'a> {
+    client_daemon: &'a mut ClientDaemon,
+    request_id: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: Option<ArchiveDone>,
+    // what went wrong getting the next chunk, Read only has room for an io::Error
+    error: Option<ClientError>,
+}
+
+impl<'a> ArchiveReader<'a> {
+    pub fn new(client_daemon: &'a mut ClientDaemon, request_id: u64) -> ArchiveReader<'a> {
+        return ArchiveReader {
+            client_daemon,
+            request_id,
+            chunk: Vec::new(),
+            pos: 0,
+            done: None,
+            error: None,
+        };
+    }
+
+    // Reads past anything left of the archive, e.g. the padding after the end of a tar, up
+    // to the ArchiveDone
+    pub fn finish(mut self) -> Result<ArchiveDone, ClientError> {
+        while self.done.is_none() {
+            self.next_chunk()?;
+        }
+        return Ok(self.done.unwrap());
+    }
+
+    // The error behind a failed read, if it came from the connection to the server
+    pub fn take_error(&mut self) -> Option<ClientError> {
+        return self.error.take();
+    }
+
+    fn next_chunk(&mut self) -> Result<(), ClientError> {
+        let buf = self.client_daemon.server_recv_frame(self.request_id)?;
+        match peek_message_type(&buf) {
+            Ok(MessageType::ArchiveChunk) => {
+                self.chunk = decode::<ArchiveChunk>(&buf)?.data;
+                self.pos = 0;
+            }
+            // an ErrorResponse or something unexpected, reported as such
+            _ => self.done = Some(decode::<ArchiveDone>(&buf)?),
+        }
+        return Ok(());
+    }
+}
+
+impl<'a> Read for ArchiveReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() && self.done.is_none() {
+            if let Err(e) = self.next_chunk() {
+                let message = e.to_string();
+                self.error = Some(e);
+                return Err(io::Error::other(message));
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
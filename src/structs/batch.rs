@@ -0,0 +1,80 @@
+extern crate rmp_serde as rmps;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::ClientError;
+use crate::messages::batch::{BatchRequest, BatchResponse, Envelope};
+use crate::messages::envelope;
+use crate::messages::messagetype::MessageTypeTrait;
+use crate::utils;
+
+// Requests collected to be sent to the server together, see ClientDaemon::flush
+#[derive(Default)]
+pub struct Batch {
+    requests: Vec<Envelope>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        return Batch::default();
+    }
+
+    // Queues `request`, returning the index its response will have in the BatchResults
+    pub fn push<T>(&mut self, request: &T) -> Result<usize, ClientError>
+    where
+        T: Serialize + MessageTypeTrait,
+    {
+        self.requests
+            .push(Envelope::new(envelope::encode(request)?));
+        return Ok(self.requests.len() - 1);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.requests.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.requests.is_empty();
+    }
+
+    pub fn into_request(self) -> BatchRequest {
+        return BatchRequest::new(self.requests);
+    }
+}
+
+// The responses to a flushed Batch, in the order their requests were pushed
+pub struct BatchResults {
+    responses: Vec<Envelope>,
+}
+
+impl BatchResults {
+    pub fn new(response: BatchResponse) -> BatchResults {
+        BatchResults {
+            responses: response.responses,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.responses.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.responses.is_empty();
+    }
+
+    // The response to the request pushed at `index`, decoded the same way as one read off
+    // the connection so a request that failed comes back as ServerReported
+    pub fn get<T>(&self, index: usize) -> Result<T, ClientError>
+    where
+        T: DeserializeOwned + MessageTypeTrait,
+    {
+        return match self.responses.get(index) {
+            Some(envelope) => utils::stream::decode::<T>(&envelope.message),
+            None => Err(ClientError::Protocol(format!(
+                "the batch has no response {}",
+                index
+            ))),
+        };
+    }
+}
@@ -1,42 +1,184 @@
 extern crate rmp_serde as rmps;
 extern crate ssh2;
 
-use std::convert::TryFrom;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
-use std::io::Read;
-use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
-use std::time::Instant;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use log::{debug, error, trace, warn};
+use rand::Rng;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tracing::info_span;
 
-use crate::handle::HandleClientDaemon;
-use crate::messages::index::IndexRequest;
-use crate::messages::messagetype::MessageType;
+use crate::error::ClientError;
+use crate::handle::{ClientHandler, Dispatcher};
+use crate::messages::archive::{ArchiveDone, ArchiveRequest};
+use crate::messages::batch::BatchResponse;
+use crate::messages::changes::{ChangesSinceRequest, ChangesSinceResponse};
+use crate::messages::completion::{CompletionRequest, CompletionResponse};
+use crate::messages::diff::{DiffRequest, DiffResponse};
+use crate::messages::dirlist::{DirEntry, DirectoryListRequest, DirectoryListResponse};
+use crate::messages::envelope;
+use crate::messages::error::{ErrorResponse, UNSUPPORTED};
+use crate::messages::filechanged::{BulkChangeNotification, FileChangedNotification};
+use crate::messages::fileops::{DeleteRequest, MkdirRequest, RenameRequest, TouchRequest};
+use crate::messages::fileread::{FileChunk, FileReadRequest, FileReadResponse, CHUNK_SIZE};
+use crate::messages::filestat::{FileStatRequest, FileStatResponse};
+use crate::messages::filewrite::FileWriteRequest;
+use crate::messages::grep::{GrepDone, GrepMatch, GrepRequest};
+use crate::messages::handshake::HandshakeResponse;
+use crate::messages::index::{IndexRequest, IndexResponse};
+use crate::messages::messagetype::{MessageType, MessageTypeError, MessageTypeTrait, Response};
+use crate::messages::ping::PingRequest;
+use crate::messages::progress::ProgressNotification;
+use crate::messages::rescan::{RescanRequest, RescanResponse};
+use crate::messages::saveall::{SaveAllRequest, SaveAllResponse, SaveFile};
+use crate::messages::version::{VersionRequest, VersionResponse};
+use crate::messages::watch::{UnwatchRequest, WatchRequest, WatchResponse};
+use crate::messages::workspaceinfo::{WorkspaceInfoRequest, WorkspaceInfoResponse};
+use crate::structs::archive::ArchiveReader;
+use crate::structs::batch::{Batch, BatchResults};
+use crate::structs::grep::read_grep_frame;
+use crate::structs::index::{index_file_name, Index, IndexEntry};
 use crate::utils;
-use crate::utils::drop_guard::DropGuard;
+use crate::utils::archive;
+use crate::utils::cache::{BlobCache, DEFAULT_CACHE_BUDGET};
+use crate::utils::connection::Connection;
+use crate::utils::frame::{DEFAULT_COMPRESSION_LEVEL, DEFAULT_MAX_FRAME_SIZE};
+use crate::utils::hash::{self, ContentHash};
+use crate::utils::net::{bind_free_port, DEFAULT_BIND_ADDR};
+use crate::utils::paths::{ensure_dirs, normalize_workspace_path};
+use crate::utils::pool::{self, ConnectionPool};
+use crate::utils::retry::{is_disconnect, RetryPolicy};
+use crate::utils::scp::ScpSession;
+use crate::utils::seal::PresharedKey;
 use crate::utils::shutil::bash;
-use crate::utils::stcp::STCPSession;
+use crate::utils::span;
+use crate::utils::stcp::{SSHOptions, STCPSession, TunnelStatus};
+use crate::utils::transport::{Endpoint, SocketOptions, Transport};
+use crate::version::{supports_request_ids, version_skew, VersionSkew, VERSION};
+
+// How often listen() checks that the tunnel is still alive, and how long a ping may take
+// before the connection is considered dead
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long the server has to answer a request before it is abandoned
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// Requests that legitimately take longer (or must fail faster) than DEFAULT_TIMEOUT
+const REQUEST_TIMEOUTS: &[(MessageType, Duration)] = &[
+    (MessageType::PingRequest, PING_TIMEOUT),
+    (MessageType::IndexRequest, Duration::from_secs(120)),
+    (MessageType::GrepRequest, Duration::from_secs(120)),
+    (MessageType::ArchiveRequest, Duration::from_secs(120)),
+];
+
+// Told how far along each chunked transfer is, those read_file reassembles as well as those
+// relayed to emacs
+pub type ProgressCallback = Arc<dyn Fn(&ProgressNotification) + Send + Sync>;
 
 #[derive(Deserialize, Serialize)]
 pub struct ClientDaemon {
     host: String,
     workspace: String,
+    // which of the server's workspaces to be served, the one it was started with if empty
+    #[serde(skip)]
+    workspace_name: String,
     // connection info
     pub emacs_remote_path: String,
     server_port: u32,
     client_port: u32,
+    // address the local daemon, the tunnel and the remote server listen on
+    #[serde(skip, default = "default_bind_addr")]
+    bind_addr: IpAddr,
+    // a server on this host listening on a Unix domain socket, used instead of the tunnel
+    #[serde(skip)]
+    socket: Option<PathBuf>,
 
     // streams
-    // #[serde(skip)]
-    // server: Option<TcpStream>,
+    #[serde(skip)]
+    server: Option<Connection>,
+    // for requests that run alongside the ones on `server`
+    #[serde(skip)]
+    pool: Option<Arc<ConnectionPool>>,
+    // zstd level for large requests, None to never compress
+    #[serde(skip, default = "default_compression_level")]
+    compression_level: Option<i32>,
+    // frames larger than this from the server or emacs are refused
+    #[serde(skip, default = "default_max_frame_size")]
+    max_frame_size: usize,
+    // applied to the server connections and the ones emacs makes
+    #[serde(skip)]
+    socket_options: SocketOptions,
+    // server connections are sealed with it, the server has to hold the same one
+    #[serde(skip)]
+    preshared_key: Option<Arc<PresharedKey>>,
+    // per request type overrides of the read/write timeout, DEFAULT_TIMEOUT otherwise
+    #[serde(skip, default = "default_timeouts")]
+    timeouts: HashMap<MessageType, Duration>,
+    // how send_request retries idempotent requests
+    #[serde(skip)]
+    retry_policy: RetryPolicy,
+    // what the connected server supports, fetched on first use after each connect
+    #[serde(skip)]
+    server_info: Option<WorkspaceInfoResponse>,
+    // whether the connected server echoes request ids, protocol 2.1 and up
+    #[serde(skip)]
+    request_ids: bool,
+    // ids start at 1 and are never reused, 0 marks an untagged frame
+    #[serde(skip)]
+    issued_request_ids: u64,
+    // announced in the handshake of every connection to the server, its pool's included
+    #[serde(skip, default = "pool::client_id")]
+    client_id: u64,
+    // id of the last request sent, the one server_recv waits for
+    #[serde(skip)]
+    last_request_id: u64,
+    // responses that arrived while waiting for a different request, by request id
+    #[serde(skip)]
+    pending: HashMap<u64, VecDeque<Vec<u8>>>,
 
     // Secure TCP connection
     #[serde(skip)]
     session: Option<STCPSession>,
+    #[serde(skip)]
+    ssh_options: SSHOptions,
+
+    // set to stop listen() after the current request
+    #[serde(skip)]
+    shutdown: Arc<AtomicBool>,
 
     // state
-    current_index_hash: u64,
+    current_index_hash: ContentHash,
+    // path -> hash of the contents last fetched from the server, the contents themselves
+    // live in the blob cache
+    #[serde(skip)]
+    files: HashMap<String, ContentHash>,
+    // shared by every workspace, its blobs are keyed by content hash salted with `salt`
+    #[serde(skip)]
+    cache: Option<BlobCache>,
+    // the server workspace's hash salt, from the last handshake
+    #[serde(skip)]
+    salt: u64,
+    // the server's index hash as of the last handshake or catch up, every change since has
+    // been pushed to this client. What it asks about after a reconnect.
+    #[serde(skip)]
+    synced_index_hash: ContentHash,
+    // the server's index as pulled over scp by init(), before the tunnel was up
+    #[serde(skip)]
+    initial_index: Option<Index>,
+    #[serde(skip)]
+    on_progress: Option<ProgressCallback>,
+
+    #[serde(skip, default = "Dispatcher::client")]
+    handlers: Dispatcher<ClientHandler>,
 }
 
 impl ClientDaemon {
@@ -44,30 +186,52 @@ impl ClientDaemon {
         host: String,
         workspace: String,
         emacs_remote_path: String,
-    ) -> Result<ClientDaemon, ()> {
+    ) -> Result<ClientDaemon, ClientError> {
+        ensure_dirs(Path::new(&emacs_remote_path))?;
+
         let mut workspace_path = PathBuf::new();
         workspace_path.push(emacs_remote_path.clone());
         workspace_path.push("client");
-        fs::create_dir_all(workspace_path.clone()).expect("Unable to create client path");
-
         workspace_path.push("workspaces");
-        fs::create_dir_all(workspace_path.clone()).expect("Unable to create workspace path");
 
         workspace_path.push(format!(
             "{}-{}.workspace",
             host,
-            utils::hash::hash(&workspace_path),
+            utils::hash::fast_hash(&workspace_path),
         ));
 
+        let mut cache_path = PathBuf::new();
+        cache_path.push(emacs_remote_path.clone());
+        cache_path.push("client");
+        cache_path.push("cache");
+        // the cache only saves round trips, the daemon works without one
+        let cache = match BlobCache::new(&cache_path, DEFAULT_CACHE_BUDGET) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                warn!("Unable to open blob cache {}: {}", cache_path.display(), e);
+                None
+            }
+        };
+
         if workspace_path.as_path().exists() {
-            let data = fs::read(workspace_path.as_path()).unwrap();
-            let mut client: ClientDaemon = rmps::from_read_ref(&data).unwrap();
+            let data = fs::read(workspace_path.as_path())?;
+            let mut client: ClientDaemon = rmps::from_read_ref(&data).map_err(|e| {
+                ClientError::InvalidWorkspace(format!(
+                    "{} is corrupt: {}",
+                    workspace_path.display(),
+                    e
+                ))
+            })?;
+            client.cache = cache;
 
             if client.host != host
                 || client.workspace != workspace
                 || client.emacs_remote_path != emacs_remote_path
             {
-                return Err(());
+                return Err(ClientError::InvalidWorkspace(format!(
+                    "{} was saved for a different workspace",
+                    workspace_path.display()
+                )));
             }
             return Ok(client);
         }
@@ -75,139 +239,1294 @@ impl ClientDaemon {
         Ok(ClientDaemon {
             host,
             workspace,
+            workspace_name: String::new(),
             emacs_remote_path,
             server_port: 0,
             client_port: 0,
-            // server: None,
+            bind_addr: DEFAULT_BIND_ADDR,
+            socket: None,
+            server: None,
+            pool: None,
+            compression_level: default_compression_level(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            socket_options: SocketOptions::default(),
+            preshared_key: None,
+            timeouts: default_timeouts(),
+            retry_policy: RetryPolicy::default(),
+            server_info: None,
+            request_ids: false,
+            issued_request_ids: 0,
+            client_id: pool::client_id(),
+            last_request_id: 0,
+            pending: HashMap::new(),
             session: None,
+            ssh_options: SSHOptions::default(),
+            shutdown: Arc::new(AtomicBool::new(false)),
             // initialize state
             current_index_hash: 0,
+            files: HashMap::new(),
+            cache,
+            salt: 0,
+            synced_index_hash: 0,
+            initial_index: None,
+            on_progress: None,
+            handlers: Dispatcher::client(),
         })
     }
 
-    pub fn init(&mut self) {
-        // self.reset_tcp_connection()
-        //     .expect("Unable to establish tcp connection");
-        self.reset_ssh_session();
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        return self.shutdown.clone();
+    }
+
+    // Takes effect the next time the ssh session is reset
+    pub fn set_ssh_options(&mut self, ssh_options: SSHOptions) {
+        self.ssh_options = ssh_options;
+    }
+
+    // Takes effect the next time the ssh session is reset or listen() is called
+    pub fn set_bind_addr(&mut self, bind_addr: IpAddr) {
+        self.bind_addr = bind_addr;
+    }
+
+    // Connects to the server over `socket` from the next connection on, instead of through
+    // the ssh tunnel, which init() then doesn't start
+    pub fn set_socket(&mut self, socket: Option<PathBuf>) {
+        self.socket = socket;
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    // Takes effect on the next connection to the server, which selects the workspace of
+    // that name on a server serving several
+    pub fn set_workspace_name(&mut self, workspace_name: String) {
+        self.workspace_name = workspace_name;
+    }
+
+    // Takes effect on the next connection to the server
+    pub fn set_preshared_key(&mut self, preshared_key: Option<PresharedKey>) {
+        self.preshared_key = preshared_key.map(Arc::new);
+    }
+
+    // Takes effect on the next connection to the server, None disables compression
+    pub fn set_compression_level(&mut self, compression_level: Option<i32>) {
+        self.compression_level = compression_level;
+    }
+
+    // Takes effect on the next connection
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    // Takes effect on the next connection
+    pub fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket_options = socket_options;
+    }
+
+    pub fn set_progress_callback(&mut self, on_progress: Option<ProgressCallback>) {
+        self.on_progress = on_progress;
+    }
+
+    pub fn report_progress(&self, progress: &ProgressNotification) {
+        if let Some(on_progress) = self.on_progress.as_ref() {
+            on_progress(progress);
+        }
+    }
+
+    // Overrides how long requests of `message_type` wait for the server
+    pub fn set_timeout(&mut self, message_type: MessageType, timeout: Duration) {
+        self.timeouts.insert(message_type, timeout);
+    }
+
+    pub fn timeout(&self, message_type: MessageType) -> Duration {
+        return match self.timeouts.get(&message_type) {
+            Some(timeout) => *timeout,
+            None => DEFAULT_TIMEOUT,
+        };
+    }
+
+    // None until init() has started the ssh tunnel
+    pub fn tunnel_status(&self) -> Option<TunnelStatus> {
+        return self.session.as_ref().map(STCPSession::status);
+    }
+
+    pub fn init(&mut self) -> Result<(), ClientError> {
+        if let Some(socket) = &self.socket {
+            debug!(
+                "Using the server on {}, not starting a tunnel",
+                socket.display()
+            );
+            return Ok(());
+        }
+        self.reset_ssh_session()?;
+
+        // The tunnel takes a while to come up, the index the server persisted last time
+        // is good enough to start with
+        match self.scp_index() {
+            Ok(index) => {
+                debug!("Loaded {} indexed files over scp", index.files.len());
+                self.initial_index = Some(index);
+            }
+            Err(e) => warn!("Unable to fetch the index over scp: {}", e),
+        }
+        return Ok(());
+    }
+
+    // Pulls the index file the server persisted for this workspace, bypassing the tunnel
+    pub fn scp_index(&self) -> Result<Index, ClientError> {
+        let remote_path = format!(".emacs_remote/server/{}", index_file_name(&self.workspace));
+        let data = ScpSession::new(&self.host)?.pull(&remote_path)?;
+        return Ok(Index::decode(&data)?);
+    }
+
+    pub fn initial_index(&self) -> Option<&Index> {
+        return self.initial_index.as_ref();
+    }
+
+    // Size, mtime and mode of `path` as of the initial index, for display
+    pub fn index_entry(&self, path: &str) -> Option<&IndexEntry> {
+        return self
+            .initial_index
+            .as_ref()?
+            .files
+            .get(&normalize_workspace_path(path));
+    }
+
+    pub fn reset_tcp_connection(&mut self) -> Result<(), ClientError> {
+        let now = Instant::now();
+        // the new connection may be to a different server build
+        self.server_info = None;
+        // nothing sent on the old connection will be answered on this one
+        self.pending.clear();
+        self.request_ids = false;
+
+        // Retrying can't help until the user has looked at the new key
+        if self.tunnel_status() == Some(TunnelStatus::HostKeyChanged) {
+            return Err(ClientError::HostKeyChanged(self.host.clone()));
+        }
+
+        // the tunnel moves to another local port if ssh couldn't listen on this one
+        if let Some(session) = &self.session {
+            self.client_port = session.client_port();
+        }
+
+        // Establishing TCP connection with server through the local end of the ssh tunnel
+        let endpoint = self.server_endpoint();
+        let server = endpoint
+            .connect_with(&self.socket_options)
+            .map_err(|source| ClientError::Connect { endpoint, source })?;
+        // a server that accepts but never answers the handshake mustn't hang us either
+        server.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
+        server.set_write_timeout(Some(DEFAULT_TIMEOUT))?;
+        let mut server = Connection::boxed(server);
+        server.set_max_frame_size(self.max_frame_size);
+        self.server = Some(server);
+
+        debug!(
+            "tcp connection established in {} milliseconds",
+            now.elapsed().as_millis()
+        );
+
+        // Every fresh connection renegotiates the protocol version
+        let response = match self.handshake() {
+            Ok(response) => response,
+            Err(e) => {
+                self.server = None;
+                return Err(e);
+            }
+        };
+
+        // Notifications pushed while there was no connection were missed
+        let since = self.synced_index_hash;
+        self.synced_index_hash = response.index_hash;
+        if since != 0 && since != response.index_hash {
+            if let Err(e) = self.catch_up(since) {
+                warn!("Unable to catch up on the changes missed: {}", e);
+                self.forget_files();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Invalidates what changed on the server since its index had the hash `since`, asked
+    // on the fresh connection without going through server_send. If the connection breaks
+    // again this fails instead of reconnecting.
+    fn catch_up(&mut self, since: ContentHash) -> Result<(), ClientError> {
+        let timeout = self.timeout(MessageType::ChangesSinceRequest);
+        let result = self
+            .send_with_timeout(&ChangesSinceRequest::new(since), timeout)
+            .and_then(|request_id| self.recv_response(request_id));
+        let buf = self.check_timeout(result, timeout)?;
+        let response = utils::stream::decode::<ChangesSinceResponse>(&buf)?;
+
+        if response.too_old {
+            debug!("Missed too many changes on the server, forgetting every file");
+            self.forget_files();
+        } else {
+            debug!("Missed {} changes on the server", response.paths.len());
+            for path in &response.paths {
+                self.file_changed(path, 0);
+            }
+        }
+        self.synced_index_hash = response.index_hash;
+        return Ok(());
+    }
+
+    // Nothing fetched before can be trusted to be current, everything is fetched again
+    fn forget_files(&mut self) {
+        self.files.clear();
+        self.current_index_hash = 0;
+    }
+
+    fn handshake(&mut self) -> Result<HandshakeResponse, ClientError> {
+        // Bypasses server_send/server_recv so a broken handshake doesn't reconnect itself
+        let response = pool::handshake(
+            self.server.as_mut().unwrap(),
+            &self.workspace_name,
+            self.client_id,
+            self.preshared_key.as_deref(),
+        )?;
+        self.request_ids = supports_request_ids(response.protocol_version);
+        self.salt = response.salt;
+
+        if response.compression {
+            let compression_level = self.compression_level;
+            if let Some(server) = self.server.as_mut() {
+                server.set_compression_level(compression_level);
+            }
+        }
+        return Ok(response);
     }
 
-    // pub fn reset_tcp_connection(&mut self) -> Result<(), ()> {
-    //     let now = Instant::now();
+    // The socket if one is set, the local end of the tunnel otherwise
+    pub fn server_endpoint(&self) -> Endpoint {
+        return match &self.socket {
+            Some(socket) => Endpoint::Unix(socket.clone()),
+            None => Endpoint::Tcp(SocketAddr::new(self.bind_addr, self.client_port as u16)),
+        };
+    }
 
-    //     // Establishing TCP connection with server
-    //     let result = TcpStream::connect(format!("localhost:{}", self.server_port));
-    //     if result.is_err() {
-    //         return Err(());
-    //     }
-    //     self.server = Some(result.unwrap());
+    // Extra connections to the same server, for requests handled off the listen() thread
+    pub fn pool(&mut self) -> Arc<ConnectionPool> {
+        let endpoint = self.server_endpoint();
+        match &self.pool {
+            Some(pool)
+                if *pool.endpoint() == endpoint && pool.workspace_name() == self.workspace_name =>
+            {
+                return pool.clone()
+            }
+            _ => {}
+        }
+        let mut pool = ConnectionPool::new(endpoint, self.compression_level, self.max_frame_size);
+        pool.set_socket_options(self.socket_options);
+        pool.set_workspace_name(self.workspace_name.clone());
+        pool.set_preshared_key(self.preshared_key.clone());
+        pool.set_client_id(self.client_id);
+        let pool = Arc::new(pool);
+        self.pool = Some(pool.clone());
+        return pool;
+    }
 
-    //     println!(
-    //         "tcp connection established in {} milliseconds",
-    //         now.elapsed().as_millis()
-    //     );
+    // Connect directly to a server listening on `port`, bypassing the ssh tunnel
+    pub fn connect(&mut self, port: u32) -> Result<(), ClientError> {
+        self.client_port = port;
+        self.reset_tcp_connection()
+    }
 
-    //     Ok(())
-    // }
+    pub fn update_index_hash(&mut self, hash: ContentHash) {
+        self.current_index_hash = hash;
+    }
 
-    pub fn reset_ssh_session(&mut self) {
-        let client_netstat = bash("netstat -atun".to_string()).unwrap();
-        let server_netstat = bash(format!("ssh {} netstat -atun", self.host))
-            .expect("Unable to get netstat info from remote host");
+    // Fails if the host can't be reached to pick a port for the server on it
+    pub fn reset_ssh_session(&mut self) -> Result<(), ClientError> {
+        if self.server_port == 0 {
+            let server_netstat =
+                bash(format!("ssh {} netstat -atun", self.host)).map_err(|status| {
+                    ClientError::HostUnreachable {
+                        host: self.host.clone(),
+                        status,
+                    }
+                })?;
 
-        for port in 49152..65535 {
-            if self.client_port == 0 && !client_netstat.contains(&port.to_string()) {
-                self.client_port = port;
-            } else if self.server_port == 0 && !server_netstat.contains(&port.to_string()) {
-                self.server_port = port;
-            } else {
-                break;
+            for port in 49152..65535 {
+                if !server_netstat.contains(&port.to_string()) {
+                    self.server_port = port;
+                    break;
+                }
             }
         }
-        assert!(self.server_port != 0);
-        assert!(self.client_port != 0);
+        if self.server_port == 0 {
+            return Err(ClientError::Io(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no free port on {} to start the server on", self.host),
+            )));
+        }
 
-        self.session = Some(STCPSession::new(
+        // The session keeps the previous local forward port if it is still free
+        let session = STCPSession::new(
             self.host.clone(),
             self.server_port,
             self.client_port,
+            self.bind_addr,
             self.workspace.clone(),
+            self.ssh_options.clone(),
+            None,
+        );
+        self.client_port = session.client_port();
+        self.session = Some(session);
+        return Ok(());
+    }
+
+    // Drops the current connection and dials the server again, renegotiating the protocol
+    pub fn reconnect(&mut self) -> Result<(), ClientError> {
+        self.server = None;
+        return self.reset_tcp_connection();
+    }
+
+    // Retries once on a fresh connection if the old one turns out to be broken. The
+    // timeout for T's message type applies until the next send. Returns the request's id,
+    // pass it to server_recv_for to collect the response out of order.
+    pub fn server_send<T>(&mut self, message: &T) -> Result<u64, ClientError>
+    where
+        T: Serialize + MessageTypeTrait,
+    {
+        if self.server.is_none() {
+            self.reset_tcp_connection()?;
+        }
+        let timeout = self.timeout(T::messagetype());
+        let result = self.send_with_timeout(message, timeout);
+        let result = match result {
+            // nothing was answered yet, but a write may have made it across in full
+            Err(e) if is_disconnect(&e) && T::messagetype().is_idempotent() => {
+                warn!("Lost connection to server, reconnecting: {}", e);
+                self.reconnect()?;
+                self.send_with_timeout(message, timeout)
+            }
+            Err(e) if is_disconnect(&e) => {
+                self.server = None;
+                Err(e)
+            }
+            result => result,
+        };
+        return self.check_timeout(result, timeout);
+    }
+
+    fn send_with_timeout<T: Serialize + MessageTypeTrait>(
+        &mut self,
+        message: &T,
+        timeout: Duration,
+    ) -> Result<u64, ClientError> {
+        let request_id = if self.request_ids {
+            self.issued_request_ids += 1;
+            self.issued_request_ids
+        } else {
+            0
+        };
+        let buffer = envelope::encode(message)?;
+
+        let server = self.server.as_mut().unwrap();
+        server.set_read_timeout(Some(timeout))?;
+        server.set_write_timeout(Some(timeout))?;
+        server.write_frame_tagged(&buffer, request_id)?;
+        self.last_request_id = request_id;
+        return Ok(request_id);
+    }
+
+    // A timed out request may still be answered later, so the connection is dropped rather
+    // than risk reading that answer as the response to the next request
+    fn check_timeout<T>(
+        &mut self,
+        result: Result<T, ClientError>,
+        timeout: Duration,
+    ) -> Result<T, ClientError> {
+        match result {
+            Err(ClientError::Io(e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                self.server = None;
+                return Err(ClientError::Timeout(timeout));
+            }
+            result => return result,
+        }
+    }
+
+    // Waits for the response to the last request sent
+    pub fn server_recv<T>(&mut self) -> Result<T, ClientError>
+    where
+        T: DeserializeOwned + MessageTypeTrait,
+    {
+        let request_id = self.last_request_id;
+        return self.server_recv_for::<T>(request_id);
+    }
+
+    // A response can't be retried on its own, so a broken connection is replaced for the
+    // next request and the error is returned. Use send_request to retry the round trip.
+    pub fn server_recv_for<T>(&mut self, request_id: u64) -> Result<T, ClientError>
+    where
+        T: DeserializeOwned + MessageTypeTrait,
+    {
+        let buf = self.server_recv_frame(request_id)?;
+        return utils::stream::decode::<T>(&buf);
+    }
+
+    // The next frame answering `request_id` undecoded, for requests answered with more
+    // than one message
+    pub fn server_recv_frame(&mut self, request_id: u64) -> Result<Vec<u8>, ClientError> {
+        let _span = info_span!(target: span::TARGET, "server_recv").entered();
+        if let Some(buf) = self.take_pending(request_id) {
+            return Ok(buf);
+        }
+        if self.server.is_none() {
+            self.reset_tcp_connection()?;
+        }
+        let server = self.server.as_mut().unwrap();
+        let timeout = server
+            .read_timeout()
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_TIMEOUT);
+        match self.recv_response(request_id) {
+            Err(e) if is_disconnect(&e) => {
+                warn!("Lost connection to server, reconnecting: {}", e);
+                if let Err(e) = self.reconnect() {
+                    debug!("Reconnect failed: {}", e);
+                }
+                return Err(e);
+            }
+            // the oversized payload is still sitting unread in the stream
+            Err(e @ ClientError::FrameTooLarge { .. }) => {
+                self.server = None;
+                return Err(e);
+            }
+            result => return self.check_timeout(result, timeout),
+        }
+    }
+
+    // Notifications pushed by the server may arrive ahead of the response, they are handled
+    // on the way. Responses to other requests are kept for their own server_recv_for.
+    fn recv_response(&mut self, request_id: u64) -> Result<Vec<u8>, ClientError> {
+        loop {
+            let (id, buf) = self.server.as_mut().unwrap().read_frame_tagged()?;
+            if id == 0 && self.handle_notification(&buf)? {
+                continue;
+            }
+            // servers that don't echo ids answer strictly in order
+            if id == request_id || id == 0 {
+                return Ok(buf);
+            }
+            self.stash(id, buf);
+        }
+    }
+
+    fn stash(&mut self, request_id: u64, buf: Vec<u8>) {
+        if request_id > self.issued_request_ids {
+            warn!("Discarding response to unknown request {}", request_id);
+            return;
+        }
+        self.pending.entry(request_id).or_default().push_back(buf);
+    }
+
+    fn take_pending(&mut self, request_id: u64) -> Option<Vec<u8>> {
+        let queue = self.pending.get_mut(&request_id)?;
+        let buf = queue.pop_front();
+        if queue.is_empty() {
+            self.pending.remove(&request_id);
+        }
+        return buf;
+    }
+
+    // Returns whether `buf` was a notification
+    fn handle_notification(&mut self, buf: &[u8]) -> Result<bool, ClientError> {
+        let msgtype = match utils::stream::peek_message_type(buf) {
+            Ok(msgtype) => msgtype,
+            // a newer server pushing something this build can't act on
+            Err(MessageTypeError::Unknown(n)) => {
+                debug!("Skipping unknown message type {}", n);
+                return Ok(true);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match msgtype {
+            MessageType::FileChangedNotification => {
+                let notification = utils::stream::decode::<FileChangedNotification>(buf)?;
+                self.file_changed(&notification.path, notification.new_hash);
+            }
+            MessageType::BulkChangeNotification => {
+                let notification = utils::stream::decode::<BulkChangeNotification>(buf)?;
+                for notification in notification.expand() {
+                    self.file_changed(&notification.path, notification.new_hash);
+                }
+            }
+            _ => return Ok(false),
+        }
+        return Ok(true);
+    }
+
+    // The blob cache is keyed by content so it stays valid, only the path's hash is stale
+    fn file_changed(&mut self, path: &str, new_hash: ContentHash) {
+        if self.files.get(path) == Some(&new_hash) {
+            return;
+        }
+        debug!("{} changed on the server", path);
+        self.files.remove(path);
+        // the index has to be fetched again as well
+        self.current_index_hash = 0;
+    }
+
+    // Handles notifications that arrived while no request was in flight, without blocking
+    // when there are none
+    fn poll_notifications(&mut self) -> Result<(), ClientError> {
+        let pooled = match &self.pool {
+            Some(pool) => pool.take_notifications(),
+            None => Vec::new(),
+        };
+        for notification in pooled {
+            self.file_changed(&notification.path, notification.new_hash);
+        }
+
+        loop {
+            let server = match self.server.as_mut() {
+                Some(server) => server,
+                None => return Ok(()),
+            };
+
+            server.set_nonblocking(true)?;
+            let mut header = [0; 1];
+            let available = server.peek(&mut header);
+            server.set_nonblocking(false)?;
+
+            match available {
+                // the server hung up, the next request reconnects
+                Ok(0) => {
+                    self.server = None;
+                    return Ok(());
+                }
+                // frames are written whole, so the rest of it follows promptly
+                Ok(_) => {
+                    let (id, buf) = self.server.as_mut().unwrap().read_frame_tagged()?;
+                    if id != 0 {
+                        self.stash(id, buf);
+                    } else if !self.handle_notification(&buf)? {
+                        warn!("Discarding unexpected message from the server");
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(ClientError::Io(e)),
+            }
+        }
+    }
+
+    // Sends `request` and waits for its response. Idempotent requests whose round trip
+    // fails with a dropped connection or a timeout are sent again as the retry policy
+    // allows, anything else fails on the first error.
+    pub fn send_request<Req>(&mut self, request: &Req) -> Result<Req::Response, ClientError>
+    where
+        Req: Serialize + Response,
+    {
+        let msgtype = Req::messagetype();
+        let mut attempt = 0;
+        loop {
+            let result = self.send_request_once(request);
+            match result {
+                Err(e) if self.retry_policy.should_retry(msgtype, attempt, &e) => {
+                    let delay = self.retry_policy.backoff(attempt);
+                    warn!(
+                        "{:?} failed, retrying in {} milliseconds: {}",
+                        msgtype,
+                        delay.as_millis(),
+                        e
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // One round trip that is never retried, for the requests the RetryPolicy leaves out
+    pub fn send_request_once<Req>(&mut self, request: &Req) -> Result<Req::Response, ClientError>
+    where
+        Req: Serialize + Response,
+    {
+        self.server_send(request)?;
+        return self.server_recv::<Req::Response>();
+    }
+
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, ClientError> {
+        return self.read_file_with(path, false);
+    }
+
+    // With `force` the server sends the file even if it is binary or over its read limit,
+    // otherwise those fail with a BINARY_OR_TOO_LARGE ServerReported
+    pub fn read_file_with(&mut self, path: &str, force: bool) -> Result<Vec<u8>, ClientError> {
+        // cached under the path the server's notifications name it by
+        let path = &normalize_workspace_path(path);
+        // Only claim to have the contents if they are still in the cache
+        let prev_hash = match (self.files.get(path), self.cache.as_ref()) {
+            (Some(hash), Some(cache)) if cache.contains(hash::salted(self.salt, *hash)) => *hash,
+            _ => 0,
+        };
+
+        let response: FileReadResponse =
+            self.send_request(&FileReadRequest::forced(path.to_string(), prev_hash, force))?;
+
+        if response.unchanged {
+            let key = hash::salted(self.salt, response.hash);
+            return match self.cache.as_mut().and_then(|cache| cache.get(key)) {
+                Some(contents) => Ok(contents),
+                // evicted or deleted in the meantime, fetch it again in full
+                None if prev_hash != 0 => {
+                    self.files.remove(path);
+                    self.read_file_with(path, force)
+                }
+                None => Err(ClientError::Protocol(format!(
+                    "server reported {} unchanged but it is not cached",
+                    path
+                ))),
+            };
+        }
+
+        // a resumed transfer may have started over on a newer version of the file
+        let (contents, expected) = if response.chunk_count > 0 {
+            self.recv_chunked_file(path, force, response)?
+        } else {
+            (response.contents, response.hash)
+        };
+
+        // Checked before anything is cached so that a bad transfer isn't served again
+        let actual = hash::hash(&contents);
+        if actual != expected {
+            self.files.remove(path);
+            return Err(ClientError::ChecksumMismatch {
+                path: path.to_string(),
+                expected,
+                actual,
+            });
+        }
+
+        self.cache_contents(path, expected, &contents);
+        return Ok(contents);
+    }
+
+    fn cache_contents(&mut self, path: &str, hash: ContentHash, contents: &[u8]) {
+        self.files.insert(path.to_string(), hash);
+        if let Some(cache) = self.cache.as_mut() {
+            cache.put(hash::salted(self.salt, hash), contents);
+        }
+    }
+
+    // Reassembles the FileChunks that follow a chunked FileReadResponse. A transfer cut
+    // short by a dropped connection or a timeout is resumed from the last chunk received, as
+    // often as the retry policy allows, instead of starting over. Returns the contents and
+    // the hash they should have.
+    fn recv_chunked_file(
+        &mut self,
+        path: &str,
+        force: bool,
+        mut response: FileReadResponse,
+    ) -> Result<(Vec<u8>, ContentHash), ClientError> {
+        let mut contents = Vec::with_capacity(response.total_size as usize);
+        let mut attempt = 0;
+        loop {
+            let e = match self.recv_chunks(path, &response, &mut contents) {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+            if !self
+                .retry_policy
+                .should_retry(MessageType::FileReadRequest, attempt, &e)
+            {
+                return Err(e);
+            }
+            let delay = self.retry_policy.backoff(attempt);
+            warn!(
+                "Transfer of {} cut short after {} of {} bytes, resuming in {} milliseconds: {}",
+                path,
+                contents.len(),
+                response.total_size,
+                delay.as_millis(),
+                e
+            );
+            thread::sleep(delay);
+            attempt += 1;
+
+            let request = FileReadRequest::resumed(
+                path.to_string(),
+                force,
+                response.hash,
+                contents.len() as u64,
+            );
+            response = self.send_request(&request)?;
+            if response.chunk_count == 0 {
+                // shrunk to a single response in the meantime
+                return Ok((response.contents, response.hash));
+            }
+            if response.offset != contents.len() as u64 {
+                if response.offset != 0 {
+                    return Err(ClientError::Protocol(format!(
+                        "asked to resume {} at {} bytes, got chunks from {}",
+                        path,
+                        contents.len(),
+                        response.offset
+                    )));
+                }
+                // the file changed, or the server doesn't resume transfers
+                contents.clear();
+            }
+        }
+
+        if contents.len() as u64 != response.total_size {
+            return Err(ClientError::Protocol(format!(
+                "{} changed while it was being sent, got {} of {} bytes",
+                path,
+                contents.len(),
+                response.total_size
+            )));
+        }
+        return Ok((contents, response.hash));
+    }
+
+    // Appends the FileChunks that follow `response` to `contents`, up to the first failure
+    fn recv_chunks(
+        &mut self,
+        path: &str,
+        response: &FileReadResponse,
+        contents: &mut Vec<u8>,
+    ) -> Result<(), ClientError> {
+        let request_id = self.last_request_id;
+        let first = (response.offset / CHUNK_SIZE as u64) as u32;
+        for index in first..first + response.chunk_count {
+            let chunk = self.server_recv::<FileChunk>()?;
+            if chunk.index != index {
+                return Err(ClientError::Protocol(format!(
+                    "expected chunk {} of {}, got {}",
+                    index, path, chunk.index
+                )));
+            }
+            contents.extend_from_slice(&chunk.data);
+            self.report_progress(&ProgressNotification::new(
+                request_id,
+                contents.len() as u64,
+                response.total_size,
+            ));
+        }
+        return Ok(());
+    }
+
+    // Returns the new hash of the file, or WriteConflict if it changed on the server
+    // since base_hash
+    pub fn write_file(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        base_hash: ContentHash,
+    ) -> Result<ContentHash, ClientError> {
+        let path = &normalize_workspace_path(path);
+        let response = self.send_request_once(&FileWriteRequest::new(
+            path.to_string(),
+            contents.clone(),
+            base_hash,
+        ))?;
+
+        if response.conflict {
+            self.files.remove(path);
+            return Err(ClientError::WriteConflict {
+                path: path.to_string(),
+                server_hash: response.hash,
+            });
+        }
+
+        self.cache_contents(path, response.hash, &contents);
+        return Ok(response.hash);
+    }
+
+    // Writes all of `files`, or none of them if any changed on the server since its base
+    // hash. The response says which ones conflicted.
+    pub fn save_all(&mut self, files: Vec<SaveFile>) -> Result<SaveAllResponse, ClientError> {
+        if !self.supports(MessageType::SaveAllRequest)? {
+            return Err(ClientError::Unsupported(MessageType::SaveAllRequest));
+        }
+        let request = SaveAllRequest::new(files);
+        let response = self.send_request_once(&request)?;
+
+        for (file, status) in request.files.iter().zip(&response.files) {
+            let path = normalize_workspace_path(&file.path);
+            if status.conflict {
+                self.files.remove(&path);
+            } else if response.saved {
+                self.cache_contents(&path, status.hash, &file.contents);
+            }
+        }
+        return Ok(response);
+    }
+
+    // Returns the new index hash, or None if `to` already exists
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<Option<ContentHash>, ClientError> {
+        let response =
+            self.send_request_once(&RenameRequest::new(from.to_string(), to.to_string()))?;
+        if !response.success {
+            return Ok(None);
+        }
+        return Ok(Some(response.index_hash));
+    }
+
+    // Returns the new index hash
+    pub fn delete(&mut self, path: &str, recursive: bool) -> Result<ContentHash, ClientError> {
+        let response = self.send_request_once(&DeleteRequest::new(path.to_string(), recursive))?;
+        return Ok(response.index_hash);
+    }
+
+    // Creates an empty file at `path`, failing if something is already there. Returns the
+    // new index hash.
+    pub fn touch(&mut self, path: &str) -> Result<ContentHash, ClientError> {
+        if !self.supports(MessageType::TouchRequest)? {
+            return Err(ClientError::Unsupported(MessageType::TouchRequest));
+        }
+        let response = self.send_request_once(&TouchRequest::new(path.to_string()))?;
+        return Ok(response.index_hash);
+    }
+
+    // Creates the directory `path`, and its missing parents if `recursive`. Returns the
+    // index hash.
+    pub fn mkdir(&mut self, path: &str, recursive: bool) -> Result<ContentHash, ClientError> {
+        if !self.supports(MessageType::MkdirRequest)? {
+            return Err(ClientError::Unsupported(MessageType::MkdirRequest));
+        }
+        let response = self.send_request_once(&MkdirRequest::new(path.to_string(), recursive))?;
+        return Ok(response.index_hash);
+    }
+
+    // Where pull_archive unpacks archives of this workspace, mirroring its layout
+    pub fn mirror_path(&self) -> PathBuf {
+        let mut mirror_path = PathBuf::new();
+        mirror_path.push(self.emacs_remote_path.clone());
+        mirror_path.push("client");
+        mirror_path.push("mirrors");
+        mirror_path.push(format!(
+            "{}-{}",
+            self.host,
+            utils::hash::fast_hash(&self.workspace)
         ));
+        return mirror_path;
+    }
+
+    // Pulls a tar of every indexed file under `path`, less those matching `ignore`, and
+    // unpacks it under mirror_path. Files already there are overwritten, others are left
+    // alone.
+    pub fn pull_archive(
+        &mut self,
+        path: &str,
+        ignore: &[String],
+        compress: bool,
+    ) -> Result<ArchiveDone, ClientError> {
+        if !self.supports(MessageType::ArchiveRequest)? {
+            return Err(ClientError::Unsupported(MessageType::ArchiveRequest));
+        }
+        let request = ArchiveRequest::new(path.to_string(), ignore.to_vec(), compress);
+        let request_id = self.server_send(&request)?;
+
+        let mirror_path = self.mirror_path();
+        let mut reader = ArchiveReader::new(self, request_id);
+        let unpacked = if compress {
+            zstd::Decoder::new(&mut reader)
+                .and_then(|decoder| archive::unpack(decoder, &mirror_path))
+        } else {
+            archive::unpack(&mut reader, &mirror_path)
+        };
+        if let Err(e) = unpacked {
+            // the server's own error says more than the io::Error it was wrapped in
+            let e = reader.take_error().unwrap_or(ClientError::Io(e));
+            // what is left of the archive would otherwise still be on its way
+            drop(reader);
+            self.server = None;
+            return Err(e);
+        }
+        let mut done = reader.finish()?;
+        done.unpacked_to = mirror_path.to_string_lossy().to_string();
+        return Ok(done);
+    }
+
+    // Round trip time to the server. A ping that times out drops the connection so the
+    // next request reconnects.
+    pub fn ping(&mut self) -> Result<Duration, ClientError> {
+        let nonce: u64 = rand::thread_rng().gen();
+        let sent_at_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        let now = Instant::now();
+        let result = self.send_request_once(&PingRequest::new(nonce, sent_at_millis));
+        if result.is_err() {
+            self.server = None;
+        }
+        let response = result?;
+
+        if response.nonce != nonce {
+            self.server = None;
+            return Err(ClientError::Protocol(format!(
+                "ping returned nonce {:x}, expected {:x}",
+                response.nonce, nonce
+            )));
+        }
+        return Ok(now.elapsed());
     }
 
-    // pub fn server_send<T: Serialize>(&mut self, message: &T) -> Result<(), ()> {
-    //     if !self.server.is_some() {
-    //         self.reset_tcp_connection()
-    //             .expect("Unable to establish tcp connection");
-    //     }
-    //     return utils::stream::send(self.server.as_mut().unwrap(), message);
-    // }
+    // Pings the server so that it doesn't reap the connection as idle. Over the ssh tunnel a
+    // failed ping restarts ssh, the tunnel looks up but a NAT timeout may have silently
+    // dropped the connection.
+    fn heartbeat(&mut self) {
+        if self.socket.is_some() {
+            if self.server.is_some() {
+                if let Err(e) = self.ping() {
+                    warn!("Heartbeat failed: {}", e);
+                }
+            }
+            return;
+        }
+        if self.tunnel_status() != Some(TunnelStatus::Connected) {
+            return;
+        }
+        match self.ping() {
+            Ok(rtt) => trace!("ping {} milliseconds", rtt.as_millis()),
+            Err(e) => {
+                warn!("Heartbeat failed, restarting ssh tunnel: {}", e);
+                if let Some(session) = self.session.as_ref() {
+                    session.restart();
+                }
+            }
+        }
+    }
 
-    // pub fn server_recv<T>(&mut self) -> Result<T, ()>
-    // where
-    //     T: DeserializeOwned + MessageTypeTrait,
-    // {
-    //     if !self.server.is_some() {
-    //         self.reset_tcp_connection()
-    //             .expect("Unable to establish tcp connection");
-    //     }
-    //     return utils::stream::recv::<T>(self.server.as_mut().unwrap());
-    // }
+    // Cached until the next reconnect
+    pub fn workspace_info(&mut self) -> Result<WorkspaceInfoResponse, ClientError> {
+        if let Some(info) = &self.server_info {
+            return Ok(info.clone());
+        }
+        let info: WorkspaceInfoResponse = self.send_request(&WorkspaceInfoRequest::new())?;
+        self.server_info = Some(info.clone());
+        return Ok(info);
+    }
 
-    pub fn listen(&mut self) {
-        let client_netstat = bash("netstat -atun".to_string()).unwrap();
+    // Which build the server is running. A server of another major version is an error,
+    // one of another minor version is only warned about.
+    pub fn server_version(&mut self) -> Result<VersionResponse, ClientError> {
+        if !self.supports(MessageType::VersionRequest)? {
+            return Err(ClientError::Unsupported(MessageType::VersionRequest));
+        }
+        let response: VersionResponse = self.send_request(&VersionRequest::new())?;
+        match version_skew(VERSION, &response.server_version) {
+            VersionSkew::None => {}
+            VersionSkew::Minor => warn!(
+                "Client is version {} but the server is version {} ({})",
+                VERSION, response.server_version, response.build_hash
+            ),
+            VersionSkew::Major => {
+                return Err(ClientError::ServerVersionMismatch {
+                    client: VERSION.to_string(),
+                    server: response.server_version,
+                })
+            }
+        }
+        return Ok(response);
+    }
+
+    // Servers that predate WorkspaceInfoRequest are given the benefit of the doubt, the
+    // request itself then fails with an ErrorResponse if they can't serve it
+    pub fn supports(&mut self, message_type: MessageType) -> Result<bool, ClientError> {
+        return match self.workspace_info() {
+            Ok(info) => Ok(info.supports(message_type)),
+            Err(ClientError::ServerReported { code, .. }) if code == UNSUPPORTED => Ok(true),
+            Err(e) => Err(e),
+        };
+    }
+
+    // Searches every indexed file on the server, see GrepRequest for the options
+    pub fn grep(
+        &mut self,
+        pattern: &str,
+        is_regex: bool,
+        case_insensitive: bool,
+        path_glob: Option<&str>,
+    ) -> Result<Vec<GrepMatch>, ClientError> {
+        let mut matches = Vec::new();
+        self.grep_each(
+            pattern,
+            is_regex,
+            case_insensitive,
+            path_glob,
+            |grep_match| matches.push(grep_match),
+        )?;
+        return Ok(matches);
+    }
 
-        let mut daemon_port: u32 = 0;
-        for port in 49152..65535 {
-            if self.client_port != port && !client_netstat.contains(&port.to_string()) {
-                daemon_port = port;
-                break;
+    // Same as grep, calling `on_match` with each match as soon as it arrives instead of
+    // collecting them
+    pub fn grep_each<F>(
+        &mut self,
+        pattern: &str,
+        is_regex: bool,
+        case_insensitive: bool,
+        path_glob: Option<&str>,
+        mut on_match: F,
+    ) -> Result<GrepDone, ClientError>
+    where
+        F: FnMut(GrepMatch),
+    {
+        if !self.supports(MessageType::GrepRequest)? {
+            return Err(ClientError::Unsupported(MessageType::GrepRequest));
+        }
+        let request_id = self.server_send(&GrepRequest::new(
+            pattern.to_string(),
+            is_regex,
+            case_insensitive,
+            path_glob.map(String::from),
+        ))?;
+        loop {
+            let buf = self.server_recv_frame(request_id)?;
+            let done = read_grep_frame(&buf, &mut |grep_match| {
+                on_match(grep_match);
+                Ok(())
+            })?;
+            if let Some(done) = done {
+                if done.truncated {
+                    warn!(
+                        "Search for {:?} stopped after {} matches",
+                        pattern, done.total
+                    );
+                }
+                return Ok(done);
             }
         }
-        assert!(daemon_port != 0);
+    }
+
+    // Has the server index the files under `path`, "" for the whole workspace. The list of
+    // them is written to the file the response names, on the server.
+    pub fn index(&mut self, path: &str) -> Result<IndexResponse, ClientError> {
+        let response: IndexResponse = self.send_request(&IndexRequest::new(0, path.to_string()))?;
+        self.update_index_hash(response.hash);
+        return Ok(response);
+    }
+
+    // Has the server rebuild its index after changes its watcher may have missed, e.g. a
+    // git checkout. With `full` every file is rehashed.
+    pub fn rescan(&mut self, full: bool) -> Result<RescanResponse, ClientError> {
+        if !self.supports(MessageType::RescanRequest)? {
+            return Err(ClientError::Unsupported(MessageType::RescanRequest));
+        }
+        let response: RescanResponse = self.send_request(&RescanRequest::new(full))?;
+        self.update_index_hash(response.hash);
+        return Ok(response);
+    }
 
-        let receiver = TcpListener::bind(format!("localhost:{}", daemon_port)).unwrap();
+    // The files changed on the server since its index had the hash `since_hash`. Reconnects
+    // catch up on their own, this is for anything else that kept track of a hash.
+    pub fn changes_since(
+        &mut self,
+        since_hash: ContentHash,
+    ) -> Result<ChangesSinceResponse, ClientError> {
+        if !self.supports(MessageType::ChangesSinceRequest)? {
+            return Err(ClientError::Unsupported(MessageType::ChangesSinceRequest));
+        }
+        return self.send_request(&ChangesSinceRequest::new(since_hash));
+    }
 
+    // How the server's copy of `path` differs from the version with the hash `client_hash`,
+    // which the server has to have had
+    pub fn diff(
+        &mut self,
+        path: &str,
+        client_hash: ContentHash,
+    ) -> Result<DiffResponse, ClientError> {
+        if !self.supports(MessageType::DiffRequest)? {
+            return Err(ClientError::Unsupported(MessageType::DiffRequest));
+        }
+        return self.send_request(&DiffRequest::new(path.to_string(), client_hash));
+    }
+
+    // Indexed paths that fuzzily match `prefix`, best match first
+    pub fn complete(&mut self, prefix: &str, limit: u32) -> Result<Vec<String>, ClientError> {
+        if !self.supports(MessageType::CompletionRequest)? {
+            return Err(ClientError::Unsupported(MessageType::CompletionRequest));
+        }
+        let response: CompletionResponse =
+            self.send_request(&CompletionRequest::new(prefix.to_string(), limit))?;
+        return Ok(response.paths);
+    }
+
+    // Only changes under `path` are pushed on the server connection from now on, along with
+    // anything else already watched. Watches belong to the connection, after a reconnect
+    // every change is pushed again. Returns every watched path.
+    pub fn watch(&mut self, path: &str, recursive: bool) -> Result<Vec<String>, ClientError> {
+        if !self.supports(MessageType::WatchRequest)? {
+            return Err(ClientError::Unsupported(MessageType::WatchRequest));
+        }
+        let response: WatchResponse =
+            self.send_request(&WatchRequest::new(path.to_string(), recursive))?;
+        return Ok(response.watching);
+    }
+
+    pub fn unwatch(&mut self, path: &str) -> Result<Vec<String>, ClientError> {
+        if !self.supports(MessageType::UnwatchRequest)? {
+            return Err(ClientError::Unsupported(MessageType::UnwatchRequest));
+        }
+        let response: WatchResponse = self.send_request(&UnwatchRequest::new(path.to_string()))?;
+        return Ok(response.watching);
+    }
+
+    // Sends every request in `batch` in one round trip. Not retried, part of the batch may
+    // already have run when the connection drops.
+    pub fn flush(&mut self, batch: Batch) -> Result<BatchResults, ClientError> {
+        if batch.is_empty() {
+            return Ok(BatchResults::new(BatchResponse::new(Vec::new())));
+        }
+        if !self.supports(MessageType::BatchRequest)? {
+            return Err(ClientError::Unsupported(MessageType::BatchRequest));
+        }
+        let expected = batch.len();
+        let results = BatchResults::new(self.send_request_once(&batch.into_request())?);
+        if results.len() != expected {
+            return Err(ClientError::Protocol(format!(
+                "sent {} requests in a batch but got {} responses",
+                expected,
+                results.len()
+            )));
+        }
+        return Ok(results);
+    }
+
+    // Metadata of `path`, describing a symlink itself rather than what it points to
+    pub fn stat(&mut self, path: &str) -> Result<FileStatResponse, ClientError> {
+        if !self.supports(MessageType::FileStatRequest)? {
+            return Err(ClientError::Unsupported(MessageType::FileStatRequest));
+        }
+        return self.send_request(&FileStatRequest::new(path.to_string()));
+    }
+
+    pub fn list_dir(&mut self, path: &str, depth: u32) -> Result<Vec<DirEntry>, ClientError> {
+        let response: DirectoryListResponse =
+            self.send_request(&DirectoryListRequest::new(path.to_string(), depth))?;
+        return Ok(response.entries);
+    }
+
+    pub fn listen(&mut self) -> Result<(), ClientError> {
         let mut daemon_file_path = PathBuf::new();
         daemon_file_path.push(self.emacs_remote_path.clone());
         daemon_file_path.push("client");
         daemon_file_path.push("daemon.port");
 
-        fs::write(daemon_file_path.clone(), daemon_port.to_string())
-            .expect("Could not write daemon port to string");
+        // Reuse the last daemon port when possible so emacs can keep talking to it
+        let previous_port = fs::read_to_string(&daemon_file_path)
+            .ok()
+            .and_then(|port| port.trim().parse::<u16>().ok());
+        let (receiver, daemon_port) = bind_free_port(self.bind_addr, previous_port)?;
+        // Poll so that the shutdown flag is noticed even when emacs is idle
+        receiver.set_nonblocking(true)?;
 
-        for stream in receiver.incoming() {
-            let mut stream = stream.unwrap();
+        fs::write(daemon_file_path, daemon_port.to_string())?;
 
-            if self.handle(&mut stream).is_err() {
-                println!("Failed to handle stream");
+        let mut last_heartbeat = Instant::now();
+        while !self.shutdown.load(Relaxed) {
+            if let Err(e) = self.poll_notifications() {
+                warn!("Failed to read notifications: {}", e);
+                self.server = None;
+            }
+            if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+                self.heartbeat();
+                last_heartbeat = Instant::now();
+            }
+
+            // emacs never negotiates compression
+            let mut stream = match receiver.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.apply(&self.socket_options) {
+                        debug!("Unable to tune connection from emacs: {}", e);
+                    }
+                    let mut stream = Connection::new(stream);
+                    stream.set_max_frame_size(self.max_frame_size);
+                    stream
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = stream.set_nonblocking(false) {
+                error!("Failed to set up connection: {}", e);
+                continue;
+            }
+            match self.handle(&mut stream) {
+                Ok(()) => {}
+                // emacs connected and hung up without asking for anything
+                Err(ClientError::ConnectionClosed) => {
+                    debug!("Connection from emacs closed before a request");
+                }
+                Err(e) => {
+                    error!("Failed to handle stream: {}", e);
+                    let response = ErrorResponse::new(e.code(), e.to_string());
+                    if let Err(e) = utils::stream::send(&mut stream, &response) {
+                        debug!("Unable to report error to emacs: {}", e);
+                    }
+                }
             }
         }
+        Ok(())
     }
 
-    fn handle(&mut self, stream: &mut TcpStream) -> Result<(), ()> {
-        let mut buf = [0; 1024];
-        stream.read(&mut buf).unwrap();
+    fn handle(&mut self, stream: &mut Connection) -> Result<(), ClientError> {
+        let buf = stream.read_frame()?;
 
-        let value: rmpv::Value = rmps::decode::from_read_ref(&buf).unwrap();
-        println!("Request: {}", serde_json::to_string(&value).unwrap());
+        utils::stream::trace_message("Request", &buf);
 
-        assert!(value.is_array());
-        assert!(value[0].is_u64());
-        let msgtype = MessageType::try_from(value[0].as_u64().unwrap()).unwrap();
-
-        match msgtype {
-            MessageType::IndexRequest => {
-                let request: IndexRequest = rmp_serde::from_read_ref(&buf).unwrap();
-                return request.handle(stream, self);
+        let msgtype = match utils::stream::peek_message_type(&buf) {
+            Ok(msgtype) => msgtype,
+            Err(MessageTypeError::Unknown(n)) => {
+                warn!("Unknown message type {}", n);
+                let response =
+                    ErrorResponse::new(UNSUPPORTED, format!("unknown message type {}", n));
+                return utils::stream::send(stream, &response);
             }
-            _ => {
-                println!("Invalid type: {:?}", msgtype);
-                return Err(());
+            Err(e) => return Err(e.into()),
+        };
+        let _span = info_span!(target: span::TARGET, "request", msgtype = ?msgtype, request_id = stream.request_id()).entered();
+        match self.handlers.get(msgtype) {
+            Some(handler) => return handler(self, stream, &buf),
+            None => {
+                warn!("Unsupported message type {:?}", msgtype);
+                let response = ErrorResponse::new(
+                    UNSUPPORTED,
+                    format!("unsupported message type {:?}", msgtype),
+                );
+                return utils::stream::send(stream, &response);
             }
         }
     }
 }
 
+fn default_timeouts() -> HashMap<MessageType, Duration> {
+    return REQUEST_TIMEOUTS.iter().cloned().collect();
+}
+
+fn default_bind_addr() -> IpAddr {
+    return DEFAULT_BIND_ADDR;
+}
+
+fn default_max_frame_size() -> usize {
+    return DEFAULT_MAX_FRAME_SIZE;
+}
+
+fn default_compression_level() -> Option<i32> {
+    return Some(DEFAULT_COMPRESSION_LEVEL);
+}
+
 // fn test_connection() {
 //     let mut client = TcpStream::connect("localhost:9130").unwrap();
 
@@ -218,8 +1537,8 @@ impl ClientDaemon {
 
 //     let now = Instant::now();
 
-//     let buffer = rmps::encode::to_vec(&request).unwrap();
-//     client.write(&buffer).unwrap();
+//     let buffer = envelope::encode(&request).unwrap();
+//     frame::write_frame(&mut client, &buffer).unwrap();
 
 //     if handle_response(&mut &client).is_err() {
 //         panic!("Unable to handle response");
@@ -230,53 +1549,3 @@ impl ClientDaemon {
 //         now.elapsed().as_millis()
 //     );
 // }
-
-// fn test_ssh() {
-//     let now = Instant::now();
-
-//     let mut session = ssh::Session::new().unwrap();
-//     session.set_host("cerebras").unwrap();
-//     session.parse_config(None).unwrap();
-//     session.connect().unwrap();
-//     println!("{:?}", session.is_server_known());
-//     session.userauth_publickey_auto(None).unwrap();
-
-//     println!(
-//         "ssh connection established in {} milliseconds",
-//         now.elapsed().as_millis()
-//     );
-
-//     for _i in 0..10 {
-//         thread::sleep(Duration::from_millis(10000));
-
-//         let now = Instant::now();
-
-//         let mut scp = session
-//             .scp_new(
-//                 ssh::READ,
-//                 "/net/antonio-dev/srv/nfs/antonio-data/ws/.emacs_remote/server/index_17645410072557185842.mp",
-//             )
-//             .unwrap();
-//         scp.init().unwrap();
-//         loop {
-//             match scp.pull_request().unwrap() {
-//                 ssh::Request::NEWFILE => {
-//                     let mut buf: Vec<u8> = vec![];
-//                     scp.accept_request().unwrap();
-//                     scp.reader().read_to_end(&mut buf).unwrap();
-
-//                     println!(
-//                         "Took {} milliseconds to read file",
-//                         now.elapsed().as_millis()
-//                     );
-//                     break;
-//                 }
-//                 ssh::Request::WARNING => {
-//                     scp.deny_request().unwrap();
-//                     break;
-//                 }
-//                 _ => scp.deny_request().unwrap(),
-//             }
-//         }
-//     }
-// }
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::ServerError;
+
+// Settings read from ~/.emacs_remote/config.toml. Every field is optional, values are
+// resolved with the precedence CLI flag > environment variable > config file > default.
+//
+//     port = 9130
+//     bind_addr = "::1"
+//     metrics_port = 9131
+//     socket = "/run/user/1000/emacs-remote.sock"
+//     workspace = "/home/me/ws"
+//     workspace_name = "ws"
+//     emacs_remote_path = "/home/me/.emacs_remote"
+//     ignore = ["target/", "*.o"]
+//     allowed_workspaces = ["/home/me/ws", "/srv/projects"]
+//     read_only = true
+//     psk = "/home/me/.emacs_remote/psk"
+//     hash_salt = 1
+//
+//     [workspaces]
+//     dotfiles = "/home/me/dotfiles"
+//
+//     [hash_salts]
+//     dotfiles = 2
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: Option<u16>,
+    pub bind_addr: Option<String>,
+    pub metrics_port: Option<u16>,
+    // listen on this Unix domain socket instead of bind_addr and port
+    pub socket: Option<String>,
+    pub workspace: Option<String>,
+    // what clients call `workspace` in their handshake
+    pub workspace_name: Option<String>,
+    // more workspaces served alongside `workspace`, by name
+    pub workspaces: BTreeMap<String, String>,
+    pub emacs_remote_path: Option<String>,
+    // .gitignore style patterns, relative to the workspace root
+    pub ignore: Vec<String>,
+    // the workspace must be one of these directories or inside one, any if empty
+    pub allowed_workspaces: Vec<String>,
+    // refuse every request that would change a workspace
+    pub read_only: bool,
+    // file holding the key every connection has to be sealed with, see utils::seal
+    pub psk: Option<String>,
+    // what clients salt the hashes they cache `workspace`'s files under with, and those of
+    // `workspaces` by name. Derived from the workspace's path if left out.
+    pub hash_salt: Option<u64>,
+    pub hash_salts: BTreeMap<String, u64>,
+}
+
+impl ServerConfig {
+    pub fn default_path() -> PathBuf {
+        let mut path = PathBuf::new();
+        path.push(dirs::home_dir().unwrap());
+        path.push(".emacs_remote");
+        path.push("config.toml");
+        return path;
+    }
+
+    // A missing file is the same as an empty one
+    pub fn load(path: &Path) -> Result<ServerConfig, ServerError> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ServerConfig::default()),
+            Err(e) => return Err(ServerError::Io(e)),
+        };
+        return toml::from_str(&data)
+            .map_err(|e| ServerError::Config(format!("{}: {}", path.display(), e)));
+    }
+}
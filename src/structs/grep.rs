@@ -0,0 +1,31 @@
+extern crate rmp_serde as rmps;
+
+use crate::error::ClientError;
+use crate::messages::grep::{GrepDone, GrepMatch, GrepMatchFrame, GrepResponse};
+use crate::messages::messagetype::MessageType;
+use crate::utils::stream::{decode, peek_message_type};
+
+// Reads one frame of the answer to a GrepRequest, calling `on_match` with the match it
+// carries. Returns the GrepDone once the last frame has been read. Servers before protocol
+// 3.1 answer with a single GrepResponse, its matches are passed on the same way.
+pub fn read_grep_frame<F>(buf: &[u8], on_match: &mut F) -> Result<Option<GrepDone>, ClientError>
+where
+    F: FnMut(GrepMatch) -> Result<(), ClientError>,
+{
+    match peek_message_type(buf) {
+        Ok(MessageType::GrepMatchFrame) => {
+            on_match(decode::<GrepMatchFrame>(buf)?.grep_match)?;
+            return Ok(None);
+        }
+        Ok(MessageType::GrepResponse) => {
+            let response = decode::<GrepResponse>(buf)?;
+            let total = response.matches.len() as u64;
+            for grep_match in response.matches {
+                on_match(grep_match)?;
+            }
+            return Ok(Some(GrepDone::new(total, response.truncated)));
+        }
+        // an ErrorResponse or something unexpected, reported as such
+        _ => return Ok(Some(decode::<GrepDone>(buf)?)),
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::messages::history::FileVersion;
+use crate::utils::hash::{self, ContentHash};
+
+// Old versions of the files the server overwrote, a safety net for bad saves. Each
+// workspace has a directory of its own under server/history: the contents of every
+// version in objects/, once per distinct hash, and `log`, a line of JSON per version
+// appended as files are overwritten. The log is only rewritten when versions are pruned,
+// the oldest first, to keep the history under its size and age limits.
+
+pub const DEFAULT_MAX_HISTORY_SIZE: u64 = 256 * 1024 * 1024;
+pub const DEFAULT_MAX_HISTORY_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+pub fn history_dir_name(workspace: &str) -> String {
+    return hash::fast_hash(&workspace).to_string();
+}
+
+pub struct History {
+    dir: PathBuf,
+    // bytes the objects may take up together
+    max_size: u64,
+    max_age: Duration,
+    // oldest first
+    versions: Vec<FileVersion>,
+    // the size of each object and how many versions refer to it
+    objects: HashMap<ContentHash, (u64, usize)>,
+    next_version: u64,
+}
+
+impl History {
+    // A log cut short by a crash loses the version being appended, the rest is read
+    pub fn open(dir: &Path, max_size: u64, max_age: Duration) -> io::Result<History> {
+        fs::create_dir_all(dir.join("objects"))?;
+        let mut history = History {
+            dir: dir.to_path_buf(),
+            max_size,
+            max_age,
+            versions: Vec::new(),
+            objects: HashMap::new(),
+            next_version: 1,
+        };
+        let log = match fs::File::open(history.log_path()) {
+            Ok(log) => log,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(history),
+            Err(e) => return Err(e),
+        };
+        for line in BufReader::new(log).lines() {
+            match serde_json::from_str::<FileVersion>(&line?) {
+                Ok(version) => history.add(version),
+                Err(e) => {
+                    warn!("Skipping unreadable history in {}: {}", dir.display(), e);
+                    break;
+                }
+            }
+        }
+        // the limits may have changed since the server last ran
+        history.prune()?;
+        return Ok(history);
+    }
+
+    // Keeps `contents`, what `path` held before it was overwritten, and returns its version.
+    // Files larger than the whole history are left out, None.
+    pub fn record(&mut self, path: &str, contents: &[u8]) -> io::Result<Option<u64>> {
+        if contents.len() as u64 > self.max_size {
+            return Ok(None);
+        }
+        let hash = hash::hash(contents);
+        let object = self.object_path(hash);
+        if !self.objects.contains_key(&hash) {
+            // written whole before it is referenced, so the log never names a partial object
+            let temp = object.with_extension("tmp");
+            fs::write(&temp, contents)?;
+            fs::rename(&temp, &object)?;
+        }
+
+        let version = FileVersion {
+            version: self.next_version,
+            path: path.to_string(),
+            hash,
+            size: contents.len() as u64,
+            replaced_at: now(),
+        };
+        let mut line = serde_json::to_string(&version)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path())?
+            .write_all(line.as_bytes())?;
+        self.add(version);
+
+        self.prune()?;
+        return Ok(Some(self.next_version - 1));
+    }
+
+    pub fn versions_of(&self, path: &str) -> Vec<FileVersion> {
+        return self
+            .versions
+            .iter()
+            .filter(|version| version.path == path)
+            .cloned()
+            .collect();
+    }
+
+    // The contents of the latest version of `path` with the hash `hash`, NotFound if there
+    // is none
+    pub fn contents_by_hash(&self, path: &str, hash: ContentHash) -> io::Result<Vec<u8>> {
+        let found = self
+            .versions
+            .iter()
+            .rev()
+            .find(|found| found.hash == hash && found.path == path);
+        return match found {
+            Some(found) => self.contents(path, found.version),
+            None => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "no version of {} with hash {:032x} in the history",
+                    path, hash
+                ),
+            )),
+        };
+    }
+
+    // NotFound if `path` has no such version, e.g. because it was pruned
+    pub fn contents(&self, path: &str, version: u64) -> io::Result<Vec<u8>> {
+        let found = self
+            .versions
+            .iter()
+            .find(|found| found.version == version && found.path == path);
+        let found = match found {
+            Some(found) => found,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("no version {} of {} in the history", version, path),
+                ))
+            }
+        };
+        let contents = fs::read(self.object_path(found.hash))?;
+        if hash::hash(&contents) != found.hash {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("version {} of {} is corrupted", version, path),
+            ));
+        }
+        return Ok(contents);
+    }
+
+    // bytes taken up by the objects
+    pub fn size(&self) -> u64 {
+        return self.objects.values().map(|(size, _)| size).sum();
+    }
+
+    fn add(&mut self, version: FileVersion) {
+        self.next_version = self.next_version.max(version.version + 1);
+        let object = self
+            .objects
+            .entry(version.hash)
+            .or_insert((version.size, 0));
+        object.1 += 1;
+        self.versions.push(version);
+    }
+
+    fn prune(&mut self) -> io::Result<()> {
+        let oldest = now().saturating_sub(self.max_age.as_secs());
+        let mut size = self.size();
+        let mut pruned = 0;
+        for version in &self.versions {
+            if size <= self.max_size && version.replaced_at >= oldest {
+                break;
+            }
+            let object = self.objects.get_mut(&version.hash).unwrap();
+            object.1 -= 1;
+            if object.1 == 0 {
+                size -= object.0;
+                self.objects.remove(&version.hash);
+                if let Err(e) = fs::remove_file(self.object_path(version.hash)) {
+                    warn!(
+                        "Unable to remove a pruned version of {}: {}",
+                        version.path, e
+                    );
+                }
+            }
+            pruned += 1;
+        }
+        if pruned == 0 {
+            return Ok(());
+        }
+        self.versions.drain(..pruned);
+
+        let mut log = String::new();
+        for version in &self.versions {
+            log.push_str(&serde_json::to_string(version)?);
+            log.push('\n');
+        }
+        let temp = self.log_path().with_extension("tmp");
+        fs::write(&temp, log)?;
+        return fs::rename(&temp, self.log_path());
+    }
+
+    fn log_path(&self) -> PathBuf {
+        return self.dir.join("log");
+    }
+
+    fn object_path(&self, hash: ContentHash) -> PathBuf {
+        return self.dir.join("objects").join(format!("{:032x}", hash));
+    }
+}
+
+fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+}
@@ -0,0 +1,166 @@
+extern crate rmp_serde as rmps;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::structs::indexer::Indexer;
+use crate::utils::atomic;
+use crate::utils::hash::{self, ContentHash};
+
+// One file of an Index. Encoded as an array of its fields in the order they are declared
+// here, with content_hash as a 16 byte big-endian bin. Fields are never reordered or
+// removed, see Index.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    // relative to the workspace root
+    pub path: String,
+    pub content_hash: ContentHash,
+    pub size: u64,
+    pub mtime_millis: u64,
+    // unix permission bits, 0 where the platform has none
+    pub mode: u32,
+}
+
+impl IndexEntry {
+    // Reads and hashes `abs`, None if it vanished or can't be read
+    pub(crate) fn read(path: String, abs: &Path) -> Option<IndexEntry> {
+        let metadata = fs::metadata(abs).ok()?;
+        let contents = fs::read(abs).ok()?;
+        return Some(IndexEntry {
+            path,
+            content_hash: hash::hash(&contents),
+            size: metadata.len(),
+            mtime_millis: mtime_millis(&metadata),
+            mode: mode(&metadata),
+        });
+    }
+
+    // Whether the file on disk still looks the same without reading its contents
+    pub(crate) fn is_fresh(&self, metadata: &fs::Metadata) -> bool {
+        return self.size == metadata.len() && self.mtime_millis == mtime_millis(metadata);
+    }
+}
+
+// The files of a workspace, as the server persists it to index_file_name() and sends it in
+// IndexResponses. It is a MessagePack array of hash, the 16 byte big-endian bin like
+// IndexEntry::content_hash, root_mtime, and files, a map of paths to IndexEntry arrays
+// sorted by path. The layout is stable: new fields are only ever appended, so a reader
+// can ignore trailing ones it doesn't know.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Index {
+    // XOR of every entry_hash, so entries can be folded in and out in any order
+    pub hash: ContentHash,
+    // mtime of the workspace root when the index was built, used as a staleness check
+    pub root_mtime: u64,
+    // path relative to the workspace root -> its entry
+    pub files: BTreeMap<String, IndexEntry>,
+}
+
+impl Index {
+    pub fn build(root: &Path) -> io::Result<Index> {
+        return Index::build_with(root, &[]);
+    }
+
+    // Builds the index skipping anything matching the extra ignore `patterns`
+    pub fn build_with(root: &Path, patterns: &[String]) -> io::Result<Index> {
+        return Indexer::new(root, patterns).build();
+    }
+
+    pub fn rebuild_with(&self, root: &Path, patterns: &[String]) -> io::Result<Index> {
+        return Indexer::new(root, patterns).rebuild(self);
+    }
+
+    pub fn update(&mut self, root: &Path, changed: &[PathBuf]) {
+        self.update_with(root, changed, &[]);
+    }
+
+    pub fn update_with(&mut self, root: &Path, changed: &[PathBuf], patterns: &[String]) {
+        Indexer::new(root, patterns).update(self, changed);
+    }
+
+    // Files under `prefix` (relative to the workspace root) along with their combined hash
+    pub fn entries_under(&self, prefix: &Path) -> (Vec<String>, ContentHash) {
+        let mut files = Vec::new();
+        let mut h = 0;
+        for (path, entry) in &self.files {
+            if Path::new(path).starts_with(prefix) {
+                h ^= entry_hash(path, entry.content_hash);
+                files.push(path.clone());
+            }
+        }
+        return (files, h);
+    }
+
+    pub(crate) fn insert(&mut self, entry: IndexEntry) {
+        self.hash ^= entry_hash(&entry.path, entry.content_hash);
+        self.files.insert(entry.path.clone(), entry);
+    }
+
+    pub(crate) fn remove(&mut self, path: &str) {
+        if let Some(entry) = self.files.remove(path) {
+            self.hash ^= entry_hash(path, entry.content_hash);
+        }
+    }
+
+    // Only the top level mtime is compared, so this catches files being added to or removed
+    // from the workspace root but not deeper changes
+    pub fn is_stale(&self, root: &Path) -> bool {
+        return match fs::metadata(root) {
+            Ok(metadata) => mtime_millis(&metadata) != self.root_mtime,
+            Err(_) => true,
+        };
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Index> {
+        return Index::decode(&fs::read(path)?);
+    }
+
+    // Replaces `path` whole, a reader never sees a partly written index
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        return atomic::write(path, &self.encode()?);
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Index> {
+        return rmps::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        return rmps::encode::to_vec(self).map_err(io::Error::other);
+    }
+}
+
+// File the server persists the index of `workspace` to, under {emacs_remote_path}/server
+pub fn index_file_name(workspace: &str) -> String {
+    return format!("index_{}.mp", hash::fast_hash(&workspace));
+}
+
+fn entry_hash(path: &str, content_hash: ContentHash) -> ContentHash {
+    return hash::hash(&(path, content_hash));
+}
+
+// milliseconds since the unix epoch, 0 if the platform can't report it
+pub fn mtime_millis(metadata: &fs::Metadata) -> u64 {
+    return match metadata
+        .modified()
+        .map(|mtime| mtime.duration_since(UNIX_EPOCH))
+    {
+        Ok(Ok(duration)) => duration.as_millis() as u64,
+        _ => 0,
+    };
+}
+
+#[cfg(unix)]
+pub fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    return metadata.permissions().mode();
+}
+
+#[cfg(not(unix))]
+pub fn mode(_metadata: &fs::Metadata) -> u32 {
+    return 0;
+}
@@ -0,0 +1,95 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::structs::index::{mtime_millis, Index, IndexEntry};
+use crate::utils::paths::normalize_workspace_path;
+use crate::utils::walk::{is_ignored_with, walk_files_with};
+
+// Builds and updates the Index of one workspace. Holds nothing but the root and the extra
+// ignore patterns, so the server, the --print-index preview and tests all index a tree the
+// same way without a connection.
+#[derive(Debug, Clone)]
+pub struct Indexer {
+    root: PathBuf,
+    ignore: Vec<String>,
+}
+
+impl Indexer {
+    // `root` should already be canonical, the index keys are paths relative to it
+    pub fn new(root: &Path, ignore: &[String]) -> Indexer {
+        return Indexer {
+            root: root.to_path_buf(),
+            ignore: ignore.to_vec(),
+        };
+    }
+
+    pub fn root(&self) -> &Path {
+        return &self.root;
+    }
+
+    // Every file that would be indexed, without reading any of them
+    pub fn files(&self) -> Vec<PathBuf> {
+        return walk_files_with(&self.root, &self.ignore);
+    }
+
+    pub fn build(&self) -> io::Result<Index> {
+        return self.rebuild(&Index::default());
+    }
+
+    // Walks the workspace again, only rehashing files whose size or mtime differ from
+    // their entry in `previous`. Files are hashed on the rayon thread pool, the index hash
+    // doesn't depend on the order they finish in.
+    pub fn rebuild(&self, previous: &Index) -> io::Result<Index> {
+        let mut index = Index {
+            hash: 0,
+            root_mtime: mtime_millis(&fs::metadata(&self.root)?),
+            files: Default::default(),
+        };
+        let entries: Vec<IndexEntry> = self
+            .files()
+            .par_iter()
+            .filter_map(|file| {
+                let rel = normalize_workspace_path(
+                    &file.strip_prefix(&self.root).ok()?.to_string_lossy(),
+                );
+                let unchanged = match (previous.files.get(&rel), fs::metadata(file)) {
+                    (Some(entry), Ok(metadata)) if entry.is_fresh(&metadata) => Some(entry.clone()),
+                    _ => None,
+                };
+                // files that vanish or can't be read mid-walk are left out
+                return unchanged.or_else(|| IndexEntry::read(rel, file));
+            })
+            .collect();
+        for entry in entries {
+            index.insert(entry);
+        }
+        Ok(index)
+    }
+
+    // Rehashes just the `changed` paths (absolute or relative to the root) in `index`,
+    // adding, updating or removing their entries
+    pub fn update(&self, index: &mut Index, changed: &[PathBuf]) {
+        for path in changed {
+            let rel = match path.strip_prefix(&self.root) {
+                Ok(rel) => rel,
+                Err(_) if path.is_relative() => path.as_path(),
+                Err(_) => continue,
+            };
+            let rel_str = normalize_workspace_path(&rel.to_string_lossy());
+            index.remove(&rel_str);
+
+            let abs = self.root.join(rel);
+            if abs.is_file() && !is_ignored_with(&self.root, &abs, &self.ignore) {
+                if let Some(entry) = IndexEntry::read(rel_str, &abs) {
+                    index.insert(entry);
+                }
+            }
+        }
+        if let Ok(metadata) = fs::metadata(&self.root) {
+            index.root_mtime = mtime_millis(&metadata);
+        }
+    }
+}
@@ -1,48 +1,1509 @@
+extern crate rmp_serde as rmps;
+
 use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, VecDeque},
     fs,
-    net::{TcpListener, TcpStream},
-    path::PathBuf,
+    io::{self, Cursor, ErrorKind},
+    net::{IpAddr, Shutdown, SocketAddr},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread,
+    time::{Duration, Instant},
 };
 
+use log::{debug, error, info, warn};
+use tracing::info_span;
+
+use crate::error::ServerError;
+use crate::handle::{Dispatcher, HandleServerDaemon, ServerHandler};
+use crate::messages::envelope;
+use crate::messages::error::{ErrorResponse, BUSY, UNSUPPORTED};
+use crate::messages::filechanged::{BulkChangeNotification, FileChangedNotification};
+use crate::messages::handshake::HandshakeRequest;
+use crate::messages::keyexchange::KeyExchangeRequest;
+use crate::messages::messagetype::{MessageType, MessageTypeError};
+use crate::messages::rescan::RescanResponse;
+use crate::structs::history::{
+    history_dir_name, History, DEFAULT_MAX_HISTORY_AGE, DEFAULT_MAX_HISTORY_SIZE,
+};
+use crate::structs::index::{index_file_name, Index};
+use crate::structs::indexer::Indexer;
+use crate::utils::connection::{Connection, Notifier};
+use crate::utils::drop_guard::DropGuard;
+use crate::utils::frame::{self, DEFAULT_COMPRESSION_LEVEL, DEFAULT_MAX_FRAME_SIZE};
+use crate::utils::fuzzy::{FuzzyScorer, Scorer};
+use crate::utils::hash::{self, ContentHash};
+use crate::utils::metrics::{self, Metrics};
+use crate::utils::net::DEFAULT_BIND_ADDR;
+use crate::utils::paths::{ensure_dirs, normalize_workspace_path};
+use crate::utils::seal::PresharedKey;
+use crate::utils::span;
+use crate::utils::stream::{peek_message_type, trace_message};
+use crate::utils::transport::{Endpoint, Listener, MemoryTransport, SocketOptions, Transport};
+use crate::utils::watch::{self, watch};
+use crate::utils::workers::Workers;
+use crate::version::supports_bulk_notifications;
+
+// Connections that send nothing for this long are closed, the client daemon pings every
+// few seconds while it is alive
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// Reads of larger files have to be forced, opening one by accident shouldn't stall the
+// connection for minutes
+pub const DEFAULT_MAX_READ_SIZE: u64 = 64 * 1024 * 1024;
+// How often the reaper looks for idle connections
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+// A connection told about more files than this at once gets one BulkChangeNotification
+// instead, if it understands them
+pub const BULK_NOTIFICATION_THRESHOLD: usize = 16;
+// Connections past this many are refused until some of them close
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+// Every open connection holds a worker, a client daemon keeps a handful open
+pub const DEFAULT_WORKERS: usize = 64;
+pub const DEFAULT_QUEUE_DEPTH: usize = 64;
+// How long a refused connection is given to take its error
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+// Rescans asked for this close together are answered by a single walk of the workspace
+pub const RESCAN_DEBOUNCE: Duration = Duration::from_millis(250);
+// Changed paths remembered for ChangesSinceRequests, the oldest changes are forgotten past
+// this many
+pub const MAX_RECENT_CHANGES: usize = 4096;
+
+// A connection listen() accepted, the address it came from and whether it has been served
+type Accepted = (Box<dyn Transport>, Option<IpAddr>, Arc<AtomicBool>);
+
+// What the reaper needs to know about an open connection
+struct Activity {
+    stream: Box<dyn Transport>,
+    last_activity: Instant,
+    // a request is being handled, however long that takes it doesn't count as idle
+    busy: bool,
+}
+
+// Who sent a request, as the authorizer sees it
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub connection_id: u64,
+    // what the client announced in its handshake, 0 if it didn't
+    pub client_id: u64,
+    // the address of the client, or the socket it connected to
+    pub peer: String,
+}
+
+impl Caller {
+    pub fn of(stream: &Connection) -> Caller {
+        return Caller {
+            connection_id: stream.id(),
+            client_id: stream.client_id(),
+            peer: stream.peer(),
+        };
+    }
+}
+
+// A request in flight. Each client picks its own request ids, so the same id from two
+// clients names two different requests. Those of a client that didn't announce an id in its
+// handshake belong to the connection they came in on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestKey {
+    client_id: u64,
+    // 0 unless client_id is
+    connection_id: u64,
+    request_id: u64,
+}
+
+impl RequestKey {
+    // The request last read from `stream`
+    pub fn of(stream: &Connection) -> RequestKey {
+        return RequestKey::with_id(stream, stream.request_id());
+    }
+
+    // The request tagged with `request_id` from the client on the other end of `stream`
+    pub fn with_id(stream: &Connection, request_id: u64) -> RequestKey {
+        let client_id = stream.client_id();
+        return RequestKey {
+            client_id,
+            connection_id: if client_id == 0 { stream.id() } else { 0 },
+            request_id,
+        };
+    }
+}
+
+// Decides whether a request of the given type from the caller is served
+pub type Authorizer = dyn Fn(MessageType, &Caller) -> bool + Send + Sync;
+
+// A connection that is told about FileChangedNotifications
+struct Subscriber {
+    id: u64,
+    notifier: Notifier,
+    // None until the connection's first WatchRequest, from then on it only hears about
+    // files these match
+    watches: Option<Vec<Watch>>,
+    // whether bursts may be sent as a BulkChangeNotification
+    bulk: bool,
+}
+
+struct Watch {
+    // relative to the workspace root
+    path: PathBuf,
+    recursive: bool,
+}
+
+impl Subscriber {
+    fn wants(&self, rel: &Path) -> bool {
+        return match &self.watches {
+            Some(watches) => watches.iter().any(|watch| watch.matches(rel)),
+            None => true,
+        };
+    }
+
+    fn watching(&self) -> Vec<String> {
+        return match &self.watches {
+            Some(watches) => watches
+                .iter()
+                .map(|watch| watch.path.to_string_lossy().to_string())
+                .collect(),
+            None => Vec::new(),
+        };
+    }
+}
+
+impl Watch {
+    // `path` itself, its direct children and, if recursive, everything below it
+    fn matches(&self, rel: &Path) -> bool {
+        if self.recursive {
+            return rel.starts_with(&self.path);
+        }
+        return rel == self.path || rel.parent() == Some(self.path.as_path());
+    }
+}
+
 pub struct ServerDaemon {
     pub emacs_remote_path: String,
     pub port: String,
     pub workspace: String,
+    // what a client names in its handshake to be served `workspace`, the empty name always
+    // selects it as well
+    pub workspace_name: String,
+    // more workspaces served on the same listener, name -> path, set up by init()
+    pub workspaces: BTreeMap<String, String>,
+    // address listen() binds, loopback unless configured otherwise
+    pub bind_addr: IpAddr,
+    // Unix domain socket listen() binds instead of bind_addr and port when set
+    pub socket: Option<PathBuf>,
+    // zstd level for large responses, None to never compress
+    pub compression_level: Option<i32>,
+    // incoming frames larger than this are refused and the connection closed
+    pub max_frame_size: usize,
+    // extra .gitignore style patterns excluded from the index
+    pub ignore: Vec<String>,
+    // port listen() serves Prometheus metrics on, next to the requests, None to not serve them
+    pub metrics_port: Option<u16>,
+    // connections silent for longer than this are closed, None to keep them open forever
+    pub idle_timeout: Option<Duration>,
+    // open connections listen() accepts, and how many of them may come from the same
+    // address, None for no limit. Connections over either limit are answered with a BUSY
+    // ErrorResponse and closed.
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    // threads listen() serves connections on, one connection each from handshake to hangup,
+    // and how many more connections may wait for one. Connections past the queue are
+    // answered with a BUSY ErrorResponse and closed.
+    pub workers: usize,
+    pub queue_depth: usize,
+    pub socket_options: SocketOptions,
+    // files larger than this are only read if the request is forced, None for no limit
+    pub max_read_size: Option<u64>,
+    // directories the workspace has to be inside of, any workspace is served if empty
+    pub allowed_workspaces: Vec<PathBuf>,
+    // refuses every request that would change the workspace, see MessageType::is_mutating
+    pub read_only: bool,
+    // connections are sealed with this key before the handshake, those that aren't are
+    // refused. Only ever used by the daemon that accepts connections.
+    pub preshared_key: Option<PresharedKey>,
+    // sent to clients in the handshake, see hash_salt()
+    pub hash_salt: Option<u64>,
+    // the hash_salt of each of `workspaces`, by name
+    pub hash_salts: BTreeMap<String, u64>,
+    // bytes of overwritten file versions kept for the workspace, None to keep none
+    pub max_history_size: Option<u64>,
+    // versions older than this are pruned
+    pub max_history_age: Duration,
+
+    // serializes read-modify-write operations on workspace files
+    write_lock: Mutex<()>,
+    // opened on the first write, see lock_history
+    history: Mutex<Option<History>>,
+    // when the last rescan started, whether it rehashed every file and what it found
+    last_rescan: Mutex<Option<(Instant, bool, RescanResponse)>>,
+    // set to stop listen() after in-flight requests finish
+    shutdown: Arc<AtomicBool>,
+
+    // read by most requests at once, written briefly when files change
+    index: RwLock<Index>,
+    // every recent change to the index, oldest first, as the hash of the index before it
+    // and the paths it added, changed or removed. Only ever locked with the index locked.
+    recent_changes: Mutex<VecDeque<(ContentHash, Vec<String>)>>,
+
+    handlers: Dispatcher<ServerHandler>,
+    // asked before every request is dispatched, after the read_only check
+    authorizer: Option<Arc<Authorizer>>,
+    // ranks the indexed paths for CompletionRequests
+    scorer: Arc<dyn Scorer>,
+
+    // connections that are told about FileChangedNotifications
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_connection_id: AtomicU64,
+    // open connections by connection id
+    activity: Mutex<HashMap<u64, Activity>>,
+
+    metrics: Arc<Metrics>,
+
+    // requests being handled, and whether a CancelRequest asked them to stop. Shared with
+    // the other workspaces' daemons, a cancel may arrive on any of the client's connections.
+    in_flight: Arc<Mutex<HashMap<RequestKey, bool>>>,
+
+    // a daemon of its own for each of `workspaces`, sharing this one's settings, metrics
+    // and shutdown flag
+    served: BTreeMap<String, Arc<ServerDaemon>>,
 }
 
 impl ServerDaemon {
-    pub fn new(emacs_remote_path: String, port: String, workspace: String) -> ServerDaemon {
-        let mut workspace_path = PathBuf::new();
-        workspace_path.push(emacs_remote_path.clone());
-        workspace_path.push("server");
-        fs::create_dir_all(workspace_path.clone()).expect("Unable to create server path");
-
-        workspace_path.push("workspaces");
-        fs::create_dir_all(workspace_path.clone()).expect("Unable to create workspace path");
+    pub fn new(
+        emacs_remote_path: String,
+        port: String,
+        workspace: String,
+    ) -> Result<ServerDaemon, ServerError> {
+        ensure_dirs(Path::new(&emacs_remote_path))?;
 
-        ServerDaemon {
+        Ok(ServerDaemon {
             emacs_remote_path,
             port,
             workspace,
+            workspace_name: String::new(),
+            workspaces: BTreeMap::new(),
+            bind_addr: DEFAULT_BIND_ADDR,
+            socket: None,
+            compression_level: Some(DEFAULT_COMPRESSION_LEVEL),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            ignore: Vec::new(),
+            metrics_port: None,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            max_connections: Some(DEFAULT_MAX_CONNECTIONS),
+            max_connections_per_ip: None,
+            workers: DEFAULT_WORKERS,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            socket_options: SocketOptions::default(),
+            max_read_size: Some(DEFAULT_MAX_READ_SIZE),
+            allowed_workspaces: Vec::new(),
+            read_only: false,
+            preshared_key: None,
+            hash_salt: None,
+            hash_salts: BTreeMap::new(),
+            max_history_size: Some(DEFAULT_MAX_HISTORY_SIZE),
+            max_history_age: DEFAULT_MAX_HISTORY_AGE,
+            write_lock: Mutex::new(()),
+            history: Mutex::new(None),
+            last_rescan: Mutex::new(None),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            index: RwLock::new(Index::default()),
+            recent_changes: Mutex::new(VecDeque::new()),
+            handlers: Dispatcher::server(),
+            authorizer: None,
+            scorer: Arc::new(FuzzyScorer),
+            subscribers: Mutex::new(Vec::new()),
+            next_connection_id: AtomicU64::new(0),
+            activity: Mutex::new(HashMap::new()),
+            metrics: Arc::new(Metrics::new()),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            served: BTreeMap::new(),
+        })
+    }
+
+    // Loads the persisted index of every workspace, rebuilding those that are missing or
+    // stale
+    pub fn init(&mut self) -> Result<(), ServerError> {
+        self.init_index()?;
+
+        let mut served = BTreeMap::new();
+        for (name, workspace) in &self.workspaces {
+            if name.is_empty() || *name == self.workspace_name {
+                return Err(ServerError::Config(format!(
+                    "workspace name {:?} is already taken by {}",
+                    name, self.workspace
+                )));
+            }
+            let mut server_daemon = self.for_workspace(name, workspace)?;
+            server_daemon.init_index()?;
+            served.insert(name.clone(), Arc::new(server_daemon));
+        }
+        self.served = served;
+        Ok(())
+    }
+
+    // A daemon serving `workspace` the way this one serves its own
+    fn for_workspace(&self, name: &str, workspace: &str) -> Result<ServerDaemon, ServerError> {
+        let mut server_daemon = ServerDaemon::new(
+            self.emacs_remote_path.clone(),
+            self.port.clone(),
+            workspace.to_string(),
+        )?;
+        server_daemon.workspace_name = name.to_string();
+        server_daemon.bind_addr = self.bind_addr;
+        server_daemon.socket = self.socket.clone();
+        server_daemon.compression_level = self.compression_level;
+        server_daemon.max_frame_size = self.max_frame_size;
+        server_daemon.ignore = self.ignore.clone();
+        server_daemon.idle_timeout = self.idle_timeout;
+        server_daemon.max_connections = self.max_connections;
+        server_daemon.max_connections_per_ip = self.max_connections_per_ip;
+        server_daemon.workers = self.workers;
+        server_daemon.queue_depth = self.queue_depth;
+        server_daemon.socket_options = self.socket_options;
+        server_daemon.max_read_size = self.max_read_size;
+        server_daemon.allowed_workspaces = self.allowed_workspaces.clone();
+        server_daemon.read_only = self.read_only;
+        server_daemon.hash_salt = self.hash_salts.get(name).copied();
+        server_daemon.max_history_size = self.max_history_size;
+        server_daemon.max_history_age = self.max_history_age;
+        server_daemon.authorizer = self.authorizer.clone();
+        server_daemon.scorer = self.scorer.clone();
+        server_daemon.shutdown = self.shutdown.clone();
+        server_daemon.metrics = self.metrics.clone();
+        server_daemon.in_flight = self.in_flight.clone();
+        return Ok(server_daemon);
+    }
+
+    // What clients salt the content hashes of this workspace's files with in their caches,
+    // so that two checkouts of the same repository don't share cached files. Unless it was
+    // configured, it is derived from the workspace's path and stays the same across
+    // restarts.
+    pub fn hash_salt(&self) -> u64 {
+        return self
+            .hash_salt
+            .unwrap_or_else(|| hash::fast_hash(&self.workspace).max(1));
+    }
+
+    // The daemon serving the workspace called `name`, None if there is none
+    pub fn workspace_daemon(&self, name: &str) -> Option<&ServerDaemon> {
+        if name.is_empty() || name == self.workspace_name {
+            return Some(self);
+        }
+        return self
+            .served
+            .get(name)
+            .map(|server_daemon| server_daemon.as_ref());
+    }
+
+    // Loads the persisted index for this workspace, rebuilding it if it is missing or stale
+    fn init_index(&mut self) -> Result<(), ServerError> {
+        check_workspace(&self.workspace)?;
+        let root = self.resolve_in_workspace("")?;
+        let index_file = self.index_file();
+
+        let index = match Index::from_file(&index_file) {
+            Ok(index) if !index.is_stale(&root) => {
+                info!("Loaded index from {}", index_file.display());
+                index
+            }
+            previous => {
+                let now = Instant::now();
+                // unchanged files keep the hash they were persisted with
+                let index =
+                    Indexer::new(&root, &self.ignore).rebuild(&previous.unwrap_or_default())?;
+                info!(
+                    "Indexed {} files in {} milliseconds",
+                    index.files.len(),
+                    now.elapsed().as_millis()
+                );
+                index.to_file(&index_file)?;
+                index
+            }
+        };
+
+        *self.write_index() = index;
+        Ok(())
+    }
+
+    pub fn index_file(&self) -> PathBuf {
+        let mut index_file = PathBuf::new();
+        index_file.push(self.emacs_remote_path.clone());
+        index_file.push("server");
+        index_file.push(index_file_name(&self.workspace));
+        return index_file;
+    }
+
+    // Returns the index, rebuilding and persisting it first if the workspace changed. Any
+    // number of readers share it, drop the guard before writing to a socket so that a
+    // file change isn't held up by a slow client.
+    pub fn index(&self) -> Result<RwLockReadGuard<'_, Index>, ServerError> {
+        let root = self.resolve_in_workspace("")?;
+
+        let previous = {
+            let index = self.read_index();
+            if !index.is_stale(&root) {
+                return Ok(index);
+            }
+            index.clone()
+        };
+
+        // Rebuilt and persisted from a copy, like a rescan, so that readers aren't held up
+        // by the walk or the disk
+        let rebuilt = {
+            let _span = info_span!(target: span::TARGET, "reindex").entered();
+            Indexer::new(&root, &self.ignore).rebuild(&previous)?
+        };
+        rebuilt.to_file(&self.index_file())?;
+        {
+            let mut index = self.write_index();
+            // otherwise a file change or another reindex got there first, and a later
+            // request rebuilds it again if it is still stale
+            if index.hash == previous.hash {
+                self.record_changes(index.hash, changed_paths(&index, &rebuilt));
+                *index = rebuilt;
+            }
         }
+        return Ok(self.read_index());
+    }
+
+    // Rebuilds the index whether or not it looks stale and tells every connected client
+    // about the files that changed. A rescan waits out RESCAN_DEBOUNCE first, so that a
+    // burst of them is answered by the one walk that starts after the last of them.
+    pub fn rescan(&self, full: bool) -> Result<RescanResponse, ServerError> {
+        let requested = Instant::now();
+        thread::sleep(RESCAN_DEBOUNCE);
+
+        let mut last_rescan = match self.last_rescan.lock() {
+            Ok(last_rescan) => last_rescan,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some((started, was_full, response)) = &*last_rescan {
+            // it saw every change made before this request
+            if *started >= requested && (*was_full || !full) {
+                return Ok(response.clone());
+            }
+        }
+
+        let started = Instant::now();
+        let root = self.resolve_in_workspace("")?;
+        let indexer = Indexer::new(&root, &self.ignore);
+        // Walked from a copy so that requests reading the index, and file changes folded
+        // into it, aren't held up for the length of the walk
+        let previous = self.read_index().clone();
+        let mut rebuilt = {
+            let _span = info_span!(target: span::TARGET, "rescan").entered();
+            match full {
+                true => indexer.build()?,
+                false => indexer.rebuild(&previous)?,
+            }
+        };
+        let mut notifications = Vec::new();
+        let response = {
+            let mut index = self.write_index();
+            // files that changed while the walk ran are kept the way file_changed left them,
+            // it looked at them last
+            for (path, entry) in &index.files {
+                if previous.files.get(path) != Some(entry) {
+                    rebuilt.remove(path);
+                    rebuilt.insert(entry.clone());
+                }
+            }
+            for path in previous.files.keys() {
+                if !index.files.contains_key(path) {
+                    rebuilt.remove(path);
+                }
+            }
+
+            for (path, entry) in &rebuilt.files {
+                let before = index.files.get(path).map(|entry| entry.content_hash);
+                if before != Some(entry.content_hash) {
+                    notifications.push(FileChangedNotification::new(
+                        path.clone(),
+                        entry.content_hash,
+                    ));
+                }
+            }
+            for path in index.files.keys() {
+                if !rebuilt.files.contains_key(path) {
+                    notifications.push(FileChangedNotification::new(path.clone(), 0));
+                }
+            }
+            let paths = notifications
+                .iter()
+                .map(|notification| notification.path.clone())
+                .collect();
+            self.record_changes(index.hash, paths);
+            *index = rebuilt;
+            RescanResponse::new(
+                index.hash,
+                index.files.len() as u64,
+                notifications.len() as u64,
+            )
+        };
+        info!(
+            "Rescanned {} files, {} changed, in {} milliseconds",
+            response.files,
+            response.changed,
+            started.elapsed().as_millis()
+        );
+        self.read_index().to_file(&self.index_file())?;
+        *last_rescan = Some((started, full, response.clone()));
+        drop(last_rescan);
+
+        self.broadcast(&notifications)?;
+        return Ok(response);
+    }
+
+    fn read_index(&self) -> RwLockReadGuard<'_, Index> {
+        match self.index.read() {
+            Ok(index) => index,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn write_index(&self) -> RwLockWriteGuard<'_, Index> {
+        match self.index.write() {
+            Ok(index) => index,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Folds just the changed paths into the index instead of rebuilding it
+    pub fn update_index(&self, changed: &[PathBuf]) -> Result<(), ServerError> {
+        let root = self.resolve_in_workspace("")?;
+
+        let indexer = Indexer::new(&root, &self.ignore);
+        let mut index = self.write_index();
+        let before = index.hash;
+        indexer.update(&mut index, changed);
+        if index.hash != before {
+            // every path asked about, whether or not it actually changed
+            let paths = changed
+                .iter()
+                .map(|path| path.strip_prefix(&root).unwrap_or(path))
+                .map(|rel| normalize_workspace_path(&rel.to_string_lossy()))
+                .collect();
+            self.record_changes(before, paths);
+        }
+        Ok(())
+    }
+
+    // Folds `changed` (absolute paths) into the index and tells every connected client
+    // about the files whose contents actually changed
+    pub fn file_changed(&self, changed: &[PathBuf]) -> Result<(), ServerError> {
+        let root = self.resolve_in_workspace("")?;
+        let indexer = Indexer::new(&root, &self.ignore);
+
+        // Only the index update happens under the write lock, the notifications are sent
+        // once it is released
+        let mut notifications = Vec::new();
+        {
+            let mut index = self.write_index();
+            let before = index.hash;
+            for path in changed {
+                let rel = match path.strip_prefix(&root) {
+                    Ok(rel) => normalize_workspace_path(&rel.to_string_lossy()),
+                    Err(_) => continue,
+                };
+                let before = index.files.get(&rel).map(|entry| entry.content_hash);
+                indexer.update(&mut index, std::slice::from_ref(path));
+                let after = index.files.get(&rel).map(|entry| entry.content_hash);
+
+                if before != after {
+                    notifications.push(FileChangedNotification::new(rel, after.unwrap_or(0)));
+                }
+            }
+            let paths = notifications
+                .iter()
+                .map(|notification| notification.path.clone())
+                .collect();
+            self.record_changes(before, paths);
+        }
+
+        for notification in &notifications {
+            debug!("{} changed", notification.path);
+        }
+        self.broadcast(&notifications)
+    }
+
+    fn lock_recent_changes(&self) -> MutexGuard<'_, VecDeque<(ContentHash, Vec<String>)>> {
+        match self.recent_changes.lock() {
+            Ok(recent_changes) => recent_changes,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Remembers that `paths` changed, taking the index from the hash `before` to the one it
+    // has now. Called with the index locked for writing so that changes are kept in the
+    // order they were made.
+    fn record_changes(&self, before: ContentHash, paths: Vec<String>) {
+        if paths.is_empty() {
+            return;
+        }
+        let mut recent_changes = self.lock_recent_changes();
+        recent_changes.push_back((before, paths));
+        let mut remembered: usize = recent_changes.iter().map(|(_, paths)| paths.len()).sum();
+        while remembered > MAX_RECENT_CHANGES {
+            match recent_changes.pop_front() {
+                Some((_, paths)) => remembered -= paths.len(),
+                None => break,
+            }
+        }
+    }
+
+    // The index's hash and the paths changed since it had the hash `since`, sorted. None
+    // if that is older than the changes remembered, or a hash it never had.
+    pub fn changes_since(&self, since: ContentHash) -> (ContentHash, Option<Vec<String>>) {
+        let index = self.read_index();
+        if since == index.hash {
+            return (index.hash, Some(Vec::new()));
+        }
+        let recent_changes = self.lock_recent_changes();
+        // the index can come back to an earlier hash, the latest time it had it is enough
+        let paths = recent_changes
+            .iter()
+            .rposition(|(before, _)| *before == since)
+            .map(|start| {
+                let paths: BTreeSet<&String> = recent_changes
+                    .range(start..)
+                    .flat_map(|(_, paths)| paths)
+                    .collect();
+                paths.into_iter().cloned().collect()
+            });
+        return (index.hash, paths);
+    }
+
+    pub fn index_hash(&self) -> ContentHash {
+        return self.read_index().hash;
+    }
+
+    // Sends each connection the notifications for the files it watches, as a single
+    // BulkChangeNotification if there are too many of them. Clients that can't be written
+    // to have hung up and are dropped.
+    fn broadcast(&self, notifications: &[FileChangedNotification]) -> Result<(), ServerError> {
+        if notifications.is_empty() {
+            return Ok(());
+        }
+        let mut payloads = Vec::with_capacity(notifications.len());
+        for notification in notifications {
+            payloads.push(envelope::encode(notification)?);
+        }
+
+        let mut result = Ok(());
+        self.lock_subscribers().retain(|subscriber| {
+            let wanted: Vec<usize> = (0..notifications.len())
+                .filter(|&i| subscriber.wants(Path::new(&notifications[i].path)))
+                .collect();
+            if wanted.is_empty() {
+                return true;
+            }
+
+            let written = if subscriber.bulk && wanted.len() > BULK_NOTIFICATION_THRESHOLD {
+                let paths = wanted
+                    .iter()
+                    .map(|&i| notifications[i].path.clone())
+                    .collect();
+                match envelope::encode(&BulkChangeNotification::new(paths)) {
+                    Ok(payload) => subscriber.notifier.write_frame(&payload),
+                    Err(e) => {
+                        result = Err(e);
+                        return true;
+                    }
+                }
+            } else {
+                wanted
+                    .iter()
+                    .try_for_each(|&i| subscriber.notifier.write_frame(&payloads[i]))
+            };
+            match written {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Dropping subscriber {}: {}", subscriber.id, e);
+                    false
+                }
+            }
+        });
+        return result.map_err(ServerError::from);
+    }
+
+    fn lock_subscribers(&self) -> MutexGuard<'_, Vec<Subscriber>> {
+        match self.subscribers.lock() {
+            Ok(subscribers) => subscribers,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn subscribe(&self, id: u64, notifier: Notifier, protocol_version: u32) {
+        self.lock_subscribers().push(Subscriber {
+            id,
+            notifier,
+            watches: None,
+            bulk: supports_bulk_notifications(protocol_version),
+        });
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.lock_subscribers()
+            .retain(|subscriber| subscriber.id != id);
+    }
+
+    // Narrows the notifications connection `id` is sent to files under `path` (relative to
+    // the workspace root), on top of anything it already watches. Returns every path the
+    // connection now watches.
+    pub fn watch(&self, id: u64, path: &str, recursive: bool) -> Vec<String> {
+        let mut subscribers = self.lock_subscribers();
+        let subscriber = match subscribers
+            .iter_mut()
+            .find(|subscriber| subscriber.id == id)
+        {
+            Some(subscriber) => subscriber,
+            None => return Vec::new(),
+        };
+        let watches = subscriber.watches.get_or_insert_with(Vec::new);
+        watches.retain(|watch| watch.path != Path::new(path));
+        watches.push(Watch {
+            path: PathBuf::from(path),
+            recursive,
+        });
+        return subscriber.watching();
+    }
+
+    // Stops watching `path`. A connection that unwatches everything hears about nothing
+    // until it watches something again.
+    pub fn unwatch(&self, id: u64, path: &str) -> Vec<String> {
+        let mut subscribers = self.lock_subscribers();
+        let subscriber = match subscribers
+            .iter_mut()
+            .find(|subscriber| subscriber.id == id)
+        {
+            Some(subscriber) => subscriber,
+            None => return Vec::new(),
+        };
+        if let Some(watches) = subscriber.watches.as_mut() {
+            watches.retain(|watch| watch.path != Path::new(path));
+        }
+        return subscriber.watching();
+    }
+
+    fn lock_in_flight(&self) -> MutexGuard<'_, HashMap<RequestKey, bool>> {
+        match self.in_flight.lock() {
+            Ok(in_flight) => in_flight,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Asks the request to stop, returns false if it isn't in flight
+    pub fn cancel(&self, request: RequestKey) -> bool {
+        return match self.lock_in_flight().get_mut(&request) {
+            Some(cancelled) => {
+                *cancelled = true;
+                true
+            }
+            None => false,
+        };
     }
 
-    pub fn init(&mut self) {}
+    // For long handlers to call every so often, fails once the request they are answering
+    // on `stream` has been cancelled
+    pub fn check_cancelled(&self, stream: &Connection) -> Result<(), ServerError> {
+        return self.check_request_cancelled(RequestKey::of(stream));
+    }
 
-    pub fn listen(&mut self) {
-        let listener = TcpListener::bind(format!("localhost:{}", self.port)).unwrap();
+    // For handlers that have lent out their stream, see check_cancelled
+    pub fn check_request_cancelled(&self, request: RequestKey) -> Result<(), ServerError> {
+        if request.request_id != 0 && self.lock_in_flight().get(&request) == Some(&true) {
+            return Err(ServerError::Cancelled(request.request_id));
+        }
+        Ok(())
+    }
 
-        for stream in listener.incoming() {
-            let mut stream = stream.unwrap();
+    fn lock_activity(&self) -> MutexGuard<'_, HashMap<u64, Activity>> {
+        match self.activity.lock() {
+            Ok(activity) => activity,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Records that connection `id` was just heard from
+    fn touch(&self, id: u64, busy: bool) {
+        if let Some(activity) = self.lock_activity().get_mut(&id) {
+            activity.last_activity = Instant::now();
+            activity.busy = busy;
+        }
+    }
 
-            if self.handle(&mut stream).is_err() {
-                println!("Failed to handle stream");
+    // Closes every connection that has been idle for longer than `idle_timeout`, their
+    // threads see EOF on their next read and wind down. Returns how many were closed.
+    pub fn reap_idle_connections(&self, idle_timeout: Duration) -> usize {
+        let mut activity = self.lock_activity();
+        let idle: Vec<u64> = activity
+            .iter()
+            .filter(|(_, activity)| {
+                !activity.busy && activity.last_activity.elapsed() > idle_timeout
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &idle {
+            if let Some(activity) = activity.remove(id) {
+                info!(
+                    "Closing connection {} from {}, idle for {} seconds",
+                    id,
+                    activity.stream.peer(),
+                    activity.last_activity.elapsed().as_secs()
+                );
+                let _ = activity.stream.shutdown(Shutdown::Both);
             }
         }
+        return idle.len();
     }
 
-    fn handle(&mut self, stream: &mut TcpStream) -> Result<(), ()> {
-        println!("Received Tcp request");
+    pub fn save_index(&self) -> Result<(), ServerError> {
+        let index = self.read_index();
+        index.to_file(&self.index_file())?;
         Ok(())
     }
+
+    // How the index and the history name a path resolve_in_workspace returned
+    pub fn relative_to_workspace(&self, path: &Path) -> Result<String, ServerError> {
+        let root = self.resolve_in_workspace("")?;
+        return match path.strip_prefix(&root) {
+            Ok(rel) => Ok(rel.to_string_lossy().to_string()),
+            Err(_) => Err(ServerError::PathEscape(path.display().to_string())),
+        };
+    }
+
+    // Resolves a client supplied path against the workspace root, following symlinks and
+    // `..` components, and rejects anything that ends up outside of it
+    pub fn resolve_in_workspace(&self, rel: &str) -> Result<PathBuf, ServerError> {
+        let root = fs::canonicalize(&self.workspace)?;
+        // checked on every request, a symlinked workspace may have been pointed elsewhere
+        self.check_workspace_allowed(&root)?;
+
+        let mut path = PathBuf::new();
+        path.push(root.clone());
+        path.push(normalize_workspace_path(rel));
+
+        let resolved = match fs::canonicalize(&path) {
+            Ok(resolved) => resolved,
+            // Files that don't exist yet are resolved through their parent directory
+            Err(e) if e.kind() == ErrorKind::NotFound => match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) => fs::canonicalize(parent)?.join(name),
+                _ => return Err(ServerError::Io(e)),
+            },
+            Err(e) => return Err(ServerError::Io(e)),
+        };
+
+        if !resolved.starts_with(&root) {
+            return Err(ServerError::PathEscape(rel.to_string()));
+        }
+        return Ok(resolved);
+    }
+
+    // Like resolve_in_workspace, except that a symlink at the end of `rel` isn't followed so
+    // that the link itself can be looked at. Only its parent has to be in the workspace.
+    pub fn resolve_link_in_workspace(&self, rel: &str) -> Result<PathBuf, ServerError> {
+        let normalized = normalize_workspace_path(rel);
+        let path = Path::new(&normalized);
+        return match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                match self.resolve_in_workspace(&parent.to_string_lossy()) {
+                    Ok(parent) => Ok(parent.join(name)),
+                    Err(ServerError::PathEscape(_)) => {
+                        Err(ServerError::PathEscape(rel.to_string()))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            // the workspace root itself, or a path ending in `..`
+            _ => self.resolve_in_workspace(rel),
+        };
+    }
+
+    fn check_workspace_allowed(&self, root: &Path) -> Result<(), ServerError> {
+        if self.allowed_workspaces.is_empty() {
+            return Ok(());
+        }
+        let allowed = self.allowed_workspaces.iter().any(|allowed| {
+            match fs::canonicalize(allowed) {
+                Ok(allowed) => root.starts_with(allowed),
+                // an allowed workspace that doesn't exist can't contain anything
+                Err(_) => false,
+            }
+        });
+        if !allowed {
+            return Err(ServerError::WorkspaceNotAllowed(self.workspace.clone()));
+        }
+        return Ok(());
+    }
+
+    // Request types this server serves once the handshake is done, less what a read-only
+    // server refuses so that clients don't try
+    pub fn supported_message_types(&self) -> Vec<MessageType> {
+        let mut message_types = self.handlers.message_types();
+        if self.read_only {
+            message_types.retain(|msgtype| !msgtype.is_mutating());
+        }
+        return message_types;
+    }
+
+    // Requests `authorizer` returns false for are refused with a permission error. Set it
+    // before init() for the other workspaces to share it.
+    pub fn set_authorizer<F>(&mut self, authorizer: F)
+    where
+        F: Fn(MessageType, &Caller) -> bool + Send + Sync + 'static,
+    {
+        self.authorizer = Some(Arc::new(authorizer));
+    }
+
+    // Completions are ranked by `scorer` instead of FuzzyScorer. Set it before init() for
+    // the other workspaces to share it.
+    pub fn set_scorer<S: Scorer + 'static>(&mut self, scorer: S) {
+        self.scorer = Arc::new(scorer);
+    }
+
+    pub fn scorer(&self) -> &dyn Scorer {
+        return self.scorer.as_ref();
+    }
+
+    fn authorize(&self, msgtype: MessageType, caller: &Caller) -> Result<(), ServerError> {
+        if self.read_only && msgtype.is_mutating() {
+            return Err(ServerError::Forbidden(msgtype));
+        }
+        match &self.authorizer {
+            Some(authorizer) if !authorizer(msgtype, caller) => {
+                warn!("Refused {:?} from {}", msgtype, caller.peer);
+                return Err(ServerError::Forbidden(msgtype));
+            }
+            _ => return Ok(()),
+        }
+    }
+
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        return self.shutdown.clone();
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        return &self.metrics;
+    }
+
+    // The workspace's history of overwritten files, None if the server keeps none
+    pub fn lock_history(&self) -> Result<MutexGuard<'_, Option<History>>, ServerError> {
+        let mut history = match self.history.lock() {
+            Ok(history) => history,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let (None, Some(max_size)) = (&*history, self.max_history_size) {
+            let dir = Path::new(&self.emacs_remote_path)
+                .join("server")
+                .join("history")
+                .join(history_dir_name(&self.workspace));
+            *history = Some(History::open(&dir, max_size, self.max_history_age)?);
+        }
+        return Ok(history);
+    }
+
+    // Keeps what `path` held before a write replaced it with something else. Called with
+    // the write lock held, a history that can't be written to doesn't stop the write.
+    pub fn keep_history(&self, path: &Path, previous: &[u8]) {
+        let result = self.relative_to_workspace(path).and_then(|rel| {
+            if let Some(history) = self.lock_history()?.as_mut() {
+                history.record(&rel, previous)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!(
+                "Unable to keep the previous version of {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    pub fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        // a handler panicking mid-write doesn't leave anything for the next writer to fix up
+        match self.write_lock.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Every connection is served on its own thread so a slow transfer doesn't block
+    // other clients
+    pub fn listen(self: Arc<Self>) -> Result<(), ServerError> {
+        let listener = self.bind()?;
+        return self.listen_on(listener);
+    }
+
+    // Binds the endpoint listen() accepts clients on, AddrInUse if another process holds it
+    pub fn bind(&self) -> Result<Listener, ServerError> {
+        let endpoint = self.endpoint()?;
+        return Listener::bind(&endpoint).map_err(|e| match e.kind() {
+            ErrorKind::AddrInUse => ServerError::AddrInUse(endpoint),
+            _ => ServerError::Io(e),
+        });
+    }
+
+    // Same as listen, on a listener bound by the caller, e.g. to an ephemeral port
+    pub fn listen_on(self: Arc<Self>, listener: Listener) -> Result<(), ServerError> {
+        info!("Listening on {}", listener.local_endpoint()?);
+        // Poll so that the shutdown flag is noticed even when no client connects
+        listener.set_nonblocking(true)?;
+
+        if let Some(metrics_port) = self.metrics_port {
+            let addr = SocketAddr::new(self.bind_addr, metrics_port);
+            metrics::serve(addr, self.metrics.clone()).map_err(|e| match e.kind() {
+                ErrorKind::AddrInUse => ServerError::AddrInUse(Endpoint::Tcp(addr)),
+                _ => ServerError::Io(e),
+            })?;
+        }
+
+        let mut watches = vec![self.watch_workspace()?];
+        for (name, server_daemon) in &self.served {
+            info!(
+                "Serving workspace {} from {}",
+                name, server_daemon.workspace
+            );
+            watches.push(server_daemon.watch_workspace()?);
+        }
+
+        let reaper = self.idle_timeout.map(|idle_timeout| {
+            let server_daemon = self.clone();
+            thread::spawn(move || {
+                let mut last_reap = Instant::now();
+                while !server_daemon.shutdown.load(Relaxed) {
+                    // short sleeps so that shutdown isn't held up by a whole interval
+                    thread::sleep(Duration::from_millis(100));
+                    if last_reap.elapsed() >= REAP_INTERVAL {
+                        server_daemon.reap_idle_connections(idle_timeout);
+                        last_reap = Instant::now();
+                    }
+                }
+            })
+        });
+
+        // Each connection is served start to end by one of the workers, those accepted while
+        // every worker is busy wait in the queue
+        let server_daemon = self.clone();
+        let workers = Workers::new(
+            self.workers,
+            self.queue_depth,
+            self.metrics.clone(),
+            move |(mut connection, served): (Connection, Arc<AtomicBool>)| {
+                let done = || served.store(true, Relaxed);
+                let _guard = DropGuard::new(&done);
+                if let Err(e) = server_daemon.handle_connection(&mut connection) {
+                    error!("Failed to handle stream: {}", e);
+                }
+            },
+        );
+
+        let mut connections: Vec<Accepted> = Vec::new();
+        while !self.shutdown.load(Relaxed) {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    connections.retain(|(_, _, served)| !served.load(Relaxed));
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Refused before it is queued, so a storm of connections costs no more than
+            // accepting them
+            connections.retain(|(_, _, served)| !served.load(Relaxed));
+            let ip = stream.peer_ip();
+            let from_ip = connections
+                .iter()
+                .filter(|(_, other, _)| ip.is_some() && *other == ip)
+                .count();
+            if let Some(reason) = self.over_connection_limit(connections.len(), from_ip) {
+                warn!("Refusing connection from {}: {}", stream.peer(), reason);
+                self.metrics.connection_refused();
+                refuse_connection(stream, reason);
+                continue;
+            }
+
+            let connection = match stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.apply(&self.socket_options))
+                .and_then(|_| stream.try_clone_transport())
+            {
+                Ok(connection) => {
+                    let mut connection = Connection::boxed(connection);
+                    connection.set_max_frame_size(self.max_frame_size);
+                    connection
+                }
+                Err(e) => {
+                    error!("Failed to set up connection: {}", e);
+                    continue;
+                }
+            };
+
+            debug!("Accepted connection from {}", stream.peer());
+            let served = Arc::new(AtomicBool::new(false));
+            if workers.submit((connection, served.clone())).is_err() {
+                let reason = format!(
+                    "server is busy, {} connections are waiting for a worker already",
+                    self.queue_depth
+                );
+                warn!("Refusing connection from {}: {}", stream.peer(), reason);
+                self.metrics.connection_refused();
+                refuse_connection(stream, reason);
+                continue;
+            }
+            connections.push((stream, ip, served));
+        }
+
+        // Closing the read half lets every connection finish the request it is working on
+        // and then see EOF on its next read, queued ones see it straight away
+        info!("Shutting down, draining {} connections", connections.len());
+        for (stream, _, _) in &connections {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        workers.join();
+        if let Some(reaper) = reaper {
+            let _ = reaper.join();
+        }
+        drop(watches);
+        for server_daemon in self.served.values() {
+            server_daemon.save_index()?;
+        }
+        return self.save_index();
+    }
+
+    // Why a new connection can't be accepted with `open` connections already open,
+    // `from_ip` of them from the same address
+    fn over_connection_limit(&self, open: usize, from_ip: usize) -> Option<String> {
+        if let Some(max_connections) = self.max_connections {
+            if open >= max_connections {
+                return Some(format!(
+                    "server is at its limit of {} connections, try again later",
+                    max_connections
+                ));
+            }
+        }
+        if let Some(max_connections_per_ip) = self.max_connections_per_ip {
+            if from_ip >= max_connections_per_ip {
+                return Some(format!(
+                    "server is at its limit of {} connections from one address, try again later",
+                    max_connections_per_ip
+                ));
+            }
+        }
+        return None;
+    }
+
+    // Changes made behind our back, e.g. by a build tool, are pushed to the clients. None if
+    // the workspace can't be watched.
+    fn watch_workspace(self: &Arc<Self>) -> Result<Option<watch::Watch>, ServerError> {
+        let server_daemon = self.clone();
+        let watch = watch(&self.resolve_in_workspace("")?, move |paths| {
+            // directories themselves aren't indexed, their files get events of their own
+            let paths: Vec<PathBuf> = paths.into_iter().filter(|path| !path.is_dir()).collect();
+            if let Err(e) = server_daemon.file_changed(&paths) {
+                error!("Failed to update index: {}", e);
+            }
+        });
+        return match watch {
+            Ok(watch) => Ok(Some(watch)),
+            Err(e) => {
+                warn!(
+                    "Unable to watch {}, clients won't see remote changes: {}",
+                    self.workspace, e
+                );
+                Ok(None)
+            }
+        };
+    }
+
+    // Where listen() accepts clients
+    pub fn endpoint(&self) -> Result<Endpoint, ServerError> {
+        if let Some(socket) = &self.socket {
+            return Ok(Endpoint::Unix(socket.clone()));
+        }
+        let port = self
+            .port
+            .parse::<u16>()
+            .map_err(|e| ServerError::Config(format!("invalid port {}: {}", self.port, e)))?;
+        return Ok(Endpoint::Tcp(SocketAddr::new(self.bind_addr, port)));
+    }
+
+    // Serves one client from the handshake until it hangs up or is reaped
+    pub fn handle_connection(&self, stream: &mut Connection) -> Result<(), ServerError> {
+        let id = self.next_connection_id.fetch_add(1, Relaxed);
+        self.lock_activity().insert(
+            id,
+            Activity {
+                stream: stream.try_clone_transport()?,
+                last_activity: Instant::now(),
+                busy: false,
+            },
+        );
+        self.metrics.connection_opened();
+        let closed = || {
+            self.lock_activity().remove(&id);
+            self.metrics.connection_closed();
+        };
+        let _guard = DropGuard::new(&closed);
+
+        let mut buf = match read_request(stream)? {
+            Some(buf) => buf,
+            None => return Ok(()),
+        };
+        // With a pre-shared key the key exchange is all that is ever said in the clear
+        if self.preshared_key.is_some()
+            || peek_message_type(&buf) == Ok(MessageType::KeyExchangeRequest)
+        {
+            {
+                let _span = info_span!(target: span::TARGET, "keyexchange", msgtype = ?MessageType::KeyExchangeRequest, request_id = stream.request_id()).entered();
+                self.exchange_keys(stream, &buf)?;
+            }
+            buf = match read_request(stream)? {
+                Some(buf) => buf,
+                None => return Ok(()),
+            };
+        }
+        // Nothing else is served until the client has proven it speaks our protocol
+        let name = {
+            let _span = info_span!(target: span::TARGET, "handshake", msgtype = ?MessageType::HandshakeRequest, request_id = stream.request_id()).entered();
+            self.handshake(stream, &buf)?
+        };
+        let workspace = match self.workspace_daemon(&name) {
+            Some(workspace) => workspace,
+            None => return Err(ServerError::UnknownWorkspace(name)),
+        };
+
+        // handlers find the connection's subscription by its id
+        stream.set_id(id);
+        workspace.subscribe(id, stream.notifier()?, stream.protocol_version());
+        let result = self.serve_requests(workspace, id, stream);
+        workspace.unsubscribe(id);
+        return result;
+    }
+
+    // Requests are handled by the daemon of the workspace the handshake selected, the
+    // connection's activity is tracked by this one
+    fn serve_requests(
+        &self,
+        workspace: &ServerDaemon,
+        id: u64,
+        stream: &mut Connection,
+    ) -> Result<(), ServerError> {
+        let caller = Caller::of(stream);
+        // A connection stays open for any number of requests until the client hangs up, or
+        // goes quiet for longer than the idle timeout
+        loop {
+            self.touch(id, false);
+            let buf = match read_request(stream)? {
+                Some(buf) => buf,
+                None => return Ok(()),
+            };
+            self.touch(id, true);
+
+            // A failed request doesn't poison the connection, if the stream itself
+            // broke the next read will end the loop
+            if let Err(e) = workspace.handle(stream, &caller, &buf) {
+                error!("Failed to handle request: {}", e);
+                if let Err(e) = send_error(stream, e.code(), e.to_string()) {
+                    debug!("Unable to report error to client: {}", e);
+                }
+            }
+        }
+    }
+
+    fn exchange_keys(&self, stream: &mut Connection, buf: &[u8]) -> Result<(), ServerError> {
+        if peek_message_type(buf)? != MessageType::KeyExchangeRequest {
+            let e = ServerError::PresharedKeyRequired;
+            send_error(stream, e.code(), e.to_string())?;
+            return Err(e);
+        }
+        let request: KeyExchangeRequest = envelope::decode(buf)?;
+        return request.handle(stream, self);
+    }
+
+    // Returns the name of the workspace the client asked for
+    fn handshake(&self, stream: &mut Connection, buf: &[u8]) -> Result<String, ServerError> {
+        let msgtype = peek_message_type(buf)?;
+        if msgtype != MessageType::HandshakeRequest {
+            return Err(ServerError::UnexpectedMessageType {
+                expected: MessageType::HandshakeRequest,
+                got: msgtype,
+            });
+        }
+
+        let request: HandshakeRequest = rmps::from_slice(buf)?;
+        request.handle(stream, self)?;
+        return Ok(request.workspace);
+    }
+
+    // Runs one request of a BatchRequest from `caller` and returns the message it was
+    // answered with. A failure is answered with an ErrorResponse, the same as on the
+    // connection itself.
+    pub fn handle_batched(&self, caller: &Caller, buf: &[u8]) -> Result<Vec<u8>, ServerError> {
+        if peek_message_type(buf) == Ok(MessageType::BatchRequest) {
+            return error_message(UNSUPPORTED, "batches can't be nested".to_string());
+        }
+
+        let capture = MemoryTransport::new();
+        let mut stream = Connection::new(capture.clone());
+        stream.set_id(caller.connection_id);
+        stream.set_client_id(caller.client_id);
+        if let Err(e) = self.handle(&mut stream, caller, buf) {
+            error!("Failed to handle batched request: {}", e);
+            send_error(&mut stream, e.code(), e.to_string())?;
+        }
+
+        let written = capture.take();
+        let mut frames = Cursor::new(&written);
+        let message = frame::read_frame_limited(&mut frames, usize::MAX)?;
+        // e.g. a large file read streamed in chunks
+        if frames.position() < written.len() as u64 {
+            return error_message(
+                UNSUPPORTED,
+                "the response doesn't fit in one message, send the request on its own".to_string(),
+            );
+        }
+        return Ok(message);
+    }
+
+    fn handle(
+        &self,
+        stream: &mut Connection,
+        caller: &Caller,
+        buf: &[u8],
+    ) -> Result<(), ServerError> {
+        trace_message("Request", buf);
+
+        // Requests from a newer client are refused rather than dropped so it can fall back
+        let msgtype = match peek_message_type(buf) {
+            Ok(msgtype) => msgtype,
+            Err(MessageTypeError::Unknown(n)) => {
+                warn!("Unknown message type {}", n);
+                return send_error(stream, UNSUPPORTED, format!("unknown message type {}", n));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let _span = info_span!(target: span::TARGET, "request", msgtype = ?msgtype, request_id = stream.request_id()).entered();
+        // Untagged requests can't be cancelled, nor can one whose id its client already has in
+        // flight
+        let request = RequestKey::of(stream);
+        let cancellable = request.request_id != 0
+            && msgtype != MessageType::CancelRequest
+            && match self.lock_in_flight().entry(request) {
+                Entry::Vacant(entry) => {
+                    entry.insert(false);
+                    true
+                }
+                Entry::Occupied(_) => {
+                    warn!(
+                        "Request id {} is already in flight, {:?} can't be cancelled",
+                        request.request_id, msgtype
+                    );
+                    false
+                }
+            };
+        let finished = || {
+            if cancellable {
+                self.lock_in_flight().remove(&request);
+            }
+        };
+        let _guard = DropGuard::new(&finished);
+        match self.handlers.get(msgtype) {
+            Some(handler) => {
+                self.authorize(msgtype, caller)?;
+                let now = Instant::now();
+                let result = handler(self, stream, buf);
+                self.metrics.record(msgtype, now.elapsed(), result.is_ok());
+                return result;
+            }
+            None => {
+                warn!("Unsupported message type {:?}", msgtype);
+                return send_error(
+                    stream,
+                    UNSUPPORTED,
+                    format!("unsupported message type {:?}", msgtype),
+                );
+            }
+        }
+    }
+}
+
+// Fails unless `workspace` is a directory that can be served, symlinks to one included
+pub fn check_workspace(workspace: &str) -> Result<(), ServerError> {
+    return match fs::metadata(workspace) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(ServerError::WorkspaceNotADirectory(workspace.to_string())),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Err(ServerError::WorkspaceNotFound(workspace.to_string()))
+        }
+        Err(e) => Err(ServerError::Io(e)),
+    };
+}
+
+// Files added, changed or removed between `before` and `after`
+fn changed_paths(before: &Index, after: &Index) -> Vec<String> {
+    let mut paths: Vec<String> = after
+        .files
+        .iter()
+        .filter(|(path, entry)| {
+            before.files.get(*path).map(|entry| entry.content_hash) != Some(entry.content_hash)
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    for path in before.files.keys() {
+        if !after.files.contains_key(path) {
+            paths.push(path.clone());
+        }
+    }
+    return paths;
+}
+
+// The next complete frame from the client, None once it has hung up. A frame cut short is
+// the same as a hangup, its partial contents are never decoded.
+fn read_request(stream: &mut Connection) -> Result<Option<Vec<u8>>, ServerError> {
+    return match stream.read_frame() {
+        Ok(buf) => Ok(Some(buf)),
+        Err(e) if frame::is_closed(&e) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            warn!("Client {} hung up mid-frame: {}", stream.peer(), e);
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    };
+}
+
+// Answers whatever the client sends first with a BUSY ErrorResponse, in the clear since no
+// keys were exchanged, and hangs up. Failures are only logged, the client is just as
+// refused if it never reads the error.
+fn refuse_connection(mut stream: Box<dyn Transport>, reason: String) {
+    let result = error_message(BUSY, reason)
+        .map_err(|e| io::Error::other(e.to_string()))
+        .and_then(|buffer| {
+            stream.set_nonblocking(false)?;
+            stream.set_write_timeout(Some(REFUSAL_TIMEOUT))?;
+            frame::write_frame(&mut stream, &buffer)?;
+            stream.shutdown(Shutdown::Write)?;
+            // what the client already sent is read so that closing doesn't reset the
+            // connection, which can discard the error before the client reads it
+            stream.set_nonblocking(true)?;
+            let _ = io::copy(&mut stream, &mut io::sink());
+            return Ok(());
+        });
+    if let Err(e) = result {
+        debug!("Failed to refuse connection from {}: {}", stream.peer(), e);
+    }
+}
+
+fn send_error(stream: &mut Connection, code: u32, message: String) -> Result<(), ServerError> {
+    let buffer = error_message(code, message)?;
+    stream.write_frame(&buffer)?;
+    Ok(())
+}
+
+fn error_message(code: u32, message: String) -> Result<Vec<u8>, ServerError> {
+    return Ok(envelope::encode(&ErrorResponse::new(code, message))?);
 }
@@ -1,6 +1,28 @@
+pub mod archive;
+pub mod atomic;
+pub mod binary;
+pub mod cache;
+pub mod connection;
 pub mod drop_guard;
+pub mod frame;
+pub mod fuzzy;
 pub mod hash;
+pub mod logfile;
+pub mod metrics;
+pub mod net;
+pub mod paths;
+pub mod pidfile;
+pub mod pool;
+pub mod retry;
+pub mod scp;
+pub mod seal;
 pub mod shutil;
+pub mod signal;
+pub mod span;
 pub mod ssh;
 pub mod stcp;
 pub mod stream;
+pub mod transport;
+pub mod walk;
+pub mod watch;
+pub mod workers;
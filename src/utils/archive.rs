@@ -0,0 +1,281 @@
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+// Tar archives of what an ArchiveRequest asks for, in the ustar format with GNU long names
+// so that any tar can list them. Only regular files and directories are written or read,
+// anything else in an archive is skipped over.
+
+const BLOCK: usize = 512;
+// the ustar size field holds 11 octal digits
+pub const MAX_ENTRY_SIZE: u64 = (1 << 33) - 1;
+// name of the entry that carries the name of the next one when it doesn't fit in a header
+const LONG_LINK: &str = "././@LongLink";
+// longer names than any path a filesystem allows are refused rather than read into memory
+const MAX_LONG_NAME: u64 = 4096;
+
+const REGULAR: u8 = b'0';
+const DIRECTORY: u8 = b'5';
+const LONG_NAME: u8 = b'L';
+
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+    // bytes written so far, the archive's size once finished
+    written: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(inner: W) -> ArchiveWriter<W> {
+        return ArchiveWriter { inner, written: 0 };
+    }
+
+    pub fn written(&self) -> u64 {
+        return self.written;
+    }
+
+    // Adds a regular file at `path`, which uses / separators and is relative to the root
+    // of the archive
+    pub fn append_file(
+        &mut self,
+        path: &str,
+        mode: u32,
+        mtime: u64,
+        contents: &[u8],
+    ) -> io::Result<()> {
+        let size = contents.len() as u64;
+        return self.append_reader(path, mode, mtime, size, &mut &contents[..]);
+    }
+
+    // Adds a regular file of `size` bytes copied from `reader`, without holding it in
+    // memory. Fails if the reader runs out first, e.g. a file that shrank since its size
+    // was taken, leaving the archive unfinished.
+    pub fn append_reader<R: Read>(
+        &mut self,
+        path: &str,
+        mode: u32,
+        mtime: u64,
+        size: u64,
+        reader: &mut R,
+    ) -> io::Result<()> {
+        if size > MAX_ENTRY_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is too large for a tar entry", path),
+            ));
+        }
+        self.append_header(path, REGULAR, mode, mtime, size)?;
+        let copied = io::copy(&mut reader.take(size), &mut self.inner)?;
+        self.written += copied;
+        if copied < size {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("{} changed while it was archived", path),
+            ));
+        }
+        return self.append_padding(size);
+    }
+
+    // Writes the end of archive marker and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write(&[0; 2 * BLOCK])?;
+        self.inner.flush()?;
+        return Ok(self.inner);
+    }
+
+    fn append_header(
+        &mut self,
+        path: &str,
+        typeflag: u8,
+        mode: u32,
+        mtime: u64,
+        size: u64,
+    ) -> io::Result<()> {
+        if path.len() > 100 {
+            // NUL terminated, the way GNU tar writes them
+            let mut name = path.as_bytes().to_vec();
+            name.push(0);
+            let header = header(LONG_LINK.as_bytes(), LONG_NAME, 0o644, 0, name.len() as u64);
+            self.write(&header)?;
+            self.append_data(&name)?;
+        }
+        let name = &path.as_bytes()[..path.len().min(100)];
+        let header = header(name, typeflag, mode, mtime, size);
+        return self.write(&header);
+    }
+
+    fn append_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write(data)?;
+        return self.append_padding(data.len() as u64);
+    }
+
+    // Entry data is padded to a whole number of blocks
+    fn append_padding(&mut self, size: u64) -> io::Result<()> {
+        let padding = (BLOCK - (size % BLOCK as u64) as usize) % BLOCK;
+        return self.write(&[0; BLOCK][..padding]);
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.written += buf.len() as u64;
+        return Ok(());
+    }
+}
+
+fn header(name: &[u8], typeflag: u8, mode: u32, mtime: u64, size: u64) -> [u8; BLOCK] {
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], (mode & 0o7777) as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // summed with the checksum field itself taken to be spaces
+    header[148..156].copy_from_slice(&[b' '; 8]);
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    octal(&mut header[148..155], checksum as u64);
+    return header;
+}
+
+// Zero padded octal digits followed by a NUL
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[field.len() - 1] = 0;
+}
+
+fn parse_octal(field: &[u8]) -> io::Result<u64> {
+    let digits: String = field
+        .iter()
+        .take_while(|b| **b != 0)
+        .map(|b| *b as char)
+        .collect();
+    let digits = digits.trim();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    return u64::from_str_radix(digits, 8).map_err(|_| invalid(format!("bad number {:?}", digits)));
+}
+
+// Writes every file and directory of the archive read from `reader` under `dest`, creating
+// it if needed, and returns the paths of the files relative to it. Entries that would land
+// outside of `dest` fail the whole unpack, as does a truncated archive.
+pub fn unpack<R: Read>(mut reader: R, dest: &Path) -> io::Result<Vec<String>> {
+    fs::create_dir_all(dest)?;
+    let mut files = Vec::new();
+    let mut long_name = None;
+    let mut header = [0; BLOCK];
+    loop {
+        reader.read_exact(&mut header)?;
+        if header.iter().all(|b| *b == 0) {
+            return Ok(files);
+        }
+        check_header(&header)?;
+
+        let size = parse_octal(&header[124..136])?;
+        let name = match long_name.take() {
+            Some(name) => name,
+            None => {
+                let name: Vec<u8> = header[..100]
+                    .iter()
+                    .take_while(|b| **b != 0)
+                    .copied()
+                    .collect();
+                String::from_utf8_lossy(&name).to_string()
+            }
+        };
+        match header[156] {
+            LONG_NAME => {
+                if size > MAX_LONG_NAME {
+                    return Err(invalid(format!("{} byte long name", size)));
+                }
+                let data = read_data(&mut reader, size)?;
+                let name: Vec<u8> = data.into_iter().take_while(|b| *b != 0).collect();
+                long_name = Some(String::from_utf8_lossy(&name).to_string());
+            }
+            REGULAR | 0 => {
+                let path = dest.join(entry_path(&name)?);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // streamed, an entry may be larger than what fits in memory
+                let copied =
+                    io::copy(&mut (&mut reader).take(size), &mut fs::File::create(&path)?)?;
+                if copied < size {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                skip_padding(&mut reader, size)?;
+                let mode = parse_octal(&header[100..108])? as u32;
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777))?;
+                files.push(name.trim_start_matches("./").to_string());
+            }
+            DIRECTORY => {
+                fs::create_dir_all(dest.join(entry_path(&name)?))?;
+                skip_data(&mut reader, size)?;
+            }
+            _ => skip_data(&mut reader, size)?,
+        }
+    }
+}
+
+fn check_header(header: &[u8; BLOCK]) -> io::Result<()> {
+    let expected = parse_octal(&header[148..156])? as u32;
+    let actual: u32 = header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if (148..156).contains(&i) {
+                b' ' as u32
+            } else {
+                *b as u32
+            }
+        })
+        .sum();
+    if actual != expected {
+        return Err(invalid("header checksum mismatch".to_string()));
+    }
+    return Ok(());
+}
+
+// Entry names are relative, a name with .. or a root in it could write anywhere
+fn entry_path(name: &str) -> io::Result<PathBuf> {
+    let path = Path::new(name);
+    let escapes = path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if escapes || name.is_empty() {
+        return Err(invalid(format!("refusing to unpack {:?}", name)));
+    }
+    return Ok(path.to_path_buf());
+}
+
+fn read_data<R: Read>(reader: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(size).read_to_end(&mut data)?;
+    if (data.len() as u64) < size {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    skip_padding(reader, size)?;
+    return Ok(data);
+}
+
+fn skip_data<R: Read>(reader: &mut R, size: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+    if skipped < size {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    return skip_padding(reader, size);
+}
+
+fn skip_padding<R: Read>(reader: &mut R, size: u64) -> io::Result<()> {
+    let padding = (BLOCK - (size % BLOCK as u64) as usize) % BLOCK;
+    return reader.read_exact(&mut [0; BLOCK][..padding]);
+}
+
+fn invalid(message: String) -> io::Error {
+    return io::Error::new(ErrorKind::InvalidData, message);
+}
@@ -0,0 +1,77 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use rand::Rng;
+
+// Replaces the contents of `path` so that a crash at any point leaves either the old file
+// or the new one, never a mix of the two. The new contents are written and synced next to
+// it, then renamed over it.
+pub fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let path = target(path);
+    let temp = stage(&path, contents)?;
+    if let Err(e) = fs::rename(&temp, &path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    sync_parent(&path);
+    return Ok(());
+}
+
+// Writes `contents` to a new file next to `path` for renaming over it, fsynced and with the
+// mode of the file it replaces, as an in place write would keep it. Returns its name.
+pub fn stage(path: &Path, contents: &[u8]) -> io::Result<PathBuf> {
+    let temp = sibling(path, "new");
+    let written = File::create(&temp).and_then(|mut file| {
+        file.write_all(contents)?;
+        if let Some(permissions) = permissions(path)? {
+            file.set_permissions(permissions)?;
+        }
+        return file.sync_all();
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    return Ok(temp);
+}
+
+// What a write to `path` changes, the file a symlink points to rather than the symlink
+pub fn target(path: &Path) -> PathBuf {
+    return match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => path.to_path_buf(),
+    };
+}
+
+// Makes a rename into the directory of `path` survive a crash. The file itself is already
+// in place, a failure is only logged.
+pub fn sync_parent(path: &Path) {
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return,
+    };
+    if let Err(e) = File::open(parent).and_then(|dir| dir.sync_all()) {
+        debug!("Unable to sync {}: {}", parent.display(), e);
+    }
+}
+
+// A hidden name in the same directory as `path`, unlikely to be taken
+pub fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let nonce: u32 = rand::thread_rng().gen();
+    return path.with_file_name(format!(".{}.{}.{:08x}", name, suffix, nonce));
+}
+
+// None if there is no such file yet
+fn permissions(path: &Path) -> io::Result<Option<fs::Permissions>> {
+    return match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.permissions())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+}
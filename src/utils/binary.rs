@@ -0,0 +1,19 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+// Files with a NUL byte in their first few KB are treated as binary
+pub const SNIFF_LEN: usize = 8 * 1024;
+
+pub fn looks_binary(contents: &[u8]) -> bool {
+    return contents[..contents.len().min(SNIFF_LEN)].contains(&0);
+}
+
+// Only reads the first SNIFF_LEN bytes of the file
+pub fn file_looks_binary(path: &Path) -> io::Result<bool> {
+    let mut prefix = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    return Ok(looks_binary(&prefix));
+}
@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::structs::index::mtime_millis;
+use crate::utils::hash::ContentHash;
+
+pub const DEFAULT_CACHE_BUDGET: u64 = 256 * 1024 * 1024;
+
+// File contents stored on disk by content hash, evicting the least recently used blobs
+// once their total size goes over the byte budget
+pub struct BlobCache {
+    dir: PathBuf,
+    budget: u64,
+    size: u64,
+    // hash -> (last use, size)
+    entries: HashMap<ContentHash, (u64, u64)>,
+    // last use -> hash, oldest first
+    lru: BTreeMap<u64, ContentHash>,
+    clock: u64,
+}
+
+impl BlobCache {
+    // Picks up blobs left in `dir` by a previous run, oldest mtime first
+    pub fn new(dir: &Path, budget: u64) -> io::Result<BlobCache> {
+        fs::create_dir_all(dir)?;
+
+        let mut existing = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let hash = match ContentHash::from_str_radix(&entry.file_name().to_string_lossy(), 16) {
+                Ok(hash) => hash,
+                // e.g. a temporary file from an interrupted put
+                Err(_) => continue,
+            };
+            let metadata = entry.metadata()?;
+            existing.push((mtime_millis(&metadata), hash, metadata.len()));
+        }
+        existing.sort();
+
+        let mut cache = BlobCache {
+            dir: dir.to_path_buf(),
+            budget,
+            size: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        };
+        for (_, hash, size) in existing {
+            cache.track(hash, size);
+        }
+        cache.evict();
+        return Ok(cache);
+    }
+
+    pub fn contains(&self, hash: ContentHash) -> bool {
+        return self.entries.contains_key(&hash);
+    }
+
+    pub fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    pub fn get(&mut self, hash: ContentHash) -> Option<Vec<u8>> {
+        if !self.contains(hash) {
+            return None;
+        }
+        match fs::read(self.blob_path(hash)) {
+            Ok(contents) => {
+                self.touch(hash);
+                Some(contents)
+            }
+            Err(e) => {
+                debug!("Dropping unreadable blob {:x}: {}", hash, e);
+                self.forget(hash);
+                None
+            }
+        }
+    }
+
+    // Blobs larger than the whole budget aren't cached at all
+    pub fn put(&mut self, hash: ContentHash, contents: &[u8]) {
+        let size = contents.len() as u64;
+        if self.contains(hash) {
+            self.touch(hash);
+            return;
+        }
+        if size > self.budget {
+            return;
+        }
+
+        // Written under a temporary name so a crash never leaves a truncated blob behind
+        let tmp = self.dir.join(format!("{:x}.tmp", hash));
+        if let Err(e) =
+            fs::write(&tmp, contents).and_then(|_| fs::rename(&tmp, self.blob_path(hash)))
+        {
+            warn!("Unable to cache blob {:x}: {}", hash, e);
+            let _ = fs::remove_file(&tmp);
+            return;
+        }
+        self.track(hash, size);
+        self.evict();
+    }
+
+    fn blob_path(&self, hash: ContentHash) -> PathBuf {
+        return self.dir.join(format!("{:032x}", hash));
+    }
+
+    fn track(&mut self, hash: ContentHash, size: u64) {
+        self.clock += 1;
+        self.entries.insert(hash, (self.clock, size));
+        self.lru.insert(self.clock, hash);
+        self.size += size;
+    }
+
+    fn touch(&mut self, hash: ContentHash) {
+        if let Some((last_use, _)) = self.entries.get_mut(&hash) {
+            self.lru.remove(last_use);
+            self.clock += 1;
+            *last_use = self.clock;
+            self.lru.insert(self.clock, hash);
+        }
+    }
+
+    fn forget(&mut self, hash: ContentHash) {
+        if let Some((last_use, size)) = self.entries.remove(&hash) {
+            self.lru.remove(&last_use);
+            self.size -= size;
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.budget {
+            let hash = match self.lru.values().next() {
+                Some(hash) => *hash,
+                None => return,
+            };
+            self.forget(hash);
+            let _ = fs::remove_file(self.blob_path(hash));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tracing::info_span;
+
+use crate::utils::frame;
+use crate::utils::seal::{Opener, Sealer};
+use crate::utils::span;
+use crate::utils::transport::Transport;
+
+// A stream together with the framing options negotiated for it
+pub struct Connection {
+    stream: Box<dyn Transport>,
+    // zstd level for outgoing frames, None until the peer has agreed to compression
+    compression_level: Option<i32>,
+    // incoming frames larger than this are refused
+    max_frame_size: usize,
+    // request id of the last frame read, echoed on the frames written in reply
+    reply_to: u64,
+    // assigned by the server so handlers can tell which connection a request came in on
+    id: u64,
+    // what the peer announced in its handshake, 0 until then
+    protocol_version: u32,
+    client_id: u64,
+    // shared with Notifiers so that pushed frames never interleave with responses, holds
+    // the sealer once the connection is sealed so frames are sealed in the order they go out
+    write_lock: Arc<Mutex<Option<Sealer>>>,
+    // shared with clones, whichever of them reads the next frame opens it
+    opener: Arc<Mutex<Option<Opener>>>,
+}
+
+impl Connection {
+    pub fn new<T: Transport + 'static>(stream: T) -> Connection {
+        return Connection::boxed(Box::new(stream));
+    }
+
+    pub fn boxed(stream: Box<dyn Transport>) -> Connection {
+        Connection {
+            stream,
+            compression_level: None,
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+            reply_to: 0,
+            id: 0,
+            protocol_version: 0,
+            client_id: 0,
+            write_lock: Arc::new(Mutex::new(None)),
+            opener: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Another handle to the same stream with the same framing options
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        Ok(Connection {
+            stream: self.stream.try_clone_transport()?,
+            compression_level: self.compression_level,
+            max_frame_size: self.max_frame_size,
+            reply_to: self.reply_to,
+            id: self.id,
+            protocol_version: self.protocol_version,
+            client_id: self.client_id,
+            write_lock: self.write_lock.clone(),
+            opener: self.opener.clone(),
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        return self.id;
+    }
+
+    pub fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        return self.protocol_version;
+    }
+
+    pub fn set_protocol_version(&mut self, protocol_version: u32) {
+        self.protocol_version = protocol_version;
+    }
+
+    pub fn client_id(&self) -> u64 {
+        return self.client_id;
+    }
+
+    pub fn set_client_id(&mut self, client_id: u64) {
+        self.client_id = client_id;
+    }
+
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    pub fn set_compression_level(&mut self, compression_level: Option<i32>) {
+        self.compression_level = compression_level;
+    }
+
+    pub fn compression_level(&self) -> Option<i32> {
+        return self.compression_level;
+    }
+
+    // Every frame written or read from now on, on this connection and its clones, is sealed
+    pub fn seal(&mut self, sealer: Sealer, opener: Opener) {
+        *lock(&self.write_lock) = Some(sealer);
+        *lock(&self.opener) = Some(opener);
+    }
+
+    // A handle other threads can use to push frames to the peer of this connection
+    pub fn notifier(&self) -> io::Result<Notifier> {
+        Ok(Notifier {
+            stream: Mutex::new(self.stream.try_clone_transport()?),
+            compression_level: self.compression_level,
+            write_lock: self.write_lock.clone(),
+        })
+    }
+
+    // The request id the last frame read was tagged with, 0 if it wasn't
+    pub fn request_id(&self) -> u64 {
+        return self.reply_to;
+    }
+
+    // Replies to the last frame read, tagged with its request id if it had one
+    pub fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        let request_id = self.reply_to;
+        return self.write_frame_tagged(payload, request_id);
+    }
+
+    pub fn write_frame_tagged(&mut self, payload: &[u8], request_id: u64) -> io::Result<()> {
+        let _span = info_span!(target: span::TARGET, "write").entered();
+        let mut sealer = lock(&self.write_lock);
+        return frame::write_frame_sealed(
+            &mut self.stream,
+            payload,
+            self.compression_level,
+            request_id,
+            sealer.as_mut(),
+        );
+    }
+
+    pub fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        let (_, payload) = self.read_frame_tagged()?;
+        return Ok(payload);
+    }
+
+    pub fn read_frame_tagged(&mut self) -> io::Result<(u64, Vec<u8>)> {
+        let mut opener = lock(&self.opener);
+        let (request_id, payload) =
+            frame::read_frame_sealed(&mut self.stream, self.max_frame_size, opener.as_mut())?;
+        self.reply_to = request_id;
+        return Ok((request_id, payload));
+    }
+}
+
+impl Deref for Connection {
+    type Target = dyn Transport;
+
+    fn deref(&self) -> &(dyn Transport + 'static) {
+        return &*self.stream;
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut (dyn Transport + 'static) {
+        return &mut *self.stream;
+    }
+}
+
+pub struct Notifier {
+    stream: Mutex<Box<dyn Transport>>,
+    compression_level: Option<i32>,
+    write_lock: Arc<Mutex<Option<Sealer>>>,
+}
+
+impl Notifier {
+    pub fn write_frame(&self, payload: &[u8]) -> io::Result<()> {
+        let mut sealer = lock(&self.write_lock);
+        let mut stream = lock(&self.stream);
+        return frame::write_frame_sealed(
+            &mut *stream,
+            payload,
+            self.compression_level,
+            0,
+            sealer.as_mut(),
+        );
+    }
+}
+
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // a writer that panicked mid-frame has already broken the stream for everyone
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
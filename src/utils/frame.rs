@@ -0,0 +1,256 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::utils::seal::{Opener, Sealer, TAG_SIZE};
+
+// Every message on the wire is a u32 big-endian length prefix and a flags byte followed
+// by exactly that many bytes of (possibly compressed) msgpack payload. Frames flagged with
+// FLAG_REQUEST_ID have a u64 big-endian request id between the header and the payload.
+// The payload of frames flagged with FLAG_SEALED is encrypted after it was compressed, and
+// followed by a tag that also covers the header and request id, see utils::seal.
+pub const FLAG_COMPRESSED: u8 = 0x1;
+pub const FLAG_REQUEST_ID: u8 = 0x2;
+pub const FLAG_SEALED: u8 = 0x4;
+
+// Payloads smaller than this aren't worth the cost of compressing
+pub const COMPRESSION_THRESHOLD: usize = 4 * 1024;
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+// Frames claiming to be larger than this are refused before anything is allocated
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// Carried inside the io::Error read_frame_limited returns, see ClientError::FrameTooLarge
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub size: usize,
+    pub max_frame_size: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame of {} bytes exceeds the {} byte limit",
+            self.size, self.max_frame_size
+        )
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+// Carried inside the UnexpectedEof io::Error read_frame_tagged returns when the peer hung up
+// between frames. Any other UnexpectedEof means it hung up in the middle of one.
+#[derive(Debug)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "connection closed")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+// Whether `e` is the peer hanging up cleanly, before sending any of the next frame
+pub fn is_closed(e: &io::Error) -> bool {
+    return e.kind() == io::ErrorKind::UnexpectedEof
+        && e.get_ref().is_some_and(|inner| inner.is::<Closed>());
+}
+
+pub fn write_frame<W: Write>(stream: &mut W, payload: &[u8]) -> io::Result<()> {
+    return write_frame_compressed(stream, payload, None);
+}
+
+// Compresses payloads over COMPRESSION_THRESHOLD with zstd at `compression_level`, None
+// never compresses. Only use this once the peer has agreed to compression.
+pub fn write_frame_compressed<W: Write>(
+    stream: &mut W,
+    payload: &[u8],
+    compression_level: Option<i32>,
+) -> io::Result<()> {
+    return write_frame_tagged(stream, payload, compression_level, 0);
+}
+
+// Tags the frame with `request_id` so the peer can match it to its request, 0 leaves the
+// frame untagged
+pub fn write_frame_tagged<W: Write>(
+    stream: &mut W,
+    payload: &[u8],
+    compression_level: Option<i32>,
+    request_id: u64,
+) -> io::Result<()> {
+    return write_frame_sealed(stream, payload, compression_level, request_id, None);
+}
+
+// Seals the frame with `sealer` if there is one, only once the connection's keys have been
+// exchanged
+pub fn write_frame_sealed<W: Write>(
+    stream: &mut W,
+    payload: &[u8],
+    compression_level: Option<i32>,
+    request_id: u64,
+    sealer: Option<&mut Sealer>,
+) -> io::Result<()> {
+    let compressed = match compression_level {
+        Some(level) if payload.len() > COMPRESSION_THRESHOLD => {
+            Some(zstd::encode_all(payload, level)?)
+        }
+        _ => None,
+    };
+    let (mut flags, payload) = match &compressed {
+        // incompressible data is sent as is
+        Some(compressed) if compressed.len() < payload.len() => (FLAG_COMPRESSED, &compressed[..]),
+        _ => (0, payload),
+    };
+    if request_id != 0 {
+        flags |= FLAG_REQUEST_ID;
+    }
+    let mut sealed_len = payload.len();
+    if sealer.is_some() {
+        flags |= FLAG_SEALED;
+        sealed_len += TAG_SIZE;
+    }
+
+    let len = match u32::try_from(sealed_len) {
+        Ok(len) => len,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {} bytes does not fit in a u32", sealed_len),
+            ))
+        }
+    };
+
+    let mut header = [0; 13];
+    header[..4].copy_from_slice(&len.to_be_bytes());
+    header[4] = flags;
+    header[5..].copy_from_slice(&request_id.to_be_bytes());
+    let header_len = if request_id != 0 { 13 } else { 5 };
+    let header = &header[..header_len];
+
+    stream.write_all(header)?;
+    match sealer {
+        Some(sealer) => stream.write_all(&sealer.seal(header, payload)?)?,
+        None => stream.write_all(payload)?,
+    }
+    stream.flush()
+}
+
+// Returns the decompressed payload
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    return read_frame_limited(stream, DEFAULT_MAX_FRAME_SIZE);
+}
+
+// Fails with FrameTooLarge if either the frame or its decompressed payload is larger than
+// `max_frame_size`. The oversized payload is left unread so the stream can't be reused.
+pub fn read_frame_limited<R: Read>(stream: &mut R, max_frame_size: usize) -> io::Result<Vec<u8>> {
+    let (_, payload) = read_frame_tagged(stream, max_frame_size)?;
+    return Ok(payload);
+}
+
+// Returns the frame's request id, 0 if it is untagged, along with its payload
+pub fn read_frame_tagged<R: Read>(
+    stream: &mut R,
+    max_frame_size: usize,
+) -> io::Result<(u64, Vec<u8>)> {
+    return read_frame_sealed(stream, max_frame_size, None);
+}
+
+// Opens the frame with `opener` if there is one. Once a connection is sealed every frame on
+// it has to be, and the other way around.
+pub fn read_frame_sealed<R: Read>(
+    stream: &mut R,
+    max_frame_size: usize,
+    opener: Option<&mut Opener>,
+) -> io::Result<(u64, Vec<u8>)> {
+    // A frame is only decoded once all of it has arrived, however many reads that takes
+    let mut header = [0; 5];
+    match read_full(stream, &mut header)? {
+        0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, Closed)),
+        n if n < header.len() => return Err(truncated("header", n, header.len())),
+        _ => {}
+    }
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let flags = header[4];
+    if len > max_frame_size {
+        return Err(too_large(len, max_frame_size));
+    }
+
+    let mut request_id = 0;
+    let mut id = [0; 8];
+    if flags & FLAG_REQUEST_ID != 0 {
+        let n = read_full(stream, &mut id)?;
+        if n < id.len() {
+            return Err(truncated("request id", n, id.len()));
+        }
+        request_id = u64::from_be_bytes(id);
+    }
+
+    let mut buf = vec![0; len];
+    let n = read_full(stream, &mut buf)?;
+    if n < len {
+        return Err(truncated("payload", n, len));
+    }
+
+    match (opener, flags & FLAG_SEALED != 0) {
+        (Some(opener), true) => {
+            let mut sealed_header = header.to_vec();
+            if flags & FLAG_REQUEST_ID != 0 {
+                sealed_header.extend_from_slice(&id);
+            }
+            buf = opener.open(&sealed_header, &buf)?;
+        }
+        (None, false) => {}
+        (Some(_), false) => return Err(invalid("unsealed frame on a sealed connection")),
+        (None, true) => return Err(invalid("sealed frame on a connection without a key")),
+    }
+
+    if flags & FLAG_COMPRESSED != 0 {
+        // a few KB of zstd can expand to gigabytes, so the output is capped as well
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::new(&buf[..])?
+            .take(max_frame_size as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > max_frame_size {
+            return Err(too_large(decompressed.len(), max_frame_size));
+        }
+        return Ok((request_id, decompressed));
+    }
+    Ok((request_id, buf))
+}
+
+// Reads until `buf` is full or the peer hangs up, returning how much of it was filled
+fn read_full<R: Read>(stream: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match stream.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(read);
+}
+
+fn truncated(part: &str, read: usize, len: usize) -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("connection closed after {} of {} {} bytes", read, len, part),
+    );
+}
+
+fn invalid(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+fn too_large(size: usize, max_frame_size: usize) -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::InvalidData,
+        FrameTooLarge {
+            size,
+            max_frame_size,
+        },
+    );
+}
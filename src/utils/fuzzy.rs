@@ -0,0 +1,101 @@
+use std::cmp::max;
+
+// Bonuses on top of the one point every matched character is worth
+const CONSECUTIVE_BONUS: i64 = 5;
+const SEGMENT_START_BONUS: i64 = 8;
+const BASENAME_BONUS: i64 = 3;
+
+// Scores completion candidates, higher is a better match. The server ranks the indexed paths
+// with FuzzyScorer unless it is given another one.
+pub trait Scorer: Send + Sync {
+    // None if `candidate` doesn't match `pattern` at all
+    fn score(&self, pattern: &str, candidate: &str) -> Option<i64>;
+}
+
+impl<F> Scorer for F
+where
+    F: Fn(&str, &str) -> Option<i64> + Send + Sync,
+{
+    fn score(&self, pattern: &str, candidate: &str) -> Option<i64> {
+        return self(pattern, candidate);
+    }
+}
+
+// Matches if every character of the pattern appears in the candidate in order, ignoring
+// case, the way fzf does. Runs of matched characters, matches at the start of a path segment,
+// word or camelCase hump and matches in the file name score higher, and of all the ways the
+// pattern can be matched the best scoring one counts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FuzzyScorer;
+
+impl Scorer for FuzzyScorer {
+    fn score(&self, pattern: &str, candidate: &str) -> Option<i64> {
+        let pattern: Vec<char> = pattern.chars().map(lowercase).collect();
+        let chars: Vec<char> = candidate.chars().collect();
+        // most candidates don't match, they are turned away before any scoring
+        if !is_subsequence(&pattern, &chars) {
+            return None;
+        }
+        if pattern.is_empty() {
+            return Some(0);
+        }
+
+        let basename_start = chars.iter().rposition(|c| *c == '/').map_or(0, |i| i + 1);
+        let bonus: Vec<i64> = (0..chars.len())
+            .map(|i| {
+                let mut bonus = 0;
+                if i == 0 || is_word_start(chars[i - 1], chars[i]) {
+                    bonus += SEGMENT_START_BONUS;
+                }
+                if i >= basename_start {
+                    bonus += BASENAME_BONUS;
+                }
+                bonus
+            })
+            .collect();
+
+        // best[i] is the best score of the pattern so far with its last character at i
+        let mut best: Vec<Option<i64>> = vec![None; chars.len()];
+        for (j, p) in pattern.iter().enumerate() {
+            let mut next = vec![None; chars.len()];
+            // the best score of the characters before p matched anywhere before i
+            let mut earlier = if j == 0 { Some(0) } else { None };
+            for i in 0..chars.len() {
+                if lowercase(chars[i]) == *p {
+                    let consecutive = match i {
+                        0 => None,
+                        _ => best[i - 1].map(|score| score + CONSECUTIVE_BONUS),
+                    };
+                    next[i] = max(earlier, consecutive).map(|score| score + 1 + bonus[i]);
+                }
+                earlier = max(earlier, best[i]);
+            }
+            best = next;
+        }
+        return best.into_iter().max().flatten();
+    }
+}
+
+// Scores `candidate` with FuzzyScorer
+pub fn score(pattern: &str, candidate: &str) -> Option<i64> {
+    return FuzzyScorer.score(pattern, candidate);
+}
+
+fn is_subsequence(pattern: &[char], chars: &[char]) -> bool {
+    let mut pattern = pattern.iter().peekable();
+    for c in chars {
+        if pattern.peek().is_some_and(|p| lowercase(*c) == **p) {
+            pattern.next();
+        }
+    }
+    return pattern.peek().is_none();
+}
+
+fn lowercase(c: char) -> char {
+    return c.to_lowercase().next().unwrap_or(c);
+}
+
+fn is_word_start(prev: char, c: char) -> bool {
+    return matches!(prev, '/' | '_' | '-' | '.' | ' ')
+        || (prev.is_lowercase() && c.is_uppercase());
+}
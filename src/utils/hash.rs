@@ -1,8 +1,109 @@
-use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
 
-pub fn hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
+use xxhash_rust::xxh3::Xxh3Default;
+
+// Hash of file contents and of the index, wide enough that collisions between the files of
+// even a very large tree aren't a practical concern
+pub type ContentHash = u128;
+
+// A hashing algorithm. Values are fed to it through std::hash::Hasher, `digest` gives the
+// result as a ContentHash whatever the algorithm's native width.
+pub trait Algorithm: Hasher + Default {
+    fn digest(&self) -> ContentHash;
+}
+
+// 128-bit XXH3, what everything compared between the daemons is hashed with
+#[derive(Default)]
+pub struct Xxh3_128(Xxh3Default);
+
+impl Hasher for Xxh3_128 {
+    fn finish(&self) -> u64 {
+        return self.0.digest();
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+}
+
+impl Algorithm for Xxh3_128 {
+    fn digest(&self) -> ContentHash {
+        return self.0.digest128();
+    }
+}
+
+// 64-bit XXH3, for hashes that only need to tell a handful of things apart locally
+#[derive(Default)]
+pub struct Xxh3_64(Xxh3Default);
+
+impl Hasher for Xxh3_64 {
+    fn finish(&self) -> u64 {
+        return self.0.digest();
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+}
+
+impl Algorithm for Xxh3_64 {
+    fn digest(&self) -> ContentHash {
+        return self.0.digest() as ContentHash;
+    }
+}
+
+pub fn hash<T: Hash + ?Sized>(t: &T) -> ContentHash {
+    return hash_with::<Xxh3_128, T>(t);
+}
+
+pub fn hash_with<A: Algorithm, T: Hash + ?Sized>(t: &T) -> ContentHash {
+    let mut s = A::default();
+    t.hash(&mut s);
+    return s.digest();
+}
+
+// `hash` scoped to a workspace hashed with `salt`, so that the same contents in two
+// workspaces are told apart. 0 is no salt and leaves the hash as it is.
+pub fn salted(salt: u64, hash: ContentHash) -> ContentHash {
+    if salt == 0 {
+        return hash;
+    }
+    return hash_with::<Xxh3_128, _>(&(salt, hash));
+}
+
+// For names derived from a hash, e.g. of the workspace path, where 64 bits are plenty
+pub fn fast_hash<T: Hash + ?Sized>(t: &T) -> u64 {
+    let mut s = Xxh3_64::default();
     t.hash(&mut s);
     return s.finish();
 }
+
+// Same result as hash(&contents) for the `len` bytes of `reader`, without holding them in
+// memory all at once
+pub fn hash_reader<R: Read>(reader: &mut R, len: u64) -> io::Result<ContentHash> {
+    return hash_reader_with::<Xxh3_128, R>(reader, len);
+}
+
+pub fn hash_reader_with<A: Algorithm, R: Read>(
+    reader: &mut R,
+    len: u64,
+) -> io::Result<ContentHash> {
+    let mut s = A::default();
+    // mirrors the length prefix that hashing a Vec<u8> writes first
+    s.write_usize(len as usize);
+
+    let mut buf = vec![0; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = remaining.min(buf.len() as u64) as usize;
+        let n = reader.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("expected {} more bytes", remaining),
+            ));
+        }
+        s.write(&buf[..n]);
+        remaining -= n as u64;
+    }
+    return Ok(s.digest());
+}
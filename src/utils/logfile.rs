@@ -0,0 +1,116 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::utils::hash;
+
+// Where relative --log-file paths go, under emacs_remote_path
+pub fn log_dir(emacs_remote_path: &str) -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(emacs_remote_path);
+    path.push("server");
+    path.push("logs");
+    return path;
+}
+
+// Log file of the server for `workspace`, so that servers for different workspaces don't
+// rotate each other's logs
+pub fn log_file_name(workspace: &str) -> String {
+    return format!("server_{}.log", hash::fast_hash(&workspace));
+}
+
+// Appends to `path` until it would grow past max_size, then shifts it to path.1, path.1 to
+// path.2 and so on, dropping whatever is beyond path.{keep}
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = File::options().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        return PathBuf::from(name);
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = File::options().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        return Ok(());
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a single record larger than max_size still goes in a file of its own
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.file.flush();
+    }
+}
+
+// A RotatingFile that more than one logger writes to, each of them writing whole records
+#[derive(Clone)]
+pub struct SharedFile(Arc<Mutex<RotatingFile>>);
+
+impl SharedFile {
+    pub fn new(file: RotatingFile) -> SharedFile {
+        return SharedFile(Arc::new(Mutex::new(file)));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RotatingFile> {
+        return match self.0.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+    }
+}
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.lock().write(buf);
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        return self.lock().write_all(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.lock().flush();
+    }
+}
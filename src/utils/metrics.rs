@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::messages::messagetype::MessageType;
+
+// Upper bounds of the request duration histogram buckets, in seconds
+const BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0, 120.0];
+
+#[derive(Default)]
+struct RequestMetrics {
+    count: u64,
+    errors: u64,
+    // non-cumulative, summed up when rendered
+    buckets: [u64; BUCKETS.len()],
+    seconds: f64,
+}
+
+// Counters for the server daemon, rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    // keyed by message type name so the output is sorted and stable
+    requests: Mutex<BTreeMap<String, RequestMetrics>>,
+    active_connections: AtomicU64,
+    connections: AtomicU64,
+    refused_connections: AtomicU64,
+    busy_workers: AtomicU64,
+    queued_connections: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        return Metrics::default();
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Relaxed);
+        self.active_connections.fetch_add(1, Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Relaxed);
+    }
+
+    pub fn connection_refused(&self) {
+        self.refused_connections.fetch_add(1, Relaxed);
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        return self.active_connections.load(Relaxed);
+    }
+
+    // Workers serving a connection, and connections waiting for one of them
+    pub fn set_workers(&self, busy: usize, queued: usize) {
+        self.busy_workers.store(busy as u64, Relaxed);
+        self.queued_connections.store(queued as u64, Relaxed);
+    }
+
+    pub fn queued_connections(&self) -> u64 {
+        return self.queued_connections.load(Relaxed);
+    }
+
+    // Records one handled request of `message_type` that took `elapsed`
+    pub fn record(&self, message_type: MessageType, elapsed: Duration, ok: bool) {
+        let mut requests = lock(&self.requests);
+        let request = requests.entry(format!("{:?}", message_type)).or_default();
+        request.count += 1;
+        if !ok {
+            request.errors += 1;
+        }
+        let seconds = elapsed.as_secs_f64();
+        request.seconds += seconds;
+        // slower than the last bucket only shows up in +Inf
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            request.buckets[bucket] += 1;
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let requests = lock(&self.requests);
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP emacs_remote_requests_total Requests handled, by message type"
+        );
+        let _ = writeln!(out, "# TYPE emacs_remote_requests_total counter");
+        for (message_type, request) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "emacs_remote_requests_total{{message_type=\"{}\"}} {}",
+                message_type, request.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP emacs_remote_request_errors_total Requests that failed, by message type"
+        );
+        let _ = writeln!(out, "# TYPE emacs_remote_request_errors_total counter");
+        for (message_type, request) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "emacs_remote_request_errors_total{{message_type=\"{}\"}} {}",
+                message_type, request.errors
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP emacs_remote_request_duration_seconds Time spent handling requests"
+        );
+        let _ = writeln!(
+            out,
+            "# TYPE emacs_remote_request_duration_seconds histogram"
+        );
+        for (message_type, request) in requests.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(request.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "emacs_remote_request_duration_seconds_bucket{{message_type=\"{}\",le=\"{}\"}} {}",
+                    message_type, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "emacs_remote_request_duration_seconds_bucket{{message_type=\"{}\",le=\"+Inf\"}} {}",
+                message_type, request.count
+            );
+            let _ = writeln!(
+                out,
+                "emacs_remote_request_duration_seconds_sum{{message_type=\"{}\"}} {}",
+                message_type, request.seconds
+            );
+            let _ = writeln!(
+                out,
+                "emacs_remote_request_duration_seconds_count{{message_type=\"{}\"}} {}",
+                message_type, request.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP emacs_remote_active_connections Client connections currently open"
+        );
+        let _ = writeln!(out, "# TYPE emacs_remote_active_connections gauge");
+        let _ = writeln!(
+            out,
+            "emacs_remote_active_connections {}",
+            self.active_connections()
+        );
+        let _ = writeln!(
+            out,
+            "# HELP emacs_remote_connections_total Client connections accepted"
+        );
+        let _ = writeln!(out, "# TYPE emacs_remote_connections_total counter");
+        let _ = writeln!(
+            out,
+            "emacs_remote_connections_total {}",
+            self.connections.load(Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP emacs_remote_refused_connections_total Client connections refused for being over a connection limit or the queue being full"
+        );
+        let _ = writeln!(out, "# TYPE emacs_remote_refused_connections_total counter");
+        let _ = writeln!(
+            out,
+            "emacs_remote_refused_connections_total {}",
+            self.refused_connections.load(Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP emacs_remote_busy_workers Workers serving a client connection"
+        );
+        let _ = writeln!(out, "# TYPE emacs_remote_busy_workers gauge");
+        let _ = writeln!(
+            out,
+            "emacs_remote_busy_workers {}",
+            self.busy_workers.load(Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP emacs_remote_queued_connections Client connections waiting for a worker"
+        );
+        let _ = writeln!(out, "# TYPE emacs_remote_queued_connections gauge");
+        let _ = writeln!(
+            out,
+            "emacs_remote_queued_connections {}",
+            self.queued_connections()
+        );
+        return out;
+    }
+}
+
+// Answers every GET /metrics on `addr` with the rendered metrics until the process exits
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    return Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &metrics));
+            if let Err(e) = result {
+                debug!("Failed to serve metrics: {}", e);
+            }
+        }
+    }));
+}
+
+// Just enough HTTP/1.1 for a scraper, the connection is closed after every response
+fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are of no interest but have to be read before answering
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    return stream.flush();
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    return match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+}
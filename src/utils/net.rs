@@ -0,0 +1,32 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+
+// Loopback only, the daemons are reached through the ssh tunnel
+pub const DEFAULT_BIND_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+// Parses the address the daemons listen on, e.g. `127.0.0.1`, `::1` or `[::1]`
+pub fn parse_bind_addr(addr: &str) -> Result<IpAddr, String> {
+    // Checked as a full SocketAddr so that the bracketed IPv6 form ssh uses is accepted
+    let socket_addr = if addr.starts_with('[') || !addr.contains(':') {
+        format!("{}:0", addr)
+    } else {
+        format!("[{}]:0", addr)
+    };
+    return match socket_addr.parse::<SocketAddr>() {
+        Ok(socket_addr) => Ok(socket_addr.ip()),
+        Err(e) => Err(format!("invalid bind address {}: {}", addr, e)),
+    };
+}
+
+// Binds `preferred` on `addr` if it is free, otherwise lets the OS pick an ephemeral port.
+// Returns the listener along with the port that was actually bound.
+pub fn bind_free_port(addr: IpAddr, preferred: Option<u16>) -> io::Result<(TcpListener, u16)> {
+    if let Some(port) = preferred {
+        if let Ok(listener) = TcpListener::bind((addr, port)) {
+            return Ok((listener, port));
+        }
+    }
+    let listener = TcpListener::bind((addr, 0))?;
+    let port = listener.local_addr()?.port();
+    return Ok((listener, port));
+}
@@ -0,0 +1,64 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+// Where the daemons keep their state, relative to emacs_remote_path
+pub const SUBDIRS: [&str; 7] = [
+    "client",
+    "client/workspaces",
+    "server",
+    "server/workspaces",
+    "server/logs",
+    "server/history",
+    "bin",
+];
+
+// The one spelling of a path relative to the workspace that the index uses and every
+// request is looked up by: no empty or `.` components and no trailing slash, the workspace
+// root itself being "". `..` is kept, what it refers to depends on symlinks and is left to
+// resolving the path. An absolute path stays absolute so that it is still refused.
+pub fn normalize_workspace_path(rel: &str) -> String {
+    let components: Vec<&str> = rel
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    let normalized = components.join("/");
+    if rel.starts_with('/') {
+        return format!("/{}", normalized);
+    }
+    return normalized;
+}
+
+// Creates emacs_remote_path and the directories below it that the daemons expect. Safe to
+// call any number of times, from any number of processes at once.
+pub fn ensure_dirs(root: &Path) -> io::Result<()> {
+    ensure_dir(root)?;
+    for subdir in SUBDIRS.iter() {
+        ensure_dir(&root.join(subdir))?;
+    }
+    return Ok(());
+}
+
+// create_dir_all already copes with another process creating the same directory, so only
+// its errors need to be made readable
+fn ensure_dir(path: &Path) -> io::Result<()> {
+    let e = match fs::create_dir_all(path) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    // a plain "File exists" doesn't say which part of the path is in the way
+    if let Some(file) = path.ancestors().find(|p| p.exists() && !p.is_dir()) {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!(
+                "unable to create {}: {} exists but is not a directory",
+                path.display(),
+                file.display()
+            ),
+        ));
+    }
+    return Err(io::Error::new(
+        e.kind(),
+        format!("unable to create {}: {}", path.display(), e),
+    ));
+}
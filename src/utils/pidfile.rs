@@ -0,0 +1,69 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often wait_for_exit looks again
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Where a daemonized server records its pid, there is one per emacs_remote_path
+pub fn pid_file(emacs_remote_path: &str) -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(emacs_remote_path);
+    path.push("server");
+    path.push("server.pid");
+    return path;
+}
+
+// The pid recorded in `path` if that process is still running. A missing pid file or one
+// left behind by a process that died is the same as no server.
+pub fn running_pid(path: &Path) -> io::Result<Option<i32>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let pid = match contents.trim().parse::<i32>() {
+        Ok(pid) if pid > 0 => pid,
+        _ => return Ok(None),
+    };
+    if !is_alive(pid) {
+        return Ok(None);
+    }
+    return Ok(Some(pid));
+}
+
+// Sends SIGTERM to the process recorded in `path`, returning its pid or None if nothing
+// was running
+pub fn stop(path: &Path) -> io::Result<Option<i32>> {
+    let pid = match running_pid(path)? {
+        Some(pid) => pid,
+        None => return Ok(None),
+    };
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(Some(pid));
+}
+
+// Waits up to `timeout` for `pid` to exit, returning whether it did
+pub fn wait_for_exit(pid: i32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while is_alive(pid) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(EXIT_POLL_INTERVAL);
+    }
+    return true;
+}
+
+fn is_alive(pid: i32) -> bool {
+    // signal 0 only checks that the process exists and may be signalled
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // it exists but belongs to someone else
+    return io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+}
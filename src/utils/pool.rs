@@ -0,0 +1,387 @@
+extern crate rmp_serde as rmps;
+
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::ClientError;
+use crate::messages::cancel::{CancelRequest, CancelResponse};
+use crate::messages::envelope;
+use crate::messages::filechanged::{BulkChangeNotification, FileChangedNotification};
+use crate::messages::handshake::{HandshakeRequest, HandshakeResponse};
+use crate::messages::keyexchange::{KeyExchangeRequest, KeyExchangeResponse};
+use crate::messages::messagetype::{MessageType, MessageTypeError, MessageTypeTrait};
+use crate::utils::connection::Connection;
+use crate::utils::seal::{self, Nonce, PresharedKey};
+use crate::utils::stream::{decode, peek_message_type, recv, send};
+use crate::utils::transport::{Endpoint, SocketOptions};
+use crate::version::{
+    protocol_major, protocol_version_string, supports_cancellation, PROTOCOL_VERSION,
+};
+
+// Idle connections beyond this are closed instead of kept around
+pub const MAX_IDLE_CONNECTIONS: usize = 4;
+// How long a CancelRequest may take, the request it cancels has been given up on already
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Extra connections to the server for requests that shouldn't wait behind the client
+// daemon's own connection, e.g. a long grep while emacs is reading files. Safe to share
+// between threads.
+pub struct ConnectionPool {
+    endpoint: Endpoint,
+    compression_level: Option<i32>,
+    max_frame_size: usize,
+    socket_options: SocketOptions,
+    // the server workspace every connection asks for, the server's own if empty
+    workspace_name: String,
+    // every connection is sealed with it before the handshake
+    preshared_key: Option<Arc<PresharedKey>>,
+    // sent in every connection's handshake, see HandshakeRequest
+    client_id: u64,
+    idle: Mutex<Vec<Connection>>,
+    // pushed by the server on pool connections, drained by the client daemon
+    notifications: Mutex<Vec<FileChangedNotification>>,
+}
+
+impl ConnectionPool {
+    pub fn new<E: Into<Endpoint>>(
+        endpoint: E,
+        compression_level: Option<i32>,
+        max_frame_size: usize,
+    ) -> ConnectionPool {
+        ConnectionPool {
+            endpoint: endpoint.into(),
+            compression_level,
+            max_frame_size,
+            socket_options: SocketOptions::default(),
+            workspace_name: String::new(),
+            preshared_key: None,
+            client_id: client_id(),
+            idle: Mutex::new(Vec::new()),
+            notifications: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Applies to connections opened from now on
+    pub fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket_options = socket_options;
+    }
+
+    // Applies to connections opened from now on
+    pub fn set_workspace_name(&mut self, workspace_name: String) {
+        self.workspace_name = workspace_name;
+    }
+
+    // Applies to connections opened from now on
+    pub fn set_preshared_key(&mut self, preshared_key: Option<Arc<PresharedKey>>) {
+        self.preshared_key = preshared_key;
+    }
+
+    // Applies to connections opened from now on. The client daemon shares its own with its
+    // pool, so that it can cancel the requests sent on either.
+    pub fn set_client_id(&mut self, client_id: u64) {
+        self.client_id = client_id;
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        return &self.endpoint;
+    }
+
+    pub fn workspace_name(&self) -> &str {
+        return &self.workspace_name;
+    }
+
+    // Sends `request` on an idle connection, or a new one, and waits up to `timeout` for
+    // its response. The connection only goes back to the pool if the round trip succeeded.
+    // Requests must be safe to send twice: one sent on an idle connection the server has
+    // since closed is sent again on a fresh connection.
+    pub fn request<Req, Resp>(&self, request: &Req, timeout: Duration) -> Result<Resp, ClientError>
+    where
+        Req: Serialize + MessageTypeTrait,
+        Resp: DeserializeOwned + MessageTypeTrait,
+    {
+        let (mut connection, reused) = self.get()?;
+        let mut result = self.round_trip(&mut connection, request, timeout);
+        if reused && is_closed(&result) {
+            debug!(
+                "Pooled connection to {} was closed, redialing",
+                self.endpoint
+            );
+            connection = self.connect()?;
+            result = self.round_trip(&mut connection, request, timeout);
+        }
+        return self.finish(connection, result, timeout);
+    }
+
+    // Like request, for requests answered with several frames. `on_frame` is called with
+    // each frame as it arrives until it returns true, the timeout applies to each frame.
+    // A closed idle connection is only redialed if nothing was received on it yet. If
+    // `on_frame` fails, e.g. because whoever the frames were for went away, the server is
+    // told to stop with a CancelRequest.
+    pub fn request_each<Req, F>(
+        &self,
+        request: &Req,
+        timeout: Duration,
+        mut on_frame: F,
+    ) -> Result<(), ClientError>
+    where
+        Req: Serialize + MessageTypeTrait,
+        F: FnMut(&[u8]) -> Result<bool, ClientError>,
+    {
+        let (mut connection, reused) = self.get()?;
+        let mut received = false;
+        let mut result = self.stream_frames(
+            &mut connection,
+            request,
+            timeout,
+            &mut on_frame,
+            &mut received,
+        );
+        if reused && !received && is_closed(&result) {
+            debug!(
+                "Pooled connection to {} was closed, redialing",
+                self.endpoint
+            );
+            connection = self.connect()?;
+            result = self.stream_frames(
+                &mut connection,
+                request,
+                timeout,
+                &mut on_frame,
+                &mut received,
+            );
+        }
+        return self.finish(connection, result, timeout);
+    }
+
+    // Notifications that arrived on pool connections since the last call
+    pub fn take_notifications(&self) -> Vec<FileChangedNotification> {
+        return std::mem::take(&mut *lock(&self.notifications));
+    }
+
+    // The connection only goes back to the pool if everything sent on it was answered
+    fn finish<T>(
+        &self,
+        connection: Connection,
+        result: Result<T, ClientError>,
+        timeout: Duration,
+    ) -> Result<T, ClientError> {
+        match result {
+            Ok(response) => {
+                self.put(connection);
+                return Ok(response);
+            }
+            Err(ClientError::Io(e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                return Err(ClientError::Timeout(timeout));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    fn round_trip<Req, Resp>(
+        &self,
+        connection: &mut Connection,
+        request: &Req,
+        timeout: Duration,
+    ) -> Result<Resp, ClientError>
+    where
+        Req: Serialize + MessageTypeTrait,
+        Resp: DeserializeOwned + MessageTypeTrait,
+    {
+        connection.set_read_timeout(Some(timeout))?;
+        connection.set_write_timeout(Some(timeout))?;
+        return send(connection, request).and_then(|_| self.recv(connection));
+    }
+
+    fn stream_frames<Req, F>(
+        &self,
+        connection: &mut Connection,
+        request: &Req,
+        timeout: Duration,
+        on_frame: &mut F,
+        received: &mut bool,
+    ) -> Result<(), ClientError>
+    where
+        Req: Serialize + MessageTypeTrait,
+        F: FnMut(&[u8]) -> Result<bool, ClientError>,
+    {
+        connection.set_read_timeout(Some(timeout))?;
+        connection.set_write_timeout(Some(timeout))?;
+        // drawn at random so that it doesn't collide with the client daemon's own requests
+        let request_id = if supports_cancellation(connection.protocol_version()) {
+            rand::random::<u64>().max(1)
+        } else {
+            0
+        };
+        connection.write_frame_tagged(&envelope::encode(request)?, request_id)?;
+        loop {
+            let buf = self.recv_frame(connection)?;
+            *received = true;
+            match on_frame(&buf) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => {
+                    if request_id != 0 {
+                        self.cancel(request_id);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn cancel(&self, request_id: u64) {
+        let request = CancelRequest::new(request_id);
+        match self.request::<_, CancelResponse>(&request, CANCEL_TIMEOUT) {
+            Ok(response) if response.cancelled => debug!("Cancelled request {}", request_id),
+            Ok(_) => debug!("Request {} had already finished", request_id),
+            Err(e) => debug!("Unable to cancel request {}: {}", request_id, e),
+        }
+    }
+
+    // Returns whether the connection was idle in the pool rather than freshly dialed
+    fn get(&self) -> Result<(Connection, bool), ClientError> {
+        if let Some(connection) = lock(&self.idle).pop() {
+            return Ok((connection, true));
+        }
+        return Ok((self.connect()?, false));
+    }
+
+    fn connect(&self) -> Result<Connection, ClientError> {
+        // Each connection negotiates the protocol once, when it is created
+        let mut connection = Connection::boxed(self.endpoint.connect_with(&self.socket_options)?);
+        connection.set_max_frame_size(self.max_frame_size);
+        let response = handshake(
+            &mut connection,
+            &self.workspace_name,
+            self.client_id,
+            self.preshared_key.as_deref(),
+        )?;
+        connection.set_protocol_version(response.protocol_version);
+        if response.compression {
+            connection.set_compression_level(self.compression_level);
+        }
+        debug!("Opened pooled connection to {}", self.endpoint);
+        return Ok(connection);
+    }
+
+    fn put(&self, connection: Connection) {
+        let mut idle = lock(&self.idle);
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(connection);
+        }
+    }
+
+    fn recv<T>(&self, connection: &mut Connection) -> Result<T, ClientError>
+    where
+        T: DeserializeOwned + MessageTypeTrait,
+    {
+        let buf = self.recv_frame(connection)?;
+        return decode::<T>(&buf);
+    }
+
+    // The next frame that isn't a notification
+    fn recv_frame(&self, connection: &mut Connection) -> Result<Vec<u8>, ClientError> {
+        loop {
+            let buf = connection.read_frame()?;
+            match peek_message_type(&buf) {
+                Ok(MessageType::FileChangedNotification) => {
+                    let notification = decode::<FileChangedNotification>(&buf)?;
+                    lock(&self.notifications).push(notification);
+                }
+                Ok(MessageType::BulkChangeNotification) => {
+                    let notification = decode::<BulkChangeNotification>(&buf)?;
+                    lock(&self.notifications).extend(notification.expand());
+                }
+                Err(MessageTypeError::Unknown(n)) => {
+                    debug!("Skipping unknown message type {}", n);
+                }
+                _ => return Ok(buf),
+            }
+        }
+    }
+}
+
+// A fresh id for a client to announce in its handshakes, never 0
+pub fn client_id() -> u64 {
+    return rand::random::<u64>().max(1);
+}
+
+// Negotiates the protocol version on a fresh connection and selects the server workspace
+// called `workspace_name`, the caller enables compression if the server supports it. With
+// a `preshared_key` the connection is sealed first, the handshake included.
+pub fn handshake(
+    connection: &mut Connection,
+    workspace_name: &str,
+    client_id: u64,
+    preshared_key: Option<&PresharedKey>,
+) -> Result<HandshakeResponse, ClientError> {
+    if let Some(key) = preshared_key {
+        exchange_keys(connection, key)?;
+    }
+    let mut request =
+        HandshakeRequest::with_workspace(PROTOCOL_VERSION, true, workspace_name.into());
+    request.client_id = client_id;
+    send(connection, &request)?;
+    let response = recv::<HandshakeResponse>(connection)?;
+
+    if protocol_major(response.protocol_version) != protocol_major(PROTOCOL_VERSION) {
+        return Err(ClientError::VersionMismatch {
+            client: PROTOCOL_VERSION,
+            server: response.protocol_version,
+        });
+    }
+    debug!(
+        "Connected to server version {} (protocol {})",
+        response.server_version,
+        protocol_version_string(response.protocol_version)
+    );
+    return Ok(response);
+}
+
+fn exchange_keys(connection: &mut Connection, key: &PresharedKey) -> Result<(), ClientError> {
+    let client_nonce = seal::nonce();
+    send(connection, &KeyExchangeRequest::new(client_nonce.to_vec()))?;
+    let response = recv::<KeyExchangeResponse>(connection)?;
+
+    let server_nonce: Nonce = response.nonce.as_slice().try_into().map_err(|_| {
+        ClientError::Protocol(format!(
+            "key exchange nonces are {} bytes, got {}",
+            seal::NONCE_SIZE,
+            response.nonce.len()
+        ))
+    })?;
+    // Nothing is sealed for a server that doesn't hold the key, it could be anyone
+    if !key.verify(&client_nonce, &server_nonce, &response.proof)? {
+        return Err(ClientError::PresharedKeyMismatch);
+    }
+    let (sealer, opener) = key.client_keys(&client_nonce, &server_nonce)?;
+    connection.seal(sealer, opener);
+    return Ok(());
+}
+
+// The server closes connections that sat idle for too long
+fn is_closed<T>(result: &Result<T, ClientError>) -> bool {
+    return match result {
+        Err(ClientError::Io(e)) => matches!(
+            e.kind(),
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof
+        ),
+        Err(ClientError::ConnectionClosed) => true,
+        _ => false,
+    };
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    return match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+}
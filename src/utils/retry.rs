@@ -0,0 +1,73 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::error::ClientError;
+use crate::messages::messagetype::MessageType;
+
+// How the client daemon retries a request that failed for reasons that have nothing to do
+// with the request itself. Only idempotent requests are retried, a write that may already
+// have landed is never sent again: the client daemon sends those with send_request_once,
+// so that they don't depend on being left out by MessageType::is_idempotent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    // retries on top of the first attempt, 0 to never retry
+    pub max_retries: u32,
+    // wait before the first retry, doubled for each one after it
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> RetryPolicy {
+        return RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        };
+    }
+
+    // Whether a request of type `msgtype` that failed with `e` on its attempt number
+    // `attempt`, counting from 0, is sent again
+    pub fn should_retry(&self, msgtype: MessageType, attempt: u32, e: &ClientError) -> bool {
+        return attempt < self.max_retries && msgtype.is_idempotent() && is_transient(e);
+    }
+
+    // How long to wait before retry number `attempt`, counting from 0
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        return self
+            .base_backoff
+            .checked_mul(1 << attempt.min(16))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+    }
+}
+
+// Errors that mean the connection itself is gone rather than a bad message
+pub fn is_disconnect(e: &ClientError) -> bool {
+    return match e {
+        ClientError::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::BrokenPipe
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::UnexpectedEof
+        ),
+        ClientError::ConnectionClosed => true,
+        _ => false,
+    };
+}
+
+// Failures a fresh connection may well not run into
+pub fn is_transient(e: &ClientError) -> bool {
+    return is_disconnect(e) || matches!(e, ClientError::Timeout(_));
+}
@@ -0,0 +1,81 @@
+use std::fs;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::Path;
+
+use ssh2::Session;
+use ssh_config::SSHConfig;
+
+// A one-off ssh connection for copying files off the remote, used while the tunnel to
+// the server daemon isn't up yet. Authenticates through the ssh agent like SSHSession.
+pub struct ScpSession {
+    session: Session,
+}
+
+impl ScpSession {
+    pub fn new(host: &str) -> io::Result<ScpSession> {
+        let (hostname, port, user) = lookup_host(host);
+
+        let tcp = TcpStream::connect((hostname.as_str(), port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+        session.userauth_agent(&user)?;
+        if !session.authenticated() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("ssh agent authentication to {} failed", host),
+            ));
+        }
+        return Ok(ScpSession { session });
+    }
+
+    // Reads `remote_path` (relative paths are relative to the remote home directory)
+    pub fn pull(&self, remote_path: &str) -> io::Result<Vec<u8>> {
+        let (mut channel, stat) = self.session.scp_recv(Path::new(remote_path))?;
+
+        let mut buf = Vec::with_capacity(stat.size() as usize);
+        channel.read_to_end(&mut buf)?;
+        if (buf.len() as u64) < stat.size() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} was truncated", remote_path),
+            ));
+        }
+
+        channel.send_eof()?;
+        channel.wait_eof()?;
+        channel.close()?;
+        channel.wait_close()?;
+        return Ok(buf);
+    }
+}
+
+// HostName, Port and User from ~/.ssh/config, falling back to `host`, 22 and the local user
+fn lookup_host(host: &str) -> (String, u16, String) {
+    let mut hostname = host.to_string();
+    let mut port = 22;
+    let mut user = whoami::username();
+
+    let config_path = match dirs::home_dir() {
+        Some(home) => home.join(".ssh").join("config"),
+        None => return (hostname, port, user),
+    };
+    let source = match fs::read_to_string(config_path) {
+        Ok(source) => source,
+        Err(_) => return (hostname, port, user),
+    };
+    if let Ok(config) = SSHConfig::parse_str(&source) {
+        let settings = config.query(host);
+        if let Some(value) = settings.get("HostName") {
+            hostname = value.to_string();
+        }
+        if let Some(value) = settings.get("Port").and_then(|port| port.parse().ok()) {
+            port = value;
+        }
+        if let Some(value) = settings.get("User") {
+            user = value.to_string();
+        }
+    }
+    return (hostname, port, user);
+}
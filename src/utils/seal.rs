@@ -0,0 +1,202 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{self, Cipher};
+
+// Frames sealed with a key both daemons were given beforehand, for when the port the
+// server listens on is forwarded across hosts that shouldn't see the traffic. Each
+// direction of a connection has its own AES-256-GCM key, derived from the pre-shared key
+// and a nonce picked by either side, and frames are sealed under consecutive counter
+// values, so one can't be replayed, dropped or spliced in from another connection without
+// opening it failing.
+
+pub const NONCE_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 16;
+// anything shorter could be guessed
+pub const MIN_KEY_SIZE: usize = 16;
+
+const KEY_SIZE: usize = 32;
+// AES-GCM's own nonce, four zero bytes and the frame counter
+const IV_SIZE: usize = 12;
+
+pub type Nonce = [u8; NONCE_SIZE];
+
+pub fn nonce() -> Nonce {
+    return rand::random();
+}
+
+pub struct PresharedKey {
+    key: Vec<u8>,
+}
+
+impl PresharedKey {
+    pub fn new(key: Vec<u8>) -> io::Result<PresharedKey> {
+        if key.len() < MIN_KEY_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "pre-shared keys must be at least {} bytes, got {}",
+                    MIN_KEY_SIZE,
+                    key.len()
+                ),
+            ));
+        }
+        return Ok(PresharedKey { key });
+    }
+
+    // The file holds the key itself, surrounding whitespace aside. Like ssh with its
+    // private keys, a file anyone but its owner can read is refused.
+    pub fn load(path: &Path) -> io::Result<PresharedKey> {
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "{} is accessible by others (mode {:o}), restrict it to its owner",
+                    path.display(),
+                    mode & 0o777
+                ),
+            ));
+        }
+        let data = fs::read(path)?;
+        let start = data.iter().position(|b| !b.is_ascii_whitespace());
+        let end = data.iter().rposition(|b| !b.is_ascii_whitespace());
+        let key = match (start, end) {
+            (Some(start), Some(end)) => data[start..=end].to_vec(),
+            _ => Vec::new(),
+        };
+        return PresharedKey::new(key);
+    }
+
+    // What the server answers a KeyExchangeRequest with to show it holds the same key
+    pub fn proof(&self, client: &Nonce, server: &Nonce) -> io::Result<[u8; KEY_SIZE]> {
+        return hmac_sha256(&self.key, &[b"emacs-remote proof", client, server]);
+    }
+
+    pub fn verify(&self, client: &Nonce, server: &Nonce, proof: &[u8]) -> io::Result<bool> {
+        let expected = self.proof(client, server)?;
+        if proof.len() != expected.len() {
+            return Ok(false);
+        }
+        return Ok(memcmp::eq(&expected, proof));
+    }
+
+    // What the client seals its frames with and opens the server's with
+    pub fn client_keys(&self, client: &Nonce, server: &Nonce) -> io::Result<(Sealer, Opener)> {
+        let (to_server, to_client) = self.session_keys(client, server)?;
+        return Ok((Sealer::new(to_server), Opener::new(to_client)));
+    }
+
+    pub fn server_keys(&self, client: &Nonce, server: &Nonce) -> io::Result<(Sealer, Opener)> {
+        let (to_server, to_client) = self.session_keys(client, server)?;
+        return Ok((Sealer::new(to_client), Opener::new(to_server)));
+    }
+
+    fn session_keys(
+        &self,
+        client: &Nonce,
+        server: &Nonce,
+    ) -> io::Result<([u8; KEY_SIZE], [u8; KEY_SIZE])> {
+        return Ok((
+            hmac_sha256(&self.key, &[b"emacs-remote to server", client, server])?,
+            hmac_sha256(&self.key, &[b"emacs-remote to client", client, server])?,
+        ));
+    }
+}
+
+// never logs the key itself
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PresharedKey({} bytes)", self.key.len())
+    }
+}
+
+// Seals the frames going one way, in the order they are written
+pub struct Sealer {
+    key: [u8; KEY_SIZE],
+    counter: u64,
+}
+
+impl Sealer {
+    fn new(key: [u8; KEY_SIZE]) -> Sealer {
+        return Sealer { key, counter: 0 };
+    }
+
+    // Encrypts `payload` and appends its tag, which also covers `header`
+    pub fn seal(&mut self, header: &[u8], payload: &[u8]) -> io::Result<Vec<u8>> {
+        let iv = next_iv(&mut self.counter)?;
+        let mut tag = [0; TAG_SIZE];
+        let cipher = Cipher::aes_256_gcm();
+        let mut sealed =
+            symm::encrypt_aead(cipher, &self.key, Some(&iv), header, payload, &mut tag)
+                .map_err(openssl_failed)?;
+        sealed.extend_from_slice(&tag);
+        return Ok(sealed);
+    }
+}
+
+// Opens the frames coming the other way, which have to arrive in the order they were sealed
+pub struct Opener {
+    key: [u8; KEY_SIZE],
+    counter: u64,
+}
+
+impl Opener {
+    fn new(key: [u8; KEY_SIZE]) -> Opener {
+        return Opener { key, counter: 0 };
+    }
+
+    pub fn open(&mut self, header: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < TAG_SIZE {
+            return Err(failed_to_open());
+        }
+        let iv = next_iv(&mut self.counter)?;
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+        let cipher = Cipher::aes_256_gcm();
+        // openssl reports a tag that doesn't match like any other failure
+        return symm::decrypt_aead(cipher, &self.key, Some(&iv), header, ciphertext, tag)
+            .map_err(|_| failed_to_open());
+    }
+}
+
+fn next_iv(counter: &mut u64) -> io::Result<[u8; IV_SIZE]> {
+    // a counter value is never used twice with the same key
+    if *counter == u64::MAX {
+        return Err(io::Error::other("ran out of frame counters, reconnect"));
+    }
+    let mut iv = [0; IV_SIZE];
+    iv[4..].copy_from_slice(&counter.to_be_bytes());
+    *counter += 1;
+    return Ok(iv);
+}
+
+fn failed_to_open() -> io::Error {
+    return io::Error::new(
+        ErrorKind::InvalidData,
+        "a sealed frame failed to open, it was tampered with or sealed with another key",
+    );
+}
+
+// HMAC-SHA256 of the concatenation of `parts`
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> io::Result<[u8; KEY_SIZE]> {
+    let key = PKey::hmac(key).map_err(openssl_failed)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(openssl_failed)?;
+    for part in parts {
+        signer.update(part).map_err(openssl_failed)?;
+    }
+    let mut mac = [0; KEY_SIZE];
+    signer.sign(&mut mac).map_err(openssl_failed)?;
+    return Ok(mac);
+}
+
+fn openssl_failed(e: ErrorStack) -> io::Error {
+    return io::Error::other(e);
+}
@@ -1,4 +1,3 @@
-use std::ffi::OsStr;
 use std::iter::FromIterator;
 use std::process::{Command, Stdio};
 use std::result::Result;
@@ -35,13 +34,19 @@ pub fn find(item: String, path: String) -> Result<Vec<String>, i32> {
         FD_EXISTS = which("fd").is_ok();
         RG_EXISTS = which("rg").is_ok();
     });
-    if unsafe { FD_EXISTS } {
-        return match bash(format!("fd -t f {} {}", item, path)) {
-            Ok(stdout) => Ok(Vec::from_iter(
-                stdout.trim_end().split("\n").map(String::from),
-            )),
-            Err(code) => Err(code),
-        };
-    }
-    return Err(-1);
+    let cmd = if unsafe { FD_EXISTS } {
+        format!("fd -t f {} {}", item, path)
+    } else {
+        format!("find {} -type f -name '*{}*'", path, item)
+    };
+    return match bash(cmd) {
+        Ok(stdout) => Ok(Vec::from_iter(
+            stdout
+                .trim_end()
+                .split('\n')
+                .filter(|line| !line.is_empty())
+                .map(String::from),
+        )),
+        Err(code) => Err(code),
+    };
 }
@@ -0,0 +1,9 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+
+// Sets `shutdown` on SIGINT/SIGTERM so that the daemons' listen loops can exit cleanly
+// instead of being killed mid-request
+pub fn shutdown_on_signal(shutdown: Arc<AtomicBool>) -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(move || shutdown.store(true, Relaxed))
+}
@@ -0,0 +1,39 @@
+use tracing::{Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, Layer, Registry};
+
+// Request spans are tracing spans under this target, entered with e.g.
+//     let _span = info_span!(target: span::TARGET, "decode").entered();
+// The outermost span of a request carries its MessageType and id, the spans entered inside
+// it are printed with them. They cost nothing beyond a check for a subscriber unless
+// --trace installed one.
+pub const TARGET: &str = "emacs_remote::span";
+
+// Prints every span as it closes, with the fields of the spans around it and how long it
+// was entered for, e.g.
+//     request{msgtype=GrepRequest request_id=4}:decode: close time.busy=31.2µs time.idle=2.1µs
+pub fn subscriber<W>(make_writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer()
+        .with_ansi(false)
+        .with_target(false)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(make_writer)
+        .with_filter(Targets::new().with_target(TARGET, Level::INFO));
+    return Registry::default().with(layer);
+}
+
+// What --trace does, next to the log crate's logger rather than instead of it
+pub fn init<W>(make_writer: W)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber(make_writer)) {
+        log::warn!("Unable to trace requests: {}", e);
+    }
+}
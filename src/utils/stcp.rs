@@ -1,35 +1,246 @@
-use std::process::Command;
-use std::sync::atomic::AtomicBool;
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, SocketAddr};
+use std::process::{ChildStderr, Command, Stdio};
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::Arc;
-use std::thread::{self, spawn, JoinHandle};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+use rand::Rng;
+
+use crate::utils::logfile::log_file_name;
+use crate::utils::net::bind_free_port;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 10;
+
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// how long ssh has to stay up before a disconnect resets the backoff
+const HEALTHY_CONNECTION: Duration = Duration::from_secs(30);
+
+// how long ssh has to stay up before the tunnel is considered connected
+const CONNECT_GRACE: Duration = Duration::from_secs(2);
+
+// how long to wait for the rest of ssh's stderr once it has exited
+const STDERR_GRACE: Duration = Duration::from_secs(1);
+
+// how often the ssh thread checks that ssh is still running, it is woken sooner to stop
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// how long dropping a session waits for the ssh thread before killing ssh itself
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// consecutive forward failures, each on a freshly picked local port, before giving up
+const MAX_FORWARD_FAILURES: u32 = 3;
+
+// Where the server started at the other end of the tunnel reads its pre-shared key from
+pub const SERVER_PSK_FILE: &str = "~/.emacs_remote/psk";
+
+// Unknown hosts are trusted on first use, a changed key is always refused. There is no
+// TTY for ssh to ask on.
+pub const DEFAULT_STRICT_HOST_KEY_CHECKING: &str = "accept-new";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TunnelStatus {
+    Connecting,
+    Connected,
+    Retrying { attempt: u32 },
+    // gave up after max_retries consecutive failures
+    Failed,
+    // ssh refused to connect because the server's key doesn't match known_hosts, this is
+    // never retried
+    HostKeyChanged,
+    // no local port could be forwarded, even after picking new ones
+    ForwardFailed,
+}
+
+// Why ssh refused the server, going by what it printed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostKeyProblem {
+    // the key differs from the one in known_hosts
+    Changed,
+    // the key couldn't be checked, e.g. an unknown host with StrictHostKeyChecking=yes
+    Unverified,
+}
+
+pub fn host_key_problem(line: &str) -> Option<HostKeyProblem> {
+    if line.contains("REMOTE HOST IDENTIFICATION HAS CHANGED") {
+        return Some(HostKeyProblem::Changed);
+    }
+    if line.contains("Host key verification failed") {
+        return Some(HostKeyProblem::Unverified);
+    }
+    return None;
+}
+
+// ssh couldn't listen on the local end of the -L forward, usually because a stale forward
+// still holds the port
+pub fn forward_failed(line: &str) -> bool {
+    return line.contains("cannot listen to port")
+        || line.contains("Address already in use")
+        || line.contains("Could not request local forwarding");
+}
+
+// What ssh complained about on stderr before it exited
+#[derive(Clone, Copy, Debug, Default)]
+struct SshProblems {
+    host_key: Option<HostKeyProblem>,
+    forward_failed: bool,
+}
+
+pub type StatusCallback = Arc<dyn Fn(&TunnelStatus) + Send + Sync>;
+
+#[derive(Clone)]
+struct StatusReporter {
+    status: Arc<Mutex<TunnelStatus>>,
+    on_change: Option<StatusCallback>,
+}
+
+impl StatusReporter {
+    fn get(&self) -> TunnelStatus {
+        return match self.status.lock() {
+            Ok(status) => status.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+    }
+
+    fn set(&self, status: TunnelStatus) {
+        {
+            let mut current = match self.status.lock() {
+                Ok(current) => current,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if *current == status {
+                return;
+            }
+            *current = status.clone();
+        }
+        // invoked outside of the lock so the callback may call status() itself
+        if let Some(on_change) = &self.on_change {
+            on_change(&status);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SSHOptions {
+    pub binary: String,
+    // inserted before the host, e.g. ["-J", "bastion", "-o", "ServerAliveInterval=30"]
+    pub extra_args: Vec<String>,
+    // consecutive failed ssh attempts before giving up
+    pub max_retries: u32,
+    // passed as -o StrictHostKeyChecking, a value in extra_args takes precedence
+    pub strict_host_key_checking: String,
+    // passed to the server as --psk, a path on the remote host
+    pub server_psk: Option<String>,
+    // set for the server on the remote host, e.g. ("RUST_LOG", "debug"). Values are passed
+    // literally, the remote shell doesn't expand them.
+    pub env: Vec<(String, String)>,
+}
+
+impl Default for SSHOptions {
+    fn default() -> Self {
+        SSHOptions {
+            binary: "ssh".to_string(),
+            extra_args: Vec::new(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            strict_host_key_checking: DEFAULT_STRICT_HOST_KEY_CHECKING.to_string(),
+            server_psk: None,
+            env: Vec::new(),
+        }
+    }
+}
 
 // Secure TCP connection module
 pub struct STCPSession {
-    host: String,      // ssh remote host name, must be defined in ~/.ssh/config
-    server_port: u32,  // port to connect and listen to
-    client_port: u32,  // port to connect and listen to
+    host: String,     // ssh remote host name, must be defined in ~/.ssh/config
+    server_port: u32, // port to connect and listen to
+    // local end of the forward, replaced by the ssh thread if ssh can't listen on it
+    client_port: Arc<AtomicU32>,
+    bind_addr: IpAddr, // address both ends of the forward listen on
     workspace: String, // remote workspace to monitor
 
+    ssh_binary: String,          // ssh executable to run
+    ssh_extra_args: Vec<String>, // extra ssh arguments, inserted before the host
+    max_retries: u32,            // consecutive failed ssh attempts before giving up
+    strict_host_key_checking: String,
+    server_psk: Option<String>,
+    env: Vec<(String, String)>, // set for the server on the remote host
+
     ssh_thread: Option<JoinHandle<()>>,
     ssh_restart_process: Arc<AtomicBool>,
+    ssh_kill_process: Arc<AtomicBool>, // set to kill the current ssh process and reconnect
+    // wakes the ssh thread to look at the flags above straight away
+    wake: Option<Sender<()>>,
+    // disconnected once the ssh thread has returned
+    ssh_exited: Option<Receiver<()>>,
+    // the running ssh process, 0 if there is none
+    ssh_pid: Arc<AtomicU32>,
+    status: StatusReporter,
 }
 
 impl STCPSession {
-    pub fn new(host: String, server_port: u32, client_port: u32, workspace: String) -> STCPSession {
+    pub fn new(
+        host: String,
+        server_port: u32,
+        client_port: u32,
+        bind_addr: IpAddr,
+        workspace: String,
+        options: SSHOptions,
+        on_status: Option<StatusCallback>,
+    ) -> STCPSession {
         let mut session = STCPSession {
             host,
             server_port,
-            client_port,
+            client_port: Arc::new(AtomicU32::new(pick_client_port(bind_addr, client_port))),
+            bind_addr,
             workspace,
+            ssh_binary: options.binary,
+            ssh_extra_args: options.extra_args,
+            max_retries: options.max_retries,
+            strict_host_key_checking: options.strict_host_key_checking,
+            server_psk: options.server_psk,
+            env: options.env,
             ssh_thread: None,
             ssh_restart_process: Arc::new(AtomicBool::new(true)),
+            ssh_kill_process: Arc::new(AtomicBool::new(false)),
+            wake: None,
+            ssh_exited: None,
+            ssh_pid: Arc::new(AtomicU32::new(0)),
+            status: StatusReporter {
+                status: Arc::new(Mutex::new(TunnelStatus::Connecting)),
+                on_change: on_status,
+            },
         };
         session.start_ssh();
         session
     }
 
+    // local end of the `-L` forward, it may change while the tunnel is being set up
+    pub fn client_port(&self) -> u32 {
+        return self.client_port.load(Relaxed);
+    }
+
+    pub fn status(&self) -> TunnelStatus {
+        return self.status.get();
+    }
+
+    // Kills the running ssh process, the ssh thread then reconnects with the usual backoff
+    pub fn restart(&self) {
+        self.ssh_kill_process.store(true, Relaxed);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+    }
+
     pub fn start_ssh(&mut self) {
         if self.ssh_thread.is_some() {
             return; // ssh thread already started
@@ -38,67 +249,302 @@ impl STCPSession {
         let host = self.host.clone();
         let workspace = self.workspace.clone();
         let server_port = self.server_port;
-        let client_port = self.client_port;
+        let client_port = self.client_port.clone();
+        let bind_addr = self.bind_addr;
+        let ssh_binary = self.ssh_binary.clone();
+        let ssh_extra_args = self.ssh_extra_args.clone();
+        let max_retries = self.max_retries;
+        let strict_host_key_checking = self.strict_host_key_checking.clone();
+        let psk_arg = match &self.server_psk {
+            Some(path) => format!(" --psk {}", path),
+            None => String::new(),
+        };
+        let env_prefix: String = self
+            .env
+            .iter()
+            .map(|(name, value)| format!("{}={} ", name, shell_quote(value)))
+            .collect();
         let ssh_restart_process = self.ssh_restart_process.clone();
+        let ssh_kill_process = self.ssh_kill_process.clone();
+        let ssh_pid = self.ssh_pid.clone();
+        let status = self.status.clone();
+        let (wake_tx, wake) = mpsc::channel();
+        let (exited_tx, exited) = mpsc::channel::<()>();
+        self.wake = Some(wake_tx);
+        self.ssh_exited = Some(exited);
 
         self.ssh_thread = Some(spawn(move || {
-            let mut retries: i32 = 0;
+            // dropped however the thread returns
+            let _exited = exited_tx;
+            let mut attempt: u32 = 0;
+            let mut forward_failures: u32 = 0;
             while ssh_restart_process.load(Relaxed) {
-                let mut child = Command::new("ssh")
+                if attempt == 0 {
+                    status.set(TunnelStatus::Connecting);
+                }
+                let local_port = client_port.load(Relaxed);
+                // a restart asked for while no ssh was running is already done
+                ssh_kill_process.store(false, Relaxed);
+                let child = Command::new(&ssh_binary)
                     .arg("-L")
-                    .arg(format!("{}:localhost:{}", client_port, server_port))
+                    .arg(format!(
+                        "{}:{}",
+                        SocketAddr::new(bind_addr, local_port as u16),
+                        SocketAddr::new(bind_addr, server_port as u16)
+                    ))
+                    // otherwise ssh carries on without the forward and the tunnel never
+                    // comes up
+                    .arg("-o")
+                    .arg("ExitOnForwardFailure=yes")
+                    .args(&ssh_extra_args)
+                    // ssh uses the first value it is given for an option
+                    .arg("-o")
+                    .arg(format!(
+                        "StrictHostKeyChecking={}",
+                        strict_host_key_checking
+                    ))
                     .arg(host.clone())
+                    // the server's own output is lost among ssh's, it logs to a file instead
                     .arg(format!(
-                        "~/.emacs_remote/bin/emacs-remote-server -w {} -p {}",
-                        workspace, server_port,
+                        "{}~/.emacs_remote/bin/emacs-remote-server -w {} -p {} --bind-addr {} --log-file {}{}",
+                        env_prefix,
+                        workspace,
+                        server_port,
+                        bind_addr,
+                        log_file_name(&workspace),
+                        psk_arg,
                     ))
-                    .spawn()
-                    .expect("Failed to start ssh server");
-
-                thread::sleep(Duration::new(2, 0));
+                    .stderr(Stdio::piped())
+                    .spawn();
+                let mut child = match child {
+                    Ok(child) => child,
+                    Err(e) => {
+                        error!("Unable to run {}: {}", ssh_binary, e);
+                        status.set(TunnelStatus::Failed);
+                        return;
+                    }
+                };
+                ssh_pid.store(child.id(), Relaxed);
+                let started = Instant::now();
+                let stderr = child.stderr.take().map(watch_stderr);
 
                 loop {
                     match child.try_wait() {
-                        Ok(Some(status)) => {
-                            if status.success() {
-                                break;
-                            } else if retries > 5 {
-                                println!("Failed to start ssh server 5 times.");
-                                return;
-                            } else {
-                                retries += 1;
-                            }
-                        }
+                        Ok(Some(_)) => break,
                         Ok(None) => {
-                            retries = 0;
                             if !ssh_restart_process.load(Relaxed) {
-                                child.kill().expect("Failed to kill child process");
+                                if let Err(e) = child.kill() {
+                                    warn!("Unable to kill ssh: {}", e);
+                                }
+                                let _ = child.wait();
+                                ssh_pid.store(0, Relaxed);
                                 return;
                             }
-                            thread::sleep(Duration::new(1, 0));
+                            if ssh_kill_process.swap(false, Relaxed) {
+                                warn!("Killing unresponsive ssh tunnel");
+                                let _ = child.kill();
+                                let _ = child.wait();
+                                break;
+                            }
+                            if started.elapsed() >= CONNECT_GRACE {
+                                status.set(TunnelStatus::Connected);
+                            }
+                            // stale wakes only cost an extra look at the flags
+                            if let Err(RecvTimeoutError::Disconnected) =
+                                wake.recv_timeout(POLL_INTERVAL)
+                            {
+                                ssh_restart_process.store(false, Relaxed);
+                            }
                         }
                         Err(e) => {
-                            println!("error attempting to wait: {}", e);
+                            error!("error attempting to wait: {}", e);
+                            status.set(TunnelStatus::Failed);
                             return;
                         }
                     }
                 }
+                ssh_pid.store(0, Relaxed);
+
+                let problems = stderr
+                    .and_then(|rx| rx.recv_timeout(STDERR_GRACE).ok())
+                    .unwrap_or_default();
+                match problems.host_key {
+                    Some(HostKeyProblem::Changed) => {
+                        error!(
+                            "The host key of {} has changed, refusing to connect. If the change is expected, remove the old key with `ssh-keygen -R {}`",
+                            host, host
+                        );
+                        status.set(TunnelStatus::HostKeyChanged);
+                        return;
+                    }
+                    Some(HostKeyProblem::Unverified) => {
+                        error!("Unable to verify the host key of {}, not retrying", host);
+                        status.set(TunnelStatus::Failed);
+                        return;
+                    }
+                    None => {}
+                }
+
+                // Retrying on the same port would fail the same way, ssh gets a new one
+                // straight away instead
+                if problems.forward_failed {
+                    forward_failures += 1;
+                    if forward_failures >= MAX_FORWARD_FAILURES {
+                        error!(
+                            "Unable to forward a local port to {}, gave up after {} ports",
+                            host, forward_failures
+                        );
+                        status.set(TunnelStatus::ForwardFailed);
+                        return;
+                    }
+                    let new_port = pick_client_port(bind_addr, 0);
+                    warn!(
+                        "Local port {} is already in use, forwarding from {} instead",
+                        local_port, new_port
+                    );
+                    client_port.store(new_port, Relaxed);
+                    continue;
+                }
+                forward_failures = 0;
+
+                // A tunnel that stayed up for a while was a successful connection, so the
+                // next drop starts backing off from scratch
+                if started.elapsed() >= HEALTHY_CONNECTION {
+                    attempt = 0;
+                }
+                if attempt >= max_retries {
+                    error!("Failed to start ssh server {} times.", attempt);
+                    status.set(TunnelStatus::Failed);
+                    return;
+                }
+
+                let delay = backoff(attempt);
+                attempt += 1;
+                status.set(TunnelStatus::Retrying { attempt });
+                warn!(
+                    "ssh exited, retrying in {} milliseconds (attempt {}/{})",
+                    delay.as_millis(),
+                    attempt,
+                    max_retries
+                );
+                sleep_while(&ssh_restart_process, &wake, delay);
             }
         }));
     }
 }
 
+// Copies ssh's stderr through to ours. The problems it reported are sent once ssh closes
+// it.
+fn watch_stderr(stderr: ChildStderr) -> Receiver<SshProblems> {
+    let (tx, rx) = mpsc::channel();
+    spawn(move || {
+        let mut problems = SshProblems::default();
+        for line in BufReader::new(stderr).split(b'\n') {
+            let line = match line {
+                Ok(line) => String::from_utf8_lossy(&line).to_string(),
+                Err(_) => break,
+            };
+            eprintln!("{}", line);
+            // a changed key also prints "Host key verification failed", it is the worse one
+            match host_key_problem(&line) {
+                Some(HostKeyProblem::Changed) => problems.host_key = Some(HostKeyProblem::Changed),
+                Some(found) if problems.host_key.is_none() => problems.host_key = Some(found),
+                _ => {}
+            }
+            if forward_failed(&line) {
+                problems.forward_failed = true;
+            }
+        }
+        let _ = tx.send(problems);
+    });
+    return rx;
+}
+
+// `value` as a single word to the remote shell, with nothing in it expanded
+fn shell_quote(value: &str) -> String {
+    return format!("'{}'", value.replace('\'', "'\\''"));
+}
+
+// Whether `name` can be set on the remote command line, [A-Za-z_][A-Za-z0-9_]*
+pub fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    return match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    };
+}
+
+// Prefers `client_port` (0 for none) and falls back to any free local port. The probe
+// listener is closed again so that ssh can bind the port for its forward.
+fn pick_client_port(bind_addr: IpAddr, client_port: u32) -> u32 {
+    let preferred = match client_port {
+        0 => None,
+        port => u16::try_from(port).ok(),
+    };
+    return match bind_free_port(bind_addr, preferred) {
+        Ok((_, port)) => port as u32,
+        Err(e) => {
+            warn!("Unable to find a free local port: {}", e);
+            client_port
+        }
+    };
+}
+
+// 1s, 2s, 4s, ... capped at MAX_BACKOFF, plus up to 25% jitter so that many clients
+// don't reconnect in lockstep
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_BACKOFF
+        .checked_mul(1 << attempt.min(16))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF);
+    let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 4);
+    return delay + Duration::from_millis(jitter);
+}
+
+// Sleeps for up to `duration`, waking as soon as `flag` is cleared and the thread woken
+fn sleep_while(flag: &AtomicBool, wake: &Receiver<()>, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while flag.load(Relaxed) {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        if let Err(RecvTimeoutError::Disconnected) = wake.recv_timeout(deadline - now) {
+            return;
+        }
+    }
+}
+
 impl Drop for STCPSession {
     fn drop(&mut self) {
         self.ssh_restart_process.store(false, Relaxed);
+        self.wake();
 
-        if self.ssh_thread.is_none() {
+        let ssh_thread = match self.ssh_thread.take() {
+            Some(ssh_thread) => ssh_thread,
+            None => return,
+        };
+        // Quitting emacs waits on this, so a thread that doesn't stop in time is left
+        // behind once ssh is killed rather than joined
+        let exited = self
+            .ssh_exited
+            .take()
+            .map(|exited| exited.recv_timeout(SHUTDOWN_TIMEOUT));
+        if let Some(Err(RecvTimeoutError::Timeout)) = exited {
+            let pid = self.ssh_pid.load(Relaxed);
+            warn!(
+                "ssh didn't exit within {} seconds, killing it",
+                SHUTDOWN_TIMEOUT.as_secs()
+            );
+            if pid != 0 {
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+            }
             return;
         }
-        let _ = self
-            .ssh_thread
-            .take()
-            .map(JoinHandle::join)
-            .expect("Unable to join thread");
+        if ssh_thread.join().is_err() {
+            error!("The ssh thread panicked");
+        }
     }
 }
@@ -1,48 +1,96 @@
 extern crate rmp_serde as rmps;
 
 use std::convert::TryFrom;
-use std::io::Read;
-use std::io::Write;
-use std::net::TcpStream;
 
+use log::{log_enabled, trace, Level};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use crate::messages::messagetype::{MessageType, MessageTypeTrait};
+use crate::error::ClientError;
+use crate::messages::envelope;
+use crate::messages::error::ErrorResponse;
+use crate::messages::messagetype::{MessageType, MessageTypeError, MessageTypeTrait};
+use crate::utils::connection::Connection;
 
-pub fn send<T: Serialize>(stream: &mut TcpStream, message: &T) -> Result<(), ()> {
-    let buffer = rmps::encode::to_vec(&message).unwrap();
-    if stream.write(&buffer).is_err() {
-        return Err(());
-    }
-    if stream.flush().is_err() {
-        return Err(());
-    }
+pub fn send<T>(stream: &mut Connection, message: &T) -> Result<(), ClientError>
+where
+    T: Serialize + MessageTypeTrait,
+{
+    let buffer = envelope::encode(message)?;
+    stream.write_frame(&buffer)?;
     Ok(())
 }
 
-pub fn recv<T>(stream: &mut TcpStream) -> Result<T, ()>
+pub fn recv<T>(stream: &mut Connection) -> Result<T, ClientError>
 where
     T: DeserializeOwned + MessageTypeTrait,
 {
-    let mut buf = [0; 1024];
-    stream.read(&mut buf).unwrap();
+    let buf = stream.read_frame()?;
+    return decode::<T>(&buf);
+}
 
-    let value: rmpv::Value = rmps::decode::from_read_ref(&buf).unwrap();
-    println!("Request: {}", serde_json::to_string(&value).unwrap());
+pub fn decode<T>(buf: &[u8]) -> Result<T, ClientError>
+where
+    T: DeserializeOwned + MessageTypeTrait,
+{
+    trace_message("Response", buf);
 
-    if !value.is_array() || !value[0].is_u64() {
-        return Err(());
+    let msgtype = peek_message_type(buf)?;
+    if msgtype == MessageType::ErrorResponse && T::messagetype() != MessageType::ErrorResponse {
+        let error: ErrorResponse = rmps::from_slice(buf)?;
+        return Err(ClientError::ServerReported {
+            code: error.code,
+            message: error.message,
+        });
     }
-    let msgtype = MessageType::try_from(value[0].as_u64().unwrap()).unwrap();
     if msgtype != T::messagetype() {
-        return Err(());
+        return Err(ClientError::UnexpectedMessageType {
+            expected: T::messagetype(),
+            got: msgtype,
+        });
     }
 
-    let result: T = rmp_serde::from_read_ref(&buf).unwrap();
+    let result: T = envelope::decode(buf)?;
     if !result.is_valid() {
-        return Err(());
+        return Err(ClientError::Protocol(format!(
+            "invalid {:?} message",
+            msgtype
+        )));
     }
 
     return Ok(result);
 }
+
+// The MessageType discriminant at the start of an encoded message, without decoding the
+// rest of it, however large
+pub fn peek_message_type(buf: &[u8]) -> Result<MessageType, MessageTypeError> {
+    let mut rest = buf;
+    match rmp::decode::read_array_len(&mut rest) {
+        Ok(len) if len > 0 => {}
+        _ => return Err(MessageTypeError::Untagged),
+    }
+    let discriminant: u64 =
+        rmp::decode::read_int(&mut rest).map_err(|_| MessageTypeError::Untagged)?;
+    return MessageType::try_from(discriminant)
+        .map_err(|_| MessageTypeError::Unknown(discriminant));
+}
+
+// Logs the whole of a message at trace level, only decoding it if that level is enabled
+pub fn trace_message(label: &str, buf: &[u8]) {
+    if log_enabled!(Level::Trace) {
+        match rmps::from_slice::<rmpv::Value>(buf) {
+            Ok(value) => trace!("{}: {}", label, value),
+            Err(e) => trace!("{}: undecodable, {}", label, e),
+        }
+    }
+}
+
+// Messages are encoded as arrays whose first element is the MessageType discriminant
+pub fn message_type(value: &rmpv::Value) -> Result<MessageType, MessageTypeError> {
+    if !value.is_array() || !value[0].is_u64() {
+        return Err(MessageTypeError::Untagged);
+    }
+    let discriminant = value[0].as_u64().unwrap();
+    return MessageType::try_from(discriminant)
+        .map_err(|_| MessageTypeError::Unknown(discriminant));
+}
@@ -0,0 +1,335 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// What the framing layer needs from a stream, so that connections work the same over TCP
+// and over Unix domain sockets
+pub trait Transport: Read + Write + Send {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn Transport>>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn read_timeout(&self) -> io::Result<Option<Duration>>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    // who is on the other end, for logging
+    fn peer(&self) -> String;
+    // the address the other end connects from, None for streams that don't have one
+    fn peer_ip(&self) -> Option<IpAddr> {
+        return None;
+    }
+    // Applies whichever of `options` make sense for this kind of stream
+    fn apply(&self, options: &SocketOptions) -> io::Result<()>;
+}
+
+// Tuning applied to every stream the daemons open or accept
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SocketOptions {
+    // sets TCP_NODELAY, small requests and responses go out at once instead of waiting on
+    // Nagle's algorithm
+    pub nodelay: bool,
+    // SO_SNDBUF and SO_RCVBUF in bytes, None leaves them to the kernel which tunes them
+    // itself as long as they aren't set
+    pub buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            buffer_size: None,
+        }
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn Transport>> {
+        return Ok(Box::new(self.try_clone()?));
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        return TcpStream::set_read_timeout(self, timeout);
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        return TcpStream::set_write_timeout(self, timeout);
+    }
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        return TcpStream::read_timeout(self);
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        return TcpStream::set_nonblocking(self, nonblocking);
+    }
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        return TcpStream::peek(self, buf);
+    }
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        return TcpStream::shutdown(self, how);
+    }
+    fn peer(&self) -> String {
+        return match self.peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "unknown peer".to_string(),
+        };
+    }
+    fn peer_ip(&self) -> Option<IpAddr> {
+        return self.peer_addr().ok().map(|addr| addr.ip());
+    }
+    fn apply(&self, options: &SocketOptions) -> io::Result<()> {
+        self.set_nodelay(options.nodelay)?;
+        if let Some(size) = options.buffer_size {
+            set_buffer_size(self, size)?;
+        }
+        return Ok(());
+    }
+}
+
+impl Transport for UnixStream {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn Transport>> {
+        return Ok(Box::new(self.try_clone()?));
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        return UnixStream::set_read_timeout(self, timeout);
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        return UnixStream::set_write_timeout(self, timeout);
+    }
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        return UnixStream::read_timeout(self);
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        return UnixStream::set_nonblocking(self, nonblocking);
+    }
+    // UnixStream::peek isn't stable yet
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe {
+            libc::recv(
+                self.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_PEEK,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(n as usize);
+    }
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        return UnixStream::shutdown(self, how);
+    }
+    fn peer(&self) -> String {
+        // clients of a listening socket are unnamed, the socket itself is the interesting part
+        return match self
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(PathBuf::from))
+        {
+            Some(path) => format!("unix:{}", path.display()),
+            None => "unix socket".to_string(),
+        };
+    }
+    // there is no Nagle to turn off on a Unix socket
+    fn apply(&self, options: &SocketOptions) -> io::Result<()> {
+        if let Some(size) = options.buffer_size {
+            set_buffer_size(self, size)?;
+        }
+        return Ok(());
+    }
+}
+
+fn set_buffer_size<S: AsRawFd>(socket: &S, size: usize) -> io::Result<()> {
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    for option in [libc::SO_SNDBUF, libc::SO_RCVBUF] {
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                option,
+                &size as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    return Ok(());
+}
+
+// Collects everything written to it and reads as EOF, so that a handler's response can be
+// captured instead of sent. Clones share the same buffer.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        return MemoryTransport::default();
+    }
+
+    // Everything written so far, leaving the buffer empty
+    pub fn take(&self) -> Vec<u8> {
+        return match self.written.lock() {
+            Ok(mut written) => std::mem::take(&mut *written),
+            Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+        };
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        return Ok(0);
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.written.lock() {
+            Ok(mut written) => written.extend_from_slice(buf),
+            Err(poisoned) => poisoned.into_inner().extend_from_slice(buf),
+        }
+        return Ok(buf.len());
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn Transport>> {
+        return Ok(Box::new(self.clone()));
+    }
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        return Ok(());
+    }
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        return Ok(());
+    }
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        return Ok(None);
+    }
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        return Ok(());
+    }
+    fn peek(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        return Ok(0);
+    }
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        return Ok(());
+    }
+    fn peer(&self) -> String {
+        return "memory".to_string();
+    }
+    fn apply(&self, _options: &SocketOptions) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+// Where a daemon listens or connects
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        return self.connect_with(&SocketOptions::default());
+    }
+
+    pub fn connect_with(&self, options: &SocketOptions) -> io::Result<Box<dyn Transport>> {
+        let stream: Box<dyn Transport> = match self {
+            Endpoint::Tcp(addr) => Box::new(TcpStream::connect(addr)?),
+            Endpoint::Unix(path) => Box::new(UnixStream::connect(path)?),
+        };
+        stream.apply(options)?;
+        return Ok(stream);
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Tcp(addr)
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    // the socket file is removed again when the listener is dropped
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    // Unix sockets are only accessible to the user running the daemon
+    pub fn bind(endpoint: &Endpoint) -> io::Result<Listener> {
+        return match endpoint {
+            Endpoint::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            Endpoint::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        };
+    }
+
+    pub fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        return match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept()?.0)),
+            Listener::Unix(listener, _) => Ok(Box::new(listener.accept()?.0)),
+        };
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        return match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        };
+    }
+
+    pub fn local_endpoint(&self) -> io::Result<Endpoint> {
+        return match self {
+            Listener::Tcp(listener) => Ok(Endpoint::Tcp(listener.local_addr()?)),
+            Listener::Unix(_, path) => Ok(Endpoint::Unix(path.clone())),
+        };
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// A socket file left behind by a daemon that died is removed, one that is still being
+// listened on is not taken over
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("{} is in use by another daemon", path.display()),
+        ));
+    }
+    return fs::remove_file(path);
+}
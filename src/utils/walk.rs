@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, WalkBuilder};
+use log::{debug, warn};
+
+// Workspace level ignore file, same syntax as .gitignore
+pub const IGNORE_FILE: &str = ".emacs_remote_ignore";
+
+// Lists every file under `root` that isn't excluded by .gitignore, .ignore or
+// .emacs_remote_ignore files. The .git directory itself is always skipped.
+pub fn walk_files(root: &Path) -> Vec<PathBuf> {
+    return walk_files_with(root, &[]);
+}
+
+// Like walk_files but also skips anything matching one of `patterns`, which use .gitignore
+// syntax relative to `root`
+pub fn walk_files_with(root: &Path, patterns: &[String]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in walker_with(root, patterns).build() {
+        match entry {
+            Ok(entry) => {
+                if entry.file_type().is_some_and(|t| t.is_file()) {
+                    files.push(entry.into_path());
+                }
+            }
+            Err(e) => debug!("Skipping unreadable entry: {}", e),
+        }
+    }
+    return files;
+}
+
+// Whether walk_files would skip `path`, checking the ignore files of every directory between
+// `root` and `path` with the deepest one taking precedence
+pub fn is_ignored(root: &Path, path: &Path) -> bool {
+    return is_ignored_with(root, path, &[]);
+}
+
+pub fn is_ignored_with(root: &Path, path: &Path, patterns: &[String]) -> bool {
+    let rel = match path.strip_prefix(root) {
+        Ok(rel) => rel,
+        Err(_) => return false,
+    };
+    if rel
+        .components()
+        .any(|component| component.as_os_str() == ".git")
+    {
+        return true;
+    }
+    if pattern_matcher(root, patterns)
+        .matched_path_or_any_parents(path, path.is_dir())
+        .is_ignore()
+    {
+        return true;
+    }
+
+    let mut dirs: Vec<PathBuf> = rel
+        .ancestors()
+        .skip(1)
+        .map(|ancestor| root.join(ancestor))
+        .collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+
+    for dir in dirs {
+        let mut builder = GitignoreBuilder::new(&dir);
+        for name in &[".gitignore", ".ignore", IGNORE_FILE] {
+            let file = dir.join(name);
+            if file.is_file() {
+                builder.add(file);
+            }
+        }
+        let matcher = match builder.build() {
+            Ok(matcher) => matcher,
+            Err(_) => continue,
+        };
+        match matcher.matched_path_or_any_parents(path, path.is_dir()) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+    }
+    return false;
+}
+
+pub fn walker(root: &Path) -> WalkBuilder {
+    return walker_with(root, &[]);
+}
+
+pub fn walker_with(root: &Path, patterns: &[String]) -> WalkBuilder {
+    let matcher = pattern_matcher(root, patterns);
+
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .hidden(false)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            entry.file_name() != ".git" && !matcher.matched(entry.path(), is_dir).is_ignore()
+        });
+    return builder;
+}
+
+// Invalid patterns are logged and skipped rather than failing the whole walk
+pub fn pattern_matcher(root: &Path, patterns: &[String]) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        if let Err(e) = builder.add_line(None, pattern) {
+            warn!("Ignoring invalid ignore pattern {:?}: {}", pattern, e);
+        }
+    }
+    return builder.build().unwrap_or_else(|_| Gitignore::empty());
+}
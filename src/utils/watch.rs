@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use notify::event::EventKind;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+// Changes are held back until there has been none for this long, so that a file written in
+// several steps is only reported once
+pub const DEBOUNCE: Duration = Duration::from_millis(50);
+// ...but never for longer than this, a build touching files nonstop still gets reported
+pub const MAX_DELAY: Duration = Duration::from_millis(500);
+
+// Watches `root` recursively and calls `on_change` from a background thread with the
+// paths touched since the last call, each of them once, see DEBOUNCE. Watching stops when
+// the Watch is dropped.
+pub struct Watch {
+    watcher: Option<RecommendedWatcher>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub fn watch<F>(root: &Path, on_change: F) -> notify::Result<Watch>
+where
+    F: Fn(Vec<PathBuf>) + Send + 'static,
+{
+    let (sender, receiver) = channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+
+    // Ends once the watcher, and with it the sending half of the channel, is dropped
+    let thread = spawn(move || {
+        while let Some(paths) = next_batch(&receiver) {
+            if !paths.is_empty() {
+                on_change(paths);
+            }
+        }
+    });
+
+    Ok(Watch {
+        watcher: Some(watcher),
+        thread: Some(thread),
+    })
+}
+
+// Waits for a change, then gathers every change that follows it within DEBOUNCE. None once
+// the watcher is gone.
+fn next_batch(receiver: &Receiver<notify::Result<Event>>) -> Option<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    add_paths(&mut paths, receiver.recv().ok()?);
+
+    let deadline = Instant::now() + MAX_DELAY;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match receiver.recv_timeout(DEBOUNCE.min(deadline - now)) {
+            Ok(event) => add_paths(&mut paths, event),
+            Err(RecvTimeoutError::Timeout) => break,
+            // report what was gathered, the next call ends the thread
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    return Some(paths);
+}
+
+fn add_paths(paths: &mut Vec<PathBuf>, event: notify::Result<Event>) {
+    match event {
+        Ok(event) => match event.kind {
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
+                for path in event.paths {
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
+            }
+            _ => {}
+        },
+        Err(e) => debug!("File watcher error: {}", e),
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        drop(self.watcher.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
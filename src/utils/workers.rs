@@ -0,0 +1,121 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::error;
+
+use crate::utils::metrics::Metrics;
+
+// A fixed number of threads that handle the jobs submitted to them, each on whichever is
+// free first. Up to queue_depth jobs wait for a thread, submitting one past that fails
+// straight away so that the caller can turn it away.
+pub struct Workers<T: Send + 'static> {
+    sender: Option<SyncSender<T>>,
+    threads: Vec<JoinHandle<()>>,
+    counts: Arc<Counts>,
+}
+
+// Jobs being handled and waiting, mirrored into the metrics as they change
+struct Counts {
+    busy: AtomicUsize,
+    queued: AtomicUsize,
+    metrics: Arc<Metrics>,
+}
+
+impl<T: Send + 'static> Workers<T> {
+    // Starts `workers` threads, at least one, that call `handle` on every job
+    pub fn new<F>(workers: usize, queue_depth: usize, metrics: Arc<Metrics>, handle: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handle = Arc::new(handle);
+        let counts = Arc::new(Counts {
+            busy: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            metrics,
+        });
+        let threads = (0..workers.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                let handle = handle.clone();
+                let counts = counts.clone();
+                thread::spawn(move || {
+                    while let Some(job) = next_job(&receiver) {
+                        counts.queued.fetch_sub(1, Relaxed);
+                        counts.busy.fetch_add(1, Relaxed);
+                        counts.update();
+                        // a job that panics doesn't take its thread down with it
+                        if panic::catch_unwind(AssertUnwindSafe(|| handle(job))).is_err() {
+                            error!("Worker panicked, carrying on with the next job");
+                        }
+                        counts.busy.fetch_sub(1, Relaxed);
+                        counts.update();
+                    }
+                })
+            })
+            .collect();
+        return Workers {
+            sender: Some(sender),
+            threads,
+            counts,
+        };
+    }
+
+    // Queues `job` for the next free thread, or hands it back if the queue is full
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        let sender = match self.sender.as_ref() {
+            Some(sender) => sender,
+            None => return Err(job),
+        };
+        // counted first, a free thread may take the job before try_send returns
+        self.counts.queued.fetch_add(1, Relaxed);
+        return match sender.try_send(job) {
+            Ok(()) => {
+                self.counts.update();
+                Ok(())
+            }
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => {
+                self.counts.queued.fetch_sub(1, Relaxed);
+                Err(job)
+            }
+        };
+    }
+
+    // Threads busy with a job
+    pub fn busy(&self) -> usize {
+        return self.counts.busy.load(Relaxed);
+    }
+
+    // Jobs waiting for a thread
+    pub fn queued(&self) -> usize {
+        return self.counts.queued.load(Relaxed);
+    }
+
+    // Waits for every queued job to be handled and the threads to exit
+    pub fn join(mut self) {
+        self.sender = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Counts {
+    fn update(&self) {
+        self.metrics
+            .set_workers(self.busy.load(Relaxed), self.queued.load(Relaxed));
+    }
+}
+
+// None once the pool is being joined and the queue has drained
+fn next_job<T>(receiver: &Mutex<Receiver<T>>) -> Option<T> {
+    let receiver = match receiver.lock() {
+        Ok(receiver) => receiver,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    return receiver.recv().ok();
+}
@@ -1 +1,93 @@
-pub const VERSION: &str = "0.0.1";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Commit the binary was built from, see build.rs
+pub const BUILD_HASH: &str = env!("EMACS_REMOTE_BUILD_HASH");
+// What --version prints
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("EMACS_REMOTE_BUILD_HASH"),
+    ")"
+);
+
+// Wire protocol version, exchanged in the handshake. Peers with different major versions
+// refuse to talk to each other, minor versions are backwards compatible.
+//   2.0: frame headers carry a flags byte, compression is negotiated in the handshake
+//   2.1: frames may be tagged with a request id, responses echo the id of their request
+//   3.0: content and index hashes are 128-bit
+//   3.1: grep results are streamed as GrepMatchFrames followed by a GrepDone
+//   3.2: binary and oversized files are only read if the FileReadRequest is forced
+//   3.3: bursts of file changes may be pushed as a single BulkChangeNotification
+//   3.4: the HandshakeRequest may name which of the server's workspaces to serve
+//   3.5: a CancelRequest stops the grep or index request with its request id
+//   3.6: a KeyExchangeRequest ahead of the handshake seals the connection with a pre-shared key
+//   3.7: the HandshakeRequest carries a client id, a CancelRequest only reaches its own client's
+//        requests
+//   3.8: the HandshakeResponse carries the workspace's hash salt
+//   3.9: the HandshakeResponse carries the index hash, a ChangesSinceRequest lists the files
+//        changed since an earlier one
+pub const PROTOCOL_MAJOR: u32 = 3;
+pub const PROTOCOL_MINOR: u32 = 9;
+pub const PROTOCOL_VERSION: u32 = (PROTOCOL_MAJOR << 16) | PROTOCOL_MINOR;
+
+pub fn protocol_major(protocol_version: u32) -> u32 {
+    return protocol_version >> 16;
+}
+
+pub fn protocol_minor(protocol_version: u32) -> u32 {
+    return protocol_version & 0xffff;
+}
+
+// Request ids came in with 2.1 and every later version has them
+pub fn supports_request_ids(protocol_version: u32) -> bool {
+    return protocol_version >= (2 << 16) | 1;
+}
+
+pub fn supports_streamed_grep(protocol_version: u32) -> bool {
+    return protocol_version >= (3 << 16) | 1;
+}
+
+pub fn supports_bulk_notifications(protocol_version: u32) -> bool {
+    return protocol_version >= (3 << 16) | 3;
+}
+
+pub fn supports_cancellation(protocol_version: u32) -> bool {
+    return protocol_version >= (3 << 16) | 5;
+}
+
+pub fn supports_changes_since(protocol_version: u32) -> bool {
+    return protocol_version >= (3 << 16) | 9;
+}
+
+pub fn protocol_version_string(protocol_version: u32) -> String {
+    return format!(
+        "{}.{}",
+        protocol_major(protocol_version),
+        protocol_minor(protocol_version)
+    );
+}
+
+// How far apart two release versions are. Builds with the same major version are meant to
+// work together, a different minor version is worth knowing about.
+#[derive(Debug, PartialEq)]
+pub enum VersionSkew {
+    None,
+    Minor,
+    Major,
+}
+
+// Versions that aren't major.minor[.patch] can't be compared and count as a major skew
+pub fn version_skew(ours: &str, theirs: &str) -> VersionSkew {
+    return match (major_minor(ours), major_minor(theirs)) {
+        (Some((a_major, _)), Some((b_major, _))) if a_major != b_major => VersionSkew::Major,
+        (Some((_, a_minor)), Some((_, b_minor))) if a_minor != b_minor => VersionSkew::Minor,
+        (Some(_), Some(_)) => VersionSkew::None,
+        _ => VersionSkew::Major,
+    };
+}
+
+fn major_minor(version: &str) -> Option<(u64, u64)> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    return Some((major, minor));
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::io::ErrorKind;
+
+use emacs_remote::utils::archive::{unpack, ArchiveWriter, MAX_ENTRY_SIZE};
+
+#[test]
+fn archives_unpack_to_what_was_written() {
+    let long_name = format!("{}/file.txt", "a".repeat(120));
+    let mut archive = ArchiveWriter::new(Vec::new());
+    archive
+        .append_file("src/main.rs", 0o644, 0, b"fn main() {}")
+        .unwrap();
+    archive.append_file(&long_name, 0o755, 0, b"long").unwrap();
+    archive.append_file("empty", 0o600, 0, b"").unwrap();
+    let written = archive.written();
+    let tar = archive.finish().unwrap();
+    // every entry takes whole blocks, the end is marked by two empty ones
+    assert_eq!(tar.len() % 512, 0);
+    assert_eq!(written + 1024, tar.len() as u64);
+
+    let dest = tempfile::tempdir().unwrap();
+    let files = unpack(&tar[..], dest.path()).unwrap();
+    assert_eq!(files, vec!["src/main.rs", long_name.as_str(), "empty"]);
+    assert_eq!(
+        fs::read(dest.path().join("src/main.rs")).unwrap(),
+        b"fn main() {}"
+    );
+    assert_eq!(fs::read(dest.path().join(&long_name)).unwrap(), b"long");
+    assert!(fs::read(dest.path().join("empty")).unwrap().is_empty());
+
+    // cut short
+    let dest = tempfile::tempdir().unwrap();
+    let truncated = unpack(&tar[..700], dest.path()).unwrap_err();
+    assert_eq!(truncated.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn entries_outside_of_the_destination_are_refused() {
+    for name in ["../escape", "/etc/escape", "src/../../escape"] {
+        let mut archive = ArchiveWriter::new(Vec::new());
+        archive.append_file(name, 0o644, 0, b"nope").unwrap();
+        let tar = archive.finish().unwrap();
+
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("dest");
+        let refused = unpack(&tar[..], &dest).unwrap_err();
+        assert_eq!(refused.kind(), ErrorKind::InvalidData, "{}", name);
+        assert!(!root.path().join("escape").exists());
+    }
+}
+
+#[test]
+fn entry_sizes_are_not_trusted() {
+    let mut archive = ArchiveWriter::new(Vec::new());
+    archive.append_file("big", 0o644, 0, b"tiny").unwrap();
+    let mut tar = archive.finish().unwrap();
+    // a header claiming the largest entry there can be, with a checksum to match
+    tar[124..136].copy_from_slice(format!("{:011o}\0", MAX_ENTRY_SIZE).as_bytes());
+    tar[148..156].copy_from_slice(&[b' '; 8]);
+    let checksum: u32 = tar[..512].iter().map(|b| *b as u32).sum();
+    tar[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+
+    // only reads what is there rather than setting aside room for all of it
+    let dest = tempfile::tempdir().unwrap();
+    let truncated = unpack(&tar[..], dest.path()).unwrap_err();
+    assert_eq!(truncated.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn files_that_shrink_while_archived_fail_the_archive() {
+    let mut archive = ArchiveWriter::new(Vec::new());
+    let failed = archive
+        .append_reader("shrunk", 0o644, 0, 10, &mut &b"short"[..])
+        .unwrap_err();
+    assert_eq!(failed.kind(), ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,39 @@
+use emacs_remote::utils::cache::BlobCache;
+
+#[test]
+fn least_recently_used_blobs_are_evicted() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut cache = BlobCache::new(dir.path(), 10).unwrap();
+
+    cache.put(1, b"aaaa");
+    cache.put(2, b"bbbb");
+    // 1 is now more recently used than 2
+    assert_eq!(cache.get(1).unwrap(), b"aaaa");
+
+    cache.put(3, b"cccc");
+    assert!(cache.contains(1));
+    assert!(!cache.contains(2));
+    assert!(cache.contains(3));
+    assert_eq!(cache.size(), 8);
+
+    // larger than the whole budget
+    cache.put(4, &[0; 11]);
+    assert!(!cache.contains(4));
+}
+
+#[test]
+fn blobs_survive_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let mut cache = BlobCache::new(dir.path(), 1024).unwrap();
+        cache.put(0xabc, b"contents");
+    }
+
+    let mut cache = BlobCache::new(dir.path(), 1024).unwrap();
+    assert_eq!(cache.get(0xabc).unwrap(), b"contents");
+    assert_eq!(cache.get(0xdef), None);
+
+    // a smaller budget evicts down to size on load
+    let cache = BlobCache::new(dir.path(), 4).unwrap();
+    assert!(!cache.contains(0xabc));
+}
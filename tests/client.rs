@@ -0,0 +1,825 @@
+extern crate rmp_serde as rmps;
+
+use std::io::{ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use emacs_remote::error::ClientError;
+use emacs_remote::messages::changes::{ChangesSinceRequest, ChangesSinceResponse};
+use emacs_remote::messages::envelope;
+use emacs_remote::messages::error::{ErrorResponse, NOT_FOUND};
+use emacs_remote::messages::filechanged::FileChangedNotification;
+use emacs_remote::messages::fileread::{FileChunk, FileReadRequest, FileReadResponse, CHUNK_SIZE};
+use emacs_remote::messages::grep::{GrepDone, GrepMatch, GrepMatchFrame, GrepRequest};
+use emacs_remote::messages::handshake::{HandshakeRequest, HandshakeResponse};
+use emacs_remote::messages::index::{IndexRequest, IndexResponse};
+use emacs_remote::messages::messagetype::MessageType;
+use emacs_remote::messages::ping::{PingRequest, PingResponse};
+use emacs_remote::messages::workspaceinfo::{WorkspaceInfoRequest, WorkspaceInfoResponse};
+use emacs_remote::structs::client::ClientDaemon;
+use emacs_remote::utils::connection::Connection;
+use emacs_remote::utils::frame::{
+    read_frame, read_frame_tagged, write_frame, write_frame_tagged, DEFAULT_MAX_FRAME_SIZE,
+};
+use emacs_remote::utils::hash::{hash, salted};
+use emacs_remote::utils::retry::RetryPolicy;
+use emacs_remote::utils::stream::recv;
+use emacs_remote::version::{PROTOCOL_MAJOR, PROTOCOL_VERSION};
+
+// Answers the client's handshake as a server speaking `protocol_version`
+fn handshake(stream: &mut TcpStream, protocol_version: u32) {
+    let buf = read_frame(stream).unwrap();
+    let request: HandshakeRequest = rmps::from_slice(&buf).unwrap();
+    assert_eq!(request.protocol_version, PROTOCOL_VERSION);
+
+    let response = HandshakeResponse::new(protocol_version, "test".to_string(), false);
+    write_frame(stream, &envelope::encode(&response).unwrap()).unwrap();
+}
+
+// Starts a fake server that answers a single IndexRequest with `reply`
+fn serve_once<F>(reply: F) -> u32
+where
+    F: FnOnce(IndexRequest) -> Vec<u8> + Send + 'static,
+{
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let buf = read_frame(&mut stream).unwrap();
+        let request: IndexRequest = envelope::decode(&buf).unwrap();
+        write_frame(&mut stream, &reply(request)).unwrap();
+    });
+
+    port
+}
+
+fn client(emacs_remote_path: &tempfile::TempDir) -> ClientDaemon {
+    ClientDaemon::new(
+        "localhost".to_string(),
+        "/workspace".to_string(),
+        emacs_remote_path.path().to_str().unwrap().to_string(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn index_request_round_trip() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let port = serve_once(|request| {
+        let response = IndexResponse::new(request.prev_hash + 1, request.index_path);
+        envelope::encode(&response).unwrap()
+    });
+
+    // large enough that it would not have fit in the old 1024 byte buffer
+    let index_path = "a/".repeat(4096);
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    client
+        .server_send(&IndexRequest::new(41, index_path.clone()))
+        .unwrap();
+
+    let response = client.server_recv::<IndexResponse>().unwrap();
+    assert_eq!(response.hash, 42);
+    assert_eq!(response.path_to_index_file, index_path);
+}
+
+#[test]
+fn index_asks_for_a_fresh_index() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let port = serve_once(|request| {
+        assert_eq!(request.prev_hash, 0);
+        assert_eq!(request.index_path, "src");
+        envelope::encode(&IndexResponse::new(7, "/index".to_string())).unwrap()
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    let response = client.index("src").unwrap();
+    assert_eq!(response.hash, 7);
+    assert_eq!(response.path_to_index_file, "/index");
+}
+
+#[test]
+fn unexpected_response_type_is_reported() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let port = serve_once(|request| envelope::encode(&request).unwrap());
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    client
+        .server_send(&IndexRequest::new(0, "src".to_string()))
+        .unwrap();
+
+    match client.server_recv::<IndexResponse>() {
+        Err(ClientError::UnexpectedMessageType { expected, got }) => {
+            assert_eq!(expected, MessageType::IndexResponse);
+            assert_eq!(got, MessageType::IndexRequest);
+        }
+        other => panic!("expected UnexpectedMessageType, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn error_response_is_surfaced() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let port = serve_once(|request| {
+        let response = ErrorResponse::new(NOT_FOUND, format!("{} not found", request.index_path));
+        envelope::encode(&response).unwrap()
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    let result = client.send_request(&IndexRequest::new(0, "src".to_string()));
+    match result {
+        Err(ClientError::ServerReported { code, message }) => {
+            assert_eq!(code, NOT_FOUND);
+            assert_eq!(message, "src not found");
+        }
+        other => panic!("expected ServerReported, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn protocol_major_mismatch_is_refused() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let server_version = (PROTOCOL_MAJOR + 1) << 16;
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, server_version);
+    });
+
+    let mut client = client(&emacs_remote_path);
+    match client.connect(port) {
+        Err(ClientError::VersionMismatch { client, server }) => {
+            assert_eq!(client, PROTOCOL_VERSION);
+            assert_eq!(server, server_version);
+        }
+        other => panic!("expected VersionMismatch, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn ping_echoes_nonce() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let buf = read_frame(&mut stream).unwrap();
+        let request: PingRequest = rmps::from_slice(&buf).unwrap();
+        let response = PingResponse::new(request.nonce, request.sent_at_millis);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    let rtt = client.ping().unwrap();
+    assert!(rtt < Duration::from_secs(5));
+}
+
+#[test]
+fn dropped_connection_is_redialed() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    thread::spawn(move || {
+        // The first connection dies mid-request, the retry lands on a second one
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        read_frame(&mut stream).unwrap();
+        drop(stream);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let buf = read_frame(&mut stream).unwrap();
+        let request: IndexRequest = envelope::decode(&buf).unwrap();
+        let response = IndexResponse::new(7, request.index_path);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    let response: IndexResponse = client
+        .send_request(&IndexRequest::new(0, "src".to_string()))
+        .unwrap();
+    assert_eq!(response.hash, 7);
+}
+
+#[test]
+fn chunked_file_is_reassembled() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let contents: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+    let expected = contents.clone();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        read_frame(&mut stream).unwrap();
+
+        let response = FileReadResponse::chunked(hash(&contents), contents.len() as u64);
+        assert_eq!(response.chunk_count, 3);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+        for (index, data) in contents.chunks(CHUNK_SIZE).enumerate() {
+            let chunk = FileChunk::new(index as u32, data.to_vec());
+            write_frame(&mut stream, &envelope::encode(&chunk).unwrap()).unwrap();
+        }
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    assert_eq!(client.read_file("big.log").unwrap(), expected);
+}
+
+#[test]
+fn chunked_reads_report_progress() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let contents = vec![7; CHUNK_SIZE * 2 + 10];
+    let total = contents.len() as u64;
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        read_frame(&mut stream).unwrap();
+
+        let response = FileReadResponse::chunked(hash(&contents), total);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+        for (index, data) in contents.chunks(CHUNK_SIZE).enumerate() {
+            let chunk = FileChunk::new(index as u32, data.to_vec());
+            write_frame(&mut stream, &envelope::encode(&chunk).unwrap()).unwrap();
+        }
+    });
+
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let mut client = client(&emacs_remote_path);
+    let on_progress = reported.clone();
+    client.set_progress_callback(Some(Arc::new(move |progress| {
+        on_progress.lock().unwrap().push(progress.clone());
+    })));
+    client.connect(port).unwrap();
+    client.read_file("big.log").unwrap();
+
+    let reported = reported.lock().unwrap();
+    let done: Vec<u64> = reported
+        .iter()
+        .map(|progress| progress.bytes_done)
+        .collect();
+    assert_eq!(done, vec![CHUNK_SIZE as u64, 2 * CHUNK_SIZE as u64, total]);
+    assert!(reported
+        .iter()
+        .all(|progress| progress.bytes_total == total));
+    assert!(reported.iter().all(|progress| progress.request_id != 0));
+}
+
+#[test]
+fn interrupted_transfers_are_resumed() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let contents: Vec<u8> = (0..CHUNK_SIZE * 3 + 10).map(|i| (i % 251) as u8).collect();
+    let expected = contents.clone();
+    thread::spawn(move || {
+        let h = hash(&contents);
+        let chunks: Vec<&[u8]> = contents.chunks(CHUNK_SIZE).collect();
+
+        // the first connection drops after a single chunk
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        read_frame(&mut stream).unwrap();
+        let response = FileReadResponse::chunked(h, contents.len() as u64);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+        let chunk = FileChunk::new(0, chunks[0].to_vec());
+        write_frame(&mut stream, &envelope::encode(&chunk).unwrap()).unwrap();
+        drop(stream);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let request: FileReadRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+        assert_eq!(request.offset, CHUNK_SIZE as u64);
+        assert_eq!(request.resume_hash, h);
+        let response = FileReadResponse::resumed(h, contents.len() as u64, request.offset);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+        for (index, data) in chunks.iter().enumerate().skip(1) {
+            let chunk = FileChunk::new(index as u32, data.to_vec());
+            write_frame(&mut stream, &envelope::encode(&chunk).unwrap()).unwrap();
+        }
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    assert_eq!(client.read_file("big.log").unwrap(), expected);
+}
+
+#[test]
+fn corrupted_file_contents_are_refused() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let contents: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+    let expected = hash(&contents);
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+
+        // the right number of bytes, but one of them flipped on the way
+        read_frame(&mut stream).unwrap();
+        let response = FileReadResponse::chunked(hash(&contents), contents.len() as u64);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+        let mut corrupted = contents.clone();
+        corrupted[CHUNK_SIZE + 3] ^= 1;
+        for (index, data) in corrupted.chunks(CHUNK_SIZE).enumerate() {
+            let chunk = FileChunk::new(index as u32, data.to_vec());
+            write_frame(&mut stream, &envelope::encode(&chunk).unwrap()).unwrap();
+        }
+
+        // nothing was cached, so the next read asks for the whole file again
+        let request: FileReadRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+        assert_eq!(request.prev_hash, 0);
+        let response = FileReadResponse::new(b"small".to_vec(), hash(&contents));
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    match client.read_file("big.log") {
+        Err(ClientError::ChecksumMismatch {
+            path, expected: e, ..
+        }) => {
+            assert_eq!(path, "big.log");
+            assert_eq!(e, expected);
+        }
+        other => panic!("expected ChecksumMismatch, got {:?}", other),
+    }
+    assert!(matches!(
+        client.read_file("big.log"),
+        Err(ClientError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn unanswered_request_times_out() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        read_frame(&mut stream).unwrap();
+        // hold the connection open without ever answering
+        thread::sleep(Duration::from_secs(5));
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.set_timeout(MessageType::IndexRequest, Duration::from_millis(200));
+    client.set_retry_policy(RetryPolicy::none());
+    client.connect(port).unwrap();
+
+    let result = client.send_request(&IndexRequest::new(0, "src".to_string()));
+    match result {
+        Err(ClientError::Timeout(timeout)) => assert_eq!(timeout, Duration::from_millis(200)),
+        other => panic!("expected Timeout, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn timed_out_reads_are_retried_on_a_fresh_connection() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    thread::spawn(move || {
+        // the first two connections never answer, the third does
+        let mut unanswered = Vec::new();
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream, PROTOCOL_VERSION);
+            read_frame(&mut stream).unwrap();
+            unanswered.push(stream);
+        }
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let request: IndexRequest = envelope::decode(&read_frame(&mut stream).unwrap()).unwrap();
+        let response = IndexResponse::new(7, request.index_path);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.set_timeout(MessageType::IndexRequest, Duration::from_millis(200));
+    client.set_retry_policy(RetryPolicy {
+        max_retries: 2,
+        base_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(10),
+    });
+    client.connect(port).unwrap();
+    let response: IndexResponse = client
+        .send_request(&IndexRequest::new(0, "src".to_string()))
+        .unwrap();
+    assert_eq!(response.hash, 7);
+}
+
+#[test]
+fn writes_are_not_resent_after_a_dropped_connection() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        read_frame(&mut stream).unwrap();
+        drop(stream);
+
+        // whatever the client dials next must not carry the delete again
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        stream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        read_frame(&mut stream).is_err()
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    assert!(client.delete("src/main.rs", false).is_err());
+    assert!(RetryPolicy::default().should_retry(
+        MessageType::IndexRequest,
+        0,
+        &ClientError::Timeout(Duration::from_millis(200))
+    ));
+    assert!(!RetryPolicy::default().should_retry(
+        MessageType::DeleteRequest,
+        0,
+        &ClientError::Timeout(Duration::from_millis(200))
+    ));
+    assert!(server.join().unwrap());
+}
+
+#[test]
+fn unknown_pushed_message_is_skipped() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let request: IndexRequest = envelope::decode(&read_frame(&mut stream).unwrap()).unwrap();
+
+        // what a newer server might push ahead of the response
+        let unknown = rmps::encode::to_vec(&(9999u64, "from the future")).unwrap();
+        write_frame(&mut stream, &unknown).unwrap();
+        let response = IndexResponse::new(request.prev_hash, request.index_path);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    let response = client
+        .send_request(&IndexRequest::new(7, "src".to_string()))
+        .unwrap();
+    assert_eq!(response.hash, 7);
+}
+
+#[test]
+fn file_changed_notification_invalidates_cached_path() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let old_hash = hash(&b"old".to_vec());
+    let new_hash = hash(&b"new".to_vec());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let next_request = |stream: &mut TcpStream| -> FileReadRequest {
+            rmps::from_slice(&read_frame(stream).unwrap()).unwrap()
+        };
+        let reply = |stream: &mut TcpStream, message: Vec<u8>| {
+            write_frame(stream, &message).unwrap();
+        };
+
+        assert_eq!(next_request(&mut stream).prev_hash, 0);
+        let response = FileReadResponse::new(b"old".to_vec(), old_hash);
+        reply(&mut stream, envelope::encode(&response).unwrap());
+
+        // the notification arrives ahead of the response to the second read
+        assert_eq!(next_request(&mut stream).prev_hash, old_hash);
+        let notification = FileChangedNotification::new("a.txt".to_string(), new_hash);
+        reply(&mut stream, envelope::encode(&notification).unwrap());
+        let response = FileReadResponse::unchanged(old_hash);
+        reply(&mut stream, envelope::encode(&response).unwrap());
+
+        assert_eq!(next_request(&mut stream).prev_hash, 0);
+        let response = FileReadResponse::new(b"new".to_vec(), new_hash);
+        reply(&mut stream, envelope::encode(&response).unwrap());
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    assert_eq!(client.read_file("a.txt").unwrap(), b"old");
+    assert_eq!(client.read_file("a.txt").unwrap(), b"old");
+    assert_eq!(client.read_file("a.txt").unwrap(), b"new");
+}
+
+#[test]
+fn reconnects_catch_up_on_missed_changes() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let b = hash(&b"b".to_vec());
+    let server = thread::spawn(move || {
+        let accept = |index_hash| -> TcpStream {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _: HandshakeRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+            let mut response = HandshakeResponse::new(PROTOCOL_VERSION, "test".to_string(), false);
+            response.index_hash = index_hash;
+            write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+            stream
+        };
+        let read = |stream: &mut TcpStream, contents: &[u8]| -> FileReadRequest {
+            let request: FileReadRequest = rmps::from_slice(&read_frame(stream).unwrap()).unwrap();
+            let response = FileReadResponse::new(contents.to_vec(), hash(&contents.to_vec()));
+            write_frame(stream, &envelope::encode(&response).unwrap()).unwrap();
+            request
+        };
+        let changes_since = |stream: &mut TcpStream, response: ChangesSinceResponse| -> u128 {
+            let request: ChangesSinceRequest =
+                envelope::decode(&read_frame(stream).unwrap()).unwrap();
+            write_frame(stream, &envelope::encode(&response).unwrap()).unwrap();
+            request.since_hash
+        };
+
+        let mut stream = accept(1);
+        read(&mut stream, b"a");
+        read(&mut stream, b"b");
+
+        // only a.txt changed while the client was away
+        let mut stream = accept(2);
+        let response = ChangesSinceResponse::new(2, vec!["a.txt".to_string()]);
+        assert_eq!(changes_since(&mut stream, response), 1);
+        assert_eq!(read(&mut stream, b"a").prev_hash, 0);
+        assert_eq!(read(&mut stream, b"b").prev_hash, b);
+
+        // the server restarted and can't say
+        let mut stream = accept(3);
+        assert_eq!(
+            changes_since(&mut stream, ChangesSinceResponse::too_old(3)),
+            2
+        );
+        assert_eq!(read(&mut stream, b"b").prev_hash, 0);
+
+        // nothing changed, nothing to ask
+        let mut stream = accept(3);
+        assert_eq!(read(&mut stream, b"b").prev_hash, b);
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    client.read_file("a.txt").unwrap();
+    client.read_file("b.txt").unwrap();
+
+    client.reconnect().unwrap();
+    client.read_file("a.txt").unwrap();
+    client.read_file("b.txt").unwrap();
+
+    client.reconnect().unwrap();
+    client.read_file("b.txt").unwrap();
+
+    client.reconnect().unwrap();
+    client.read_file("b.txt").unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn cached_files_are_scoped_by_the_workspace_salt() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let h = hash(&b"same in both checkouts".to_vec());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _: HandshakeRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+        let mut response = HandshakeResponse::new(PROTOCOL_VERSION, "test".to_string(), false);
+        response.salt = 7;
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+
+        let _: FileReadRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+        let response = FileReadResponse::new(b"same in both checkouts".to_vec(), h);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+
+        let request: FileReadRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+        assert_eq!(request.prev_hash, h);
+        let response = FileReadResponse::unchanged(h);
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    assert_eq!(
+        client.read_file("a.txt").unwrap(),
+        b"same in both checkouts"
+    );
+    assert_eq!(
+        client.read_file("a.txt").unwrap(),
+        b"same in both checkouts"
+    );
+
+    // another workspace's files with the same contents are cached under a different key
+    let blobs = emacs_remote_path.path().join("client/cache");
+    assert!(blobs.join(format!("{:032x}", salted(7, h))).exists());
+    assert!(!blobs.join(format!("{:032x}", h)).exists());
+}
+
+#[test]
+fn grep_is_refused_when_the_server_lacks_it() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let _: WorkspaceInfoRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+
+        let supported = vec![u64::from(MessageType::IndexRequest) as u32];
+        let response = WorkspaceInfoResponse::new(
+            "old".to_string(),
+            PROTOCOL_VERSION,
+            supported,
+            0,
+            0,
+            "/workspace".to_string(),
+        );
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    match client.grep("main", false, false, None) {
+        Err(ClientError::Unsupported(msgtype)) => assert_eq!(msgtype, MessageType::GrepRequest),
+        other => panic!("expected Unsupported, got {:?}", other.err()),
+    }
+    // the capabilities are cached, the fake server would not answer a second request
+    assert!(client.supports(MessageType::IndexRequest).unwrap());
+}
+
+#[test]
+fn pipelined_responses_are_matched_by_request_id() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let (request_id, buf) = read_frame_tagged(&mut stream, DEFAULT_MAX_FRAME_SIZE).unwrap();
+            let request: IndexRequest = envelope::decode(&buf).unwrap();
+            requests.push((request_id, request));
+        }
+        // answer the second request first
+        for (request_id, request) in requests.into_iter().rev() {
+            let response = IndexResponse::new(request.prev_hash, request.index_path);
+            let buf = envelope::encode(&response).unwrap();
+            write_frame_tagged(&mut stream, &buf, None, request_id).unwrap();
+        }
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    let first = client
+        .server_send(&IndexRequest::new(1, "first".to_string()))
+        .unwrap();
+    let second = client
+        .server_send(&IndexRequest::new(2, "second".to_string()))
+        .unwrap();
+    assert_ne!(first, second);
+
+    let response = client.server_recv_for::<IndexResponse>(first).unwrap();
+    assert_eq!(response.hash, 1);
+    let response = client.server_recv_for::<IndexResponse>(second).unwrap();
+    assert_eq!(response.path_to_index_file, "second");
+}
+
+#[test]
+fn setup_errors_are_returned_instead_of_panicking() {
+    let dir = tempfile::tempdir().unwrap();
+    // the client directory can't be created below a regular file
+    let emacs_remote_path = dir.path().join("not-a-directory");
+    std::fs::write(&emacs_remote_path, "").unwrap();
+    let result = ClientDaemon::new(
+        "localhost".to_string(),
+        "/workspace".to_string(),
+        emacs_remote_path.to_str().unwrap().to_string(),
+    );
+    match result {
+        Err(ClientError::Io(e)) => assert!(e.to_string().contains("not-a-directory")),
+        other => panic!("expected Io, got {:?}", other.err()),
+    }
+
+    // nothing listens on a port that was just released
+    let port = TcpListener::bind("localhost:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port() as u32;
+    let mut client = client(&dir);
+    match client.connect(port) {
+        Err(e @ ClientError::Connect { .. }) => {
+            assert!(e
+                .to_string()
+                .starts_with(&format!("could not connect to server on port {}", port)));
+        }
+        other => panic!("expected Connect, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn grep_matches_are_passed_on_as_they_arrive() {
+    let emacs_remote_path = tempfile::tempdir().unwrap();
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    let grep_match = |line_number| GrepMatch {
+        path: "src/main.rs".to_string(),
+        line_number,
+        line: "fn main() {}".to_string(),
+    };
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, PROTOCOL_VERSION);
+        let _: WorkspaceInfoRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+        let supported = vec![u64::from(MessageType::GrepRequest) as u32];
+        let response = WorkspaceInfoResponse::new(
+            "test".to_string(),
+            PROTOCOL_VERSION,
+            supported,
+            0,
+            0,
+            "/workspace".to_string(),
+        );
+        write_frame(&mut stream, &envelope::encode(&response).unwrap()).unwrap();
+
+        let request: GrepRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+        assert_eq!(request.pattern, "main");
+        for line_number in 1..=3 {
+            let frame = GrepMatchFrame::new(grep_match(line_number));
+            write_frame(&mut stream, &envelope::encode(&frame).unwrap()).unwrap();
+        }
+        let done = GrepDone::new(3, true);
+        write_frame(&mut stream, &envelope::encode(&done).unwrap()).unwrap();
+    });
+
+    let mut client = client(&emacs_remote_path);
+    client.connect(port).unwrap();
+    let mut lines = Vec::new();
+    let done = client
+        .grep_each("main", false, false, None, |m| lines.push(m.line_number))
+        .unwrap();
+    assert_eq!(lines, vec![1, 2, 3]);
+    assert_eq!(done.total, 3);
+    assert!(done.truncated);
+}
+
+#[test]
+fn hangups_between_frames_are_orderly() {
+    let listener = TcpListener::bind("localhost:0").unwrap();
+
+    let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+    drop(peer);
+    match recv::<PingResponse>(&mut stream) {
+        Err(ClientError::ConnectionClosed) => {}
+        other => panic!("expected ConnectionClosed, got {:?}", other),
+    }
+
+    // hanging up partway through a frame is still a failure
+    let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+    peer.write_all(&[0, 0, 0]).unwrap();
+    drop(peer);
+    match recv::<PingResponse>(&mut stream) {
+        Err(ClientError::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+        other => panic!("expected a truncated frame, got {:?}", other),
+    }
+}
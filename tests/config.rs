@@ -0,0 +1,71 @@
+use std::fs;
+
+use emacs_remote::error::ServerError;
+use emacs_remote::structs::config::ServerConfig;
+
+#[test]
+fn config_file_is_parsed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(
+        &path,
+        "port = 9200\nbind_addr = \"::1\"\nworkspace = \"/home/me/ws\"\nignore = [\"target/\", \"*.o\"]\nallowed_workspaces = [\"/home/me\"]\n",
+    )
+    .unwrap();
+
+    let config = ServerConfig::load(&path).unwrap();
+    assert_eq!(
+        config,
+        ServerConfig {
+            port: Some(9200),
+            bind_addr: Some("::1".to_string()),
+            metrics_port: None,
+            socket: None,
+            workspace: Some("/home/me/ws".to_string()),
+            workspace_name: None,
+            workspaces: Default::default(),
+            emacs_remote_path: None,
+            ignore: vec!["target/".to_string(), "*.o".to_string()],
+            allowed_workspaces: vec!["/home/me".to_string()],
+            read_only: false,
+            psk: None,
+            hash_salt: None,
+            hash_salts: Default::default(),
+        }
+    );
+}
+
+#[test]
+fn workspaces_are_a_table_of_names() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(
+        &path,
+        "workspace = \"/home/me/ws\"\nworkspace_name = \"ws\"\n\n[workspaces]\ndotfiles = \"/home/me/dotfiles\"\n",
+    )
+    .unwrap();
+
+    let config = ServerConfig::load(&path).unwrap();
+    assert_eq!(config.workspace_name.as_deref(), Some("ws"));
+    assert_eq!(config.workspaces.len(), 1);
+    assert_eq!(config.workspaces["dotfiles"], "/home/me/dotfiles");
+}
+
+#[test]
+fn missing_config_file_is_empty() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ServerConfig::load(&dir.path().join("config.toml")).unwrap();
+    assert_eq!(config, ServerConfig::default());
+}
+
+#[test]
+fn unknown_keys_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, "prot = 9200\n").unwrap();
+
+    match ServerConfig::load(&path) {
+        Err(ServerError::Config(_)) => {}
+        other => panic!("expected a config error, got {:?}", other),
+    }
+}
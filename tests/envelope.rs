@@ -0,0 +1,78 @@
+extern crate rmp_serde as rmps;
+
+use emacs_remote::messages::envelope::{self, Envelope};
+use emacs_remote::messages::index::{IndexRequest, IndexResponse};
+use emacs_remote::messages::messagetype::{MessageType, MessageTypeError};
+use emacs_remote::messages::ping::PingRequest;
+use emacs_remote::utils::stream::peek_message_type;
+
+#[test]
+fn envelopes_round_trip() {
+    let envelope = Envelope::tagged(IndexRequest::new(u128::MAX - 1, "src".to_string()), 9);
+    assert_eq!(envelope.msg_type, MessageType::IndexRequest);
+
+    let decoded = Envelope::<IndexRequest>::decode(&envelope.encode().unwrap(), 9).unwrap();
+    assert_eq!(decoded, envelope);
+}
+
+#[test]
+fn enveloped_messages_keep_the_wire_format() {
+    // the same array a message with its own message_type field is encoded as
+    let legacy = rmps::encode::to_vec(&(
+        u64::from(MessageType::IndexRequest),
+        7u128,
+        "src".to_string(),
+    ))
+    .unwrap();
+    let encoded = envelope::encode(&IndexRequest::new(7, "src".to_string())).unwrap();
+    assert_eq!(encoded, legacy);
+    assert_eq!(
+        envelope::decode::<IndexRequest>(&legacy).unwrap(),
+        IndexRequest::new(7, "src".to_string())
+    );
+
+    // messages that aren't enveloped are encoded as they always were
+    let ping = PingRequest::new(3, 4);
+    assert_eq!(
+        envelope::encode(&ping).unwrap(),
+        rmps::encode::to_vec(&ping).unwrap()
+    );
+}
+
+#[test]
+fn envelopes_of_another_type_are_refused() {
+    let encoded = envelope::encode(&IndexRequest::new(7, "src".to_string())).unwrap();
+    assert!(Envelope::<IndexResponse>::decode(&encoded, 0).is_err());
+    assert!(envelope::decode::<IndexResponse>(&encoded).is_err());
+
+    let untagged = rmps::encode::to_vec(&("src".to_string(), 7u64)).unwrap();
+    assert!(envelope::decode::<IndexRequest>(&untagged).is_err());
+}
+
+#[test]
+fn message_types_are_read_without_decoding_the_rest() {
+    let encoded = envelope::encode(&IndexRequest::new(7, "src".to_string())).unwrap();
+    assert_eq!(peek_message_type(&encoded), Ok(MessageType::IndexRequest));
+    // only the discriminant has to be there
+    assert_eq!(
+        peek_message_type(&encoded[..2]),
+        Ok(MessageType::IndexRequest)
+    );
+
+    let unknown = rmps::encode::to_vec(&(u64::MAX, "src".to_string())).unwrap();
+    assert_eq!(
+        peek_message_type(&unknown),
+        Err(MessageTypeError::Unknown(u64::MAX))
+    );
+    for untagged in [
+        rmps::encode::to_vec(&("src".to_string(), 7u64)).unwrap(),
+        rmps::encode::to_vec(&Vec::<u64>::new()).unwrap(),
+        rmps::encode::to_vec(&7u64).unwrap(),
+        Vec::new(),
+    ] {
+        assert_eq!(
+            peek_message_type(&untagged),
+            Err(MessageTypeError::Untagged)
+        );
+    }
+}
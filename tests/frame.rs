@@ -0,0 +1,203 @@
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+
+use emacs_remote::error::ClientError;
+use emacs_remote::utils::frame::{
+    is_closed, read_frame, read_frame_limited, read_frame_tagged, write_frame,
+    write_frame_compressed, write_frame_tagged, COMPRESSION_THRESHOLD, DEFAULT_MAX_FRAME_SIZE,
+    FLAG_COMPRESSED, FLAG_REQUEST_ID,
+};
+
+#[test]
+fn large_frames_are_compressed() {
+    let payload = b"emacs-remote ".repeat(COMPRESSION_THRESHOLD);
+
+    let mut wire = Vec::new();
+    write_frame_compressed(&mut wire, &payload, Some(3)).unwrap();
+    assert_eq!(wire[4], FLAG_COMPRESSED);
+    assert!(wire.len() < payload.len() / 10);
+
+    assert_eq!(read_frame(&mut Cursor::new(wire)).unwrap(), payload);
+}
+
+#[test]
+fn small_and_uncompressed_frames_are_sent_as_is() {
+    for compression_level in [None, Some(3)] {
+        let payload = b"small".to_vec();
+
+        let mut wire = Vec::new();
+        write_frame_compressed(&mut wire, &payload, compression_level).unwrap();
+        assert_eq!(wire[4], 0);
+        assert_eq!(&wire[5..], &payload[..]);
+
+        assert_eq!(read_frame(&mut Cursor::new(wire)).unwrap(), payload);
+    }
+
+    let payload = vec![7; COMPRESSION_THRESHOLD * 2];
+    let mut wire = Vec::new();
+    write_frame(&mut wire, &payload).unwrap();
+    assert_eq!(wire[4], 0);
+    assert_eq!(read_frame(&mut Cursor::new(wire)).unwrap(), payload);
+}
+
+#[test]
+fn oversized_frames_are_refused() {
+    // a header announcing 4GB must fail without trying to allocate it
+    let mut wire = u32::MAX.to_be_bytes().to_vec();
+    wire.push(0);
+    let e = read_frame(&mut Cursor::new(wire)).unwrap_err();
+    match ClientError::from(e) {
+        ClientError::FrameTooLarge {
+            size,
+            max_frame_size,
+        } => {
+            assert_eq!(size, u32::MAX as usize);
+            assert_eq!(max_frame_size, 64 * 1024 * 1024);
+        }
+        other => panic!("expected FrameTooLarge, got {:?}", other),
+    }
+
+    // small on the wire but too large once decompressed
+    let payload = vec![0; COMPRESSION_THRESHOLD * 4];
+    let mut wire = Vec::new();
+    write_frame_compressed(&mut wire, &payload, Some(3)).unwrap();
+    assert!(wire.len() < COMPRESSION_THRESHOLD);
+    let e = read_frame_limited(&mut Cursor::new(wire), COMPRESSION_THRESHOLD).unwrap_err();
+    assert!(matches!(
+        ClientError::from(e),
+        ClientError::FrameTooLarge { .. }
+    ));
+}
+
+#[test]
+fn request_ids_survive_the_round_trip() {
+    let payload = b"emacs-remote ".repeat(COMPRESSION_THRESHOLD);
+
+    let mut wire = Vec::new();
+    write_frame_tagged(&mut wire, &payload, Some(3), 42).unwrap();
+    write_frame_tagged(&mut wire, b"untagged", None, 0).unwrap();
+    assert_eq!(wire[4], FLAG_COMPRESSED | FLAG_REQUEST_ID);
+
+    let mut wire = Cursor::new(wire);
+    let (request_id, read) = read_frame_tagged(&mut wire, DEFAULT_MAX_FRAME_SIZE).unwrap();
+    assert_eq!(request_id, 42);
+    assert_eq!(read, payload);
+    // readers that don't care about ids still see the payload
+    assert_eq!(read_frame(&mut wire).unwrap(), b"untagged");
+}
+
+// Hands out at most `step` bytes per read, like a TCP stream delivering a frame in pieces
+struct Trickle {
+    data: Cursor<Vec<u8>>,
+    step: usize,
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.step);
+        self.data.read(&mut buf[..len])
+    }
+}
+
+// Takes at most `step` bytes per write, like a socket with a full send buffer
+struct Dribble {
+    data: Vec<u8>,
+    step: usize,
+}
+
+impl Write for Dribble {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.step);
+        self.data.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn frames_are_written_out_in_full_through_short_writes() {
+    let payload: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let mut stream = Dribble {
+        data: Vec::new(),
+        step: 7,
+    };
+    write_frame_tagged(&mut stream, &payload, None, 42).unwrap();
+    assert_eq!(stream.data.len(), 13 + payload.len());
+
+    let (request_id, read) =
+        read_frame_tagged(&mut Cursor::new(stream.data), DEFAULT_MAX_FRAME_SIZE).unwrap();
+    assert_eq!(request_id, 42);
+    assert_eq!(read, payload);
+
+    // a peer that stops taking bytes fails the write instead of truncating the frame
+    let mut stalled = Dribble {
+        data: Vec::new(),
+        step: 0,
+    };
+    let e = write_frame(&mut stalled, b"never sent").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::WriteZero);
+}
+
+// Counts the reads it is asked for
+struct Counting {
+    data: Cursor<Vec<u8>>,
+    reads: usize,
+}
+
+impl Read for Counting {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.data.read(buf)
+    }
+}
+
+#[test]
+fn large_frames_are_read_in_one_go() {
+    let payload = vec![7; 16 * 1024 * 1024];
+    let mut wire = Vec::new();
+    write_frame_tagged(&mut wire, &payload, None, 42).unwrap();
+
+    let mut stream = Counting {
+        data: Cursor::new(wire),
+        reads: 0,
+    };
+    let (_, read) = read_frame_tagged(&mut stream, 32 * 1024 * 1024).unwrap();
+    assert_eq!(read.len(), payload.len());
+    // the header, the request id and the payload, straight into a buffer of its size
+    assert_eq!(stream.reads, 3);
+}
+
+#[test]
+fn frames_are_assembled_from_short_reads() {
+    let payload: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let mut wire = Vec::new();
+    write_frame_tagged(&mut wire, &payload, None, 42).unwrap();
+    write_frame(&mut wire, b"next").unwrap();
+
+    let mut stream = Trickle {
+        data: Cursor::new(wire),
+        step: 3,
+    };
+    let (request_id, read) = read_frame_tagged(&mut stream, DEFAULT_MAX_FRAME_SIZE).unwrap();
+    assert_eq!(request_id, 42);
+    assert_eq!(read, payload);
+    assert_eq!(read_frame(&mut stream).unwrap(), b"next");
+
+    // the peer hanging up between frames is an orderly close
+    let e = read_frame(&mut stream).unwrap_err();
+    assert!(is_closed(&e), "{}", e);
+}
+
+#[test]
+fn truncated_frames_are_not_an_orderly_close() {
+    let mut wire = Vec::new();
+    write_frame(&mut wire, b"cut short").unwrap();
+
+    for len in [2, 7] {
+        let e = read_frame(&mut Cursor::new(wire[..len].to_vec())).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert!(!is_closed(&e), "{}", e);
+    }
+}
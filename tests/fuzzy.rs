@@ -0,0 +1,26 @@
+use emacs_remote::utils::fuzzy::score;
+
+#[test]
+fn subsequences_match_and_rank() {
+    assert!(score("smr", "src/main.rs").is_some());
+    assert!(score("msr", "src/main.rs").is_none());
+    assert!(score("Main", "src/MAIN.rs").is_some());
+    assert_eq!(score("", "anything"), Some(0));
+
+    // a run at the start of the file name beats scattered matches
+    assert!(score("main", "src/main.rs") > score("main", "src/manifest.rs"));
+    assert!(score("ui", "src/ui.rs") > score("ui", "src/build.rs"));
+}
+
+#[test]
+fn the_best_alignment_counts() {
+    // the c of src doesn't keep client from matching as a run in the file name
+    assert_eq!(
+        score("client", "src/structs/client.rs"),
+        score("client", "client.rs")
+    );
+    assert!(score("stcl", "src/structs/client.rs") > score("stcl", "src/stacks/cool.rs"));
+
+    // camelCase humps count as word starts
+    assert!(score("fre", "src/FileRead.rs") > score("fre", "src/fileread.rs"));
+}
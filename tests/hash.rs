@@ -0,0 +1,46 @@
+use std::io::Cursor;
+
+use emacs_remote::utils::hash::{
+    fast_hash, hash, hash_reader, hash_reader_with, hash_with, salted, Xxh3_128, Xxh3_64,
+};
+
+#[test]
+fn hash_reader_matches_hash_of_contents() {
+    for len in [0, 1, 64 * 1024, 64 * 1024 + 1, 300 * 1024] {
+        let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let streamed = hash_reader(&mut Cursor::new(&contents), len as u64).unwrap();
+        assert_eq!(streamed, hash(&contents), "len {}", len);
+    }
+}
+
+#[test]
+fn hash_reader_reports_short_reads() {
+    let contents = vec![1; 10];
+    assert!(hash_reader(&mut Cursor::new(&contents), 11).is_err());
+}
+
+#[test]
+fn algorithms_can_be_swapped() {
+    let contents = b"fn main() {}".to_vec();
+    assert_eq!(hash_with::<Xxh3_128, _>(&contents), hash(&contents));
+
+    // the 64-bit option only fills the low half
+    let fast = hash_with::<Xxh3_64, _>(&contents);
+    assert_eq!(fast, fast_hash(&contents) as u128);
+    assert_ne!(fast, hash(&contents));
+    let streamed =
+        hash_reader_with::<Xxh3_64, _>(&mut Cursor::new(&contents), contents.len() as u64);
+    assert_eq!(streamed.unwrap(), fast);
+
+    // wide enough that the high half is used too
+    assert_ne!(hash(&contents) >> 64, 0);
+}
+
+#[test]
+fn salts_tell_workspaces_apart() {
+    let h = hash(&b"fn main() {}".to_vec());
+    assert_eq!(salted(0, h), h);
+    assert_ne!(salted(1, h), h);
+    assert_ne!(salted(1, h), salted(2, h));
+    assert_eq!(salted(1, h), salted(1, h));
+}
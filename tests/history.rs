@@ -0,0 +1,71 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use emacs_remote::structs::history::History;
+use emacs_remote::utils::hash;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[test]
+fn versions_survive_reopening() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut history = History::open(dir.path(), 1024, DAY).unwrap();
+    assert_eq!(history.record("src/main.rs", b"first").unwrap(), Some(1));
+    assert_eq!(history.record("src/lib.rs", b"first").unwrap(), Some(2));
+    assert_eq!(history.record("src/main.rs", b"second").unwrap(), Some(3));
+    // identical contents are stored once
+    assert_eq!(history.size(), 11);
+
+    let history = History::open(dir.path(), 1024, DAY).unwrap();
+    let versions = history.versions_of("src/main.rs");
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0].hash, hash::hash(b"first"));
+    assert_eq!(history.contents("src/main.rs", 3).unwrap(), b"second");
+    assert_eq!(history.contents("src/lib.rs", 2).unwrap(), b"first");
+
+    // a version of another file isn't one of this file
+    let e = history.contents("src/lib.rs", 3).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn the_oldest_versions_are_pruned_first() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut history = History::open(dir.path(), 10, DAY).unwrap();
+    history.record("a", b"1234").unwrap();
+    history.record("a", b"5678").unwrap();
+    history.record("a", b"abcd").unwrap();
+    let versions: Vec<_> = history.versions_of("a").iter().map(|v| v.version).collect();
+    assert_eq!(versions, vec![2, 3]);
+    assert_eq!(history.size(), 8);
+    assert_eq!(fs::read_dir(dir.path().join("objects")).unwrap().count(), 2);
+
+    // files larger than the whole history aren't kept
+    assert_eq!(history.record("a", b"far too large").unwrap(), None);
+
+    // a smaller limit takes effect when the history is next opened
+    let history = History::open(dir.path(), 4, DAY).unwrap();
+    assert_eq!(history.versions_of("a").len(), 1);
+    assert_eq!(history.contents("a", 3).unwrap(), b"abcd");
+}
+
+#[test]
+fn corrupted_versions_are_not_restored() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut history = History::open(dir.path(), 1024, DAY).unwrap();
+    history.record("a", b"contents").unwrap();
+    let object = fs::read_dir(dir.path().join("objects"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    fs::write(object, b"tampered").unwrap();
+
+    let e = history.contents("a", 1).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+}
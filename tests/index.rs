@@ -0,0 +1,148 @@
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+
+use emacs_remote::structs::index::{Index, IndexEntry};
+
+#[test]
+fn incremental_update_matches_full_rebuild() {
+    let root = tempfile::tempdir().unwrap();
+    let root = fs::canonicalize(root.path()).unwrap();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+    fs::write(root.join("src/lib.rs"), "pub mod a;").unwrap();
+    fs::write(root.join("README"), "hello").unwrap();
+
+    let mut index = Index::build(&root).unwrap();
+    let original_hash = index.hash;
+
+    fs::write(root.join("src/main.rs"), "fn main() { println!(); }").unwrap();
+    fs::write(root.join("src/a.rs"), "").unwrap();
+    fs::remove_file(root.join("README")).unwrap();
+
+    index.update(
+        &root,
+        &[
+            root.join("src/main.rs"),
+            PathBuf::from("src/a.rs"),
+            root.join("README"),
+        ],
+    );
+
+    let rebuilt = Index::build(&root).unwrap();
+    assert_ne!(index.hash, original_hash);
+    assert_eq!(index.hash, rebuilt.hash);
+    assert_eq!(index.files, rebuilt.files);
+}
+
+#[test]
+fn update_skips_ignored_files() {
+    let root = tempfile::tempdir().unwrap();
+    let root = fs::canonicalize(root.path()).unwrap();
+    fs::write(root.join(".gitignore"), "target/\n").unwrap();
+    fs::create_dir_all(root.join("target")).unwrap();
+
+    let mut index = Index::build(&root).unwrap();
+    let original_hash = index.hash;
+
+    fs::write(root.join("target/out.o"), "binary").unwrap();
+    index.update(&root, &[root.join("target/out.o")]);
+
+    assert_eq!(index.hash, original_hash);
+    assert!(!index.files.contains_key("target/out.o"));
+}
+
+#[test]
+fn rebuild_only_rehashes_changed_files() {
+    let root = tempfile::tempdir().unwrap();
+    let root = fs::canonicalize(root.path()).unwrap();
+    fs::write(root.join("same"), "aaaa").unwrap();
+    fs::write(root.join("grown"), "aaaa").unwrap();
+
+    let index = Index::build(&root).unwrap();
+    let entry = &index.files["same"];
+    assert_eq!(entry.path, "same");
+    assert_eq!(entry.size, 4);
+    assert_ne!(entry.mtime_millis, 0);
+
+    // same size and mtime, so the stale hash is trusted without reading the file
+    let mtime = fs::metadata(root.join("same")).unwrap().modified().unwrap();
+    fs::write(root.join("same"), "bbbb").unwrap();
+    let file = OpenOptions::new()
+        .write(true)
+        .open(root.join("same"))
+        .unwrap();
+    file.set_modified(mtime).unwrap();
+    fs::write(root.join("grown"), "aaaaaaaa").unwrap();
+
+    let rebuilt = index.rebuild_with(&root, &[]).unwrap();
+    assert_eq!(rebuilt.files["same"], index.files["same"]);
+    assert_ne!(
+        rebuilt.files["grown"].content_hash,
+        index.files["grown"].content_hash
+    );
+    assert_eq!(rebuilt.files["grown"].size, 8);
+}
+
+#[test]
+fn parallel_build_is_deterministic() {
+    let root = tempfile::tempdir().unwrap();
+    let root = fs::canonicalize(root.path()).unwrap();
+    for i in 0..64 {
+        fs::write(root.join(format!("file{}", i)), i.to_string()).unwrap();
+    }
+
+    let build_on = |num_threads| {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        pool.install(|| Index::build(&root).unwrap())
+    };
+    let serial = build_on(1);
+    let parallel = build_on(8);
+    assert_eq!(serial.hash, parallel.hash);
+    assert_eq!(serial.files, parallel.files);
+}
+
+#[test]
+fn index_files_keep_their_layout() {
+    let mut index = Index {
+        hash: 1,
+        root_mtime: 2,
+        ..Index::default()
+    };
+    index.files.insert(
+        "a".to_string(),
+        IndexEntry {
+            path: "a".to_string(),
+            content_hash: 3,
+            size: 4,
+            mtime_millis: 5,
+            mode: 6,
+        },
+    );
+    let mut hash = vec![0xc4, 0x10];
+    hash.extend_from_slice(&1u128.to_be_bytes());
+    let mut content_hash = vec![0xc4, 0x10];
+    content_hash.extend_from_slice(&3u128.to_be_bytes());
+    let expected = [
+        &[0x93][..],
+        &hash,
+        &[0x02, 0x81, 0xa1, b'a', 0x95, 0xa1, b'a'],
+        &content_hash,
+        &[0x04, 0x05, 0x06],
+    ]
+    .concat();
+    assert_eq!(index.encode().unwrap(), expected);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("index.mp");
+    index.to_file(&path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), expected);
+    assert_eq!(Index::from_file(&path).unwrap(), index);
+    // as written by a version that appended a field
+    let mut longer = expected.clone();
+    longer[0] = 0x94;
+    longer.push(0x07);
+    assert_eq!(Index::decode(&longer).unwrap(), index);
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use emacs_remote::structs::index::Index;
+use emacs_remote::structs::indexer::Indexer;
+use emacs_remote::utils::hash::hash;
+
+fn tree() -> (tempfile::TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::create_dir_all(root.join("build")).unwrap();
+    fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+    fs::write(root.join("src/lib.rs"), "pub mod a;").unwrap();
+    fs::write(root.join("build/out.o"), "object").unwrap();
+    fs::write(root.join("README"), "hello").unwrap();
+    (dir, root)
+}
+
+fn relative(root: &Path, files: Vec<PathBuf>) -> Vec<String> {
+    let mut files: Vec<String> = files
+        .iter()
+        .map(|file| {
+            file.strip_prefix(root)
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+    files.sort_unstable();
+    files
+}
+
+#[test]
+fn build_indexes_everything_not_ignored() {
+    let (_dir, root) = tree();
+    let indexer = Indexer::new(&root, &["build/".to_string()]);
+
+    assert_eq!(
+        relative(&root, indexer.files()),
+        vec!["README", "src/lib.rs", "src/main.rs"]
+    );
+
+    let index = indexer.build().unwrap();
+    let paths: Vec<&str> = index.files.keys().map(String::as_str).collect();
+    assert_eq!(paths, vec!["README", "src/lib.rs", "src/main.rs"]);
+    assert_eq!(
+        index.files["src/main.rs"].content_hash,
+        hash(&b"fn main() {}".to_vec())
+    );
+    // the same as what the Index shorthand builds
+    let built = Index::build_with(&root, &["build/".to_string()]).unwrap();
+    assert_eq!(index.hash, built.hash);
+}
+
+#[test]
+fn update_follows_changes_without_a_rebuild() {
+    let (_dir, root) = tree();
+    let indexer = Indexer::new(&root, &["build/".to_string()]);
+    let mut index = indexer.build().unwrap();
+
+    fs::write(root.join("src/main.rs"), "fn main() { run() }").unwrap();
+    fs::write(root.join("src/new.rs"), "").unwrap();
+    fs::write(root.join("build/other.o"), "object").unwrap();
+    fs::remove_file(root.join("README")).unwrap();
+    indexer.update(
+        &mut index,
+        &[
+            root.join("src/main.rs"),
+            PathBuf::from("src/new.rs"),
+            root.join("build/other.o"),
+            root.join("README"),
+        ],
+    );
+
+    let rebuilt = indexer.build().unwrap();
+    assert_eq!(index.files, rebuilt.files);
+    assert_eq!(index.hash, rebuilt.hash);
+    assert!(!index.files.contains_key("build/other.o"));
+}
+
+#[test]
+fn updated_paths_are_indexed_by_their_normalized_spelling() {
+    let (_dir, root) = tree();
+    let indexer = Indexer::new(&root, &["build/".to_string()]);
+    let mut index = indexer.build().unwrap();
+
+    fs::write(root.join("src/new.rs"), "").unwrap();
+    indexer.update(&mut index, &[PathBuf::from("./src//new.rs")]);
+    assert!(index.files.contains_key("src/new.rs"));
+    assert_eq!(index.hash, indexer.build().unwrap().hash);
+}
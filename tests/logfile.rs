@@ -0,0 +1,44 @@
+use std::fs;
+use std::io::Write;
+
+use emacs_remote::utils::logfile::{log_file_name, RotatingFile};
+
+#[test]
+fn logs_rotate_and_old_ones_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.log");
+
+    let mut log = RotatingFile::open(&path, 10, 2).unwrap();
+    for record in &["first\n", "second\n", "third\n", "fourth\n"] {
+        log.write_all(record.as_bytes()).unwrap();
+    }
+    log.flush().unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+    assert_eq!(
+        fs::read_to_string(dir.path().join("server.log.1")).unwrap(),
+        "third\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("server.log.2")).unwrap(),
+        "second\n"
+    );
+    assert!(!dir.path().join("server.log.3").exists());
+}
+
+#[test]
+fn reopened_logs_keep_their_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.log");
+    fs::write(&path, "12345678").unwrap();
+
+    let mut log = RotatingFile::open(&path, 10, 1).unwrap();
+    log.write_all(b"abc").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "abc");
+    assert_eq!(
+        fs::read_to_string(dir.path().join("server.log.1")).unwrap(),
+        "12345678"
+    );
+
+    assert_ne!(log_file_name("/a"), log_file_name("/b"));
+}
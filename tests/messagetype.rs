@@ -0,0 +1,121 @@
+use std::convert::TryFrom;
+
+use emacs_remote::messages::fileops::{DeleteRequest, FileOpResponse};
+use emacs_remote::messages::fileread::FileReadRequest;
+use emacs_remote::messages::index::{IndexRequest, IndexResponse};
+use emacs_remote::messages::messagetype::{
+    is_experimental, MessageType, MessageTypeError, MessageTypeTrait, Response,
+    EXPERIMENTAL_MESSAGE_TYPES,
+};
+use emacs_remote::messages::watch::{UnwatchRequest, WatchResponse};
+use emacs_remote::utils::stream::peek_message_type;
+
+// What every message type is on the wire. Released numbers must never change, a new message
+// type is added at the end of this table.
+const WIRE: &[(MessageType, u64)] = &[
+    (MessageType::ShellRequest, 0),
+    (MessageType::FileRequest, 1),
+    (MessageType::PathResponse, 2),
+    (MessageType::DataResponse, 3),
+    (MessageType::ProjectileInvalidCacheRequest, 4),
+    (MessageType::IndexRequest, 5),
+    (MessageType::IndexResponse, 6),
+    (MessageType::FileReadRequest, 7),
+    (MessageType::FileReadResponse, 8),
+    (MessageType::FileWriteRequest, 9),
+    (MessageType::FileWriteResponse, 10),
+    (MessageType::DirectoryListRequest, 11),
+    (MessageType::DirectoryListResponse, 12),
+    (MessageType::HandshakeRequest, 13),
+    (MessageType::HandshakeResponse, 14),
+    (MessageType::PingRequest, 15),
+    (MessageType::PingResponse, 16),
+    (MessageType::FileChunk, 17),
+    (MessageType::GrepRequest, 18),
+    (MessageType::GrepResponse, 19),
+    (MessageType::FileChangedNotification, 20),
+    (MessageType::ErrorResponse, 21),
+    (MessageType::WorkspaceInfoRequest, 22),
+    (MessageType::WorkspaceInfoResponse, 23),
+    (MessageType::RenameRequest, 24),
+    (MessageType::DeleteRequest, 25),
+    (MessageType::FileOpResponse, 26),
+    (MessageType::CompletionRequest, 27),
+    (MessageType::CompletionResponse, 28),
+    (MessageType::WatchRequest, 29),
+    (MessageType::UnwatchRequest, 30),
+    (MessageType::WatchResponse, 31),
+    (MessageType::BatchRequest, 32),
+    (MessageType::BatchResponse, 33),
+    (MessageType::VersionRequest, 34),
+    (MessageType::VersionResponse, 35),
+    (MessageType::GrepMatchFrame, 36),
+    (MessageType::GrepDone, 37),
+    (MessageType::SaveAllRequest, 38),
+    (MessageType::SaveAllResponse, 39),
+    (MessageType::FileStatRequest, 40),
+    (MessageType::FileStatResponse, 41),
+    (MessageType::BulkChangeNotification, 42),
+    (MessageType::TouchRequest, 43),
+    (MessageType::MkdirRequest, 44),
+    (MessageType::CancelRequest, 45),
+    (MessageType::CancelResponse, 46),
+    (MessageType::ArchiveRequest, 47),
+    (MessageType::ArchiveChunk, 48),
+    (MessageType::ArchiveDone, 49),
+    (MessageType::KeyExchangeRequest, 50),
+    (MessageType::KeyExchangeResponse, 51),
+    (MessageType::HistoryRequest, 52),
+    (MessageType::HistoryResponse, 53),
+    (MessageType::RestoreRequest, 54),
+    (MessageType::RestoreResponse, 55),
+    (MessageType::RescanRequest, 56),
+    (MessageType::RescanResponse, 57),
+    (MessageType::ProgressNotification, 58),
+    (MessageType::ChangesSinceRequest, 59),
+    (MessageType::ChangesSinceResponse, 60),
+    (MessageType::DiffRequest, 61),
+    (MessageType::DiffResponse, 62),
+];
+
+#[test]
+fn message_types_keep_their_wire_discriminants() {
+    for (msgtype, discriminant) in WIRE {
+        assert_eq!(u64::from(*msgtype), *discriminant, "{:?}", msgtype);
+        assert_eq!(MessageType::try_from(*discriminant), Ok(*msgtype));
+    }
+    // nothing but the table is known
+    assert!(MessageType::try_from(WIRE.len() as u64).is_err());
+}
+
+#[test]
+fn experimental_discriminants_are_never_released() {
+    assert!(WIRE
+        .iter()
+        .all(|(_, discriminant)| !is_experimental(*discriminant)));
+    for discriminant in [
+        *EXPERIMENTAL_MESSAGE_TYPES.start(),
+        *EXPERIMENTAL_MESSAGE_TYPES.end(),
+    ] {
+        assert!(MessageType::try_from(discriminant).is_err());
+        let message = rmp_serde::encode::to_vec(&(discriminant, "trial")).unwrap();
+        assert_eq!(
+            peek_message_type(&message),
+            Err(MessageTypeError::Unknown(discriminant))
+        );
+    }
+}
+
+// only compiles if `Req` is answered by a `Resp`
+fn answered_by<Req: Response<Response = Resp>, Resp>() {}
+
+#[test]
+fn requests_are_paired_with_their_responses() {
+    answered_by::<IndexRequest, IndexResponse>();
+    answered_by::<UnwatchRequest, WatchResponse>();
+    answered_by::<DeleteRequest, FileOpResponse>();
+    assert_eq!(
+        <FileReadRequest as Response>::Response::messagetype(),
+        MessageType::FileReadResponse
+    );
+}
@@ -0,0 +1,68 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use emacs_remote::messages::messagetype::MessageType;
+use emacs_remote::utils::metrics::{serve, Metrics};
+
+#[test]
+fn requests_are_counted_per_message_type() {
+    let metrics = Metrics::new();
+    metrics.connection_opened();
+    metrics.connection_opened();
+    metrics.connection_closed();
+    metrics.record(MessageType::GrepRequest, Duration::from_millis(20), true);
+    metrics.record(MessageType::GrepRequest, Duration::from_secs(600), false);
+    metrics.record(MessageType::PingRequest, Duration::from_micros(10), true);
+
+    let rendered = metrics.render();
+    let lines: Vec<&str> = rendered.lines().collect();
+    for expected in &[
+        "emacs_remote_requests_total{message_type=\"GrepRequest\"} 2",
+        "emacs_remote_requests_total{message_type=\"PingRequest\"} 1",
+        "emacs_remote_request_errors_total{message_type=\"GrepRequest\"} 1",
+        "emacs_remote_request_errors_total{message_type=\"PingRequest\"} 0",
+        "emacs_remote_request_duration_seconds_bucket{message_type=\"GrepRequest\",le=\"0.01\"} 0",
+        "emacs_remote_request_duration_seconds_bucket{message_type=\"GrepRequest\",le=\"0.05\"} 1",
+        "emacs_remote_request_duration_seconds_bucket{message_type=\"GrepRequest\",le=\"120\"} 1",
+        "emacs_remote_request_duration_seconds_bucket{message_type=\"GrepRequest\",le=\"+Inf\"} 2",
+        "emacs_remote_request_duration_seconds_count{message_type=\"GrepRequest\"} 2",
+        "emacs_remote_active_connections 1",
+        "emacs_remote_connections_total 2",
+    ] {
+        assert!(
+            lines.contains(expected),
+            "missing {} in\n{}",
+            expected,
+            rendered
+        );
+    }
+}
+
+#[test]
+fn metrics_are_served_over_http() {
+    let metrics = Arc::new(Metrics::new());
+    metrics.record(MessageType::IndexRequest, Duration::from_millis(1), true);
+
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    serve(addr, metrics).unwrap();
+
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("emacs_remote_requests_total{message_type=\"IndexRequest\"} 1\n"));
+    assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
@@ -0,0 +1,36 @@
+use emacs_remote::utils::net::{bind_free_port, parse_bind_addr, DEFAULT_BIND_ADDR};
+
+#[test]
+fn bind_free_port_falls_back_when_preferred_is_taken() {
+    let (_taken, port) = bind_free_port(DEFAULT_BIND_ADDR, None).unwrap();
+    assert_ne!(port, 0);
+
+    let (_listener, fallback) = bind_free_port(DEFAULT_BIND_ADDR, Some(port)).unwrap();
+    assert_ne!(fallback, port);
+}
+
+#[test]
+fn bind_free_port_uses_preferred_when_free() {
+    let port = {
+        let (_probe, port) = bind_free_port(DEFAULT_BIND_ADDR, None).unwrap();
+        port
+    };
+
+    let (_listener, bound) = bind_free_port(DEFAULT_BIND_ADDR, Some(port)).unwrap();
+    assert_eq!(bound, port);
+}
+
+#[test]
+fn parse_bind_addr_accepts_ipv4_and_ipv6() {
+    assert_eq!(parse_bind_addr("127.0.0.1").unwrap(), DEFAULT_BIND_ADDR);
+    assert_eq!(parse_bind_addr("::1").unwrap().to_string(), "::1");
+    assert_eq!(parse_bind_addr("[::1]").unwrap().to_string(), "::1");
+
+    for addr in &["localhost", "127.0.0.1:9130", "::1::2", ""] {
+        assert!(
+            parse_bind_addr(addr).is_err(),
+            "{} should be rejected",
+            addr
+        );
+    }
+}
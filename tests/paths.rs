@@ -0,0 +1,64 @@
+use std::fs;
+use std::io::ErrorKind;
+
+use emacs_remote::structs::server::ServerDaemon;
+use emacs_remote::utils::paths::{ensure_dirs, normalize_workspace_path, SUBDIRS};
+
+#[test]
+fn dirs_are_created_idempotently() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("missing").join(".emacs_remote");
+
+    ensure_dirs(&root).unwrap();
+    ensure_dirs(&root).unwrap();
+    for subdir in SUBDIRS.iter() {
+        assert!(root.join(subdir).is_dir(), "{}", subdir);
+    }
+}
+
+#[test]
+fn files_in_the_way_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join(".emacs_remote");
+    fs::write(&root, "").unwrap();
+
+    let e = ensure_dirs(&root).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+    assert!(e.to_string().contains("is not a directory"), "{}", e);
+
+    // the server reports it too instead of panicking
+    let result = ServerDaemon::new(
+        root.to_str().unwrap().to_string(),
+        "0".to_string(),
+        dir.path().to_str().unwrap().to_string(),
+    );
+    assert!(result.is_err());
+
+    // and so does a file further down
+    fs::remove_file(&root).unwrap();
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("server"), "").unwrap();
+    let e = ensure_dirs(&root).unwrap_err();
+    assert!(
+        e.to_string()
+            .contains("server exists but is not a directory"),
+        "{}",
+        e
+    );
+}
+
+#[test]
+fn workspace_paths_are_normalized() {
+    for (path, normalized) in [
+        ("./a//b/", "a/b"),
+        ("a/b", "a/b"),
+        ("a/./b/.", "a/b"),
+        ("", ""),
+        ("./", ""),
+        // left for resolving, which knows about symlinks
+        ("a/../b", "a/../b"),
+        ("/etc//passwd", "/etc/passwd"),
+    ] {
+        assert_eq!(normalize_workspace_path(path), normalized, "{:?}", path);
+    }
+}
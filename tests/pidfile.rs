@@ -0,0 +1,60 @@
+use std::fs;
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+
+use emacs_remote::utils::pidfile::{pid_file, running_pid, stop, wait_for_exit};
+
+#[test]
+fn stale_and_missing_pid_files_mean_no_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = pid_file(dir.path().to_str().unwrap());
+    assert!(path.ends_with("server/server.pid"));
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    assert_eq!(running_pid(&path).unwrap(), None);
+
+    fs::write(&path, format!("{}\n", process::id())).unwrap();
+    assert_eq!(running_pid(&path).unwrap(), Some(process::id() as i32));
+
+    // the child has been reaped, nothing runs under its pid anymore
+    let mut child = Command::new("true").spawn().unwrap();
+    child.wait().unwrap();
+    fs::write(&path, child.id().to_string()).unwrap();
+    assert_eq!(running_pid(&path).unwrap(), None);
+    assert_eq!(stop(&path).unwrap(), None);
+}
+
+#[test]
+fn stop_terminates_the_recorded_process() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.pid");
+    let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+    fs::write(&path, child.id().to_string()).unwrap();
+
+    assert_eq!(stop(&path).unwrap(), Some(child.id() as i32));
+    let status = child.wait().unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn replaced_servers_are_waited_for() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.pid");
+    let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+    let pid = child.id() as i32;
+    fs::write(&path, pid.to_string()).unwrap();
+
+    // still running, and this process never will have exited
+    assert!(!wait_for_exit(pid, Duration::from_millis(100)));
+    assert!(!wait_for_exit(
+        process::id() as i32,
+        Duration::from_millis(100)
+    ));
+
+    assert_eq!(stop(&path).unwrap(), Some(pid));
+    // a real server isn't a child of the one replacing it, this one has to be reaped
+    let reaper = thread::spawn(move || child.wait().unwrap());
+    assert!(wait_for_exit(pid, Duration::from_secs(5)));
+    assert!(!reaper.join().unwrap().success());
+    assert_eq!(running_pid(&path).unwrap(), None);
+}
@@ -0,0 +1,138 @@
+extern crate rmp_serde as rmps;
+
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use emacs_remote::error::ClientError;
+use emacs_remote::messages::cancel::{CancelRequest, CancelResponse};
+use emacs_remote::messages::envelope;
+use emacs_remote::messages::filechanged::FileChangedNotification;
+use emacs_remote::messages::grep::{GrepMatch, GrepMatchFrame, GrepRequest};
+use emacs_remote::messages::handshake::{HandshakeRequest, HandshakeResponse};
+use emacs_remote::messages::ping::{PingRequest, PingResponse};
+use emacs_remote::utils::frame::{read_frame, read_frame_tagged, write_frame, write_frame_tagged};
+use emacs_remote::utils::pool::ConnectionPool;
+use emacs_remote::version::PROTOCOL_VERSION;
+
+// Echoes pings on every connection, pushing a notification ahead of each response.
+// Returns the address and the number of connections accepted so far.
+fn serve() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+
+    let counter = accepted.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || echo(stream.unwrap()));
+        }
+    });
+    (addr, accepted)
+}
+
+fn echo(mut stream: TcpStream) {
+    let _: HandshakeRequest = rmps::from_slice(&read_frame(&mut stream).unwrap()).unwrap();
+    let response = HandshakeResponse::new(PROTOCOL_VERSION, "test".to_string(), false);
+    write_frame(&mut stream, &rmps::encode::to_vec(&response).unwrap()).unwrap();
+
+    while let Ok(buf) = read_frame(&mut stream) {
+        let request: PingRequest = rmps::from_slice(&buf).unwrap();
+        let notification = FileChangedNotification::new("a.txt".to_string(), request.nonce.into());
+        write_frame(&mut stream, &rmps::encode::to_vec(&notification).unwrap()).unwrap();
+        let response = PingResponse::new(request.nonce, request.sent_at_millis);
+        write_frame(&mut stream, &rmps::encode::to_vec(&response).unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn idle_connections_are_reused() {
+    let (addr, accepted) = serve();
+    let pool = ConnectionPool::new(addr, None, 1024 * 1024);
+    let timeout = Duration::from_secs(5);
+
+    for nonce in 0..3 {
+        let response: PingResponse = pool.request(&PingRequest::new(nonce, 0), timeout).unwrap();
+        assert_eq!(response.nonce, nonce);
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    let hashes: Vec<u128> = pool
+        .take_notifications()
+        .iter()
+        .map(|notification| notification.new_hash)
+        .collect();
+    assert_eq!(hashes, vec![0, 1, 2]);
+    assert!(pool.take_notifications().is_empty());
+}
+
+#[test]
+fn concurrent_requests_get_their_own_connections() {
+    let (addr, accepted) = serve();
+    let pool = Arc::new(ConnectionPool::new(addr, None, 1024 * 1024));
+
+    let handles: Vec<_> = (0..4)
+        .map(|nonce| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                let request = PingRequest::new(nonce, 0);
+                let response: PingResponse =
+                    pool.request(&request, Duration::from_secs(5)).unwrap();
+                assert_eq!(response.nonce, nonce);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let accepted = accepted.load(Ordering::SeqCst);
+    assert!((1..=4).contains(&accepted));
+}
+
+#[test]
+fn abandoned_streams_are_cancelled() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (ids, received) = mpsc::channel();
+    thread::spawn(move || {
+        let handshake = |stream: &mut TcpStream| {
+            read_frame(stream).unwrap();
+            let response = HandshakeResponse::new(PROTOCOL_VERSION, "test".to_string(), false);
+            write_frame(stream, &rmps::encode::to_vec(&response).unwrap()).unwrap();
+        };
+
+        // a grep that has more to send than anyone wants
+        let (mut grep, _) = listener.accept().unwrap();
+        handshake(&mut grep);
+        let (grep_id, _) = read_frame_tagged(&mut grep, usize::MAX).unwrap();
+        let grep_match = GrepMatch {
+            path: "a.txt".to_string(),
+            line_number: 1,
+            line: "a".to_string(),
+        };
+        let frame = rmps::encode::to_vec(&GrepMatchFrame::new(grep_match)).unwrap();
+        write_frame_tagged(&mut grep, &frame, None, grep_id).unwrap();
+
+        let (mut other, _) = listener.accept().unwrap();
+        handshake(&mut other);
+        let request: CancelRequest = envelope::decode(&read_frame(&mut other).unwrap()).unwrap();
+        let response = envelope::encode(&CancelResponse::new(true)).unwrap();
+        write_frame(&mut other, &response).unwrap();
+        ids.send((grep_id, request.request_id)).unwrap();
+    });
+
+    let pool = ConnectionPool::new(addr, None, 1024 * 1024);
+    let request = GrepRequest::new("a".to_string(), false, false, None);
+    let result = pool.request_each(&request, Duration::from_secs(5), |_| {
+        Err(ClientError::Io(io::Error::from(ErrorKind::BrokenPipe)))
+    });
+    assert!(result.is_err());
+
+    let (grep_id, cancelled_id) = received.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_ne!(grep_id, 0);
+    assert_eq!(cancelled_id, grep_id);
+}
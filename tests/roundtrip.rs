@@ -0,0 +1,366 @@
+use std::fs;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use flate2::read::GzDecoder;
+
+use emacs_remote::error::{ClientError, ServerError};
+use emacs_remote::messages::error::{BINARY_OR_TOO_LARGE, BUSY};
+use emacs_remote::messages::fileread::CHUNK_SIZE;
+use emacs_remote::messages::index::{IndexRequest, IndexResponse};
+use emacs_remote::messages::saveall::{SaveFile, SaveStatus};
+use emacs_remote::structs::client::ClientDaemon;
+use emacs_remote::structs::server::ServerDaemon;
+use emacs_remote::utils::hash::hash;
+use emacs_remote::utils::net::DEFAULT_BIND_ADDR;
+use emacs_remote::utils::transport::{Endpoint, Listener};
+
+// A real server listening on an ephemeral port in this process, serving a workspace seeded
+// with known files
+struct Harness {
+    root: tempfile::TempDir,
+    port: u32,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<Result<(), ServerError>>>,
+}
+
+impl Harness {
+    fn start(files: &[(&str, Vec<u8>)]) -> Harness {
+        Harness::start_with(files, |_| {})
+    }
+
+    // `configure` sets the server up before it starts listening
+    fn start_with<F>(files: &[(&str, Vec<u8>)], configure: F) -> Harness
+    where
+        F: FnOnce(&mut ServerDaemon),
+    {
+        let root = tempfile::tempdir().unwrap();
+        let workspace = root.path().join("workspace");
+        for (path, contents) in files {
+            let path = workspace.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let mut server = ServerDaemon::new(
+            root.path().join("server").to_str().unwrap().to_string(),
+            "0".to_string(),
+            workspace.to_str().unwrap().to_string(),
+        )
+        .unwrap();
+        configure(&mut server);
+        server.init().unwrap();
+        let shutdown = server.shutdown_flag();
+
+        let listener =
+            Listener::bind(&Endpoint::Tcp(SocketAddr::new(DEFAULT_BIND_ADDR, 0))).unwrap();
+        let port = match listener.local_endpoint().unwrap() {
+            Endpoint::Tcp(addr) => addr.port() as u32,
+            endpoint => panic!("expected a tcp endpoint, got {}", endpoint),
+        };
+        let server = Arc::new(server);
+        let handle = thread::spawn(move || server.listen_on(listener));
+
+        Harness {
+            root,
+            port,
+            shutdown,
+            handle: Some(handle),
+        }
+    }
+
+    fn workspace(&self) -> PathBuf {
+        self.root.path().join("workspace")
+    }
+
+    fn client(&self) -> ClientDaemon {
+        let mut client = self.unconnected_client();
+        client.connect(self.port).unwrap();
+        client
+    }
+
+    fn unconnected_client(&self) -> ClientDaemon {
+        ClientDaemon::new(
+            "localhost".to_string(),
+            self.workspace().to_str().unwrap().to_string(),
+            self.root
+                .path()
+                .join("client")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        )
+        .unwrap()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let result = handle.join().unwrap();
+            if !thread::panicking() {
+                result.unwrap();
+            }
+        }
+    }
+}
+
+fn seed() -> Vec<(&'static str, Vec<u8>)> {
+    // big enough to be sent in chunks
+    let large: Vec<u8> = (0..CHUNK_SIZE * 2 + 17)
+        .map(|i| b'a' + (i % 26) as u8)
+        .collect();
+    vec![
+        ("src/main.rs", b"fn main() {}\n".to_vec()),
+        ("src/lib.rs", b"pub mod roundtrip;\n".to_vec()),
+        ("README", b"read me\n".to_vec()),
+        ("large.txt", large),
+        ("blob.bin", b"\x7fELF\0\0\x01".to_vec()),
+    ]
+}
+
+#[test]
+fn index_lists_the_workspace() {
+    let harness = Harness::start(&seed());
+    let mut client = harness.client();
+
+    let response: IndexResponse = client
+        .send_request(&IndexRequest::new(0, "".to_string()))
+        .unwrap();
+
+    let mut listing = String::new();
+    GzDecoder::new(fs::File::open(&response.path_to_index_file).unwrap())
+        .read_to_string(&mut listing)
+        .unwrap();
+    let mut paths: Vec<&str> = listing.split(';').filter(|p| !p.is_empty()).collect();
+    paths.sort_unstable();
+    assert_eq!(
+        paths,
+        vec![
+            "README",
+            "blob.bin",
+            "large.txt",
+            "src/lib.rs",
+            "src/main.rs"
+        ]
+    );
+
+    // asking again for the same tree gives the same hash
+    let again: IndexResponse = client
+        .send_request(&IndexRequest::new(response.hash, "".to_string()))
+        .unwrap();
+    assert_eq!(again.hash, response.hash);
+}
+
+#[test]
+fn file_contents_round_trip() {
+    let files = seed();
+    let harness = Harness::start(&files);
+    let mut client = harness.client();
+
+    for (path, contents) in &files {
+        if *path == "blob.bin" {
+            continue;
+        }
+        assert_eq!(&client.read_file(path).unwrap(), contents, "{}", path);
+        // the second read is answered from the cache
+        assert_eq!(&client.read_file(path).unwrap(), contents, "{}", path);
+    }
+
+    match client.read_file("blob.bin") {
+        Err(ClientError::ServerReported { code, .. }) => assert_eq!(code, BINARY_OR_TOO_LARGE),
+        other => panic!("expected the binary file to be refused, got {:?}", other),
+    }
+    assert_eq!(client.read_file_with("blob.bin", true).unwrap(), files[4].1);
+
+    // a write lands on disk and shows up in the next read, also on a fresh connection
+    let updated = b"fn main() { run() }\n".to_vec();
+    let new_hash = client
+        .write_file("src/main.rs", updated.clone(), hash(&files[0].1))
+        .unwrap();
+    assert_eq!(new_hash, hash(&updated));
+    assert_eq!(
+        fs::read(harness.workspace().join("src/main.rs")).unwrap(),
+        updated
+    );
+    client.reconnect().unwrap();
+    assert_eq!(client.read_file("src/main.rs").unwrap(), updated);
+}
+
+#[test]
+fn save_all_writes_every_file_or_none() {
+    let files = seed();
+    let harness = Harness::start(&files);
+    let mut client = harness.client();
+    let workspace = harness.workspace();
+
+    // one stale base hash and nothing is written, the conflict is reported for that file
+    let response = client
+        .save_all(vec![
+            SaveFile::new(
+                "src/main.rs".to_string(),
+                b"one\n".to_vec(),
+                hash(&files[0].1),
+            ),
+            SaveFile::new(
+                "README".to_string(),
+                b"two\n".to_vec(),
+                hash(&b"stale".to_vec()),
+            ),
+        ])
+        .unwrap();
+    assert!(!response.saved);
+    assert_eq!(
+        response.files,
+        vec![
+            SaveStatus::new("src/main.rs".to_string(), hash(&files[0].1), false),
+            SaveStatus::new("README".to_string(), hash(&files[2].1), true),
+        ]
+    );
+    assert_eq!(fs::read(workspace.join("src/main.rs")).unwrap(), files[0].1);
+    assert_eq!(fs::read(workspace.join("README")).unwrap(), files[2].1);
+
+    // with current hashes everything lands, including a new file, and nothing is left behind
+    let response = client
+        .save_all(vec![
+            SaveFile::new(
+                "src/main.rs".to_string(),
+                b"one\n".to_vec(),
+                hash(&files[0].1),
+            ),
+            SaveFile::new("README".to_string(), b"two\n".to_vec(), hash(&files[2].1)),
+            SaveFile::new("src/new.rs".to_string(), b"three\n".to_vec(), 0),
+        ])
+        .unwrap();
+    assert!(response.saved);
+    assert!(response.files.iter().all(|status| !status.conflict));
+    assert_eq!(fs::read(workspace.join("src/main.rs")).unwrap(), b"one\n");
+    assert_eq!(fs::read(workspace.join("README")).unwrap(), b"two\n");
+    assert_eq!(fs::read(workspace.join("src/new.rs")).unwrap(), b"three\n");
+    let mut names: Vec<String> = fs::read_dir(workspace.join("src"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["lib.rs", "main.rs", "new.rs"]);
+
+    // saving the same file twice in one request is refused
+    assert!(client
+        .save_all(vec![
+            SaveFile::new(
+                "README".to_string(),
+                b"a".to_vec(),
+                hash(&b"two\n".to_vec())
+            ),
+            SaveFile::new(
+                "README".to_string(),
+                b"b".to_vec(),
+                hash(&b"two\n".to_vec())
+            ),
+        ])
+        .is_err());
+    assert_eq!(client.read_file("README").unwrap(), b"two\n");
+}
+
+#[test]
+fn archives_seed_the_mirror() {
+    let long_name = format!("src/{}/deep.rs", "nested".repeat(20));
+    let mut files = seed();
+    files.push((".gitignore", b"target/\n".to_vec()));
+    files.push(("target/debug/out.o", b"object".to_vec()));
+    files.push((
+        Box::leak(long_name.clone().into_boxed_str()),
+        b"deep".to_vec(),
+    ));
+    let harness = Harness::start(&files);
+    let mut client = harness.client();
+    let mirror = client.mirror_path();
+
+    for compress in [false, true] {
+        let _ = fs::remove_dir_all(&mirror);
+        let done = client
+            .pull_archive("", &["*.bin".to_string()], compress)
+            .unwrap();
+        assert_eq!(done.unpacked_to, mirror.to_str().unwrap());
+        assert_eq!(done.files, 6);
+
+        for (path, contents) in &files {
+            let unpacked = mirror.join(path);
+            if path.starts_with("target/") || path.ends_with(".bin") {
+                assert!(!unpacked.exists(), "{}", path);
+            } else {
+                assert_eq!(&fs::read(&unpacked).unwrap(), contents, "{}", path);
+            }
+        }
+    }
+
+    // only what is under the path, and the connection is still good afterwards
+    let _ = fs::remove_dir_all(&mirror);
+    let done = client.pull_archive("src", &[], true).unwrap();
+    assert_eq!(done.files, 3);
+    assert!(!mirror.join("README").exists());
+    assert!(mirror.join("src/main.rs").exists());
+    assert!(matches!(
+        client.pull_archive("../", &[], false),
+        Err(ClientError::ServerReported { .. })
+    ));
+    assert_eq!(client.read_file("README").unwrap(), b"read me\n".to_vec());
+}
+
+#[test]
+fn connections_over_the_limit_are_refused() {
+    let harness = Harness::start_with(&seed(), |server| server.max_connections = Some(1));
+    let mut client = harness.client();
+    client.ping().unwrap();
+
+    let mut refused = harness.unconnected_client();
+    match refused.connect(harness.port) {
+        Err(ClientError::ServerReported { code, .. }) => assert_eq!(code, BUSY),
+        other => panic!("expected a BUSY error, got {:?}", other),
+    }
+
+    // the server notices the first connection closing within a poll of its listener
+    drop(client);
+    let mut attempts = 0;
+    while refused.connect(harness.port).is_err() {
+        attempts += 1;
+        assert!(attempts < 50, "the freed connection was never reused");
+        thread::sleep(Duration::from_millis(50));
+    }
+    refused.ping().unwrap();
+}
+
+#[test]
+fn connections_past_the_queue_are_refused() {
+    let harness = Harness::start_with(&seed(), |server| {
+        server.workers = 1;
+        server.queue_depth = 1;
+    });
+    let mut client = harness.client();
+    client.ping().unwrap();
+
+    // the only worker is serving the first connection and the second fills the queue
+    let queued =
+        TcpStream::connect(SocketAddr::new(DEFAULT_BIND_ADDR, harness.port as u16)).unwrap();
+    let mut refused = harness.unconnected_client();
+    match refused.connect(harness.port) {
+        Err(ClientError::ServerReported { code, .. }) => assert_eq!(code, BUSY),
+        other => panic!("expected a BUSY error, got {:?}", other),
+    }
+
+    drop(client);
+    drop(queued);
+    let mut attempts = 0;
+    while refused.connect(harness.port).is_err() {
+        attempts += 1;
+        assert!(attempts < 50, "the worker was never freed");
+        thread::sleep(Duration::from_millis(50));
+    }
+    refused.ping().unwrap();
+}
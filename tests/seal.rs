@@ -0,0 +1,159 @@
+use std::fs;
+use std::io::{Cursor, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+
+use emacs_remote::utils::frame::{
+    read_frame_sealed, write_frame, write_frame_sealed, COMPRESSION_THRESHOLD,
+    DEFAULT_MAX_FRAME_SIZE, FLAG_SEALED,
+};
+use emacs_remote::utils::seal::{self, PresharedKey};
+
+fn key(key: &str) -> PresharedKey {
+    PresharedKey::new(key.as_bytes().to_vec()).unwrap()
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+// Known answers, computed with another HMAC-SHA256 and AES-256-GCM, so that what goes over
+// the wire stays the same whatever implements them
+#[test]
+fn sealing_matches_hmac_sha256_and_aes_256_gcm() {
+    let key = key("0123456789abcdef");
+    let (client, server) = ([1; seal::NONCE_SIZE], [2; seal::NONCE_SIZE]);
+    let proof = key.proof(&client, &server).unwrap();
+    assert_eq!(
+        proof.to_vec(),
+        unhex("b72b3b4379cc0f911fe37df8286a33a4458731b8de1690f5771d17da338938ad")
+    );
+
+    let (mut sealer, _) = key.client_keys(&client, &server).unwrap();
+    let sealed = sealer.seal(b"hdr", b"hello").unwrap();
+    assert_eq!(sealed, unhex("30bb59f9e04b0484070c88c1dd67bbd288f0e7285c"));
+    let (_, mut opener) = key.server_keys(&client, &server).unwrap();
+    assert_eq!(opener.open(b"hdr", &sealed).unwrap(), b"hello");
+}
+
+#[test]
+fn sealed_frames_open_on_the_other_end() {
+    let key = key("correct horse battery staple");
+    let (client, server) = (seal::nonce(), seal::nonce());
+    let (mut sealer, _) = key.client_keys(&client, &server).unwrap();
+    let (_, mut opener) = key.server_keys(&client, &server).unwrap();
+
+    let small = b"find-file".to_vec();
+    let large = b"emacs-remote ".repeat(COMPRESSION_THRESHOLD);
+    let mut wire = Vec::new();
+    write_frame_sealed(&mut wire, &small, None, 0, Some(&mut sealer)).unwrap();
+    assert_eq!(wire[4], FLAG_SEALED);
+    assert!(!wire.windows(small.len()).any(|w| w == &small[..]));
+    write_frame_sealed(&mut wire, &large, Some(3), 7, Some(&mut sealer)).unwrap();
+
+    let mut wire = Cursor::new(wire);
+    for expected in [(0, small), (7, large)] {
+        let frame = read_frame_sealed(&mut wire, DEFAULT_MAX_FRAME_SIZE, Some(&mut opener));
+        assert_eq!(frame.unwrap(), expected);
+    }
+}
+
+#[test]
+fn tampered_replayed_and_foreign_frames_fail_to_open() {
+    let key = key("correct horse battery staple");
+    let (client, server) = (seal::nonce(), seal::nonce());
+    let seal_frame = |payload: &[u8], request_id| {
+        let (mut sealer, _) = key.client_keys(&client, &server).unwrap();
+        let mut wire = Vec::new();
+        write_frame_sealed(&mut wire, payload, None, request_id, Some(&mut sealer)).unwrap();
+        wire
+    };
+    let open = |wires: &[&Vec<u8>]| {
+        let (_, mut opener) = key.server_keys(&client, &server).unwrap();
+        let mut result = Ok((0, Vec::new()));
+        for wire in wires {
+            result = read_frame_sealed(
+                &mut Cursor::new(wire.to_vec()),
+                DEFAULT_MAX_FRAME_SIZE,
+                Some(&mut opener),
+            );
+        }
+        result
+    };
+
+    let wire = seal_frame(b"find-file", 3);
+    assert!(open(&[&wire]).is_ok());
+    // the request id is covered as well as the payload
+    for byte in [12, wire.len() - 1] {
+        let mut tampered = wire.clone();
+        tampered[byte] ^= 1;
+        assert_eq!(
+            open(&[&tampered]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+    assert!(open(&[&wire, &wire]).is_err());
+
+    // another connection's keys
+    let (_, mut opener) = key.server_keys(&client, &seal::nonce()).unwrap();
+    assert!(read_frame_sealed(
+        &mut Cursor::new(wire.clone()),
+        DEFAULT_MAX_FRAME_SIZE,
+        Some(&mut opener)
+    )
+    .is_err());
+
+    // sealed and unsealed frames don't mix
+    let mut plain = Vec::new();
+    write_frame(&mut plain, b"find-file").unwrap();
+    assert!(open(&[&plain]).is_err());
+    assert!(read_frame_sealed(&mut Cursor::new(wire), DEFAULT_MAX_FRAME_SIZE, None).is_err());
+}
+
+#[test]
+fn only_the_same_key_proves_itself() {
+    let (client, server) = (seal::nonce(), seal::nonce());
+    let proof = key("correct horse battery staple")
+        .proof(&client, &server)
+        .unwrap();
+    assert!(key("correct horse battery staple")
+        .verify(&client, &server, &proof)
+        .unwrap());
+    assert!(!key("incorrect horse battery staple")
+        .verify(&client, &server, &proof)
+        .unwrap());
+    assert!(!key("correct horse battery staple")
+        .verify(&server, &client, &proof)
+        .unwrap());
+}
+
+#[test]
+fn key_files_must_be_private_and_long_enough() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("psk");
+    let write = |contents: &str, mode| {
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    };
+
+    write("  correct horse battery staple\n", 0o600);
+    let loaded = PresharedKey::load(&path).unwrap();
+    let (client, server) = (seal::nonce(), seal::nonce());
+    let proof = loaded.proof(&client, &server).unwrap();
+    assert!(key("correct horse battery staple")
+        .verify(&client, &server, &proof)
+        .unwrap());
+
+    write("correct horse battery staple\n", 0o644);
+    assert_eq!(
+        PresharedKey::load(&path).unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    write("hunter2\n", 0o600);
+    assert_eq!(
+        PresharedKey::load(&path).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+}
@@ -0,0 +1,1340 @@
+extern crate rmp_serde as rmps;
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use emacs_remote::error::{ClientError, ServerError};
+use emacs_remote::handle::HandleServerDaemon;
+use emacs_remote::messages::batch::{BatchRequest, BatchResponse, Envelope};
+use emacs_remote::messages::cancel::{CancelRequest, CancelResponse};
+use emacs_remote::messages::changes::{ChangesSinceRequest, ChangesSinceResponse};
+use emacs_remote::messages::completion::{CompletionRequest, CompletionResponse};
+use emacs_remote::messages::diff::{DiffRequest, DiffResponse, MAX_DIFF_SIZE};
+use emacs_remote::messages::envelope;
+use emacs_remote::messages::error::{
+    ErrorResponse, BINARY_OR_TOO_LARGE, CANCELLED, CONFLICT, NOT_FOUND, PERMISSION_DENIED,
+    UNSUPPORTED,
+};
+use emacs_remote::messages::filechanged::{BulkChangeNotification, FileChangedNotification};
+use emacs_remote::messages::fileops::{
+    DeleteRequest, FileOpResponse, MkdirRequest, RenameRequest, TouchRequest,
+};
+use emacs_remote::messages::fileread::{FileChunk, FileReadRequest, FileReadResponse, CHUNK_SIZE};
+use emacs_remote::messages::filestat::{FileStatRequest, FileStatResponse};
+use emacs_remote::messages::filewrite::{FileWriteRequest, FileWriteResponse};
+use emacs_remote::messages::grep::{
+    GrepDone, GrepMatch, GrepMatchFrame, GrepRequest, GrepResponse,
+};
+use emacs_remote::messages::handshake::{HandshakeRequest, HandshakeResponse};
+use emacs_remote::messages::history::{
+    HistoryRequest, HistoryResponse, RestoreRequest, RestoreResponse,
+};
+use emacs_remote::messages::messagetype::MessageType;
+use emacs_remote::messages::rescan::{RescanRequest, RescanResponse};
+use emacs_remote::messages::version::{VersionRequest, VersionResponse};
+use emacs_remote::messages::watch::{UnwatchRequest, WatchRequest, WatchResponse};
+use emacs_remote::messages::workspaceinfo::{WorkspaceInfoRequest, WorkspaceInfoResponse};
+use emacs_remote::structs::index::index_file_name;
+use emacs_remote::structs::server::{ServerDaemon, BULK_NOTIFICATION_THRESHOLD};
+use emacs_remote::utils::connection::Connection;
+use emacs_remote::utils::frame::{
+    read_frame, read_frame_tagged, write_frame, write_frame_tagged, DEFAULT_MAX_FRAME_SIZE,
+};
+use emacs_remote::utils::fuzzy;
+use emacs_remote::utils::hash;
+use emacs_remote::utils::pool;
+use emacs_remote::utils::seal::PresharedKey;
+use emacs_remote::utils::stream::{peek_message_type, recv, send};
+use emacs_remote::utils::transport::MemoryTransport;
+use emacs_remote::version::{BUILD_HASH, PROTOCOL_VERSION, VERSION};
+
+fn server(root: &tempfile::TempDir) -> ServerDaemon {
+    let workspace = root.path().join("workspace");
+    fs::create_dir_all(workspace.join("src")).unwrap();
+    fs::write(workspace.join("src").join("main.rs"), "fn main() {}").unwrap();
+    fs::write(root.path().join("secret"), "hunter2").unwrap();
+
+    ServerDaemon::new(
+        root.path()
+            .join(".emacs_remote")
+            .to_str()
+            .unwrap()
+            .to_string(),
+        "0".to_string(),
+        workspace.to_str().unwrap().to_string(),
+    )
+    .unwrap()
+}
+
+// Runs `request` through its server handler and returns the raw response frame
+fn serve<T: HandleServerDaemon>(server: &ServerDaemon, request: T) -> Vec<u8> {
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+
+    request.handle(&mut stream, server).unwrap();
+    read_frame(&mut client).unwrap()
+}
+
+#[test]
+fn resolve_in_workspace_accepts_workspace_paths() {
+    let root = tempfile::tempdir().unwrap();
+    let server = server(&root);
+
+    let resolved = server.resolve_in_workspace("src/main.rs").unwrap();
+    assert!(resolved.ends_with("workspace/src/main.rs"));
+    for path in &["./src//main.rs", "src/main.rs/", "src/./main.rs"] {
+        assert_eq!(
+            server.resolve_in_workspace(path).unwrap(),
+            resolved,
+            "{}",
+            path
+        );
+    }
+
+    // files that don't exist yet still resolve so they can be created
+    let resolved = server.resolve_in_workspace("src/new.rs").unwrap();
+    assert!(resolved.ends_with("workspace/src/new.rs"));
+}
+
+#[test]
+fn resolve_in_workspace_rejects_escapes() {
+    let root = tempfile::tempdir().unwrap();
+    let server = server(&root);
+
+    for path in &["../secret", "src/../../secret", "../does-not-exist"] {
+        match server.resolve_in_workspace(path) {
+            Err(ServerError::PathEscape(escaped)) => assert_eq!(&escaped, path),
+            other => panic!("expected PathEscape for {}, got {:?}", path, other),
+        }
+    }
+
+    let secret = root.path().join("secret");
+    match server.resolve_in_workspace(secret.to_str().unwrap()) {
+        Err(ServerError::PathEscape(_)) => {}
+        other => panic!("expected PathEscape, got {:?}", other),
+    }
+}
+
+#[test]
+fn workspaces_outside_the_allowlist_are_refused() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+
+    server.allowed_workspaces = vec![root.path().join("elsewhere"), root.path().to_path_buf()];
+    server.init().unwrap();
+    assert!(server.resolve_in_workspace("src/main.rs").is_ok());
+
+    server.allowed_workspaces = vec![root.path().join("workspace").join("src")];
+    match server.init() {
+        Err(ServerError::WorkspaceNotAllowed(workspace)) => {
+            assert_eq!(workspace, server.workspace)
+        }
+        other => panic!("expected WorkspaceNotAllowed, got {:?}", other),
+    }
+
+    // requests are refused too, with a permission error the client can show
+    let request = FileReadRequest::new("src/main.rs".to_string(), 0);
+    let e = request.handle(&mut Connection::new(MemoryTransport::new()), &server);
+    assert_eq!(e.unwrap_err().code(), PERMISSION_DENIED);
+}
+
+#[test]
+fn ports_in_use_are_reported_as_such() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    server.port = port.to_string();
+
+    match server.bind() {
+        Err(e @ ServerError::AddrInUse(_)) => assert_eq!(
+            e.to_string(),
+            format!(
+                "port {} already in use; is another emacs-remote server running?",
+                port
+            )
+        ),
+        other => panic!("expected AddrInUse, got {:?}", other.err()),
+    }
+
+    drop(taken);
+    assert!(server.bind().is_ok());
+}
+
+#[test]
+fn missing_workspaces_are_reported_as_such() {
+    let root = tempfile::tempdir().unwrap();
+    let emacs_remote_path = root.path().join(".emacs_remote");
+    let new_server = |workspace: &Path| {
+        ServerDaemon::new(
+            emacs_remote_path.to_str().unwrap().to_string(),
+            "0".to_string(),
+            workspace.to_str().unwrap().to_string(),
+        )
+        .unwrap()
+    };
+
+    let missing = root.path().join("missing");
+    assert!(matches!(
+        new_server(&missing).init(),
+        Err(ServerError::WorkspaceNotFound(workspace)) if workspace == missing.to_str().unwrap()
+    ));
+
+    let file = root.path().join("file");
+    fs::write(&file, "not a directory").unwrap();
+    let mut server = new_server(&file);
+    assert!(matches!(
+        server.init(),
+        Err(ServerError::WorkspaceNotADirectory(_))
+    ));
+}
+
+#[test]
+fn stat_describes_symlinks_themselves() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let root = tempfile::tempdir().unwrap();
+    let server = server(&root);
+    let workspace = root.path().join("workspace");
+    fs::set_permissions(
+        workspace.join("src/main.rs"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+    symlink("main.rs", workspace.join("src/link.rs")).unwrap();
+    // a link out of the workspace can be described, just not followed
+    symlink(root.path().join("secret"), workspace.join("secret")).unwrap();
+
+    let stat = |path: &str| -> FileStatResponse {
+        envelope::decode(&serve(&server, FileStatRequest::new(path.to_string()))).unwrap()
+    };
+
+    let file = stat("src/main.rs");
+    assert_eq!(file.size, 12);
+    assert_eq!(file.mode & 0o777, 0o755);
+    assert!(!file.is_symlink && !file.is_dir);
+    assert_eq!(file.symlink_target, None);
+    assert_ne!(file.mtime, 0);
+    assert_ne!(file.ctime, 0);
+
+    let link = stat("src/link.rs");
+    assert!(link.is_symlink);
+    assert_eq!(link.symlink_target, Some("main.rs".to_string()));
+
+    let outside = stat("secret");
+    assert!(outside.is_symlink);
+    assert_eq!(
+        outside.symlink_target,
+        Some(root.path().join("secret").to_str().unwrap().to_string())
+    );
+
+    assert!(stat("src").is_dir);
+
+    let request = FileStatRequest::new("../secret".to_string());
+    match request.handle(&mut Connection::new(MemoryTransport::new()), &server) {
+        Err(ServerError::PathEscape(path)) => assert_eq!(path, "../secret"),
+        other => panic!("expected PathEscape, got {:?}", other),
+    }
+}
+
+#[test]
+fn grep_searches_indexed_files() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let workspace = root.path().join("workspace");
+    fs::write(workspace.join("src/lib.rs"), "pub fn Main() {}\n// main\n").unwrap();
+    fs::write(workspace.join("notes.txt"), "main street").unwrap();
+    fs::write(workspace.join("blob.bin"), b"main\0\x01").unwrap();
+    server.init().unwrap();
+
+    let request = GrepRequest::new(
+        "fn main".to_string(),
+        false,
+        true,
+        Some("src/**".to_string()),
+    );
+    let response: GrepResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    assert!(!response.truncated);
+    assert_eq!(
+        response.matches,
+        vec![
+            GrepMatch {
+                path: "src/lib.rs".to_string(),
+                line_number: 1,
+                line: "pub fn Main() {}".to_string(),
+            },
+            GrepMatch {
+                path: "src/main.rs".to_string(),
+                line_number: 1,
+                line: "fn main() {}".to_string(),
+            },
+        ]
+    );
+
+    // binary files are skipped
+    let request = GrepRequest::new("^main".to_string(), true, false, None);
+    let response: GrepResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    let paths: Vec<&str> = response.matches.iter().map(|m| m.path.as_str()).collect();
+    assert_eq!(paths, vec!["notes.txt"]);
+}
+
+#[test]
+fn grep_streams_matches_to_peers_that_take_them() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let workspace = root.path().join("workspace");
+    fs::write(workspace.join("src/lib.rs"), "fn main() {}\n// main\n").unwrap();
+    server.init().unwrap();
+
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+    stream.set_protocol_version(PROTOCOL_VERSION);
+
+    let request = GrepRequest::new("main".to_string(), false, false, None);
+    request.handle(&mut stream, &server).unwrap();
+
+    let mut lines = Vec::new();
+    for _ in 0..3 {
+        let frame: GrepMatchFrame = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+        lines.push((frame.grep_match.path, frame.grep_match.line_number));
+    }
+    assert_eq!(
+        lines,
+        vec![
+            ("src/lib.rs".to_string(), 1),
+            ("src/lib.rs".to_string(), 2),
+            ("src/main.rs".to_string(), 1),
+        ]
+    );
+    let done: GrepDone = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+    assert_eq!(done.total, 3);
+    assert!(!done.truncated);
+}
+
+#[test]
+fn binary_and_large_files_are_only_read_when_forced() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let workspace = root.path().join("workspace");
+    fs::write(workspace.join("blob.bin"), b"ELF\0\x01").unwrap();
+    fs::write(workspace.join("big.txt"), "x".repeat(100)).unwrap();
+    server.max_read_size = Some(64);
+
+    for path in &["blob.bin", "big.txt"] {
+        let request = FileReadRequest::new(path.to_string(), 0);
+        let e = request.handle(&mut Connection::new(MemoryTransport::new()), &server);
+        assert_eq!(e.unwrap_err().code(), BINARY_OR_TOO_LARGE, "{}", path);
+
+        let request = FileReadRequest::forced(path.to_string(), 0, true);
+        let response: FileReadResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+        assert_eq!(response.contents, fs::read(workspace.join(path)).unwrap());
+    }
+
+    // text under the limit needs no force
+    let request = FileReadRequest::new("src/main.rs".to_string(), 0);
+    let response: FileReadResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    assert_eq!(response.contents, b"fn main() {}");
+}
+
+#[test]
+fn file_changed_updates_the_index() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+    let before = server.index().unwrap().files["src/main.rs"].content_hash;
+
+    let path = server.resolve_in_workspace("src/main.rs").unwrap();
+    fs::write(&path, "fn main() { println!(); }").unwrap();
+    let new = server.resolve_in_workspace("src/new.rs").unwrap();
+    fs::write(&new, "").unwrap();
+    server.file_changed(&[path, new]).unwrap();
+
+    let index = server.index().unwrap();
+    assert_ne!(index.files["src/main.rs"].content_hash, before);
+    assert!(index.files.contains_key("src/new.rs"));
+}
+
+#[test]
+fn index_readers_do_not_wait_for_each_other() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+
+    let held = server.index().unwrap();
+    let count = thread::scope(|s| {
+        s.spawn(|| server.index().unwrap().files.len())
+            .join()
+            .unwrap()
+    });
+    assert_eq!(count, held.files.len());
+    drop(held);
+
+    // a handler reading the index meanwhile isn't held up either
+    let guard = server.index().unwrap();
+    let response: CompletionResponse = rmps::from_slice(&serve(
+        &server,
+        CompletionRequest::new("main".to_string(), 10),
+    ))
+    .unwrap();
+    assert_eq!(response.paths, vec!["src/main.rs"]);
+    drop(guard);
+}
+
+#[test]
+fn workspace_info_reports_capabilities() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+
+    let response: WorkspaceInfoResponse =
+        rmps::from_slice(&serve(&server, WorkspaceInfoRequest::new())).unwrap();
+    assert_eq!(response.file_count, 1);
+    assert_eq!(response.index_hash, server.index().unwrap().hash);
+    assert!(response.workspace_root.ends_with("workspace"));
+    assert!(response.supports(MessageType::GrepRequest));
+    assert!(response.supports(MessageType::WorkspaceInfoRequest));
+    assert!(!response.supports(MessageType::HandshakeRequest));
+}
+
+#[test]
+fn version_reports_the_running_build() {
+    let root = tempfile::tempdir().unwrap();
+    let server = server(&root);
+
+    let response: VersionResponse =
+        rmps::from_slice(&serve(&server, VersionRequest::new())).unwrap();
+    assert_eq!(response.server_version, VERSION);
+    assert_eq!(response.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(response.build_hash, BUILD_HASH);
+    assert!(!response.build_hash.is_empty());
+}
+
+#[test]
+fn index_file_is_named_for_the_workspace() {
+    let root = tempfile::tempdir().unwrap();
+    let server = server(&root);
+
+    // clients pull this file over scp before the tunnel is up
+    let name = index_file_name(&server.workspace);
+    assert!(server.index_file().ends_with(format!("server/{}", name)));
+}
+
+#[test]
+fn rename_and_delete_update_the_index() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let workspace = root.path().join("workspace");
+    fs::write(workspace.join("notes.txt"), "notes").unwrap();
+    server.init().unwrap();
+
+    let request = RenameRequest::new("src".to_string(), "lib".to_string());
+    let response: FileOpResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    assert!(response.success);
+    assert_eq!(response.index_hash, server.index().unwrap().hash);
+    let paths: Vec<String> = server.index().unwrap().files.keys().cloned().collect();
+    assert_eq!(paths, vec!["lib/main.rs", "notes.txt"]);
+
+    // an existing destination is left alone
+    let request = RenameRequest::new("notes.txt".to_string(), "lib/main.rs".to_string());
+    let response: FileOpResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    assert!(!response.success);
+    assert!(workspace.join("notes.txt").exists());
+
+    // non-empty directories need recursive
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+    assert!(DeleteRequest::new("lib".to_string(), false)
+        .handle(&mut stream, &server)
+        .is_err());
+    match DeleteRequest::new(".".to_string(), true).handle(&mut stream, &server) {
+        Err(e) => assert_eq!(e.code(), PERMISSION_DENIED),
+        Ok(()) => panic!("deleted the workspace root"),
+    }
+
+    let response: FileOpResponse =
+        rmps::from_slice(&serve(&server, DeleteRequest::new("lib".to_string(), true))).unwrap();
+    assert!(response.success);
+    assert!(!workspace.join("lib").exists());
+    assert_eq!(
+        server.index().unwrap().files.keys().collect::<Vec<_>>(),
+        vec!["notes.txt"]
+    );
+}
+
+#[test]
+fn touch_and_mkdir_create_what_isnt_there() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+    let workspace = root.path().join("workspace");
+
+    let request = MkdirRequest::new("docs/notes".to_string(), true);
+    let response: FileOpResponse = envelope::decode(&serve(&server, request)).unwrap();
+    assert!(response.success);
+    assert!(workspace.join("docs/notes").is_dir());
+
+    let request = TouchRequest::new("docs/notes/todo.org".to_string());
+    let response: FileOpResponse = envelope::decode(&serve(&server, request)).unwrap();
+    assert_eq!(response.index_hash, server.index().unwrap().hash);
+    assert_eq!(
+        fs::read(workspace.join("docs/notes/todo.org")).unwrap(),
+        b""
+    );
+    assert!(server
+        .index()
+        .unwrap()
+        .files
+        .contains_key("docs/notes/todo.org"));
+
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+    let code = |result: Result<(), ServerError>| result.unwrap_err().code();
+    // existing paths are left alone
+    assert_eq!(
+        code(TouchRequest::new("src/main.rs".to_string()).handle(&mut stream, &server)),
+        CONFLICT
+    );
+    assert_eq!(
+        fs::read(workspace.join("src/main.rs")).unwrap(),
+        b"fn main() {}"
+    );
+    assert_eq!(
+        code(MkdirRequest::new("docs".to_string(), true).handle(&mut stream, &server)),
+        CONFLICT
+    );
+    // parents are only created when asked to
+    assert_eq!(
+        code(MkdirRequest::new("a/b".to_string(), false).handle(&mut stream, &server)),
+        NOT_FOUND
+    );
+    assert_eq!(
+        code(TouchRequest::new("../escape".to_string()).handle(&mut stream, &server)),
+        PERMISSION_DENIED
+    );
+}
+
+#[test]
+fn completion_ranks_indexed_paths() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let workspace = root.path().join("workspace");
+    fs::create_dir_all(workspace.join("src/handlers")).unwrap();
+    fs::write(workspace.join("src/handlers/main_menu.rs"), "").unwrap();
+    fs::write(workspace.join("src/domain.rs"), "").unwrap();
+    fs::write(workspace.join("README"), "").unwrap();
+    server.init().unwrap();
+
+    let response: CompletionResponse = rmps::from_slice(&serve(
+        &server,
+        CompletionRequest::new("main".to_string(), 10),
+    ))
+    .unwrap();
+    assert_eq!(
+        response.paths,
+        vec!["src/main.rs", "src/handlers/main_menu.rs", "src/domain.rs"]
+    );
+    assert!(!response.truncated);
+
+    let response: CompletionResponse = rmps::from_slice(&serve(
+        &server,
+        CompletionRequest::new("SrcMa".to_string(), 1),
+    ))
+    .unwrap();
+    assert_eq!(response.paths, vec!["src/main.rs"]);
+    assert!(response.truncated);
+    assert_eq!(
+        response.scores,
+        vec![fuzzy::score("SrcMa", "src/main.rs").unwrap()]
+    );
+}
+
+#[test]
+fn completion_finds_files_in_a_realistic_tree() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let workspace = root.path().join("workspace");
+    for path in [
+        "Cargo.toml",
+        "README.org",
+        "emacs_remote/emacs-remote.el",
+        "src/error.rs",
+        "src/structs/client.rs",
+        "src/structs/server.rs",
+        "src/structs/index.rs",
+        "src/structs/history.rs",
+        "src/handlers/fileread.rs",
+        "src/handlers/cancel.rs",
+        "src/messages/fileread.rs",
+        "src/messages/completion.rs",
+        "src/utils/connection.rs",
+        "src/utils/frame.rs",
+        "src/utils/seal.rs",
+        "tests/client.rs",
+        "tests/server.rs",
+        "tests/roundtrip.rs",
+    ] {
+        let path = workspace.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+    server.init().unwrap();
+
+    let complete = |pattern: &str| {
+        let response: CompletionResponse = rmps::from_slice(&serve(
+            &server,
+            CompletionRequest::new(pattern.to_string(), 5),
+        ))
+        .unwrap();
+        response.paths
+    };
+    assert_eq!(complete("stcl")[0], "src/structs/client.rs");
+    assert_eq!(complete("hafr")[0], "src/handlers/fileread.rs");
+    assert_eq!(complete("mefr")[0], "src/messages/fileread.rs");
+    assert_eq!(complete("tecl")[0], "tests/client.rs");
+    // the files named after the pattern come before the paths it merely spells out
+    let mut named = complete("client")[..2].to_vec();
+    named.sort();
+    assert_eq!(named, ["src/structs/client.rs", "tests/client.rs"]);
+}
+
+#[test]
+fn completion_scorers_can_be_swapped() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    fs::write(root.path().join("workspace/src/lib.rs"), "").unwrap();
+    server.set_scorer(|pattern: &str, path: &str| {
+        path.starts_with(pattern).then(|| -(path.len() as i64))
+    });
+    server.init().unwrap();
+
+    let response: CompletionResponse = rmps::from_slice(&serve(
+        &server,
+        CompletionRequest::new("src/".to_string(), 10),
+    ))
+    .unwrap();
+    assert_eq!(response.paths, vec!["src/lib.rs", "src/main.rs"]);
+    assert_eq!(response.scores, vec![-10, -11]);
+
+    let response: CompletionResponse = rmps::from_slice(&serve(
+        &server,
+        CompletionRequest::new("main".to_string(), 10),
+    ))
+    .unwrap();
+    assert!(response.paths.is_empty());
+}
+
+#[test]
+fn idle_connections_are_reaped() {
+    let root = tempfile::tempdir().unwrap();
+    let server = Arc::new(server(&root));
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+
+    let serving = server.clone();
+    let handle = thread::spawn(move || serving.handle_connection(&mut stream));
+    let request = HandshakeRequest::new(PROTOCOL_VERSION, false);
+    write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+    let _: HandshakeResponse = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+
+    assert_eq!(server.reap_idle_connections(Duration::from_secs(60)), 0);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(server.reap_idle_connections(Duration::from_millis(10)), 1);
+
+    // the connection thread sees EOF and winds down
+    assert!(read_frame(&mut client).is_err());
+    handle.join().unwrap().unwrap();
+    assert_eq!(server.metrics().active_connections(), 0);
+}
+
+#[test]
+fn watches_narrow_notifications_to_their_subtree() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+    let server = Arc::new(server);
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+
+    let serving = server.clone();
+    let handle = thread::spawn(move || serving.handle_connection(&mut stream));
+    let request = HandshakeRequest::new(PROTOCOL_VERSION, false);
+    write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+    let _: HandshakeResponse = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+
+    let request = WatchRequest::new("src/../src".to_string(), false);
+    write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+    let response: WatchResponse = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+    assert_eq!(response.watching, vec!["src"]);
+
+    let change = |rel: &str| {
+        let path = root.path().join("workspace").join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, rel).unwrap();
+        let path = server.resolve_in_workspace(rel).unwrap();
+        server.file_changed(&[path]).unwrap();
+    };
+    // outside of src, and too deep for a watch that isn't recursive
+    change("README.md");
+    change("src/nested/lib.rs");
+    change("src/main.rs");
+    let notification: FileChangedNotification =
+        rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+    assert_eq!(notification.path, "src/main.rs");
+
+    let request = UnwatchRequest::new("src".to_string());
+    write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+    let response: WatchResponse = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+    assert!(response.watching.is_empty());
+
+    // watching nothing at all, so the next frame is the response to the escape
+    change("src/main.rs");
+    let request = WatchRequest::new("..".to_string(), true);
+    write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+    let response: ErrorResponse = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+    assert_eq!(response.code, PERMISSION_DENIED);
+
+    drop(client);
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn bursts_of_changes_are_sent_as_one_notification() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+    let server = Arc::new(server);
+    let listener = TcpListener::bind("localhost:0").unwrap();
+
+    let connect = |protocol_version: u32| {
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Connection::new(listener.accept().unwrap().0);
+        let serving = server.clone();
+        let handle = thread::spawn(move || serving.handle_connection(&mut stream));
+
+        let request = HandshakeRequest::new(protocol_version, false);
+        write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+        let _: HandshakeResponse = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+        // once this is answered the connection is subscribed
+        let request = VersionRequest::new();
+        write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+        let _: VersionResponse = rmps::from_slice(&read_frame(&mut client).unwrap()).unwrap();
+        (client, handle)
+    };
+    let (mut current, current_handle) = connect(PROTOCOL_VERSION);
+    let (mut legacy, legacy_handle) = connect((3 << 16) | 2);
+
+    let count = BULK_NOTIFICATION_THRESHOLD + 4;
+    let paths: Vec<String> = (0..count).map(|i| format!("src/gen{}.rs", i)).collect();
+    for path in &paths {
+        fs::write(root.path().join("workspace").join(path), path).unwrap();
+    }
+    let changed: Vec<_> = paths
+        .iter()
+        .map(|path| server.resolve_in_workspace(path).unwrap())
+        .collect();
+    server.file_changed(&changed).unwrap();
+
+    let notification: BulkChangeNotification =
+        envelope::decode(&read_frame(&mut current).unwrap()).unwrap();
+    assert_eq!(notification.paths, paths);
+
+    // older clients don't know the bulk notification and get every change on its own
+    for path in &paths {
+        let notification: FileChangedNotification =
+            rmps::from_slice(&read_frame(&mut legacy).unwrap()).unwrap();
+        assert_eq!(&notification.path, path);
+    }
+
+    drop(current);
+    drop(legacy);
+    current_handle.join().unwrap().unwrap();
+    legacy_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn handshakes_select_a_workspace_by_name() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let other = root.path().join("other");
+    fs::create_dir_all(&other).unwrap();
+    fs::write(other.join("lib.rs"), "pub fn lib() {}").unwrap();
+    server.workspace_name = "main".to_string();
+    server
+        .workspaces
+        .insert("other".to_string(), other.to_str().unwrap().to_string());
+    server.init().unwrap();
+    let server = Arc::new(server);
+    let listener = TcpListener::bind("localhost:0").unwrap();
+
+    let connect = |workspace: &str| {
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Connection::new(listener.accept().unwrap().0);
+        let serving = server.clone();
+        let handle = thread::spawn(move || serving.handle_connection(&mut stream));
+
+        let request = HandshakeRequest::with_workspace(PROTOCOL_VERSION, false, workspace.into());
+        write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+        let response = read_frame(&mut client).unwrap();
+        (client, handle, response)
+    };
+    let complete = |client: &mut TcpStream, pattern: &str| {
+        let request = CompletionRequest::new(pattern.to_string(), 10);
+        write_frame(client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+        let response: CompletionResponse = rmps::from_slice(&read_frame(client).unwrap()).unwrap();
+        response.paths
+    };
+
+    for name in &["", "main"] {
+        let (mut client, handle, _) = connect(name);
+        assert_eq!(complete(&mut client, "main"), vec!["src/main.rs"]);
+        assert!(complete(&mut client, "lib").is_empty());
+        drop(client);
+        handle.join().unwrap().unwrap();
+    }
+
+    let (mut client, handle, _) = connect("other");
+    assert_eq!(complete(&mut client, "lib"), vec!["lib.rs"]);
+    assert!(complete(&mut client, "main").is_empty());
+    drop(client);
+    handle.join().unwrap().unwrap();
+
+    // refused before anything is served
+    let (_client, handle, response) = connect("missing");
+    let response: ErrorResponse = rmps::from_slice(&response).unwrap();
+    assert_eq!(response.code, NOT_FOUND);
+    assert!(matches!(
+        handle.join().unwrap(),
+        Err(ServerError::UnknownWorkspace(name)) if name == "missing"
+    ));
+}
+
+#[test]
+fn each_workspace_has_its_own_hash_salt() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let checkout = root.path().join("checkout");
+    let other = root.path().join("other");
+    fs::create_dir_all(&checkout).unwrap();
+    fs::create_dir_all(&other).unwrap();
+    server.workspaces.insert(
+        "checkout".to_string(),
+        checkout.to_str().unwrap().to_string(),
+    );
+    server
+        .workspaces
+        .insert("other".to_string(), other.to_str().unwrap().to_string());
+    server.hash_salts.insert("other".to_string(), 42);
+    server.init().unwrap();
+
+    let salt = |workspace: &str| server.workspace_daemon(workspace).unwrap().hash_salt();
+    assert_ne!(salt(""), 0);
+    assert_ne!(salt(""), salt("checkout"));
+    assert_eq!(salt("other"), 42);
+
+    // and tells clients about it
+    let request = HandshakeRequest::with_workspace(PROTOCOL_VERSION, false, "other".into());
+    let response: HandshakeResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    assert_eq!(response.salt, 42);
+}
+
+#[test]
+fn preshared_keys_seal_connections() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let key = |key: &str| PresharedKey::new(key.as_bytes().to_vec()).unwrap();
+    server.preshared_key = Some(key("correct horse battery staple"));
+    server.init().unwrap();
+    let server = Arc::new(server);
+    let listener = TcpListener::bind("localhost:0").unwrap();
+
+    let connect = |preshared_key: Option<PresharedKey>| {
+        let mut client =
+            Connection::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let mut stream = Connection::new(listener.accept().unwrap().0);
+        let serving = server.clone();
+        let handle = thread::spawn(move || serving.handle_connection(&mut stream));
+        let response = pool::handshake(&mut client, "", pool::client_id(), preshared_key.as_ref());
+        (client, handle, response)
+    };
+
+    let (mut client, handle, response) = connect(Some(key("correct horse battery staple")));
+    assert_eq!(response.unwrap().protocol_version, PROTOCOL_VERSION);
+    send(&mut client, &CompletionRequest::new("main".to_string(), 10)).unwrap();
+    let response: CompletionResponse = recv(&mut client).unwrap();
+    assert_eq!(response.paths, vec!["src/main.rs"]);
+    drop(client);
+    handle.join().unwrap().unwrap();
+
+    // refused before anything is served
+    let (_client, handle, response) = connect(None);
+    assert!(matches!(
+        response,
+        Err(ClientError::ServerReported { code, .. }) if code == PERMISSION_DENIED
+    ));
+    assert!(matches!(
+        handle.join().unwrap(),
+        Err(ServerError::PresharedKeyRequired)
+    ));
+
+    let (client, handle, response) = connect(Some(key("incorrect horse battery staple")));
+    assert!(matches!(response, Err(ClientError::PresharedKeyMismatch)));
+    drop(client);
+    assert!(handle.join().unwrap().is_ok());
+}
+
+// Connects to `server` as the client with `client_id`
+fn connect_client(server: &Arc<ServerDaemon>, listener: &TcpListener, client_id: u64) -> TcpStream {
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut stream = Connection::new(listener.accept().unwrap().0);
+    let serving = server.clone();
+    thread::spawn(move || serving.handle_connection(&mut stream));
+
+    let mut request = HandshakeRequest::new(PROTOCOL_VERSION, false);
+    request.client_id = client_id;
+    write_frame(&mut client, &rmps::encode::to_vec(&request).unwrap()).unwrap();
+    read_frame(&mut client).unwrap();
+    client
+}
+
+fn cancel(client: &mut TcpStream, request_id: u64) -> bool {
+    let request = envelope::encode(&CancelRequest::new(request_id)).unwrap();
+    write_frame_tagged(client, &request, None, 1).unwrap();
+    let response: CancelResponse = envelope::decode(&read_frame(client).unwrap()).unwrap();
+    response.cancelled
+}
+
+fn send_grep(client: &mut TcpStream, request_id: u64) {
+    let request = GrepRequest::new("main".to_string(), false, false, None);
+    let buf = rmps::encode::to_vec(&request).unwrap();
+    write_frame_tagged(client, &buf, None, request_id).unwrap();
+}
+
+#[test]
+fn cancelled_requests_stop_early() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    // the grep is held up until it has been cancelled
+    let (cancelled, held) = mpsc::channel::<()>();
+    let held = Mutex::new(held);
+    server.set_authorizer(move |msgtype, _| {
+        if msgtype == MessageType::GrepRequest {
+            held.lock().unwrap().recv().unwrap();
+        }
+        true
+    });
+    server.init().unwrap();
+    let server = Arc::new(server);
+    let listener = TcpListener::bind("localhost:0").unwrap();
+
+    // two connections of the same client
+    let mut grep = connect_client(&server, &listener, 7);
+    let mut other = connect_client(&server, &listener, 7);
+    assert!(!cancel(&mut other, 42));
+    send_grep(&mut grep, 42);
+    while !cancel(&mut other, 42) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    cancelled.send(()).unwrap();
+
+    let (request_id, buf) = read_frame_tagged(&mut grep, DEFAULT_MAX_FRAME_SIZE).unwrap();
+    assert_eq!(request_id, 42);
+    let response: ErrorResponse = rmps::from_slice(&buf).unwrap();
+    assert_eq!(response.code, CANCELLED);
+    // finished requests can't be cancelled any more
+    assert!(!cancel(&mut other, 42));
+}
+
+#[test]
+fn cancelling_only_reaches_the_clients_own_requests() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    let (release, held) = mpsc::channel::<()>();
+    let held = Mutex::new(held);
+    server.set_authorizer(move |msgtype, _| {
+        if msgtype == MessageType::GrepRequest {
+            held.lock().unwrap().recv().unwrap();
+        }
+        true
+    });
+    server.init().unwrap();
+    let server = Arc::new(server);
+    let listener = TcpListener::bind("localhost:0").unwrap();
+
+    // both clients number their requests the same way
+    let mut first = connect_client(&server, &listener, 1);
+    let mut second = connect_client(&server, &listener, 2);
+    let mut second_other = connect_client(&server, &listener, 2);
+    send_grep(&mut first, 42);
+    send_grep(&mut second, 42);
+    while !cancel(&mut second_other, 42) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    // nor can a client that didn't say who it is reach either of them
+    let mut anonymous = connect_client(&server, &listener, 0);
+    assert!(!cancel(&mut anonymous, 42));
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+
+    let (request_id, buf) = read_frame_tagged(&mut second, DEFAULT_MAX_FRAME_SIZE).unwrap();
+    assert_eq!(request_id, 42);
+    let response: ErrorResponse = rmps::from_slice(&buf).unwrap();
+    assert_eq!(response.code, CANCELLED);
+    let (request_id, buf) = read_frame_tagged(&mut first, DEFAULT_MAX_FRAME_SIZE).unwrap();
+    assert_eq!(request_id, 42);
+    assert_eq!(
+        peek_message_type(&buf).unwrap(),
+        MessageType::GrepMatchFrame
+    );
+}
+
+#[test]
+fn read_only_servers_refuse_changes() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.read_only = true;
+    server.init().unwrap();
+
+    let request = BatchRequest::new(vec![
+        Envelope::new(
+            rmps::encode::to_vec(&DeleteRequest::new("src/main.rs".to_string(), false)).unwrap(),
+        ),
+        Envelope::new(
+            rmps::encode::to_vec(&CompletionRequest::new("main".to_string(), 10)).unwrap(),
+        ),
+    ]);
+    let response: BatchResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    let refused: ErrorResponse = rmps::from_slice(&response.responses[0].message).unwrap();
+    assert_eq!(refused.code, PERMISSION_DENIED);
+    let completion: CompletionResponse = rmps::from_slice(&response.responses[1].message).unwrap();
+    assert_eq!(completion.paths, vec!["src/main.rs"]);
+    assert!(root.path().join("workspace/src/main.rs").exists());
+
+    let supported = server.supported_message_types();
+    assert!(supported.contains(&MessageType::FileReadRequest));
+    assert!(!supported.contains(&MessageType::DeleteRequest));
+    assert!(!supported.contains(&MessageType::FileWriteRequest));
+}
+
+#[test]
+fn the_authorizer_sees_every_request() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.set_authorizer(|msgtype, caller| {
+        assert!(caller.peer.starts_with("127.0.0.1:") || caller.peer.starts_with("[::1]:"));
+        msgtype != MessageType::CompletionRequest
+    });
+    server.init().unwrap();
+
+    let request = BatchRequest::new(vec![
+        Envelope::new(
+            rmps::encode::to_vec(&CompletionRequest::new("main".to_string(), 10)).unwrap(),
+        ),
+        Envelope::new(rmps::encode::to_vec(&VersionRequest::new()).unwrap()),
+    ]);
+    let response: BatchResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    let refused: ErrorResponse = rmps::from_slice(&response.responses[0].message).unwrap();
+    assert_eq!(refused.code, PERMISSION_DENIED);
+    let _: VersionResponse = rmps::from_slice(&response.responses[1].message).unwrap();
+}
+
+#[test]
+fn batched_requests_are_answered_in_order() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+
+    let envelope = |message: Vec<u8>| Envelope::new(message);
+    let request = BatchRequest::new(vec![
+        envelope(rmps::encode::to_vec(&CompletionRequest::new("main".to_string(), 10)).unwrap()),
+        envelope(
+            rmps::encode::to_vec(&DeleteRequest::new("../secret".to_string(), false)).unwrap(),
+        ),
+        envelope(rmps::encode::to_vec(&BatchRequest::new(Vec::new())).unwrap()),
+        envelope(rmps::encode::to_vec(&WorkspaceInfoRequest::new()).unwrap()),
+    ]);
+    let response: BatchResponse = rmps::from_slice(&serve(&server, request)).unwrap();
+    assert_eq!(response.responses.len(), 4);
+
+    let completion: CompletionResponse = rmps::from_slice(&response.responses[0].message).unwrap();
+    assert_eq!(completion.paths, vec!["src/main.rs"]);
+    // a failed request doesn't stop the ones after it
+    let escape: ErrorResponse = rmps::from_slice(&response.responses[1].message).unwrap();
+    assert_eq!(escape.code, PERMISSION_DENIED);
+    assert!(root.path().join("secret").exists());
+    let nested: ErrorResponse = rmps::from_slice(&response.responses[2].message).unwrap();
+    assert_eq!(nested.code, UNSUPPORTED);
+    let info: WorkspaceInfoResponse = rmps::from_slice(&response.responses[3].message).unwrap();
+    assert!(info.supports(MessageType::BatchRequest));
+}
+
+#[test]
+fn writes_replace_files_whole() {
+    use std::io::Read;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let root = tempfile::tempdir().unwrap();
+    let server = server(&root);
+    let src = root.path().join("workspace/src");
+    fs::set_permissions(src.join("main.rs"), fs::Permissions::from_mode(0o751)).unwrap();
+    symlink("main.rs", src.join("link.rs")).unwrap();
+    let mut before = fs::File::open(src.join("main.rs")).unwrap();
+
+    let request = FileWriteRequest::new(
+        "src/link.rs".to_string(),
+        b"fn main() { 1 }".to_vec(),
+        hash::hash(b"fn main() {}"),
+    );
+    let response: FileWriteResponse = envelope::decode(&serve(&server, request)).unwrap();
+    assert!(!response.conflict);
+
+    // the new contents were renamed into place, a reader of the old file never sees a mix
+    let mut contents = String::new();
+    before.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "fn main() {}");
+    assert_eq!(
+        fs::read_to_string(src.join("main.rs")).unwrap(),
+        "fn main() { 1 }"
+    );
+    let metadata = fs::metadata(src.join("main.rs")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o751);
+    assert!(fs::symlink_metadata(src.join("link.rs"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    let mut names: Vec<_> = fs::read_dir(&src)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["link.rs", "main.rs"]);
+}
+
+#[test]
+fn diffs_are_against_versions_the_server_kept() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+    let write = |path: &str, contents: &[u8], base_hash| {
+        let request = FileWriteRequest::new(path.to_string(), contents.to_vec(), base_hash);
+        let response: FileWriteResponse = envelope::decode(&serve(&server, request)).unwrap();
+        assert!(!response.conflict);
+        response.hash
+    };
+    let diff = |path: &str, client_hash| -> DiffResponse {
+        envelope::decode(&serve(
+            &server,
+            DiffRequest::new(path.to_string(), client_hash),
+        ))
+        .unwrap()
+    };
+
+    let original = hash::hash(b"fn main() {}");
+    let current = write("src/main.rs", b"fn main() {\n    1\n}\n", original);
+    let response = diff("src/main.rs", original);
+    assert_eq!(response.server_hash, current);
+    assert!(!response.identical && !response.too_large);
+    assert_eq!(
+        response.diff,
+        "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1 +1,3 @@\n-fn main() {}\n\\ No newline at end of file\n+fn main() {\n+    1\n+}\n"
+    );
+
+    let response = diff("./src/main.rs", current);
+    assert!(response.identical);
+    assert!(response.diff.is_empty());
+
+    // a version the server never had can't be diffed against
+    let request = DiffRequest::new("src/main.rs".to_string(), 42);
+    let e = request.handle(&mut Connection::new(MemoryTransport::new()), &server);
+    assert_eq!(e.unwrap_err().code(), NOT_FOUND);
+
+    let big = vec![b'x'; MAX_DIFF_SIZE + 1];
+    write("src/main.rs", &big, current);
+    let response = diff("src/main.rs", current);
+    assert!(response.too_large && !response.identical);
+    assert_eq!(response.server_hash, hash::hash(&big));
+    let response = diff("src/main.rs", hash::hash(&big));
+    assert!(response.identical && !response.too_large);
+}
+
+#[test]
+fn overwritten_files_can_be_restored() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+    let main = root.path().join("workspace/src/main.rs");
+
+    let mut base_hash = hash::hash(b"fn main() {}");
+    for contents in &["fn main() { 1 }", "fn main() { 2 }"] {
+        let request = FileWriteRequest::new(
+            "src/main.rs".to_string(),
+            contents.as_bytes().to_vec(),
+            base_hash,
+        );
+        let response: FileWriteResponse = envelope::decode(&serve(&server, request)).unwrap();
+        assert!(!response.conflict);
+        base_hash = response.hash;
+    }
+
+    let request = HistoryRequest::new("./src/main.rs".to_string());
+    let history: HistoryResponse = envelope::decode(&serve(&server, request)).unwrap();
+    let hashes: Vec<_> = history
+        .versions
+        .iter()
+        .map(|version| version.hash)
+        .collect();
+    assert_eq!(
+        hashes,
+        vec![hash::hash(b"fn main() {}"), hash::hash(b"fn main() { 1 }")]
+    );
+
+    let request = RestoreRequest::new("src/main.rs".to_string(), history.versions[0].version);
+    let restored: RestoreResponse = envelope::decode(&serve(&server, request)).unwrap();
+    assert_eq!(restored.hash, hash::hash(b"fn main() {}"));
+    assert_eq!(fs::read_to_string(&main).unwrap(), "fn main() {}");
+
+    // the restore can be undone in turn
+    let request = HistoryRequest::new("src/main.rs".to_string());
+    let history: HistoryResponse = envelope::decode(&serve(&server, request)).unwrap();
+    assert_eq!(history.versions.len(), 3);
+    assert_eq!(history.versions[2].hash, hash::hash(b"fn main() { 2 }"));
+
+    let request = RestoreRequest::new("src/main.rs".to_string(), 99);
+    let e = request.handle(&mut Connection::new(MemoryTransport::new()), &server);
+    assert_eq!(e.unwrap_err().code(), NOT_FOUND);
+}
+
+#[test]
+fn rescans_pick_up_changes_the_watcher_missed() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+    let before = server.index().unwrap().hash;
+
+    const CONTENTS: &[u8] = b"pub fn lib() {}";
+    let workspace = root.path().join("workspace");
+    fs::write(workspace.join("src/lib.rs"), CONTENTS).unwrap();
+    fs::remove_file(workspace.join("src/main.rs")).unwrap();
+
+    let request = RescanRequest::new(false);
+    let response: RescanResponse = envelope::decode(&serve(&server, request)).unwrap();
+    assert_ne!(response.hash, before);
+    assert_eq!(response.files, 1);
+    assert_eq!(response.changed, 2);
+    let index = server.index().unwrap();
+    assert_eq!(index.hash, response.hash);
+    assert_eq!(index.files["src/lib.rs"].content_hash, hash::hash(CONTENTS));
+    drop(index);
+
+    // rescans asked for at once are answered alike, with nothing left to change
+    let server = Arc::new(server);
+    let rescans: Vec<_> = (0..4)
+        .map(|_| {
+            let server = server.clone();
+            thread::spawn(move || server.rescan(false).unwrap())
+        })
+        .collect();
+    for rescan in rescans {
+        let rescanned = rescan.join().unwrap();
+        assert_eq!(rescanned.hash, response.hash);
+        assert_eq!(rescanned.changed, 0);
+    }
+}
+
+#[test]
+fn changes_since_lists_the_files_a_client_missed() {
+    let root = tempfile::tempdir().unwrap();
+    let mut server = server(&root);
+    server.init().unwrap();
+    let workspace = root.path().join("workspace");
+    let changes_since = |since_hash| -> ChangesSinceResponse {
+        envelope::decode(&serve(&server, ChangesSinceRequest::new(since_hash))).unwrap()
+    };
+
+    let first = server.index().unwrap().hash;
+    fs::write(workspace.join("src/main.rs"), "fn main() { 1 }").unwrap();
+    server
+        .file_changed(&[workspace.join("src/main.rs")])
+        .unwrap();
+    let second = server.index().unwrap().hash;
+    fs::write(workspace.join("src/lib.rs"), "pub fn lib() {}").unwrap();
+    server.rescan(false).unwrap();
+
+    let response = changes_since(first);
+    assert!(!response.too_old);
+    assert_eq!(response.paths, vec!["src/lib.rs", "src/main.rs"]);
+    assert_eq!(response.index_hash, server.index().unwrap().hash);
+    assert_eq!(changes_since(second).paths, vec!["src/lib.rs"]);
+
+    let response = changes_since(response.index_hash);
+    assert!(!response.too_old);
+    assert!(response.paths.is_empty());
+
+    // a hash the index never had, or one from before a restart, can't be caught up on
+    let response = changes_since(42);
+    assert!(response.too_old);
+    assert!(response.paths.is_empty());
+    let mut restarted = self::server(&root);
+    restarted.init().unwrap();
+    let response: ChangesSinceResponse =
+        envelope::decode(&serve(&restarted, ChangesSinceRequest::new(second))).unwrap();
+    assert!(response.too_old);
+}
+
+#[test]
+fn chunked_reads_resume_where_they_were_cut_short() {
+    let root = tempfile::tempdir().unwrap();
+    let server = server(&root);
+    let contents: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+    fs::write(root.path().join("workspace/big.log"), &contents).unwrap();
+    let h = hash::hash(&contents);
+
+    let server = &server;
+    let read = |request: FileReadRequest| {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Connection::new(listener.accept().unwrap().0);
+        // the chunks don't fit in the socket buffers, they are read as they are written
+        thread::scope(|scope| {
+            scope.spawn(move || request.handle(&mut stream, server).unwrap());
+            let response: FileReadResponse =
+                envelope::decode(&read_frame(&mut client).unwrap()).unwrap();
+            let chunks: Vec<FileChunk> = (0..response.chunk_count)
+                .map(|_| envelope::decode(&read_frame(&mut client).unwrap()).unwrap())
+                .collect();
+            (response, chunks)
+        })
+    };
+
+    let (response, chunks) = read(FileReadRequest::resumed(
+        "big.log".to_string(),
+        true,
+        h,
+        CHUNK_SIZE as u64,
+    ));
+    assert_eq!(response.offset, CHUNK_SIZE as u64);
+    assert_eq!(response.total_size, contents.len() as u64);
+    let indices: Vec<u32> = chunks.iter().map(|chunk| chunk.index).collect();
+    assert_eq!(indices, vec![1, 2]);
+    let rest: Vec<u8> = chunks.into_iter().flat_map(|chunk| chunk.data).collect();
+    assert_eq!(rest, &contents[CHUNK_SIZE..]);
+
+    // a part of some other version of the file is no use, all of it is sent again
+    let (response, chunks) = read(FileReadRequest::resumed(
+        "big.log".to_string(),
+        true,
+        h ^ 1,
+        CHUNK_SIZE as u64,
+    ));
+    assert_eq!(response.offset, 0);
+    assert_eq!(chunks.len(), 3);
+}
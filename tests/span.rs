@@ -0,0 +1,53 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use tracing::info_span;
+
+use emacs_remote::messages::messagetype::MessageType;
+use emacs_remote::utils::span;
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn spans_are_printed_with_the_request_they_are_entered_in() {
+    let captured = Captured::default();
+    let subscriber = span::subscriber({
+        let captured = captured.clone();
+        move || captured.clone()
+    });
+    tracing::subscriber::with_default(subscriber, || {
+        let msgtype = MessageType::BatchRequest;
+        let _request =
+            info_span!(target: span::TARGET, "request", msgtype = ?msgtype, request_id = 3)
+                .entered();
+        let _decode = info_span!(target: span::TARGET, "decode").entered();
+        // spans of other targets are left out
+        let _other = info_span!("other").entered();
+    });
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", output);
+    assert!(
+        lines[0].contains("request{msgtype=BatchRequest request_id=3}:decode: close time.busy="),
+        "{}",
+        lines[0]
+    );
+    assert!(
+        lines[1].contains("request{msgtype=BatchRequest request_id=3}: close time.busy="),
+        "{}",
+        lines[1]
+    );
+}
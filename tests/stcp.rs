@@ -0,0 +1,234 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use emacs_remote::utils::stcp::{
+    forward_failed, host_key_problem, is_env_name, HostKeyProblem, SSHOptions, STCPSession,
+    TunnelStatus,
+};
+
+// Stands in for ssh, recording its arguments and printing what a changed host key prints
+fn fake_ssh(dir: &Path) -> String {
+    let script = dir.join("ssh");
+    let log = dir.join("args");
+    fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\n\
+             echo \"$@\" >> {}\n\
+             echo '@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@' >&2\n\
+             echo '@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @' >&2\n\
+             echo 'Host key verification failed.' >&2\n\
+             exit 255\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    script.to_string_lossy().to_string()
+}
+
+#[test]
+fn changed_host_keys_are_not_retried() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = SSHOptions {
+        binary: fake_ssh(dir.path()),
+        ..SSHOptions::default()
+    };
+    let session = STCPSession::new(
+        "example.com".to_string(),
+        50000,
+        0,
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        "/workspace".to_string(),
+        options,
+        None,
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while session.status() != TunnelStatus::HostKeyChanged {
+        assert!(Instant::now() < deadline, "status {:?}", session.status());
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // long enough for the first retry, had there been one
+    thread::sleep(Duration::from_millis(1500));
+    let args = fs::read_to_string(dir.path().join("args")).unwrap();
+    assert_eq!(args.lines().count(), 1);
+    assert!(args.contains("-o StrictHostKeyChecking=accept-new example.com"));
+    assert!(args.contains("--log-file server_"));
+}
+
+#[test]
+fn host_key_problems_are_told_apart() {
+    assert_eq!(
+        host_key_problem("@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @"),
+        Some(HostKeyProblem::Changed)
+    );
+    assert_eq!(
+        host_key_problem("Host key verification failed."),
+        Some(HostKeyProblem::Unverified)
+    );
+    assert_eq!(
+        host_key_problem("Warning: Permanently added 'example.com'"),
+        None
+    );
+}
+
+#[test]
+fn busy_local_ports_are_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    // fails the forward the first time it is run, then stays up like a working tunnel
+    let script = dir.path().join("ssh");
+    fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\n\
+             echo \"$@\" >> {args}\n\
+             if [ ! -e {ran} ]; then\n\
+               touch {ran}\n\
+               echo 'bind [127.0.0.1]:1: Address already in use' >&2\n\
+               echo 'channel_setup_fwd_listener_tcpip: cannot listen to port: 1' >&2\n\
+               echo 'Could not request local forwarding.' >&2\n\
+               exit 255\n\
+             fi\n\
+             sleep 30\n",
+            args = dir.path().join("args").display(),
+            ran = dir.path().join("ran").display(),
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let options = SSHOptions {
+        binary: script.to_string_lossy().to_string(),
+        ..SSHOptions::default()
+    };
+
+    let session = STCPSession::new(
+        "example.com".to_string(),
+        50000,
+        0,
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        "/workspace".to_string(),
+        options,
+        None,
+    );
+    let first_port = session.client_port();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while session.status() != TunnelStatus::Connected {
+        assert!(Instant::now() < deadline, "status {:?}", session.status());
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_ne!(session.client_port(), first_port);
+
+    let args = fs::read_to_string(dir.path().join("args")).unwrap();
+    let forwards: Vec<&str> = args
+        .lines()
+        .map(|line| line.split(' ').nth(1).unwrap())
+        .collect();
+    assert_eq!(forwards.len(), 2);
+    assert!(forwards[0].starts_with(&format!("127.0.0.1:{}:", first_port)));
+    assert!(forwards[1].starts_with(&format!("127.0.0.1:{}:", session.client_port())));
+    assert!(args.contains("-o ExitOnForwardFailure=yes"));
+}
+
+#[test]
+fn forward_failures_are_recognized() {
+    assert!(forward_failed(
+        "channel_setup_fwd_listener_tcpip: cannot listen to port: 9130"
+    ));
+    assert!(forward_failed("bind [::1]:9130: Address already in use"));
+    assert!(!forward_failed("Warning: Permanently added 'example.com'"));
+}
+
+// Stands in for ssh, running `body` after recording that it was started
+fn scripted_ssh(dir: &Path, body: &str) -> SSHOptions {
+    let script = dir.join("ssh");
+    fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\n{}\n",
+            dir.join("args").display(),
+            body
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    SSHOptions {
+        binary: script.to_string_lossy().to_string(),
+        ..SSHOptions::default()
+    }
+}
+
+fn start(options: SSHOptions) -> STCPSession {
+    STCPSession::new(
+        "example.com".to_string(),
+        50000,
+        0,
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        "/workspace".to_string(),
+        options,
+        None,
+    )
+}
+
+#[test]
+fn dropping_a_session_stops_ssh_promptly() {
+    // a tunnel that is up
+    let dir = tempfile::tempdir().unwrap();
+    let session = start(scripted_ssh(dir.path(), "exec sleep 30"));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while session.status() != TunnelStatus::Connected {
+        assert!(Instant::now() < deadline, "status {:?}", session.status());
+        thread::sleep(Duration::from_millis(50));
+    }
+    let dropped = Instant::now();
+    drop(session);
+    assert!(dropped.elapsed() < Duration::from_millis(500));
+
+    // and one backing off between attempts
+    let dir = tempfile::tempdir().unwrap();
+    let session = start(scripted_ssh(dir.path(), "exit 1"));
+    while !matches!(session.status(), TunnelStatus::Retrying { .. }) {
+        assert!(Instant::now() < deadline, "status {:?}", session.status());
+        thread::sleep(Duration::from_millis(50));
+    }
+    let dropped = Instant::now();
+    drop(session);
+    assert!(dropped.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn remote_env_is_set_for_the_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = SSHOptions {
+        env: vec![
+            ("RUST_LOG".to_string(), "debug".to_string()),
+            ("GREETING".to_string(), "it's $HOME".to_string()),
+        ],
+        ..scripted_ssh(dir.path(), "exec sleep 30")
+    };
+    let session = start(options);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while session.status() != TunnelStatus::Connected {
+        assert!(Instant::now() < deadline, "status {:?}", session.status());
+        thread::sleep(Duration::from_millis(50));
+    }
+    drop(session);
+
+    let args = fs::read_to_string(dir.path().join("args")).unwrap();
+    assert!(
+        args.contains("RUST_LOG='debug' GREETING='it'\\''s $HOME' ~/.emacs_remote/bin/emacs-remote-server -w /workspace"),
+        "{}",
+        args
+    );
+    assert!(is_env_name("RUST_LOG"));
+    assert!(is_env_name("_x1"));
+    assert!(!is_env_name("1X"));
+    assert!(!is_env_name("A B"));
+    assert!(!is_env_name(""));
+}
@@ -0,0 +1,119 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use emacs_remote::messages::completion::{CompletionRequest, CompletionResponse};
+use emacs_remote::messages::dirlist::{DirectoryListRequest, DirectoryListResponse};
+use emacs_remote::structs::batch::Batch;
+use emacs_remote::structs::client::ClientDaemon;
+use emacs_remote::structs::server::ServerDaemon;
+use emacs_remote::utils::transport::{Endpoint, Listener, SocketOptions, Transport};
+
+#[test]
+fn daemons_talk_over_a_unix_socket() {
+    let root = tempfile::tempdir().unwrap();
+    let workspace = root.path().join("workspace");
+    fs::create_dir_all(workspace.join("src")).unwrap();
+    fs::write(workspace.join("src/main.rs"), "fn main() {}").unwrap();
+    let socket = root.path().join("server.sock");
+
+    let mut server = ServerDaemon::new(
+        root.path().join("server").to_str().unwrap().to_string(),
+        "0".to_string(),
+        workspace.to_str().unwrap().to_string(),
+    )
+    .unwrap();
+    server.socket = Some(socket.clone());
+    server.init().unwrap();
+    let shutdown = server.shutdown_flag();
+    let server = Arc::new(server);
+    let handle = thread::spawn(move || server.listen());
+    while !socket.exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mode = fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut client = ClientDaemon::new(
+        "localhost".to_string(),
+        workspace.to_str().unwrap().to_string(),
+        root.path().join("client").to_str().unwrap().to_string(),
+    )
+    .unwrap();
+    client.set_socket(Some(socket.clone()));
+    client.reconnect().unwrap();
+    client.ping().unwrap();
+    assert_eq!(client.complete("main", 10).unwrap(), vec!["src/main.rs"]);
+
+    let mut batch = Batch::new();
+    let completion = batch
+        .push(&CompletionRequest::new("main".to_string(), 10))
+        .unwrap();
+    let listing = batch
+        .push(&DirectoryListRequest::new("src".to_string(), 1))
+        .unwrap();
+    let results = client.flush(batch).unwrap();
+    let response: CompletionResponse = results.get(completion).unwrap();
+    assert_eq!(response.paths, vec!["src/main.rs"]);
+    let response: DirectoryListResponse = results.get(listing).unwrap();
+    assert_eq!(response.entries.len(), 1);
+    drop(client);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap().unwrap();
+    assert!(!socket.exists());
+}
+
+#[test]
+fn live_sockets_are_not_taken_over() {
+    let dir = tempfile::tempdir().unwrap();
+    let endpoint = Endpoint::Unix(dir.path().join("daemon.sock"));
+
+    let listener = Listener::bind(&endpoint).unwrap();
+    match Listener::bind(&endpoint) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::AddrInUse),
+        Ok(_) => panic!("bound a socket that is still being listened on"),
+    }
+    drop(listener);
+
+    // a socket file left behind by a daemon that died is replaced
+    let path = dir.path().join("stale.sock");
+    std::os::unix::net::UnixListener::bind(&path).unwrap();
+    assert!(path.exists());
+    Listener::bind(&Endpoint::Unix(path)).unwrap();
+}
+
+#[test]
+fn socket_options_are_applied() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    assert!(!stream.nodelay().unwrap());
+
+    let options = SocketOptions {
+        nodelay: true,
+        buffer_size: Some(256 * 1024),
+    };
+    stream.apply(&options).unwrap();
+    assert!(stream.nodelay().unwrap());
+
+    let mut size: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &mut size as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(result, 0);
+    // the kernel may round the size up, e.g. Linux doubles it for its own bookkeeping
+    assert!(size as usize >= 256 * 1024);
+}
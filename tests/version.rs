@@ -0,0 +1,17 @@
+use emacs_remote::version::{version_skew, VersionSkew, LONG_VERSION, VERSION};
+
+#[test]
+fn version_skew_compares_major_and_minor() {
+    assert_eq!(version_skew("1.2.3", "1.2.7"), VersionSkew::None);
+    assert_eq!(version_skew("1.2", "1.2.0"), VersionSkew::None);
+    assert_eq!(version_skew("1.2.3", "1.3.0"), VersionSkew::Minor);
+    assert_eq!(version_skew("1.2.3", "2.2.3"), VersionSkew::Major);
+    // nothing to compare, assume the worst
+    assert_eq!(version_skew("1.2.3", "dev"), VersionSkew::Major);
+}
+
+#[test]
+fn long_version_includes_the_build() {
+    assert!(LONG_VERSION.starts_with(VERSION));
+    assert!(LONG_VERSION.ends_with(')'));
+}
@@ -0,0 +1,66 @@
+use std::fs;
+use std::path::Path;
+
+use emacs_remote::utils::walk::{is_ignored_with, walk_files, walk_files_with, IGNORE_FILE};
+
+fn touch(root: &Path, path: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, "").unwrap();
+}
+
+#[test]
+fn walk_files_respects_ignore_files() {
+    let root = tempfile::tempdir().unwrap();
+    touch(root.path(), "src/main.rs");
+    touch(root.path(), "target/debug/build.rs");
+    touch(root.path(), "node_modules/pkg/index.js");
+    touch(root.path(), "logs/server.log");
+    touch(root.path(), ".git/HEAD");
+    fs::write(root.path().join(".gitignore"), "target/\n").unwrap();
+    fs::write(root.path().join(".ignore"), "node_modules/\n").unwrap();
+    fs::write(root.path().join(IGNORE_FILE), "*.log\n").unwrap();
+
+    let mut files: Vec<String> = walk_files(root.path())
+        .iter()
+        .map(|file| {
+            let file = file.strip_prefix(root.path()).unwrap();
+            file.to_string_lossy().to_string()
+        })
+        .collect();
+    files.sort();
+
+    assert_eq!(
+        files,
+        vec![IGNORE_FILE, ".gitignore", ".ignore", "src/main.rs"]
+    );
+}
+
+#[test]
+fn configured_patterns_are_ignored() {
+    let root = tempfile::tempdir().unwrap();
+    touch(root.path(), "src/main.rs");
+    touch(root.path(), "target/debug/build.rs");
+    touch(root.path(), "src/gen.o");
+
+    let patterns = vec!["target/".to_string(), "*.o".to_string()];
+    let files: Vec<String> = walk_files_with(root.path(), &patterns)
+        .iter()
+        .map(|file| {
+            let file = file.strip_prefix(root.path()).unwrap();
+            file.to_string_lossy().to_string()
+        })
+        .collect();
+    assert_eq!(files, vec!["src/main.rs"]);
+
+    assert!(is_ignored_with(
+        root.path(),
+        &root.path().join("target/debug/build.rs"),
+        &patterns
+    ));
+    assert!(!is_ignored_with(
+        root.path(),
+        &root.path().join("src/main.rs"),
+        &patterns
+    ));
+}
@@ -0,0 +1,65 @@
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use emacs_remote::utils::metrics::Metrics;
+use emacs_remote::utils::workers::Workers;
+
+#[test]
+fn jobs_past_the_queue_are_handed_back() {
+    let metrics = Arc::new(Metrics::new());
+    let (release, held) = mpsc::channel::<()>();
+    let held = Mutex::new(held);
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let workers = {
+        let handled = handled.clone();
+        Workers::new(1, 1, metrics.clone(), move |job: u32| {
+            held.lock().unwrap().recv().unwrap();
+            handled.lock().unwrap().push(job);
+        })
+    };
+
+    workers.submit(1).unwrap();
+    while workers.busy() == 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    workers.submit(2).unwrap();
+    assert_eq!(workers.submit(3), Err(3));
+
+    let rendered = metrics.render();
+    for expected in &[
+        "emacs_remote_busy_workers 1",
+        "emacs_remote_queued_connections 1",
+    ] {
+        assert!(
+            rendered.lines().any(|line| line == *expected),
+            "{}",
+            expected
+        );
+    }
+
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    workers.join();
+    assert_eq!(*handled.lock().unwrap(), vec![1, 2]);
+    assert_eq!(metrics.queued_connections(), 0);
+}
+
+#[test]
+fn a_panicking_job_leaves_its_worker_running() {
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let workers = {
+        let handled = handled.clone();
+        Workers::new(1, 2, Arc::new(Metrics::new()), move |job: u32| {
+            if job == 1 {
+                panic!("job {}", job);
+            }
+            handled.lock().unwrap().push(job);
+        })
+    };
+    workers.submit(1).unwrap();
+    workers.submit(2).unwrap();
+    workers.join();
+    assert_eq!(*handled.lock().unwrap(), vec![2]);
+}